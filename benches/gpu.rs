@@ -0,0 +1,151 @@
+//! GPU benchmarks of the optimization and rendering passes
+//!
+//! Timings depend heavily on the GPU and driver, so there are no absolute reference numbers.
+//! Save a baseline before a change with `cargo bench -- --save-baseline before`, and compare
+//! with `cargo bench -- --baseline before`. On a given machine, expect:
+//! - `optimize/*`: throughput roughly inversely proportional to the number of grid cells
+//!   (19², 32² and 56² cells for the small, medium and large grids)
+//! - `render/*`: 2048² about 16 times slower than 512² once fragment-bound
+//! - `readback/*`: bandwidth close to the PCIe transfer rate for the large grid
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tinygl::prelude::*;
+
+use phasor::probe::HeadlessContext;
+use phasor::*;
+
+/// Noise bandwidths of the benchmarked grid sizes
+const GRIDS: &[(&str, f32)] = &[("small", 1.0), ("medium", 1.692_568_8), ("large", 3.0)];
+
+/// Wait for all submitted GL commands to complete
+fn wait_gpu(gl: &Rc<tinygl::Context>) {
+    unsafe {
+        let fence = gl
+            .fence_sync(tinygl::gl::SYNC_GPU_COMMANDS_COMPLETE, 0)
+            .expect("failed to create fence");
+        gl.client_wait_sync(fence, tinygl::gl::SYNC_FLUSH_COMMANDS_BIT, std::i32::MAX);
+        gl.delete_sync(fence);
+    }
+}
+
+/// Time `iters` runs of `f`, including the time for the GPU to complete them
+fn time_gpu(gl: &Rc<tinygl::Context>, iters: u64, mut f: impl FnMut()) -> Duration {
+    wait_gpu(gl);
+
+    let start = Instant::now();
+    for _ in 0..iters {
+        f();
+    }
+    wait_gpu(gl);
+
+    start.elapsed()
+}
+
+fn grid_params(noise_bandwidth: f32) -> Params {
+    Params {
+        noise_bandwidth,
+        grid_size: Params::compute_grid_size(noise_bandwidth),
+        ..Default::default()
+    }
+}
+
+fn gpu_benchmarks(c: &mut Criterion) {
+    if !probe::gl_available() {
+        eprintln!("skipped: no GL");
+        c.benchmark_group("gpu").finish();
+        return;
+    }
+
+    let context = HeadlessContext::new(512, 512).expect("failed to create context");
+    let gl = &context.gl;
+
+    let vao = tinygl::wrappers::VertexArray::new(gl).expect("failed to create VAO");
+    unsafe {
+        vao.bind(gl);
+    }
+
+    let mut state = State::new(gl).expect("failed to initialize state");
+
+    let steps = 4;
+    let mut group = c.benchmark_group("optimize");
+    group.throughput(Throughput::Elements(steps as u64));
+    for (name, noise_bandwidth) in GRIDS {
+        let params = grid_params(*noise_bandwidth);
+        state
+            .run_init(gl, &params)
+            .expect("failed to initialize kernels");
+
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| {
+                time_gpu(gl, iters, || {
+                    state
+                        .run_optimize(gl, OptimizationMode::Optimize, steps, &params)
+                        .expect("failed to optimize");
+                })
+            })
+        });
+    }
+    group.finish();
+
+    let params = Params::default();
+    state
+        .run_init(gl, &params)
+        .expect("failed to initialize kernels");
+
+    let mut buffer_main = Vec::new();
+    let mut buffer_extra = Vec::new();
+    let mut group = c.benchmark_group("render");
+    group.sample_size(20);
+    for size in &[512, 2048] {
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter_custom(|iters| {
+                time_gpu(gl, iters, || {
+                    state
+                        .render_to_texture(
+                            gl,
+                            *size,
+                            *size,
+                            1,
+                            NormalizeMode::None,
+                            DisplayMode::Noise,
+                            &params,
+                            &mut buffer_main,
+                            &mut buffer_extra,
+                        )
+                        .expect("failed to render");
+                })
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("readback");
+    for (name, noise_bandwidth) in GRIDS {
+        let params = grid_params(*noise_bandwidth);
+        state
+            .run_init(gl, &params)
+            .expect("failed to initialize kernels");
+
+        let mut kernels = vec![
+            0.0f32;
+            (params.grid_size.x * params.grid_size.y * params.grid_size.z)
+                as usize
+                * params.kernel_count as usize
+                * shared_types::NFLOATS
+        ];
+
+        group.throughput(Throughput::Bytes(
+            (kernels.len() * std::mem::size_of::<f32>()) as u64,
+        ));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_custom(|iters| time_gpu(gl, iters, || state.read_kernels(gl, &mut kernels)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, gpu_benchmarks);
+criterion_main!(benches);
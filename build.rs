@@ -1,6 +1,13 @@
 use std::env;
 use std::path::PathBuf;
 
+/// Comment at the top of the C header, on how the functions find their instance
+const C_API_HEADER: &str = "/*
+ * Functions ending in _h act on the instance of the given handle, created by pg_create. The
+ * other functions act on the default instance created by pg_init, and fail with an invalid
+ * handle error reported by pg_get_error until it is called.
+ */";
+
 fn main() {
     let mut compiler = tinygl_compiler::CompilerBuilder::new().build().unwrap();
 
@@ -8,12 +15,23 @@ fn main() {
     let display_vert = compiler.wrap_shader("shaders/display.vert").unwrap();
     let init_comp = compiler.wrap_shader("shaders/init.comp").unwrap();
     let opt_comp = compiler.wrap_shader("shaders/opt.comp").unwrap();
+    let range_comp = compiler.wrap_shader("shaders/range.comp").unwrap();
+    let normalize_frag = compiler.wrap_shader("shaders/normalize.frag").unwrap();
+    let kernel_viz_vert = compiler.wrap_shader("shaders/kernel_viz.vert").unwrap();
+    let kernel_viz_frag = compiler.wrap_shader("shaders/kernel_viz.frag").unwrap();
 
     let display_prog = compiler
         .wrap_program(&[&display_vert, &display_frag], "display")
         .unwrap();
     let init_prog = compiler.wrap_program(&[&init_comp], "init").unwrap();
     let opt_prog = compiler.wrap_program(&[&opt_comp], "opt").unwrap();
+    let range_prog = compiler.wrap_program(&[&range_comp], "range").unwrap();
+    let normalize_prog = compiler
+        .wrap_program(&[&display_vert, &normalize_frag], "normalize")
+        .unwrap();
+    let kernel_viz_prog = compiler
+        .wrap_program(&[&kernel_viz_vert, &kernel_viz_frag], "kernel_viz")
+        .unwrap();
 
     let shared_uniforms = compiler
         .wrap_uniforms(&[&init_prog, &display_prog], "shared")
@@ -30,9 +48,16 @@ fn main() {
                 &display_vert,
                 &init_comp,
                 &opt_comp,
+                &range_comp,
+                &normalize_frag,
+                &kernel_viz_vert,
+                &kernel_viz_frag,
                 &display_prog,
                 &init_prog,
                 &opt_prog,
+                &range_prog,
+                &normalize_prog,
+                &kernel_viz_prog,
                 &shared_uniforms,
                 &global_uniforms,
             ],
@@ -56,6 +81,7 @@ fn main() {
         .with_config(cbindgen::Config {
             cpp_compat: true,
             language: cbindgen::Language::C,
+            header: Some(C_API_HEADER.to_owned()),
             includes: vec!["shaders/shared.h".to_owned()],
             ..Default::default()
         })
@@ -5,15 +5,17 @@ fn main() {
 
     let mesh_vert = compiler.wrap_shader("shaders/mesh.frag").unwrap();
     let mesh_frag = compiler.wrap_shader("shaders/mesh.vert").unwrap();
+    let gcode_comp = compiler.wrap_shader("shaders/gcode.comp").unwrap();
 
     let mesh_prog = compiler
         .wrap_program(&[&mesh_vert, &mesh_frag], "mesh")
         .unwrap();
+    let gcode_prog = compiler.wrap_program(&[&gcode_comp], "gcode").unwrap();
 
     compiler
         .write_root_include(
             env::var("OUT_DIR").unwrap(),
-            &[&mesh_vert, &mesh_frag, &mesh_prog],
+            &[&mesh_vert, &mesh_frag, &gcode_comp, &mesh_prog, &gcode_prog],
         )
         .unwrap();
 }
@@ -0,0 +1,29 @@
+;FLAVOR:Griffin
+;TIME:1234
+;EXTRUDER_TRAIN.0.INITIAL_TEMPERATURE:210
+;EXTRUDER_TRAIN.0.MATERIAL.VOLUME_USED:1520
+;EXTRUDER_TRAIN.0.NOZZLE.DIAMETER:0.4
+;EXTRUDER_TRAIN.0.NOZZLE.NAME:AA 0.4
+;EXTRUDER_TRAIN.1.INITIAL_TEMPERATURE:210
+;EXTRUDER_TRAIN.1.NOZZLE.DIAMETER:0.8
+;EXTRUDER_TRAIN.1.NOZZLE.NAME:BB 0.8
+;BUILD_PLATE.INITIAL_TEMPERATURE:60
+;PRINT.SIZE.MIN.X:9
+;PRINT.SIZE.MIN.Y:6
+;PRINT.SIZE.MIN.Z:0.27
+;END_OF_HEADER
+;Generated with Cura_SteamEngine 4.8.0
+T0
+M82
+G92 E0
+;LAYER_COUNT:2
+;LAYER:0
+M107
+G0 F3600 X10 Y10 Z0.27
+;TYPE:WALL-OUTER
+G1 F1500 X20 Y10 E0.5
+;LAYER:1
+G0 X10 Y10 Z0.37
+;TYPE:SKIN
+G1 X20 Y10 E1.0
+;SETTING_3 {"global_quality": "[values]\\nmaterial_diameter = 2.85\\n"}
@@ -0,0 +1,16 @@
+; Generated by IceSL
+; nozzle_diameter_mm_0 : 0.4
+; nozzle_diameter_mm_1 : 0.6
+; filament_diameter_mm_0 : 1.75
+; z_layer_height_mm : 0.2
+; print_perimeter : true
+G90
+M82
+G28
+G92 E0
+; <layer>
+G0 F6000 X10 Y10 Z0.2
+; perimeter
+G1 F1200 X20 Y10 E0.5
+G1 X20 Y20 E1.0
+; </layer>
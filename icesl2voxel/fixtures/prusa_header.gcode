@@ -0,0 +1,32 @@
+; generated by PrusaSlicer 2.3.0+linux-x64 on 2021-01-11 at 14:02:31 UTC
+
+; external perimeters extrusion width = 0.45mm
+; perimeters extrusion width = 0.45mm
+; infill extrusion width = 0.45mm
+
+M73 P0 R12
+M201 X1000 Y1000 Z200 E5000
+G21 ; set units to millimeters
+G90 ; use absolute coordinates
+M82 ; use absolute distances for extrusion
+G92 E0
+;LAYER_CHANGE
+;Z:0.2
+;HEIGHT:0.2
+G1 Z0.200 F10800.000
+G1 X10.000 Y10.000
+;TYPE:Perimeter
+G1 X20 Y10 E0.5
+;LAYER_CHANGE
+;Z:0.4
+;HEIGHT:0.2
+G1 Z0.400 F10800.000
+G1 X10.000 Y10.000
+;TYPE:Solid infill
+G1 X20 Y20 E1.0
+
+; filament used [mm] = 12.34
+; nozzle_diameter = 0.4,0.25
+; filament_diameter = 1.75,2.85
+; layer_height = 0.2
+; printer_model = MK3S
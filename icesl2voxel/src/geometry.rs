@@ -1,11 +1,182 @@
 use std::fs::File;
+use std::io::BufRead;
 use std::path::Path;
 
-use super::utils::BoundingBox;
+use super::utils::{is_gzip_path, open_input, strip_gzip_extension, BoundingBox};
 
+mod marching_cubes;
+
+pub use marching_cubes::extract_isosurface;
+
+/// Load an STL or OBJ mesh. OBJ meshes may be gzip-compressed, but STL meshes are read with
+/// seeks and must be uncompressed.
 pub fn load_mesh(mesh_path: &Path) -> Result<stl_io::IndexedMesh, failure::Error> {
-    let mut mesh = File::open(mesh_path)?;
-    Ok(stl_io::read_stl(&mut mesh)?)
+    match strip_gzip_extension(mesh_path)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .as_deref()
+    {
+        Some("obj") => read_obj(&mut open_input(mesh_path)?),
+        _ if is_gzip_path(mesh_path) => Err(failure::err_msg(format!(
+            "{}: compressed STL meshes are not supported",
+            mesh_path.display()
+        ))),
+        _ => Ok(stl_io::read_stl(&mut File::open(mesh_path)?)?),
+    }
+}
+
+fn parse_obj_index(token: &str, vertex_count: usize) -> Result<usize, failure::Error> {
+    // Faces reference vertices as v, v/vt, v//vn or v/vt/vn
+    let index: isize = token.split('/').next().unwrap_or("").parse()?;
+
+    let resolved = if index < 0 {
+        // Negative indices are relative to the end of the current vertex list
+        vertex_count as isize + index
+    } else {
+        // Positive indices are 1-based
+        index - 1
+    };
+
+    if resolved < 0 || resolved >= vertex_count as isize {
+        return Err(failure::err_msg(format!(
+            "invalid vertex index in obj face: {}",
+            token
+        )));
+    }
+
+    Ok(resolved as usize)
+}
+
+/// Read a Wavefront OBJ mesh. Only vertex positions and faces are considered, non-triangular faces
+/// are fan-triangulated and materials are ignored.
+pub fn read_obj(src: &mut dyn BufRead) -> Result<stl_io::IndexedMesh, failure::Error> {
+    let mut vertices: Vec<stl_io::Vertex> = Vec::new();
+    let mut faces = Vec::new();
+
+    for (line_idx, line) in src.lines().enumerate() {
+        let line = line?;
+        let mut tokens = line.split_whitespace();
+
+        match tokens.next() {
+            Some("v") => {
+                let coords: Result<Vec<f32>, _> = tokens.take(3).map(str::parse).collect();
+                let coords = coords.map_err(|e| {
+                    failure::err_msg(format!(
+                        "invalid obj vertex on line {}: {}",
+                        line_idx + 1,
+                        e
+                    ))
+                })?;
+
+                if coords.len() != 3 {
+                    return Err(failure::err_msg(format!(
+                        "invalid obj vertex on line {}: expected 3 coordinates",
+                        line_idx + 1
+                    )));
+                }
+
+                vertices.push([coords[0], coords[1], coords[2]]);
+            }
+            Some("f") => {
+                let indices: Result<Vec<usize>, _> = tokens
+                    .map(|token| parse_obj_index(token, vertices.len()))
+                    .collect();
+                let indices = indices?;
+
+                if indices.len() < 3 {
+                    warn!("skipping degenerate obj face on line {}", line_idx + 1);
+                    continue;
+                }
+
+                // Fan triangulation
+                for i in 1..indices.len() - 1 {
+                    let tri = [indices[0], indices[i], indices[i + 1]];
+
+                    let v0 = nalgebra::Vector3::from(vertices[tri[0]]);
+                    let v1 = nalgebra::Vector3::from(vertices[tri[1]]);
+                    let v2 = nalgebra::Vector3::from(vertices[tri[2]]);
+                    let normal = (v1 - v0).cross(&(v2 - v0));
+                    let normal = normal.try_normalize(0.0).unwrap_or(normal);
+
+                    faces.push(stl_io::IndexedTriangle {
+                        normal: [normal.x, normal.y, normal.z],
+                        vertices: tri,
+                    });
+                }
+            }
+            _ => {
+                // Normals, texture coordinates, groups and materials are not needed
+            }
+        }
+    }
+
+    Ok(stl_io::IndexedMesh { vertices, faces })
+}
+
+/// Write a mesh as a binary STL file
+pub fn write_stl(mesh: &stl_io::IndexedMesh, path: &Path) -> Result<(), failure::Error> {
+    let mut file = std::io::BufWriter::new(File::create(path)?);
+
+    stl_io::write_stl(
+        &mut file,
+        mesh.faces.iter().map(|face| stl_io::Triangle {
+            normal: face.normal,
+            vertices: [
+                mesh.vertices[face.vertices[0]],
+                mesh.vertices[face.vertices[1]],
+                mesh.vertices[face.vertices[2]],
+            ],
+        }),
+    )?;
+
+    Ok(())
+}
+
+/// How the input mesh is moved onto the printed geometry before voxelizing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    /// Match the centers of both bounding boxes
+    Center,
+    /// Match the minimum corners of both bounding boxes
+    Origin,
+    /// Keep the mesh where it is
+    None,
+}
+
+impl std::str::FromStr for Align {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "center" => Ok(Self::Center),
+            "origin" => Ok(Self::Origin),
+            "none" => Ok(Self::None),
+            _ => Err(failure::err_msg("expected center, origin or none")),
+        }
+    }
+}
+
+impl Align {
+    /// Translation that moves `mesh_bbox` onto `printer_bbox`
+    pub fn offset(
+        self,
+        mesh_bbox: &BoundingBox<f32>,
+        printer_bbox: &BoundingBox<f32>,
+    ) -> nalgebra::Vector3<f32> {
+        match self {
+            Self::Center => printer_bbox.center() - mesh_bbox.center(),
+            Self::Origin => printer_bbox.min() - mesh_bbox.min(),
+            Self::None => nalgebra::Vector3::zeros(),
+        }
+    }
+}
+
+pub fn translate_mesh(mesh: &mut stl_io::IndexedMesh, offset: nalgebra::Vector3<f32>) {
+    for vertex in &mut mesh.vertices {
+        vertex[0] += offset.x;
+        vertex[1] += offset.y;
+        vertex[2] += offset.z;
+    }
 }
 
 pub fn get_bounding_box(mesh: &stl_io::IndexedMesh) -> BoundingBox<f32> {
@@ -34,3 +205,85 @@ pub fn get_bounding_box(mesh: &stl_io::IndexedMesh) -> BoundingBox<f32> {
         max_z,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CUBE_OBJ: &str = "# unit cube
+mtllib cube.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v 0 0 1
+v 1 0 1
+v 1 1 1
+v 0 1 1
+vn 0 0 -1
+usemtl default
+f 1//1 4//1 3//1 2//1
+f 5 6 7 8
+f 1/1 2/1 6/1 5/1
+f 2 3 7 6
+f 3 4 8 7
+f -8 -4 -1 -5
+";
+
+    #[test]
+    fn read_obj_cube() {
+        let mesh = read_obj(&mut CUBE_OBJ.as_bytes()).unwrap();
+
+        assert_eq!(mesh.vertices.len(), 8);
+        // 6 quads, fan-triangulated
+        assert_eq!(mesh.faces.len(), 12);
+
+        let bbox = get_bounding_box(&mesh);
+        assert_eq!(
+            bbox,
+            BoundingBox {
+                min_x: 0.,
+                min_y: 0.,
+                min_z: 0.,
+                max_x: 1.,
+                max_y: 1.,
+                max_z: 1.,
+            }
+        );
+    }
+
+    #[test]
+    fn align_mesh_bbox() {
+        let mut mesh = read_obj(&mut CUBE_OBJ.as_bytes()).unwrap();
+        let mesh_bbox = get_bounding_box(&mesh);
+        let printer_bbox = BoundingBox {
+            min_x: 100.,
+            min_y: 50.,
+            min_z: 1.,
+            max_x: 104.,
+            max_y: 52.,
+            max_z: 2.,
+        };
+
+        let offset = Align::Center.offset(&mesh_bbox, &printer_bbox);
+        assert_eq!(offset, nalgebra::Vector3::new(101.5, 50.5, 1.));
+        let offset = Align::Origin.offset(&mesh_bbox, &printer_bbox);
+        assert_eq!(offset, nalgebra::Vector3::new(100., 50., 1.));
+        assert_eq!(
+            Align::None.offset(&mesh_bbox, &printer_bbox),
+            nalgebra::Vector3::zeros()
+        );
+
+        // Moving the mesh moves its bounding box by the same amount
+        let mut translated = mesh_bbox;
+        translated.translate(offset);
+        translate_mesh(&mut mesh, offset);
+        assert_eq!(get_bounding_box(&mesh), translated);
+        assert_eq!(translated.min(), printer_bbox.min());
+    }
+
+    #[test]
+    fn read_obj_invalid_index() {
+        assert!(read_obj(&mut "v 0 0 0\nf 1 2 3\n".as_bytes()).is_err());
+    }
+}
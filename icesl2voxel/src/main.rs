@@ -6,6 +6,12 @@
 //!     # Extract fields and parameters from file.xml into file.h5 (and file.xdmf)
 //!     cargo run -- -i file.xml -o file.h5
 //!
+//!     # Regenerate outputs from a previously written (possibly post-processed) HDF5 file
+//!     cargo run -- --from-h5 -i file.h5 -o processed.h5
+//!
+//!     # Write series.xdmf to view snapshots at t=0 and t=10 as a time series
+//!     cargo run -- --xdmf-series 0=step0.h5:10=step1.h5 -o series.h5
+//!
 //! ## Author
 //!
 //! Vincent Tavernier <vince.tavernier@gmail.com>
@@ -13,9 +19,8 @@
 #[macro_use]
 extern crate log;
 
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::time::Instant;
 
@@ -24,6 +29,8 @@ use structopt::StructOpt;
 pub struct FieldMap {
     output_name: String,
     coords: Vec<String>,
+    /// Keywords following the coordinates, as in `name=a,b:keyword`
+    flags: Vec<String>,
 }
 
 impl std::str::FromStr for FieldMap {
@@ -31,22 +38,194 @@ impl std::str::FromStr for FieldMap {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let kv_parts: Vec<_> = s.splitn(2, '=').collect();
-        let coord_parts: Vec<_> = kv_parts[1].split(',').map(str::to_owned).collect();
+        let mut value_parts = kv_parts
+            .get(1)
+            .ok_or_else(|| failure::err_msg("expected name=source,..."))?
+            .split(':');
+        let coord_parts: Vec<_> = value_parts
+            .next()
+            .unwrap()
+            .split(',')
+            .map(str::to_owned)
+            .collect();
 
         Ok(Self {
             output_name: kv_parts[0].to_owned(),
             coords: coord_parts,
+            flags: value_parts.map(str::to_owned).collect(),
+        })
+    }
+}
+
+impl FieldMap {
+    /// True if the `orientation` keyword follows the first coordinate
+    fn has_orientation_keyword(&self) -> bool {
+        self.coords.iter().skip(1).any(|c| c == "orientation")
+    }
+
+    /// First number following the first coordinate, if any
+    fn sigma(&self) -> Option<f32> {
+        self.coords.iter().skip(1).find_map(|c| c.parse().ok())
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+pub struct ForceField {
+    name: String,
+    mode: param_field::ArrayToFieldMode,
+}
+
+impl std::str::FromStr for ForceField {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.splitn(2, ':').collect();
+
+        Ok(Self {
+            name: parts[0].to_owned(),
+            mode: match parts.get(1) {
+                Some(mode) => mode.parse()?,
+                None => param_field::ArrayToFieldMode::Linear,
+            },
         })
     }
 }
 
+pub struct FieldOpSpec {
+    output_name: String,
+    op: param_field::FieldOp,
+    lhs: String,
+    rhs: param_bag::FieldOperand,
+}
+
+impl std::str::FromStr for FieldOpSpec {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || failure::err_msg("expected a field operation as output=op:lhs,rhs");
+
+        let kv_parts: Vec<_> = s.splitn(2, '=').collect();
+        let op_parts: Vec<_> = kv_parts.get(1).ok_or_else(err)?.splitn(2, ':').collect();
+        let operands: Vec<_> = op_parts.get(1).ok_or_else(err)?.split(',').collect();
+
+        match operands[..] {
+            [lhs, rhs] => Ok(Self {
+                output_name: kv_parts[0].to_owned(),
+                op: op_parts[0].parse()?,
+                lhs: lhs.to_owned(),
+                rhs: rhs.parse()?,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+pub struct MaskedStats {
+    field: String,
+    mask: String,
+    prefix: String,
+}
+
+impl std::str::FromStr for MaskedStats {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split(':').collect();
+
+        match parts[..] {
+            [field, mask, prefix] => Ok(Self {
+                field: field.to_owned(),
+                mask: mask.to_owned(),
+                prefix: prefix.to_owned(),
+            }),
+            _ => Err(failure::err_msg(
+                "expected masked statistics as field:mask:prefix",
+            )),
+        }
+    }
+}
+
+pub struct SeriesStep {
+    time: f64,
+    path: String,
+}
+
+impl std::str::FromStr for SeriesStep {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kv_parts: Vec<_> = s.splitn(2, '=').collect();
+
+        match kv_parts[..] {
+            [time, path] => Ok(Self {
+                time: time.parse()?,
+                path: path.to_owned(),
+            }),
+            _ => Err(failure::err_msg("expected a timestep as time=file.h5")),
+        }
+    }
+}
+
+pub struct Resolution([usize; 3]);
+
+impl std::str::FromStr for Resolution {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Result<Vec<usize>, _> = s.split(',').map(str::parse).collect();
+
+        match parts?[..] {
+            [x, y, z] if x > 0 && y > 0 && z > 0 => Ok(Self([x, y, z])),
+            _ => Err(failure::err_msg(
+                "expected three non-zero cell counts: X,Y,Z",
+            )),
+        }
+    }
+}
+
+pub struct SlicesRange(f32, f32);
+
+impl std::str::FromStr for SlicesRange {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Result<Vec<f32>, _> = s.split(',').map(str::parse).collect();
+
+        match parts?[..] {
+            [min, max] if min < max => Ok(Self(min, max)),
+            _ => Err(failure::err_msg("expected an increasing range: min,max")),
+        }
+    }
+}
+
+fn parse_compression(s: &str) -> Result<u8, failure::Error> {
+    match s.parse()? {
+        level if level <= 9 => Ok(level),
+        _ => Err(failure::err_msg("expected a compression level from 0 to 9")),
+    }
+}
+
+fn parse_voxel_size(s: &str) -> Result<f32, failure::Error> {
+    match s.parse()? {
+        size if size > 0.0 && f32::is_finite(size) => Ok(size),
+        _ => Err(failure::err_msg("expected a positive voxel size")),
+    }
+}
+
 #[derive(StructOpt)]
 struct Opts {
-    /// Input model XML file path
-    #[structopt(short, long)]
-    input: PathBuf,
+    /// Input model XML file path, possibly gzip-compressed
+    #[structopt(short, long, required_unless = "xdmf-series")]
+    input: Option<PathBuf>,
+
+    /// Read the input as an HDF5 file written by a previous run instead of an XML model
+    #[structopt(long)]
+    from_h5: bool,
 
-    /// Geometry input
+    /// Geometry input (STL or OBJ, OBJ files may be gzip-compressed)
     #[structopt(short, long)]
     mesh: Option<PathBuf>,
 
@@ -54,9 +233,10 @@ struct Opts {
     #[structopt(short, long)]
     output: PathBuf,
 
-    /// List of array parameters to force as fields
+    /// List of array parameters to force as fields, as name[:mode] where the mode is linear (the
+    /// default) to interpolate between layers or step to keep each layer's value
     #[structopt(long)]
-    force_field: Vec<String>,
+    force_field: Vec<ForceField>,
 
     /// List of fields to assemble as spherical vector fields
     #[structopt(
@@ -67,10 +247,64 @@ struct Opts {
     )]
     assemble_spherical: Vec<FieldMap>,
 
-    /// Gcode to extract extruded segments from
+    /// Vector fields to assemble from their component fields, as name=x,y,z[:normalize]. Give the
+    /// option once per field.
+    #[structopt(long)]
+    assemble_cartesian: Vec<FieldMap>,
+
+    /// Scalar fields to derive from vector fields, as name=source[,method] where the method is
+    /// magnitude (the default), x, y or z
+    #[structopt(long, use_delimiter = true, value_delimiter = ":")]
+    scalarize: Vec<FieldMap>,
+
+    /// Gcode to extract extruded segments from, possibly gzip-compressed
     #[structopt(short, long)]
     gcode: Option<PathBuf>,
 
+    /// Slicer that generated the gcode (icesl, cura or prusa), detected from the header by default
+    #[structopt(long)]
+    slicer: Option<voxelizer::Slicer>,
+
+    /// Maximum chord deviation in mm when tessellating G2/G3 arc moves
+    #[structopt(long, default_value = "0.05")]
+    arc_tolerance: f32,
+
+    /// Width of the extruded beads: nozzle for the nozzle diameter, volumetric to derive it from
+    /// the extruded filament and the layer height
+    #[structopt(long, default_value = "nozzle")]
+    extrusion_model: voxelizer::ExtrusionModel,
+
+    /// Extract the index of the tool used for each voxel as output_tool
+    #[structopt(long)]
+    extract_tool_field: bool,
+
+    /// Extract the mean feedrate and fan speed for each voxel as output_feedrate and output_fan
+    #[structopt(long)]
+    extract_motion_fields: bool,
+
+    /// Extract the type of the dominant printed feature for each voxel as output_feature, the
+    /// code names are written as label_<code> attributes of the HDF5 dataset
+    #[structopt(long)]
+    extract_feature_field: bool,
+
+    /// Regex matching feature comments for another slicer dialect, the first group that matched
+    /// holds the feature label. Matches IceSL, Cura and PrusaSlicer comments by default
+    #[structopt(long)]
+    feature_regex: Option<regex::Regex>,
+
+    /// Record the positions of retractions and seams as /points/retractions and /points/seams.
+    /// Empty point sets can only be written with --hdf5-compression 0
+    #[structopt(long)]
+    extract_seams: bool,
+
+    /// Voxelize gcode on the GPU, falls back to the CPU if no OpenGL context can be created
+    #[structopt(long)]
+    gpu: bool,
+
+    /// OpenGL context backend (egl or glutin), EGL is used by default when there is no display
+    #[structopt(long)]
+    gl_backend: Option<voxelizer::GlBackend>,
+
     /// Number of samples for voxelizing geometry
     #[structopt(long, default_value = "4")]
     samples: std::num::NonZeroUsize,
@@ -79,11 +313,84 @@ struct Opts {
     #[structopt(long)]
     export_depth_images: bool,
 
+    /// Directory for --export-depth-images, defaults to the directory of the output file
+    #[structopt(long, requires = "export-depth-images")]
+    depth_image_dir: Option<PathBuf>,
+
     /// Export arrays in XDMF
     #[structopt(long)]
     xdmf_export_arrays: bool,
 
-    /// Compute output geometry statistics
+    /// Names of the arrays to export in XDMF, all arrays are exported if empty
+    #[structopt(long, use_delimiter = true)]
+    xdmf_array_filter: Vec<String>,
+
+    /// Also export fields as a VTK image data file (.vti)
+    #[structopt(long)]
+    vtk: bool,
+
+    /// Number of Z layers in each chunk of HDF5 field datasets
+    #[structopt(long, default_value = "1")]
+    hdf5_chunk_depth: std::num::NonZeroUsize,
+
+    /// Gzip compression level of HDF5 datasets, from 0 to 9. 0 disables compression
+    #[structopt(long, default_value = "6", parse(try_from_str = parse_compression))]
+    hdf5_compression: u8,
+
+    /// Enable the shuffle filter on HDF5 datasets to improve compression of float data
+    #[structopt(long)]
+    hdf5_shuffle: bool,
+
+    /// Only write an XDMF time series referencing existing HDF5 files, as t0=file0.h5:t1=file1.h5
+    #[structopt(long, use_delimiter = true, value_delimiter = ":")]
+    xdmf_series: Vec<SeriesStep>,
+
+    /// Also write field bounding boxes as HDF5 datasets for readers of the previous layout
+    #[structopt(long)]
+    legacy_layout: bool,
+
+    /// Write string parameters as fixed-length ASCII for HDF5 readers without variable-length
+    /// string support. Non-ASCII characters are replaced with `?`.
+    #[structopt(long)]
+    hdf5_fixed_strings: bool,
+
+    /// Also export arrays and scalar parameters as CSV to the given path
+    #[structopt(long)]
+    csv: Option<PathBuf>,
+
+    /// Write a JSON summary of the fields, arrays and pipeline stage timings to the given path
+    #[structopt(long)]
+    summary: Option<PathBuf>,
+
+    /// List of fields to export as raw volumes with NRRD headers
+    #[structopt(long, use_delimiter = true)]
+    export_raw: Vec<String>,
+
+    /// List of fields to export as one PNG per layer
+    #[structopt(long, use_delimiter = true)]
+    export_slices: Vec<String>,
+
+    /// Directory for --export-slices, <output>_slices by default
+    #[structopt(long)]
+    slices_dir: Option<PathBuf>,
+
+    /// Range of values mapped to false colors by --export-slices, as min,max. Defaults to the range
+    /// of each field
+    #[structopt(long)]
+    slices_range: Option<SlicesRange>,
+
+    /// Export the surface of the output geometry as an STL mesh
+    #[structopt(long)]
+    export_surface: Option<PathBuf>,
+
+    /// Threshold of the output geometry for --export-surface
+    #[structopt(long, default_value = "128")]
+    iso: u8,
+
+    /// Compute output geometry statistics, as name=kernel_size_mm[,sigma_mm] followed by optional
+    /// keywords. The Gaussian smoothing sigma defaults to half the kernel size. The `orientation`
+    /// keyword also computes the orientation tensor of the extruded segments and its fractional
+    /// anisotropy
     #[structopt(
         long,
         default_value = "output_stats=10",
@@ -92,7 +399,9 @@ struct Opts {
     )]
     output_statistics: Vec<FieldMap>,
 
-    /// Resample input fields using nearest interpolation and the input geometry mask
+    /// Resample input fields using the input geometry mask. An interpolation method can follow
+    /// the source field (e.g. input_dir=infill_dir,nearest), otherwise nearest is used for byte
+    /// fields and trilinear for others
     #[structopt(
         long,
         default_value = "input_percentage=infill_percentage:input_dir=infill_dir:input_isotropy=infill_isotropy",
@@ -105,27 +414,95 @@ struct Opts {
     #[structopt(long, default_value = "1.0")]
     xy_sampling_factor: f32,
 
+    /// Size of output voxels in mm, instead of one cell per layer
+    #[structopt(long, parse(try_from_str = parse_voxel_size))]
+    voxel_size_mm: Option<f32>,
+
+    /// Number of output cells as X,Y,Z, instead of one cell per layer
+    #[structopt(long, conflicts_with = "voxel-size-mm")]
+    resolution: Option<Resolution>,
+
+    /// Region to restrict fields and voxelization to, as min_x,min_y,min_z,max_x,max_y,max_z in mm
+    #[structopt(long)]
+    crop: Option<utils::BoundingBox<f32>>,
+
+    /// Printer bounding box to use instead of the extent of the gcode, as
+    /// min_x,min_y,min_z,max_x,max_y,max_z in mm
+    #[structopt(long)]
+    bbox_override: Option<utils::BoundingBox<f32>>,
+
+    /// Move the input mesh and fields onto the printed geometry before voxelizing (center, origin
+    /// or none)
+    #[structopt(long, default_value = "none")]
+    align: geometry::Align,
+
+    /// Only warn when written fields of the same grid size have different bounding boxes
+    #[structopt(long)]
+    allow_mismatched_boxes: bool,
+
+    /// Do not resample fields onto a common grid before writing
+    #[structopt(long)]
+    no_harmonize: bool,
+
+    /// Field whose grid all fields are resampled onto, output_geometry or the largest field by
+    /// default
+    #[structopt(long)]
+    harmonize_reference: Option<String>,
+
+    /// Resampling method (nearest or trilinear) used to bring fields onto a common grid, nearest
+    /// for byte fields and trilinear for the others by default
+    #[structopt(long)]
+    harmonize_method: Option<param_field::ResampleMethod>,
+
+    /// Fields to compute after harmonizing grids, as output=op:lhs,rhs where op is add, sub, mul,
+    /// threshold or mask, and rhs a field name or a number
+    #[structopt(long)]
+    field_op: Vec<FieldOpSpec>,
+
+    /// Statistics of a field inside a mask to record as parameters, as field:mask:prefix. Adds
+    /// the prefix_count, prefix_mean, prefix_std, prefix_min and prefix_max parameters.
+    #[structopt(long)]
+    masked_stats: Vec<MaskedStats>,
+
     /// Number of rays to sample directions in output geometry
     #[structopt(long, default_value = "32")]
     dir_samples: usize,
 
-    /// Pad all written fields with a single layer of 0 to generate closed surfaces
+    /// Pad all written fields with layers of 0 to generate closed surfaces
     #[structopt(long)]
     pad_fields: bool,
+
+    /// Number of layers added on each side by --pad-fields
+    #[structopt(long, default_value = "1")]
+    pad_width: usize,
+
+    /// Downsample all written fields by the given factor
+    #[structopt(long, default_value = "1")]
+    downsample: std::num::NonZeroUsize,
+
+    /// Do not display progress bars
+    #[structopt(short, long)]
+    quiet: bool,
 }
 
 impl Opts {
-    pub fn get_force_field(&self) -> HashSet<String> {
-        let mut res =
-            HashSet::with_capacity(self.force_field.len() + self.assemble_spherical.len() * 3);
+    pub fn get_force_field(&self) -> HashMap<String, param_field::ArrayToFieldMode> {
+        let mut res = HashMap::with_capacity(
+            self.force_field.len()
+                + (self.assemble_spherical.len() + self.assemble_cartesian.len()) * 3,
+        );
 
-        res.extend(self.force_field.iter().cloned());
         res.extend(
             self.assemble_spherical
                 .iter()
-                .flat_map(|df| df.coords.iter())
-                .cloned(),
+                .chain(self.assemble_cartesian.iter())
+                .flat_map(|df| {
+                    df.coords
+                        .iter()
+                        .map(|c| (c.clone(), param_field::ArrayToFieldMode::Linear))
+                }),
         );
+        res.extend(self.force_field.iter().map(|ff| (ff.name.clone(), ff.mode)));
 
         res
     }
@@ -138,15 +515,28 @@ mod param_bag;
 mod param_field;
 mod parse;
 mod stats;
+mod summary;
 mod utils;
 mod voxelizer;
 
+use param_array::ParamArray;
 use param_bag::ParamBag;
+use stats::LayerStats;
+use summary::StageTiming;
 
-fn write_hdf5(output: &Path, param_bag: &ParamBag) -> Result<(), failure::Error> {
+fn write_hdf5(output: &Path, param_bag: &ParamBag, opts: &Opts) -> Result<(), failure::Error> {
     let _e = hdf5::silence_errors();
     let file = hdf5::File::create(&output)?;
-    param_bag.write_hdf5(&file)
+    param_bag.write_hdf5(
+        &file,
+        &param_field::Hdf5Options {
+            chunk_depth: opts.hdf5_chunk_depth.into(),
+            compression: opts.hdf5_compression,
+            shuffle: opts.hdf5_shuffle,
+            legacy_layout: opts.legacy_layout,
+            fixed_length_strings: opts.hdf5_fixed_strings,
+        },
+    )
 }
 
 fn write_xdmf(
@@ -156,7 +546,134 @@ fn write_xdmf(
     opts: &Opts,
 ) -> Result<(), failure::Error> {
     let mut meta = File::create(opts.output.with_extension("xdmf"))?;
-    Ok(param_bag.write_xdmf(offsets, h5_file_name, &mut meta, opts.xdmf_export_arrays)?)
+    Ok(param_bag.write_xdmf(
+        offsets,
+        h5_file_name,
+        &mut meta,
+        opts.xdmf_export_arrays,
+        &opts.xdmf_array_filter,
+    )?)
+}
+
+fn write_xdmf_series(opts: &Opts) -> Result<(), failure::Error> {
+    let _e = hdf5::silence_errors();
+
+    let bags = opts
+        .xdmf_series
+        .iter()
+        .map(|step| ParamBag::read_hdf5(&hdf5::File::open(&step.path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let steps: Vec<_> = opts
+        .xdmf_series
+        .iter()
+        .zip(bags.iter())
+        .map(|(step, bag)| (step.time, step.path.as_str(), bag))
+        .collect();
+
+    let mut meta = File::create(opts.output.with_extension("xdmf"))?;
+    ParamBag::write_xdmf_series(
+        &steps[..],
+        nalgebra::Vector3::zeros(),
+        &mut meta,
+        opts.xdmf_export_arrays,
+        &opts.xdmf_array_filter,
+    )
+}
+
+fn write_vtk(
+    offsets: nalgebra::Vector3<f32>,
+    param_bag: &ParamBag,
+    opts: &Opts,
+) -> Result<(), failure::Error> {
+    let mut vti = std::io::BufWriter::new(File::create(opts.output.with_extension("vti"))?);
+    Ok(param_bag.write_vtk(offsets, &mut vti)?)
+}
+
+/// Writes the outputs other than the XDMF and HDF5 files
+fn write_outputs(
+    offsets: nalgebra::Vector3<f32>,
+    param_bag: &ParamBag,
+    opts: &Opts,
+    stages: &mut Vec<StageTiming>,
+) -> Result<(), failure::Error> {
+    // Write VTK
+    if opts.vtk {
+        write_vtk(offsets, param_bag, opts)?;
+    }
+
+    // Write CSV summary
+    if let Some(csv_path) = &opts.csv {
+        let mut csv = std::io::BufWriter::new(File::create(csv_path)?);
+        param_bag.write_csv_summary(&mut csv)?;
+    }
+
+    // Write raw volumes
+    for name in &opts.export_raw {
+        if let Some(field) = param_bag.get_field(name) {
+            let stem = opts.output.file_stem().unwrap().to_string_lossy();
+            field.write_nrrd(
+                &opts
+                    .output
+                    .with_file_name(format!("{}_{}.nhdr", stem, name)),
+            )?;
+        } else {
+            error!("field {} not found for raw export", name);
+        }
+    }
+
+    // Write layer images
+    if !opts.export_slices.is_empty() {
+        let slices_dir = opts.slices_dir.clone().unwrap_or_else(|| {
+            let stem = opts.output.file_stem().unwrap().to_string_lossy();
+            opts.output.with_file_name(format!("{}_slices", stem))
+        });
+
+        for name in &opts.export_slices {
+            if let Some(field) = param_bag.get_field(name) {
+                let paths = field.write_png_stack(
+                    &slices_dir,
+                    name,
+                    opts.slices_range.as_ref().map(|r| (r.0, r.1)),
+                )?;
+                debug!(
+                    "wrote {} layer images of {} to {}",
+                    paths.len(),
+                    name,
+                    slices_dir.display()
+                );
+            } else {
+                error!("field {} not found for slice export", name);
+            }
+        }
+    }
+
+    // Write the printed surface
+    if let Some(surface_path) = &opts.export_surface {
+        let field = param_bag
+            .get_field("output_geometry")
+            .ok_or_else(|| failure::err_msg("output_geometry not found for surface export"))?;
+        let start = Instant::now();
+
+        // Close the surface on the grid boundary if the fields weren't padded
+        let mesh = if opts.pad_fields {
+            geometry::extract_isosurface(field, opts.iso)?
+        } else {
+            let mut field = field.clone();
+            field.pad(1);
+            geometry::extract_isosurface(&field, opts.iso)?
+        };
+
+        geometry::write_stl(&mesh, surface_path)?;
+        debug!(
+            "extracted surface with {} triangles in {:.2}ms",
+            mesh.faces.len(),
+            start.elapsed().as_millis()
+        );
+        stages.push(StageTiming::since("export surface", start));
+    }
+
+    Ok(())
 }
 
 #[paw::main]
@@ -167,30 +684,47 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
     .format_timestamp(None)
     .init();
 
+    if !opts.xdmf_series.is_empty() {
+        return write_xdmf_series(&opts);
+    }
+
+    let mut stages = Vec::new();
+
     let mut param_bag = {
         let start = Instant::now();
+        let input = opts.input.as_ref().unwrap();
 
-        let file = File::open(&opts.input)?;
-        let mut file = BufReader::new(file);
-        let bag = ParamBag::parse(&mut file)?;
+        let bag = if opts.from_h5 {
+            let _e = hdf5::silence_errors();
+            ParamBag::read_hdf5(&hdf5::File::open(input)?)?
+        } else {
+            ParamBag::parse(&mut utils::open_input(input)?)?
+        };
 
         debug!("loaded parameters in {:.2}ms", start.elapsed().as_millis());
+        stages.push(StageTiming::since("load parameters", start));
 
         bag
     };
 
-    for force_field in &opts.get_force_field() {
+    for (force_field, mode) in &opts.get_force_field() {
         if param_bag.get_field(force_field).is_some() {
             // Nothing to do
         } else {
             let start = Instant::now();
 
-            match param_bag.convert_to_field(force_field) {
-                Ok(_) => info!(
-                    "converted {} to a field in {:.2}ms",
-                    force_field,
-                    start.elapsed().as_millis()
-                ),
+            match param_bag.convert_to_field(force_field, *mode) {
+                Ok(_) => {
+                    info!(
+                        "converted {} to a field in {:.2}ms",
+                        force_field,
+                        start.elapsed().as_millis()
+                    );
+                    stages.push(StageTiming::since(
+                        format!("convert {}", force_field),
+                        start,
+                    ));
+                }
                 Err(error) => error!("could not convert {} to a field: {}", force_field, error),
             }
         }
@@ -203,11 +737,17 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
             &assemble_spherical.output_name,
             &assemble_spherical.coords[..],
         ) {
-            Ok(_) => info!(
-                "assembled {} as spherical vector field in {:.2}ms",
-                assemble_spherical.output_name,
-                start.elapsed().as_millis(),
-            ),
+            Ok(_) => {
+                info!(
+                    "assembled {} as spherical vector field in {:.2}ms",
+                    assemble_spherical.output_name,
+                    start.elapsed().as_millis(),
+                );
+                stages.push(StageTiming::since(
+                    format!("assemble {}", assemble_spherical.output_name),
+                    start,
+                ));
+            }
             Err(error) => error!(
                 "could not assemble {}: {}",
                 assemble_spherical.output_name, error
@@ -215,11 +755,74 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
         }
     }
 
-    let (geometry_bounding_box, offsets, mesh) = if let Some(mesh_path) = &opts.mesh {
+    for assemble_cartesian in &opts.assemble_cartesian {
+        let start = Instant::now();
+
+        match param_bag.assemble_cartesian(
+            &assemble_cartesian.output_name,
+            &assemble_cartesian.coords[..],
+            assemble_cartesian.has_flag("normalize"),
+        ) {
+            Ok(_) => {
+                info!(
+                    "assembled {} as cartesian vector field in {:.2}ms",
+                    assemble_cartesian.output_name,
+                    start.elapsed().as_millis(),
+                );
+                stages.push(StageTiming::since(
+                    format!("assemble {}", assemble_cartesian.output_name),
+                    start,
+                ));
+            }
+            Err(error) => error!(
+                "could not assemble {}: {}",
+                assemble_cartesian.output_name, error
+            ),
+        }
+    }
+
+    for scalarize in &opts.scalarize {
+        let method = match scalarize.coords.get(1) {
+            Some(method) => method.parse()?,
+            None => param_field::VectorScalarization::Magnitude,
+        };
+
+        match param_bag
+            .get_field(&scalarize.coords[0])
+            .ok_or_else(|| failure::err_msg("field not found"))
+            .and_then(|field| field.map_vec3_to_scalar(method))
+        {
+            Ok(field) => param_bag.add_field(
+                &scalarize.output_name,
+                field.with_description(format!(
+                    "{} of {}",
+                    scalarize.coords.get(1).map_or("magnitude", String::as_str),
+                    scalarize.coords[0]
+                )),
+            ),
+            Err(error) => error!(
+                "could not derive {} from {}: {}",
+                scalarize.output_name, scalarize.coords[0], error
+            ),
+        }
+    }
+
+    if let Some(crop) = &opts.crop {
+        param_bag.crop_fields(crop)?;
+    }
+
+    let (mut geometry_bounding_box, mut offsets, mut mesh) = if let Some(mesh_path) = &opts.mesh {
         let start = Instant::now();
 
         let mesh = geometry::load_mesh(mesh_path)?;
         let bbox = geometry::get_bounding_box(&mesh);
+        let bbox = if let Some(crop) = &opts.crop {
+            bbox.intersection(crop).ok_or_else(|| {
+                failure::err_msg("crop region doesn't intersect the input geometry")
+            })?
+        } else {
+            bbox
+        };
         let offsets = bbox.center();
 
         debug!(
@@ -227,50 +830,125 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
             start.elapsed().as_millis(),
             bbox
         );
+        stages.push(StageTiming::since("load mesh", start));
 
         (Some(bbox), offsets, Some(mesh))
     } else {
         (None, nalgebra::Vector3::zeros(), None)
     };
 
+    let progress = utils::ProgressConfig { quiet: opts.quiet };
+
     // Voxelize printed geometry
     if let Some(gcode_path) = &opts.gcode {
         let start = Instant::now();
 
-        let voxelized_field =
-            voxelizer::voxelize_gcode(gcode_path, opts.samples.into(), opts.xy_sampling_factor)?;
+        let voxelized_gcode = voxelizer::voxelize_gcode(
+            gcode_path,
+            &voxelizer::GcodeOptions {
+                samples: opts.samples.into(),
+                xy_sampling_factor: opts.xy_sampling_factor,
+                arc_tolerance: opts.arc_tolerance,
+                extrusion_model: opts.extrusion_model,
+                extract_tool_field: opts.extract_tool_field,
+                extract_motion_fields: opts.extract_motion_fields,
+                extract_orientation_field: mesh.is_some()
+                    && opts
+                        .output_statistics
+                        .iter()
+                        .any(FieldMap::has_orientation_keyword),
+                extract_feature_field: opts.extract_feature_field,
+                feature_regex: opts.feature_regex.clone(),
+                slicer: opts.slicer,
+                extract_seams: opts.extract_seams,
+                voxel_size_mm: opts.voxel_size_mm,
+                resolution: opts.resolution.as_ref().map(|r| r.0),
+                bbox_override: opts.bbox_override,
+                crop: opts.crop,
+                gpu: opts.gpu,
+                gl_backend: opts.gl_backend,
+                progress,
+            },
+        )?;
+        let voxelized_field = voxelized_gcode.geometry;
+
+        // Z cells follow the gcode layers unless the grid is overridden
+        let layer_thickness_mm =
+            if opts.voxel_size_mm.is_none() && opts.resolution.is_none() && opts.crop.is_none() {
+                Some(&voxelized_gcode.layer_thickness[..])
+            } else {
+                None
+            };
 
         debug!(
             "voxelized printed geometry in {:.2}ms",
             start.elapsed().as_millis()
         );
+        stages.push(StageTiming::since("voxelize gcode", start));
 
-        if let Some(mesh) = &mesh {
+        if let Some(mesh) = &mut mesh {
             let start = Instant::now();
 
+            let mesh_bbox = geometry_bounding_box.as_mut().unwrap();
+            let offset = opts.align.offset(mesh_bbox, &voxelized_field.field_box_mm);
+            if offset != nalgebra::Vector3::zeros() {
+                debug!("aligning input geometry and fields by {:?}", offset);
+
+                geometry::translate_mesh(mesh, offset);
+                mesh_bbox.translate(offset);
+                param_bag.translate_fields(offset);
+                offsets = mesh_bbox.center();
+            }
+
+            let depth_images = if opts.export_depth_images {
+                Some(voxelizer::DepthImages::for_output(
+                    &opts.output,
+                    opts.depth_image_dir.as_deref(),
+                ))
+            } else {
+                None
+            };
+
             let voxelized_mesh = voxelizer::voxelize_mesh(
                 mesh,
                 geometry_bounding_box.as_ref().unwrap(),
                 &voxelized_field,
-                opts.export_depth_images,
+                depth_images.as_ref(),
+                opts.gl_backend,
             )?;
 
             debug!(
                 "voxelized input geometry in {:.2}ms",
                 start.elapsed().as_millis()
             );
+            stages.push(StageTiming::since("voxelize mesh", start));
 
             for input_spec in &opts.resample_fields {
                 if let Some(field) = param_bag.get_field(&input_spec.coords[0]) {
                     let start = Instant::now();
 
-                    let field = field.resample(&voxelized_mesh);
+                    let method = match input_spec.coords.get(1) {
+                        Some(method) => method.parse()?,
+                        None => field.default_resample_method(),
+                    };
+
+                    // The unit of the source is kept by resampling
+                    let field = field
+                        .resample(&voxelized_mesh, method)
+                        .with_description(format!(
+                            "{} resampled onto input_geometry",
+                            input_spec.coords[0]
+                        ));
                     debug!(
                         "resampled {} as {} in {:.2}ms",
                         input_spec.coords[0],
                         input_spec.output_name,
                         start.elapsed().as_millis()
                     );
+                    stages.push(StageTiming::since(
+                        format!("resample {}", input_spec.output_name),
+                        start,
+                    ));
 
                     param_bag.add_field(&input_spec.output_name, field);
                 } else {
@@ -285,13 +963,23 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
                     &voxelized_field,
                     &voxelized_mesh,
                     param_bag.get_field("input_dir"),
-                    out_spec
-                        .coords
-                        .iter()
-                        .next()
-                        .ok_or_else(|| failure::err_msg("you need to specify the kernel size"))
-                        .and_then(|f| f.parse::<f32>().map_err(|e| e.into()))?,
-                    opts.dir_samples,
+                    if out_spec.has_orientation_keyword() {
+                        voxelized_gcode.orientation.as_ref()
+                    } else {
+                        None
+                    },
+                    &stats::StatsOptions {
+                        kernel_size_mm: out_spec
+                            .coords
+                            .iter()
+                            .next()
+                            .ok_or_else(|| failure::err_msg("you need to specify the kernel size"))
+                            .and_then(|f| f.parse::<f32>().map_err(|e| e.into()))?,
+                        sigma_mm: out_spec.sigma(),
+                        dir_samples: opts.dir_samples,
+                        layer_thickness_mm,
+                        progress,
+                    },
                 )?;
 
                 debug!(
@@ -299,6 +987,10 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
                     out_spec.output_name,
                     start.elapsed().as_millis()
                 );
+                stages.push(StageTiming::since(
+                    format!("statistics {}", out_spec.output_name),
+                    start,
+                ));
 
                 param_bag.add_field(
                     &format!("{}_mean", out_spec.output_name),
@@ -326,16 +1018,180 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
                         dir_correlation,
                     );
                 }
+                if let Some(dir_misalignment_deg) = output_stats.dir_misalignment_deg {
+                    param_bag.add_field(
+                        &format!("{}_dir_misalignment_deg", out_spec.output_name),
+                        dir_misalignment_deg,
+                    );
+                }
+                if let Some(orientation_tensor) = output_stats.orientation_tensor {
+                    param_bag.add_field(
+                        &format!("{}_orientation", out_spec.output_name),
+                        orientation_tensor,
+                    );
+                }
+                if let Some(fractional_anisotropy) = output_stats.fractional_anisotropy {
+                    param_bag.add_field(
+                        &format!("{}_anisotropy", out_spec.output_name),
+                        fractional_anisotropy,
+                    );
+                }
+            }
+
+            let layers = stats::layer_report(&voxelized_field, &voxelized_mesh)?;
+
+            info!("layer coverage of the input geometry:");
+            info!(
+                "{:>6} {:>10} {:>10} {:>9}",
+                "layer", "in_cells", "covered", "porosity"
+            );
+            for layer in &layers {
+                info!(
+                    "{:>6} {:>10} {:>10} {:>8.2}%",
+                    layer.layer,
+                    layer.in_cells,
+                    layer.covered_cells,
+                    layer.porosity * 100.0
+                );
             }
 
-            param_bag.add_field("input_geometry", voxelized_mesh);
+            param_bag.add_array(
+                "layer_porosity",
+                ParamArray::from_f64(layers.iter().map(|layer| layer.porosity).collect()),
+            );
+            param_bag.add_array(
+                "layer_coverage",
+                ParamArray::from_f64(layers.iter().map(LayerStats::coverage).collect()),
+            );
+
+            param_bag.add_field(
+                "input_geometry",
+                voxelized_mesh.with_description("input geometry coverage, 0 to 255"),
+            );
+        }
+
+        param_bag.add_field(
+            "output_geometry",
+            voxelized_field.with_description("printed material coverage, 0 to 255"),
+        );
+
+        param_bag.add_array(
+            "layer_z",
+            ParamArray::from_f64(voxelized_gcode.layer_z.iter().map(|&z| z as f64).collect()),
+        );
+        param_bag.add_array(
+            "layer_thickness",
+            ParamArray::from_f64(
+                voxelized_gcode
+                    .layer_thickness
+                    .iter()
+                    .map(|&t| t as f64)
+                    .collect(),
+            ),
+        );
+
+        if let Some(tool_field) = voxelized_gcode.tool {
+            param_bag.add_field(
+                "output_tool",
+                tool_field.with_description("index of the extruder that printed the cell"),
+            );
         }
 
-        param_bag.add_field("output_geometry", voxelized_field);
+        if let Some(feedrate_field) = voxelized_gcode.feedrate {
+            param_bag.add_field(
+                "output_feedrate",
+                feedrate_field
+                    .with_unit("mm/min")
+                    .with_description("mean feedrate of the extrusions"),
+            );
+        }
+
+        if let Some(fan_field) = voxelized_gcode.fan {
+            param_bag.add_field(
+                "output_fan",
+                fan_field.with_description("mean fan speed of the extrusions, 0 to 255"),
+            );
+        }
+
+        if let Some(feature_field) = voxelized_gcode.feature {
+            param_bag.add_field(
+                "output_feature",
+                feature_field
+                    .with_description("dominant extrusion feature, see the label attributes"),
+            );
+            param_bag.add_field_labels("output_feature", &voxelizer::FEATURE_NAMES);
+        }
+
+        if let Some(retractions) = voxelized_gcode.retractions {
+            param_bag.add_points("retractions", retractions);
+        }
+
+        if let Some(seams) = voxelized_gcode.seams {
+            param_bag.add_points("seams", seams);
+        }
+    }
+
+    let downsample: usize = opts.downsample.into();
+    if downsample > 1 {
+        param_bag.downsample_fields(downsample);
     }
 
     if opts.pad_fields {
-        param_bag.pad_fields(1);
+        param_bag.pad_fields(opts.pad_width);
+    }
+
+    if !opts.no_harmonize {
+        // Output fields share the voxelized gcode grid
+        let reference = opts.harmonize_reference.clone().or_else(|| {
+            if param_bag.get_field("output_geometry").is_some() {
+                Some("output_geometry".to_owned())
+            } else {
+                param_bag.largest_field_name().map(str::to_owned)
+            }
+        });
+
+        if let Some(reference) = reference {
+            let start = Instant::now();
+            param_bag.harmonize_grids(&reference, opts.harmonize_method)?;
+            debug!(
+                "harmonized field grids with {} in {:.2}ms",
+                reference,
+                start.elapsed().as_millis()
+            );
+            stages.push(StageTiming::since("harmonize grids", start));
+        }
+    }
+
+    for field_op in &opts.field_op {
+        if let Err(error) = param_bag.apply_op(
+            &field_op.output_name,
+            field_op.op,
+            &field_op.lhs,
+            &field_op.rhs,
+        ) {
+            error!("could not compute {}: {}", field_op.output_name, error);
+        }
+    }
+
+    for masked_stats in &opts.masked_stats {
+        if let Err(error) = param_bag.masked_stats(
+            &masked_stats.field,
+            &masked_stats.mask,
+            &masked_stats.prefix,
+        ) {
+            error!(
+                "could not compute statistics of {} in {}: {}",
+                masked_stats.field, masked_stats.mask, error
+            );
+        }
+    }
+
+    if let Err(error) = param_bag.check_boxes() {
+        if opts.allow_mismatched_boxes {
+            warn!("{}", error);
+        } else {
+            return Err(error);
+        }
     }
 
     let h5_file_name = opts.output.file_name().unwrap().to_string_lossy();
@@ -344,7 +1200,49 @@ fn main(opts: Opts) -> Result<(), failure::Error> {
     write_xdmf(offsets, &param_bag, &h5_file_name, &opts)?;
 
     // Write HDF5
-    write_hdf5(&opts.output, &param_bag)?;
+    let start = Instant::now();
+    write_hdf5(&opts.output, &param_bag, &opts)?;
+    stages.push(StageTiming::since("write hdf5", start));
+
+    write_outputs(offsets, &param_bag, &opts, &mut stages)?;
+
+    // Write JSON summary
+    if let Some(summary_path) = &opts.summary {
+        let summary = summary::RunSummary::new(
+            &param_bag,
+            geometry_bounding_box,
+            stages,
+            std::env::args().collect(),
+        );
+
+        let mut json = std::io::BufWriter::new(File::create(summary_path)?);
+        summary.write_json(&mut json)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voxel_size_is_positive() {
+        assert_eq!(parse_voxel_size("0.5").unwrap(), 0.5);
+        for size in &["0", "-1", "NaN", "inf"] {
+            assert!(parse_voxel_size(size).is_err());
+        }
+    }
+
+    #[test]
+    fn depth_image_dir_requires_export() {
+        let args = |extra: &[&str]| {
+            let mut args = vec!["icesl2voxel", "-i", "part.xml", "-o", "part.h5"];
+            args.extend_from_slice(extra);
+            Opts::from_iter_safe(args)
+        };
+
+        assert!(args(&["--depth-image-dir", "depth"]).is_err());
+        assert!(args(&["--export-depth-images", "--depth-image-dir", "depth"]).is_ok());
+    }
+}
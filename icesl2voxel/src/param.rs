@@ -1,10 +1,40 @@
+use hdf5::types::{FixedAscii, VarLenUnicode};
 use serde_derive::{Deserialize, Serialize};
 
+use super::param_field::Hdf5Options;
 use super::parse::Parse;
+use super::utils::csv_escape;
+
+/// Capacity of fixed-length HDF5 strings, in bytes
+pub const FIXED_STRING_LEN: usize = 256;
+
+pub type FixedString = FixedAscii<[u8; FIXED_STRING_LEN]>;
+
+/// Fixed-length ASCII version of `value`. Non-ASCII characters are replaced with `?` and long
+/// strings are truncated.
+pub fn to_fixed_string(value: &str) -> FixedString {
+    let bytes: Vec<u8> = value
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'?' })
+        .take(FIXED_STRING_LEN)
+        .collect();
+
+    FixedString::from_ascii(&bytes[..]).expect("sanitized string")
+}
+
+/// Variable-length version of `value`
+pub fn to_var_len_string(value: &str) -> VarLenUnicode {
+    // Only fails on interior null bytes
+    value
+        .replace('\0', "")
+        .parse()
+        .expect("string without null bytes")
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Param {
     Bool(bool),
+    Int(i64),
     Float(f64),
     String(String),
 }
@@ -13,7 +43,9 @@ impl std::convert::TryFrom<&str> for Param {
     type Error = failure::Error;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        Ok(if let Ok(value) = <f64 as Parse<f64>>::parse(value) {
+        Ok(if let Ok(value) = <i64 as Parse<i64>>::parse(value) {
+            Param::Int(value)
+        } else if let Ok(value) = <f64 as Parse<f64>>::parse(value) {
             Param::Float(value)
         } else if let Ok(value) = <bool as Parse<bool>>::parse(value) {
             Param::Bool(value)
@@ -31,36 +63,115 @@ impl Param {
         }
     }
 
+    pub fn as_int(&self) -> i64 {
+        match self {
+            Self::Int(value) => *value,
+            _ => panic!(),
+        }
+    }
+
+    /// Value of a float parameter, integers are converted
     pub fn as_float(&self) -> f64 {
         match self {
+            Self::Int(value) => *value as f64,
             Self::Float(value) => *value,
             _ => panic!(),
         }
     }
 
-    pub fn write_hdf5(&self, path: &str, file: &hdf5::File) -> Result<(), hdf5::Error> {
+    /// Value of this parameter as a CSV cell
+    pub fn csv_value(&self) -> String {
+        match self {
+            Self::Bool(value) => value.to_string(),
+            Self::Int(value) => value.to_string(),
+            Self::Float(value) => value.to_string(),
+            Self::String(value) => csv_escape(value).into_owned(),
+        }
+    }
+
+    pub fn write_hdf5(
+        &self,
+        path: &str,
+        file: &hdf5::File,
+        options: &Hdf5Options,
+    ) -> Result<(), hdf5::Error> {
         match self {
             Self::Bool(value) => {
-                file.new_dataset::<u8>()
+                options
+                    .new_dataset::<u8>(file)
                     .create(&path, (1,))?
                     .write(&[if *value { 1 } else { 0 }])?;
             }
+            Self::Int(value) => {
+                options
+                    .new_dataset::<i64>(file)
+                    .create(&path, (1,))?
+                    .write(&[*value])?;
+            }
             Self::Float(value) => {
-                file.new_dataset::<f64>()
+                options
+                    .new_dataset::<f64>(file)
                     .create(&path, (1,))?
                     .write(&[*value])?;
             }
+            // Filters don't apply to variable-length data, so strings are never compressed
             Self::String(value) => {
-                let bytes = value.as_bytes();
-
-                file.new_dataset::<u8>()
-                    .create(&path, (bytes.len(),))?
-                    .write(bytes)?;
+                if options.fixed_length_strings {
+                    file.new_dataset::<FixedString>()
+                        .create(&path, (1,))?
+                        .write(&[to_fixed_string(value)])?;
+                } else {
+                    file.new_dataset::<VarLenUnicode>()
+                        .create(&path, (1,))?
+                        .write(&[to_var_len_string(value)])?;
+                }
             }
         }
 
         Ok(())
     }
+
+    /// Read a parameter written by `write_hdf5`. Older files store strings as bytes like bools,
+    /// so a single 0 or 1 byte is read back as a bool.
+    pub fn read_hdf5(path: &str, file: &hdf5::File) -> Result<Self, failure::Error> {
+        let dataset = file.dataset(path)?;
+        let dtype = dataset.dtype()?;
+
+        if dtype.is::<i64>() {
+            dataset
+                .read_raw::<i64>()?
+                .first()
+                .map(|value| Self::Int(*value))
+                .ok_or_else(|| failure::err_msg("empty integer parameter"))
+        } else if dtype.is::<f64>() {
+            dataset
+                .read_raw::<f64>()?
+                .first()
+                .map(|value| Self::Float(*value))
+                .ok_or_else(|| failure::err_msg("empty float parameter"))
+        } else if dtype.is::<VarLenUnicode>() {
+            dataset
+                .read_raw::<VarLenUnicode>()?
+                .first()
+                .map(|value| Self::String(value.as_str().to_owned()))
+                .ok_or_else(|| failure::err_msg("empty string parameter"))
+        } else if dtype.is::<FixedString>() {
+            dataset
+                .read_raw::<FixedString>()?
+                .first()
+                .map(|value| Self::String(value.as_str().to_owned()))
+                .ok_or_else(|| failure::err_msg("empty string parameter"))
+        } else if dtype.is::<u8>() {
+            let bytes = dataset.read_raw::<u8>()?;
+
+            Ok(match bytes[..] {
+                [value] if value <= 1 => Self::Bool(value == 1),
+                _ => Self::String(String::from_utf8(bytes)?),
+            })
+        } else {
+            Err(failure::err_msg("unsupported parameter type"))
+        }
+    }
 }
 
 impl Into<bool> for Param {
@@ -69,6 +180,12 @@ impl Into<bool> for Param {
     }
 }
 
+impl Into<i64> for Param {
+    fn into(self) -> i64 {
+        self.as_int()
+    }
+}
+
 impl Into<f64> for Param {
     fn into(self) -> f64 {
         self.as_float()
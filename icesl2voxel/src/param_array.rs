@@ -1,14 +1,18 @@
 use std::borrow::Cow;
 use std::convert::TryFrom;
 
+use hdf5::types::VarLenUnicode;
 use serde_derive::{Deserialize, Serialize};
 
-use super::param::Param;
+use super::param::{to_fixed_string, to_var_len_string, FixedString, Param};
+use super::param_field::Hdf5Options;
 use super::parse::Parse;
+use super::utils::csv_escape;
 
 #[derive(Debug, Serialize, Deserialize)]
 enum ParamArrayStorage {
     Bool(Vec<bool>),
+    Int(Vec<i64>),
     Float(Vec<f64>),
     String(Vec<String>),
 }
@@ -49,18 +53,34 @@ impl ParamArrayStorage {
         Ok(())
     }
 
+    /// Convert integer values to floats. Returns true if the storage was an integer one.
+    fn promote_to_float(&mut self) -> bool {
+        if let Self::Int(tgt) = self {
+            *self = Self::Float(tgt.iter().map(|v| *v as f64).collect());
+            true
+        } else {
+            false
+        }
+    }
+
     /// Returns Ok(is_empty)
     fn try_add(&mut self, idx: usize, value: &str) -> Result<bool, failure::Error> {
         match self {
             Self::Bool(tgt) => Self::parse_and_add(tgt, idx, value),
+            Self::Int(tgt) => Self::parse_and_add(tgt, idx, value),
             Self::Float(tgt) => Self::parse_and_add(tgt, idx, value),
             Self::String(tgt) => Self::parse_and_add(tgt, idx, value),
         }
     }
 
     fn add(&mut self, idx: usize, value: Param) {
+        if let Param::Float(_) = value {
+            self.promote_to_float();
+        }
+
         match self {
             Self::Bool(tgt) => Self::add_value(tgt, idx, value.into()),
+            Self::Int(tgt) => Self::add_value(tgt, idx, value.into()),
             Self::Float(tgt) => Self::add_value(tgt, idx, value.into()),
             Self::String(tgt) => Self::add_value(tgt, idx, value.into()),
         }
@@ -70,6 +90,7 @@ impl ParamArrayStorage {
     fn len(&self) -> usize {
         match self {
             Self::Bool(tgt) => tgt.len(),
+            Self::Int(tgt) => tgt.len(),
             Self::Float(tgt) => tgt.len(),
             Self::String(tgt) => tgt.len(),
         }
@@ -87,6 +108,9 @@ impl ParamArray {
             Param::Bool(_) => Self {
                 values: ParamArrayStorage::Bool(Vec::with_capacity(256)),
             },
+            Param::Int(_) => Self {
+                values: ParamArrayStorage::Int(Vec::with_capacity(256)),
+            },
             Param::Float(_) => Self {
                 values: ParamArrayStorage::Float(Vec::with_capacity(256)),
             },
@@ -99,6 +123,12 @@ impl ParamArray {
         this
     }
 
+    pub fn from_f64(values: Vec<f64>) -> Self {
+        Self {
+            values: ParamArrayStorage::Float(values),
+        }
+    }
+
     pub fn new(idx: usize, value: &str) -> Result<Self, failure::Error> {
         Ok(if value.is_empty() {
             Self::from_val(idx, Param::String(String::new()))
@@ -107,8 +137,16 @@ impl ParamArray {
         })
     }
 
-    pub fn add(&mut self, idx: usize, value: &str) -> Result<(), failure::Error> {
-        self.values.try_add(idx, value).map(|_| ())
+    /// Parse and set the value at `idx`. Integer arrays are promoted to float arrays by values
+    /// that are only valid floats, in which case true is returned.
+    pub fn add(&mut self, idx: usize, value: &str) -> Result<bool, failure::Error> {
+        let promoted = matches!(self.values, ParamArrayStorage::Int(_))
+            && <i64 as Parse<i64>>::parse(value).is_err()
+            && <f64 as Parse<f64>>::parse(value).is_ok()
+            && self.values.promote_to_float();
+
+        self.values.try_add(idx, value)?;
+        Ok(promoted)
     }
 
     pub fn add_param(&mut self, idx: usize, param: Param) {
@@ -122,6 +160,9 @@ impl ParamArray {
     pub fn as_f64_slice(&self) -> Option<Cow<[f64]>> {
         match &self.values {
             ParamArrayStorage::Float(vec) => Some(Cow::Borrowed(&vec[..])),
+            ParamArrayStorage::Int(vec) => {
+                Some(Cow::Owned(vec.iter().map(|x| *x as f64).collect()))
+            }
             ParamArrayStorage::Bool(vec) => {
                 let other: Vec<_> = vec.iter().map(|x| if *x { 1. } else { 0. }).collect();
                 Some(Cow::Owned(other))
@@ -130,11 +171,28 @@ impl ParamArray {
         }
     }
 
-    pub fn write_hdf5(&self, path: &str, file: &hdf5::File) -> Result<(), failure::Error> {
+    /// Smallest and largest values of numeric arrays, ignoring NaNs
+    pub fn range(&self) -> Option<(f64, f64)> {
+        let values = self.as_f64_slice()?;
+        values
+            .iter()
+            .filter(|v| !v.is_nan())
+            .fold(None, |range, &v| match range {
+                Some((min, max)) => Some((v.min(min), v.max(max))),
+                None => Some((v, v)),
+            })
+    }
+
+    pub fn write_hdf5(
+        &self,
+        path: &str,
+        file: &hdf5::File,
+        options: &Hdf5Options,
+    ) -> Result<(), failure::Error> {
         match &self.values {
             ParamArrayStorage::Bool(value) => {
-                file.new_dataset::<u8>()
-                    .gzip(6)
+                options
+                    .new_dataset::<u8>(file)
                     .create(&path, (value.len(),))?
                     .write(
                         &value
@@ -143,23 +201,105 @@ impl ParamArray {
                             .collect::<Vec<_>>(),
                     )?;
             }
+            ParamArrayStorage::Int(value) => {
+                options
+                    .new_dataset::<i64>(file)
+                    .create(&path, (value.len(),))?
+                    .write(&value[..])?;
+            }
             ParamArrayStorage::Float(value) => {
-                file.new_dataset::<f64>()
-                    .gzip(6)
+                options
+                    .new_dataset::<f64>(file)
                     .create(&path, (value.len(),))?
                     .write(&value[..])?;
             }
-            _ => {
-                return Err(failure::err_msg("unsupported array type"));
+            ParamArrayStorage::String(value) => {
+                if options.fixed_length_strings {
+                    file.new_dataset::<FixedString>()
+                        .create(&path, (value.len(),))?
+                        .write(&value.iter().map(|s| to_fixed_string(s)).collect::<Vec<_>>())?;
+                } else {
+                    file.new_dataset::<VarLenUnicode>()
+                        .create(&path, (value.len(),))?
+                        .write(
+                            &value
+                                .iter()
+                                .map(|s| to_var_len_string(s))
+                                .collect::<Vec<_>>(),
+                        )?;
+                }
             }
         }
 
         Ok(())
     }
 
+    pub fn read_hdf5(path: &str, file: &hdf5::File) -> Result<Self, failure::Error> {
+        let dataset = file.dataset(path)?;
+        let dtype = dataset.dtype()?;
+
+        let values = if dtype.is::<u8>() {
+            ParamArrayStorage::Bool(
+                dataset
+                    .read_raw::<u8>()?
+                    .into_iter()
+                    .map(|b| b != 0)
+                    .collect(),
+            )
+        } else if dtype.is::<i64>() {
+            ParamArrayStorage::Int(dataset.read_raw()?)
+        } else if dtype.is::<f64>() {
+            ParamArrayStorage::Float(dataset.read_raw()?)
+        } else if dtype.is::<VarLenUnicode>() {
+            ParamArrayStorage::String(
+                dataset
+                    .read_raw::<VarLenUnicode>()?
+                    .iter()
+                    .map(|s| s.as_str().to_owned())
+                    .collect(),
+            )
+        } else if dtype.is::<FixedString>() {
+            ParamArrayStorage::String(
+                dataset
+                    .read_raw::<FixedString>()?
+                    .iter()
+                    .map(|s| s.as_str().to_owned())
+                    .collect(),
+            )
+        } else {
+            return Err(failure::err_msg("unsupported array type"));
+        };
+
+        Ok(Self { values })
+    }
+
+    /// Value at `idx` as a CSV cell, `None` past the end of the array
+    pub fn csv_value(&self, idx: usize) -> Option<String> {
+        match &self.values {
+            ParamArrayStorage::Bool(vec) => vec.get(idx).map(bool::to_string),
+            ParamArrayStorage::Int(vec) => vec.get(idx).map(i64::to_string),
+            ParamArrayStorage::Float(vec) => vec.get(idx).map(f64::to_string),
+            ParamArrayStorage::String(vec) => {
+                vec.get(idx).map(|value| csv_escape(value).into_owned())
+            }
+        }
+    }
+
+    /// Write this array as a single CSV column with `name` as its header
+    pub fn write_csv(&self, name: &str, dest: &mut dyn std::io::Write) -> std::io::Result<()> {
+        writeln!(dest, "{}", csv_escape(name))?;
+
+        for idx in 0..self.len() {
+            writeln!(dest, "{}", self.csv_value(idx).unwrap())?;
+        }
+
+        Ok(())
+    }
+
     pub fn xdmf_type(&self) -> Option<(&'static str, usize)> {
         match &self.values {
             ParamArrayStorage::Bool(_) => Some(("UInt", 1)),
+            ParamArrayStorage::Int(_) => Some(("Int", 8)),
             ParamArrayStorage::Float(_) => Some(("Float", 8)),
             _ => None,
         }
@@ -2,6 +2,7 @@ use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 
+use hdf5::types::VarLenUnicode;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use ndarray::prelude::*;
@@ -11,17 +12,63 @@ use xml::reader::{EventReader, XmlEvent};
 
 use super::param::Param;
 use super::param_array::ParamArray;
-use super::param_field::ParamField;
+use super::param_field::{
+    ArrayToFieldMode, AttributeType, Downsample, FieldOp, FieldOpRhs, Hdf5Options, ParamField,
+    ResampleMethod,
+};
+use super::utils::{csv_escape, BoundingBox};
+
+/// Version of the layout written by `ParamBag::write_hdf5`. Version 1 files have no version
+/// attribute and store field bounding boxes as datasets.
+pub const HDF5_FORMAT_VERSION: u32 = 2;
+
+const HDF5_GENERATOR: &str = concat!("icesl2voxel ", env!("CARGO_PKG_VERSION"));
 
 lazy_static! {
     static ref ELEMENT_NAME_PARAM_RE: Regex = Regex::new(r"^(.*)_(\d*)$").unwrap();
 }
 
+/// Right-hand side of a field operation: a field name, or a scalar if it parses as a number
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldOperand {
+    Field(String),
+    Scalar(f32),
+}
+
+impl std::str::FromStr for FieldOperand {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.parse() {
+            Ok(value) => Self::Scalar(value),
+            Err(_) => Self::Field(s.to_owned()),
+        })
+    }
+}
+
+fn write_xdmf_header(dest: &mut dyn std::io::Write) -> std::io::Result<()> {
+    writeln!(dest, "<?xml version=\"1.0\" encoding=\"utf-8\" ?>")?;
+    writeln!(dest, "<!DOCTYPE Xdmf SYSTEM \"Xdmf.dtd\" []>")?;
+    writeln!(dest, "<Xdmf Version=\"2.0\">")?;
+    writeln!(dest, "  <Domain>")
+}
+
+fn write_xdmf_footer(dest: &mut dyn std::io::Write) -> std::io::Result<()> {
+    writeln!(dest, "  </Domain>")?;
+    writeln!(dest, "</Xdmf>")
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct ParamBag {
     param_fields: HashMap<String, ParamField>,
     param_arrays: HashMap<String, ParamArray>,
     params: HashMap<String, Param>,
+    /// Names of the values of categorical fields, indexed by value
+    #[serde(default)]
+    field_labels: HashMap<String, Vec<String>>,
+    /// Point annotations as (x, y, z, layer), in printer coordinates
+    #[serde(default)]
+    param_points: HashMap<String, Vec<[f32; 4]>>,
 }
 
 impl ParamBag {
@@ -86,14 +133,18 @@ impl ParamBag {
         Ok(param_bag)
     }
 
-    pub fn convert_to_field(&mut self, name: &str) -> Result<(), failure::Error> {
+    pub fn convert_to_field(
+        &mut self,
+        name: &str,
+        mode: ArrayToFieldMode,
+    ) -> Result<(), failure::Error> {
         if let Some(array) = self.param_arrays.get(name) {
             // Insert converted field
             if let Some(field) = self
                 .param_fields
                 .values()
                 .next()
-                .and_then(|first_field| first_field.derive_from_array(&array))
+                .and_then(|first_field| first_field.derive_from_array(&array, mode))
             {
                 // Add field to list
                 self.param_fields.insert(name.to_owned(), field);
@@ -117,6 +168,35 @@ impl ParamBag {
         self.param_fields.get(name)
     }
 
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &ParamField)> {
+        self.param_fields
+            .iter()
+            .map(|(name, field)| (name.as_str(), field))
+    }
+
+    pub fn arrays(&self) -> impl Iterator<Item = (&str, &ParamArray)> {
+        self.param_arrays
+            .iter()
+            .map(|(name, array)| (name.as_str(), array))
+    }
+
+    pub fn params(&self) -> impl Iterator<Item = (&str, &Param)> {
+        self.params
+            .iter()
+            .map(|(name, param)| (name.as_str(), param))
+    }
+
+    /// Name of the field with the most cells, if any
+    pub fn largest_field_name(&self) -> Option<&str> {
+        self.param_fields
+            .iter()
+            .max_by_key(|(name, field)| {
+                let d = field.dim();
+                (d.0 * d.1 * d.2, std::cmp::Reverse(*name))
+            })
+            .map(|(name, _field)| name.as_str())
+    }
+
     pub fn assemble_spherical(
         &mut self,
         name: &str,
@@ -148,6 +228,17 @@ impl ParamBag {
             Cow::Owned(ndarray::Array3::ones((dim.0, dim.1, dim.2)))
         };
 
+        // Vector fields only make sense as radii, through their magnitude
+        let angle_sources = if sources.len() >= 3 { 1 } else { 0 };
+        if let Some(idx) =
+            (angle_sources..sources.len()).find(|idx| sources[*idx].as_vec3().is_some())
+        {
+            return Err(failure::err_msg(format!(
+                "{} is a vector field and can't be used as an angle",
+                source_names[idx].as_ref()
+            )));
+        }
+
         let param_theta = if sources.len() >= 1 {
             let idx = if sources.len() >= 3 { 1 } else { 0 };
             sources[idx].as_f32_array(180.0).ok_or_else(|| {
@@ -188,6 +279,184 @@ impl ParamBag {
         Ok(self.param_fields.get(name).unwrap())
     }
 
+    /// Assemble a vector field from its X, Y and Z component fields, which must be scalar fields
+    /// on the same grid. Byte fields are mapped to [0, 1]. With only two sources, the Z component
+    /// is zero.
+    pub fn assemble_cartesian(
+        &mut self,
+        name: &str,
+        source_names: &[impl AsRef<str>],
+        normalize: bool,
+    ) -> Result<&ParamField, failure::Error> {
+        if source_names.len() < 2 || source_names.len() > 3 {
+            return Err(failure::err_msg(format!(
+                "expected 3 component fields, got {}",
+                source_names.len()
+            )));
+        } else if source_names.len() == 2 {
+            warn!("no z component field for {}, using zeros", name);
+        }
+
+        let sources: Result<Vec<&ParamField>, _> = source_names
+            .iter()
+            .map(|src_name| {
+                self.param_fields.get(src_name.as_ref()).ok_or_else(|| {
+                    failure::err_msg(format!("{} field not found", src_name.as_ref()))
+                })
+            })
+            .collect();
+
+        let sources = sources?;
+
+        let components: Result<Vec<_>, _> = sources
+            .iter()
+            .zip(source_names)
+            .map(|(source, src_name)| {
+                if source.as_vec3().is_some() {
+                    Err(failure::err_msg(format!(
+                        "{} is a vector field and can't be used as a component",
+                        src_name.as_ref()
+                    )))
+                } else if !source.has_same_box(sources[0]) || source.dim() != sources[0].dim() {
+                    Err(failure::err_msg(format!(
+                        "{} is not on the grid of {}",
+                        src_name.as_ref(),
+                        source_names[0].as_ref()
+                    )))
+                } else {
+                    source.as_f32_array(1.0).ok_or_else(|| {
+                        failure::err_msg(format!(
+                            "could not convert {} field to float",
+                            src_name.as_ref()
+                        ))
+                    })
+                }
+            })
+            .collect();
+
+        let components = components?;
+
+        let dim = sources[0].dim();
+        let mut data = ndarray::Array4::zeros((dim.0, dim.1, dim.2, 3));
+
+        for (c, component) in components.iter().enumerate() {
+            data.index_axis_mut(Axis(3), c).assign(&**component);
+        }
+
+        if normalize {
+            for mut vec in data.lanes_mut(Axis(3)) {
+                let norm = vec.dot(&vec).sqrt();
+                if norm > 0. {
+                    vec /= norm;
+                }
+            }
+        }
+
+        let field = sources[0].derive_vec3_from_field(data);
+
+        self.param_fields.insert(name.to_owned(), field);
+        Ok(self.param_fields.get(name).unwrap())
+    }
+
+    /// Add the field `output` computed by applying `op` between the fields `lhs` and `rhs`. See
+    /// `ParamField::apply_op` for the supported fields.
+    pub fn apply_op(
+        &mut self,
+        output: &str,
+        op: FieldOp,
+        lhs: &str,
+        rhs: &FieldOperand,
+    ) -> Result<&ParamField, failure::Error> {
+        let get_field = |name: &str| {
+            self.param_fields
+                .get(name)
+                .ok_or_else(|| failure::err_msg(format!("{} field not found", name)))
+        };
+
+        let (rhs, rhs_name) = match rhs {
+            FieldOperand::Field(name) => (FieldOpRhs::Field(get_field(name)?), name.clone()),
+            FieldOperand::Scalar(value) => (FieldOpRhs::Scalar(*value), value.to_string()),
+        };
+
+        let description = match op {
+            FieldOp::Add => format!("{} + {}", lhs, rhs_name),
+            FieldOp::Sub => format!("{} - {}", lhs, rhs_name),
+            FieldOp::Mul => format!("{} * {}", lhs, rhs_name),
+            FieldOp::Threshold => format!("255 where {} >= {}, 0 elsewhere", lhs, rhs_name),
+            FieldOp::Mask => format!("{} where {} is non-zero, 0 elsewhere", lhs, rhs_name),
+        };
+
+        let field = get_field(lhs)?
+            .apply_op(op, rhs)?
+            .with_description(description);
+
+        self.param_fields.insert(output.to_owned(), field);
+        Ok(self.param_fields.get(output).unwrap())
+    }
+
+    /// Record statistics of `field` over the cells where `mask` is non-zero as the parameters
+    /// `<prefix>_count`, `<prefix>_mean`, `<prefix>_std`, `<prefix>_min` and `<prefix>_max`.
+    /// Byte values are mapped to [0, 1] and vector fields reduced to their magnitude.
+    pub fn masked_stats(
+        &mut self,
+        field: &str,
+        mask: &str,
+        prefix: &str,
+    ) -> Result<(), failure::Error> {
+        let get_values = |name: &str| {
+            self.param_fields
+                .get(name)
+                .ok_or_else(|| failure::err_msg(format!("{} field not found", name)))
+                .and_then(|field| {
+                    field.as_f32_array(1.0).ok_or_else(|| {
+                        failure::err_msg(format!("could not convert {} field to float", name))
+                    })
+                })
+        };
+
+        let values = get_values(field)?;
+        let mask_values = get_values(mask)?;
+        if !self.param_fields[field].has_same_box(&self.param_fields[mask]) {
+            return Err(failure::err_msg(format!(
+                "{} and {} have different grids, harmonize them first",
+                field, mask
+            )));
+        }
+
+        let masked: Vec<f64> = values
+            .iter()
+            .zip(mask_values.iter())
+            .filter(|(_v, m)| **m > 0.0)
+            .map(|(v, _m)| f64::from(*v))
+            .collect();
+
+        if masked.is_empty() {
+            return Err(failure::err_msg(format!("{} mask is empty", mask)));
+        }
+
+        let count = masked.len() as f64;
+        let mean = masked.iter().sum::<f64>() / count;
+        let std = (masked.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / count).sqrt();
+        let min = masked.iter().cloned().fold(std::f64::INFINITY, f64::min);
+        let max = masked
+            .iter()
+            .cloned()
+            .fold(std::f64::NEG_INFINITY, f64::max);
+
+        for (name, value) in &[
+            ("count", count),
+            ("mean", mean),
+            ("std", std),
+            ("min", min),
+            ("max", max),
+        ] {
+            self.params
+                .insert(format!("{}_{}", prefix, name), Param::Float(*value));
+        }
+
+        Ok(())
+    }
+
     fn add_item(&mut self, name: &str, value: &str) -> Result<(), failure::Error> {
         self.params.insert(name.to_owned(), Param::try_from(value)?);
         Ok(())
@@ -197,6 +466,23 @@ impl ParamBag {
         self.param_fields.insert(name.to_owned(), field);
     }
 
+    pub fn add_array(&mut self, name: &str, array: ParamArray) {
+        self.param_arrays.insert(name.to_owned(), array);
+    }
+
+    pub fn add_points(&mut self, name: &str, points: Vec<[f32; 4]>) {
+        self.param_points.insert(name.to_owned(), points);
+    }
+
+    /// Name the values of a categorical field, written as `label_<value>` attributes of its HDF5
+    /// dataset
+    pub fn add_field_labels(&mut self, name: &str, labels: &[&str]) {
+        self.field_labels.insert(
+            name.to_owned(),
+            labels.iter().map(|label| (*label).to_owned()).collect(),
+        );
+    }
+
     fn add_parsed_field(
         &mut self,
         name: &str,
@@ -223,7 +509,12 @@ impl ParamBag {
             .parse()?;
 
         if let Some(array) = self.param_arrays.get_mut(param_name) {
-            array.add(param_idx, value)?;
+            if array.add(param_idx, value)? {
+                warn!(
+                    "array {} has non-integer value `{}` at index {}, promoted to float",
+                    param_name, value, param_idx
+                );
+            }
         } else {
             self.param_arrays
                 .insert(param_name.to_owned(), ParamArray::new(param_idx, value)?);
@@ -232,32 +523,163 @@ impl ParamBag {
         Ok(())
     }
 
+    pub fn crop_fields(&mut self, bbox: &BoundingBox<f32>) -> Result<(), failure::Error> {
+        for (name, field) in self.param_fields.iter_mut() {
+            *field = field
+                .crop(bbox)
+                .map_err(|e| failure::err_msg(format!("could not crop {}: {}", name, e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Move all fields by `offset` in mm
+    pub fn translate_fields(&mut self, offset: nalgebra::Vector3<f32>) {
+        for field in self.param_fields.values_mut() {
+            field.field_box_mm.translate(offset);
+        }
+    }
+
+    /// Resample the fields that don't share the grid of the `reference` field onto it, with
+    /// `method` or the default method of each field
+    pub fn harmonize_grids(
+        &mut self,
+        reference: &str,
+        method: Option<ResampleMethod>,
+    ) -> Result<(), failure::Error> {
+        let reference_field = self
+            .param_fields
+            .get(reference)
+            .ok_or_else(|| failure::err_msg(format!("reference field {} not found", reference)))?
+            .clone();
+
+        let mut errors = Vec::new();
+        for (name, field) in self.param_fields.iter_mut() {
+            if field.has_same_box(&reference_field) {
+                continue;
+            }
+
+            let method = method.unwrap_or_else(|| field.default_resample_method());
+            match field.resample_grid(&reference_field, method) {
+                Ok(resampled) => {
+                    debug!("resampled {} onto the grid of {}", name, reference);
+                    *field = resampled;
+                }
+                Err(error) => errors.push(format!("{} ({})", name, error)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            errors.sort();
+            Err(failure::err_msg(format!(
+                "could not resample fields onto the grid of {}: {}",
+                reference,
+                errors.join(", ")
+            )))
+        }
+    }
+
+    /// Check that fields sharing a grid size also share a bounding box, since they are written to
+    /// the same XDMF grid
+    pub fn check_boxes(&self) -> Result<(), failure::Error> {
+        let mut grids: HashMap<_, (&str, &ParamField)> = HashMap::new();
+        let mut mismatched = Vec::new();
+
+        for (name, field) in self
+            .param_fields
+            .iter()
+            .sorted_by_key(|(name, _field)| *name)
+        {
+            let d = field.dim();
+            let (first_name, first_field) = *grids.entry((d.0, d.1, d.2)).or_insert((name, field));
+
+            if !field.has_same_box(first_field) {
+                mismatched.push(format!(
+                    "{} ({:?}, expected {:?} from {})",
+                    name, field.field_box_mm, first_field.field_box_mm, first_name
+                ));
+            }
+        }
+
+        if mismatched.is_empty() {
+            Ok(())
+        } else {
+            Err(failure::err_msg(format!(
+                "fields don't have the same bounding box as the other fields of their grid: {}",
+                mismatched.join(", ")
+            )))
+        }
+    }
+
+    /// Downsample all fields by `factor`. Geometry fields (`*_geometry`) are treated as masks, and
+    /// the fields resampled with the nearest method by default, such as categories, keep the most
+    /// frequent value.
+    pub fn downsample_fields(&mut self, factor: usize) {
+        for (name, field) in self.param_fields.iter_mut() {
+            let method = if name.ends_with("_geometry") {
+                Downsample::MajorityThreshold
+            } else if field.default_resample_method() == ResampleMethod::Nearest {
+                Downsample::Mode
+            } else {
+                Downsample::Mean
+            };
+
+            *field = field.downsample(factor, method);
+        }
+    }
+
+    /// Pad all fields with `pad` layers of zeros on each side, arrays and parameters are unchanged
     pub fn pad_fields(&mut self, pad: usize) {
         for field in self.param_fields.values_mut() {
             field.pad(pad);
         }
     }
 
-    pub fn write_hdf5(&self, file: &hdf5::File) -> Result<(), failure::Error> {
-        // Assume all fields share the same grid
-        let first_field = self.param_fields.iter().next().unwrap().1;
-
-        // For converting into standard layout
-        let dim = first_field.dim();
-        let mut std_layout = ndarray::Array3::<u8>::zeros((dim.0, dim.1, dim.2));
+    pub fn write_hdf5(
+        &self,
+        file: &hdf5::File,
+        options: &Hdf5Options,
+    ) -> Result<(), failure::Error> {
+        // Format metadata
+        file.new_attr::<u32>()
+            .create("format_version", ())?
+            .write_scalar(&HDF5_FORMAT_VERSION)?;
+        file.new_attr::<VarLenUnicode>()
+            .create("generator", ())?
+            .write_scalar(
+                &HDF5_GENERATOR
+                    .parse::<VarLenUnicode>()
+                    .map_err(|_| failure::err_msg("invalid generator string"))?,
+            )?;
 
         // Write fields
         for (name, field) in &self.param_fields {
             let path = format!("/fields/{}", name);
 
-            field.write_hdf5(&path, &file, &mut std_layout)?;
+            field.write_hdf5(&path, &file, options)?;
+
+            if let Some(labels) = self.field_labels.get(name) {
+                let data = file.dataset(&format!("{}/data", path))?;
+
+                for (value, label) in labels.iter().enumerate() {
+                    data.new_attr::<VarLenUnicode>()
+                        .create(&format!("label_{}", value), ())?
+                        .write_scalar(
+                            &label
+                                .parse::<VarLenUnicode>()
+                                .map_err(|_| failure::err_msg("invalid field label"))?,
+                        )?;
+                }
+            }
         }
 
         // Write array params
         for (name, array) in &self.param_arrays {
             let path = format!("/arrays/{}", name);
 
-            match array.write_hdf5(&path, &file) {
+            match array.write_hdf5(&path, &file, options) {
                 Err(err) => {
                     warn!("array {} not written to HDF5 file: {}", name, err);
                 }
@@ -265,11 +687,33 @@ impl ParamBag {
             }
         }
 
+        // Write point annotations
+        for (name, points) in &self.param_points {
+            // Filtered datasets are chunked, and HDF5 can't chunk an empty dataset
+            if points.is_empty() && options.compression > 0 {
+                return Err(failure::err_msg(format!(
+                    "points {} are empty and can't be written with compression",
+                    name
+                )));
+            }
+
+            let path = format!("/points/{}", name);
+            let data = ndarray::Array2::from_shape_fn((points.len(), 4), |(i, j)| points[i][j]);
+
+            options
+                .new_dataset::<f32>(file)
+                .create(&path, data.dim())
+                .and_then(|dataset| dataset.write(&data))
+                .map_err(|err| {
+                    failure::err_msg(format!("points {} not written to HDF5 file: {}", name, err))
+                })?;
+        }
+
         // Write params
         for (name, param) in &self.params {
             let path = format!("/parameters/{}", name);
 
-            match param.write_hdf5(&path, &file) {
+            match param.write_hdf5(&path, &file, options) {
                 Err(err) => {
                     error!("param {} not written to HDF5 file: {}", name, err);
                 }
@@ -280,20 +724,216 @@ impl ParamBag {
         Ok(())
     }
 
+    /// Read a bag written by `write_hdf5`. Fields stored as ByteVec4 are read back as Byte fields.
+    pub fn read_hdf5(file: &hdf5::File) -> Result<Self, failure::Error> {
+        let mut param_bag = ParamBag::new();
+
+        // Files without a version use the legacy layout
+        match file.attr("format_version") {
+            Ok(version) => {
+                let version = version.read_scalar::<u32>()?;
+                if version > HDF5_FORMAT_VERSION {
+                    warn!(
+                        "HDF5 file format version {} is newer than supported version {}",
+                        version, HDF5_FORMAT_VERSION
+                    );
+                }
+            }
+            Err(_) => debug!("HDF5 file has no format version, assuming legacy layout"),
+        }
+
+        for group in file.member_names()? {
+            let names = match group.as_str() {
+                "fields" | "arrays" | "points" | "parameters" => {
+                    file.group(&group)?.member_names()?
+                }
+                _ => {
+                    warn!("skipping unknown group {} in HDF5 file", group);
+                    continue;
+                }
+            };
+
+            for name in names {
+                let path = format!("/{}/{}", group, name);
+
+                match group.as_str() {
+                    "fields" => {
+                        param_bag.add_field(&name, ParamField::read_hdf5(&path, file)?);
+                    }
+                    "points" => {
+                        let values = file.dataset(&path)?.read_raw::<f32>()?;
+                        param_bag.add_points(
+                            &name,
+                            values
+                                .chunks_exact(4)
+                                .map(|p| [p[0], p[1], p[2], p[3]])
+                                .collect(),
+                        );
+                    }
+                    "arrays" => match ParamArray::read_hdf5(&path, file) {
+                        Ok(array) => {
+                            param_bag.param_arrays.insert(name, array);
+                        }
+                        Err(err) => {
+                            warn!("array {} not read from HDF5 file: {}", name, err);
+                        }
+                    },
+                    _ => match Param::read_hdf5(&path, file) {
+                        Ok(param) => {
+                            param_bag.params.insert(name, param);
+                        }
+                        Err(err) => {
+                            error!("param {} not read from HDF5 file: {}", name, err);
+                        }
+                    },
+                }
+            }
+        }
+
+        Ok(param_bag)
+    }
+
+    /// Write arrays as CSV columns, padded to the length of the longest one, followed by a
+    /// name,value section for scalar parameters
+    pub fn write_csv_summary(&self, dest: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let arrays: Vec<_> = self
+            .param_arrays
+            .iter()
+            .sorted_by_key(|(name, _array)| name.as_str())
+            .collect();
+
+        if !arrays.is_empty() {
+            writeln!(
+                dest,
+                "{}",
+                arrays
+                    .iter()
+                    .map(|(name, _array)| csv_escape(name))
+                    .join(",")
+            )?;
+
+            let rows = arrays
+                .iter()
+                .map(|(_name, array)| array.len())
+                .max()
+                .unwrap();
+            for idx in 0..rows {
+                writeln!(
+                    dest,
+                    "{}",
+                    arrays
+                        .iter()
+                        .map(|(_name, array)| array.csv_value(idx).unwrap_or_default())
+                        .join(",")
+                )?;
+            }
+
+            writeln!(dest)?;
+        }
+
+        writeln!(dest, "name,value")?;
+        for (name, param) in self
+            .params
+            .iter()
+            .sorted_by_key(|(name, _param)| name.as_str())
+        {
+            writeln!(dest, "{},{}", csv_escape(name), param.csv_value())?;
+        }
+
+        Ok(())
+    }
+
     pub fn write_xdmf(
         &self,
         offsets: nalgebra::Vector3<f32>,
         h5_file_name: &str,
         dest: &mut dyn std::io::Write,
         export_arrays: bool,
+        array_filter: &[String],
     ) -> std::io::Result<()> {
-        writeln!(dest, "<?xml version=\"1.0\" encoding=\"utf-8\" ?>")?;
-        writeln!(dest, "<!DOCTYPE Xdmf SYSTEM \"Xdmf.dtd\" []>")?;
-        writeln!(dest, "<Xdmf Version=\"2.0\">")?;
-        writeln!(dest, "  <Domain>")?;
+        write_xdmf_header(dest)?;
 
         writeln!(dest, "    <Grid Name=\"root\" GridType=\"Collection\">")?;
+        self.write_xdmf_grids(offsets, h5_file_name, dest, export_arrays, array_filter)?;
+        writeln!(dest, "    </Grid>")?;
+
+        write_xdmf_footer(dest)
+    }
+
+    /// Write a temporal collection referencing one HDF5 file per timestep. `steps` lists the
+    /// time, HDF5 file name and contents of each timestep, which must all have the same fields on
+    /// the same grids.
+    pub fn write_xdmf_series(
+        steps: &[(f64, &str, &ParamBag)],
+        offsets: nalgebra::Vector3<f32>,
+        dest: &mut dyn std::io::Write,
+        export_arrays: bool,
+        array_filter: &[String],
+    ) -> Result<(), failure::Error> {
+        let field_dims = |bag: &ParamBag| -> Vec<_> {
+            bag.param_fields
+                .iter()
+                .map(|(name, field)| (name.clone(), field.dim()))
+                .sorted()
+                .collect()
+        };
+
+        if let Some((_, first_name, first_bag)) = steps.first() {
+            let first_dims = field_dims(first_bag);
 
+            for (_, name, bag) in &steps[1..] {
+                if field_dims(bag) != first_dims {
+                    return Err(failure::err_msg(format!(
+                        "fields in {} don't match the fields in {}",
+                        name, first_name
+                    )));
+                }
+            }
+        }
+
+        write_xdmf_header(dest)?;
+        writeln!(
+            dest,
+            "    <Grid Name=\"series\" GridType=\"Collection\" CollectionType=\"Temporal\">"
+        )?;
+
+        for (idx, (time, h5_file_name, bag)) in steps.iter().enumerate() {
+            writeln!(
+                dest,
+                "      <Grid Name=\"step{idx}\" GridType=\"Collection\">",
+                idx = idx
+            )?;
+            writeln!(dest, "        <Time Value=\"{}\" />", time)?;
+
+            // Indent the timestep grids one level deeper
+            let mut grids = Vec::new();
+            bag.write_xdmf_grids(
+                offsets,
+                h5_file_name,
+                &mut grids,
+                export_arrays,
+                array_filter,
+            )?;
+            for line in String::from_utf8(grids)?.lines() {
+                writeln!(dest, "  {}", line)?;
+            }
+
+            writeln!(dest, "      </Grid>")?;
+        }
+
+        writeln!(dest, "    </Grid>")?;
+        Ok(write_xdmf_footer(dest)?)
+    }
+
+    /// Write the uniform grids for fields and arrays, to be nested in a collection
+    fn write_xdmf_grids(
+        &self,
+        offsets: nalgebra::Vector3<f32>,
+        h5_file_name: &str,
+        dest: &mut dyn std::io::Write,
+        export_arrays: bool,
+        array_filter: &[String],
+    ) -> std::io::Result<()> {
         // List of all fields to dump
         let mut all_fields: Vec<_> = self.param_fields.iter().collect();
 
@@ -366,7 +1006,7 @@ impl ParamBag {
             // Write fields
             for (name, field) in fields {
                 let path = format!("/fields/{}", name);
-                if let Some((data_type, precision, components)) = field.xdmf_type() {
+                if let Some((data_type, precision, attribute_type)) = field.xdmf_type() {
                     // Since we assume all fields have the same bounding box, check that it's actually the
                     // case
                     if !field.has_same_box(first_field) {
@@ -382,41 +1022,66 @@ impl ParamBag {
                             dest,
                             "        <Attribute Name=\"{name}\" AttributeType=\"{attribute_type}\" Center=\"Cell\">",
                             name = name,
-                            attribute_type = if components == 1 {
-                                "Scalar"
-                            } else {
-                                "Vector"
-                            }
+                            attribute_type = attribute_type.xdmf_name(),
                         )?;
+                        for (info, value) in
+                            &[("unit", &field.unit), ("description", &field.description)]
+                        {
+                            if let Some(value) = value {
+                                writeln!(
+                                    dest,
+                                    "          <Information Name=\"{info}\" Value=\"{value}\" />",
+                                    info = info,
+                                    value = xml::escape::escape_str_attribute(value),
+                                )?;
+                            }
+                        }
                         writeln!(dest, "          <DataItem Dimensions=\"{z} {y} {x}{d}\" Format=\"HDF5\" DataType=\"{data_type}\" Precision=\"{precision}\">",
                             x = dim.2,
                             y = dim.1,
                             z = dim.0,
                             data_type = data_type,
                             precision = precision,
-                            d = if components == 1 {
+                            d = if attribute_type == AttributeType::Scalar {
                                 "".to_owned()
                             } else {
-                                format!(" {}", components)
+                                format!(" {}", attribute_type.components())
                             },
                         )?;
                         writeln!(dest, "            {}:{}", h5_file_name, path)?;
                         writeln!(dest, "          </DataItem>")?;
                         writeln!(dest, "        </Attribute>")?;
                     }
+                } else {
+                    warn!(
+                        "field {} not written to XDMF: {} components per cell is not a scalar, vector or tensor",
+                        name,
+                        field.dim().3
+                    );
                 }
             }
 
             writeln!(dest, "      </Grid>")?;
         }
 
+        // Points are in printer coordinates, move them like the fields
+        let translation = offsets - all_fields[0].1.field_box_mm.center();
+        self.write_xdmf_points(translation, h5_file_name, dest)?;
+
         // Size of the smallest field
         let box_size = all_fields[0].1.field_box_mm.size();
 
         if export_arrays {
-            // Write array params
-            let mut arrays: Vec<_> = self.param_arrays.iter().collect();
-            arrays.sort_by_key(|(_, array)| array.len());
+            // Write array params, only keeping the ones in the filter if there is one
+            let mut arrays: Vec<_> = self
+                .param_arrays
+                .iter()
+                .filter(|(name, array)| {
+                    array.xdmf_type().is_some()
+                        && (array_filter.is_empty() || array_filter.contains(name))
+                })
+                .collect();
+            arrays.sort_by_key(|(name, array)| (array.len(), name.as_str()));
 
             for (len, arrays) in &arrays.iter().group_by(|(_, array)| array.len()) {
                 let mut scale = None;
@@ -471,13 +1136,7 @@ impl ParamBag {
                         scale = Some((array_x_scale, array_y_scale, array_z_scale));
                     }
 
-                    let mut xdmf_type = array.xdmf_type();
-
-                    if *name != "infill_theta" {
-                        xdmf_type = None;
-                    }
-
-                    if let Some((data_type, precision)) = xdmf_type {
+                    if let Some((data_type, precision)) = array.xdmf_type() {
                         writeln!(
                             dest,
                             "        <Attribute Name=\"{name}\" AttributeType=\"Scalar\" Center=\"Cell\">",
@@ -500,10 +1159,1445 @@ impl ParamBag {
             }
         }
 
-        writeln!(dest, "    </Grid>")?;
-        writeln!(dest, "  </Domain>")?;
-        writeln!(dest, "</Xdmf>")?;
+        Ok(())
+    }
+
+    /// Write a Polyvertex grid for each set of points, with the layer as a node attribute
+    fn write_xdmf_points(
+        &self,
+        translation: nalgebra::Vector3<f32>,
+        h5_file_name: &str,
+        dest: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        // Column of the (x, y, z, layer) dataset
+        let column = |dest: &mut dyn std::io::Write, path: &str, n: usize, col: usize| {
+            writeln!(
+                dest,
+                "            <DataItem ItemType=\"HyperSlab\" Dimensions=\"{n} 1\">",
+                n = n
+            )?;
+            writeln!(
+                dest,
+                "              <DataItem Format=\"XML\" Dimensions=\"3 2\">0 {col} 1 1 {n} 1</DataItem>",
+                n = n,
+                col = col
+            )?;
+            writeln!(dest, "              <DataItem Dimensions=\"{n} 4\" Format=\"HDF5\" DataType=\"Float\" Precision=\"4\">",
+                n = n
+            )?;
+            writeln!(dest, "                {}:{}", h5_file_name, path)?;
+            writeln!(dest, "              </DataItem>")?;
+            writeln!(dest, "            </DataItem>")
+        };
+
+        for (name, points) in self.param_points.iter().sorted_by_key(|(name, _)| *name) {
+            // Empty grids aren't valid
+            if points.is_empty() {
+                continue;
+            }
+
+            let path = format!("/points/{}", name);
+            let n = points.len();
+
+            writeln!(
+                dest,
+                "      <Grid Name=\"points_{name}\" GridType=\"Uniform\">",
+                name = name
+            )?;
+            writeln!(
+                dest,
+                "        <Topology TopologyType=\"Polyvertex\" NumberOfElements=\"{n}\" />",
+                n = n
+            )?;
+            writeln!(dest, "        <Geometry GeometryType=\"X_Y_Z\">")?;
+            for (col, offset) in translation.iter().enumerate() {
+                // The XDMF function parser has no unary minus
+                let (op, offset) = if *offset < 0.0 {
+                    ('-', -offset)
+                } else {
+                    ('+', *offset)
+                };
+
+                writeln!(dest, "          <DataItem ItemType=\"Function\" Function=\"$0 {op} {offset}\" Dimensions=\"{n} 1\">",
+                    op = op,
+                    offset = offset,
+                    n = n
+                )?;
+                column(dest, &path, n, col)?;
+                writeln!(dest, "          </DataItem>")?;
+            }
+            writeln!(dest, "        </Geometry>")?;
+            writeln!(
+                dest,
+                "        <Attribute Name=\"layer\" AttributeType=\"Scalar\" Center=\"Node\">"
+            )?;
+            column(dest, &path, n, 3)?;
+            writeln!(dest, "        </Attribute>")?;
+            writeln!(dest, "      </Grid>")?;
+        }
 
         Ok(())
     }
+
+    /// Write fields as a VTK ImageData file with appended raw data. VTK only supports a single
+    /// grid per file, so only fields with the same grid as the largest field are written.
+    pub fn write_vtk(
+        &self,
+        offsets: nalgebra::Vector3<f32>,
+        dest: &mut dyn std::io::Write,
+    ) -> std::io::Result<()> {
+        let mut all_fields: Vec<_> = self.param_fields.iter().collect();
+        all_fields.sort_by_key(|(name, _field)| *name);
+
+        // Use the field with the most elements as the reference grid
+        let first_field = match all_fields.iter().max_by_key(|(_key, field)| {
+            let d = field.dim();
+            d.0 * d.1 * d.2
+        }) {
+            Some((_key, field)) => *field,
+            None => return Ok(()),
+        };
+
+        let fields: Vec<_> = all_fields
+            .into_iter()
+            .filter(|(name, field)| {
+                if field.has_same_box(first_field) {
+                    true
+                } else {
+                    warn!("field {} doesn't share the VTK grid, skipping", name);
+                    false
+                }
+            })
+            .collect();
+
+        let dim = first_field.dim();
+        let box_size = first_field.field_box_mm.size();
+
+        let extent = format!("0 {} 0 {} 0 {}", dim.2, dim.1, dim.0);
+
+        writeln!(dest, "<?xml version=\"1.0\"?>")?;
+        writeln!(dest, "<VTKFile type=\"ImageData\" version=\"1.0\" byte_order=\"LittleEndian\" header_type=\"UInt64\">")?;
+        writeln!(
+            dest,
+            "  <ImageData WholeExtent=\"{extent}\" Origin=\"{x} {y} {z}\" Spacing=\"{sx} {sy} {sz}\">",
+            extent = extent,
+            x = offsets.x + box_size.x / -2.0,
+            y = offsets.y + box_size.y / -2.0,
+            z = offsets.z + box_size.z / -2.0,
+            sx = box_size.x / dim.2 as f32,
+            sy = box_size.y / dim.1 as f32,
+            sz = box_size.z / dim.0 as f32,
+        )?;
+        writeln!(dest, "    <Piece Extent=\"{}\">", extent)?;
+        writeln!(dest, "      <CellData>")?;
+
+        // Each appended array is prefixed by its byte count
+        let mut offset = 0;
+        for (name, field) in &fields {
+            let (data_type, components) = field.vtk_type();
+
+            writeln!(
+                dest,
+                "        <DataArray type=\"{data_type}\" Name=\"{name}\" NumberOfComponents=\"{components}\" format=\"appended\" offset=\"{offset}\" />",
+                data_type = data_type,
+                name = name,
+                components = components,
+                offset = offset,
+            )?;
+
+            offset += std::mem::size_of::<u64>() + field.raw_len();
+        }
+
+        writeln!(dest, "      </CellData>")?;
+        writeln!(dest, "    </Piece>")?;
+        writeln!(dest, "  </ImageData>")?;
+        writeln!(dest, "  <AppendedData encoding=\"raw\">")?;
+        write!(dest, "_")?;
+
+        for (_name, field) in &fields {
+            dest.write_all(&(field.raw_len() as u64).to_le_bytes())?;
+            field.write_raw(dest)?;
+        }
+
+        writeln!(dest)?;
+        writeln!(dest, "  </AppendedData>")?;
+        writeln!(dest, "</VTKFile>")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn harmonize_grids() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 4.,
+            max_z: 1.,
+        };
+        let mut other_box = bbox;
+        other_box.pad_all(nalgebra::Vector3::new(1., 1., 1.));
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "output_geometry",
+            ParamField::new_u8(bbox, ndarray::Array3::from_elem((2, 4, 2), 255)),
+        );
+        bag.add_field(
+            "input_density",
+            ParamField::new_f32(other_box, ndarray::Array3::from_elem((3, 3, 3), 0.5)),
+        );
+        bag.add_field(
+            "input_dir",
+            ParamField::new_vec3(other_box, ndarray::Array4::ones((4, 4, 4, 3))),
+        );
+
+        assert_eq!(bag.largest_field_name(), Some("input_dir"));
+        bag.harmonize_grids("output_geometry", Some(ResampleMethod::Trilinear))
+            .unwrap();
+
+        let reference = bag.get_field("output_geometry").unwrap();
+        for name in &["input_density", "input_dir"] {
+            assert!(bag.get_field(name).unwrap().has_same_box(reference));
+        }
+
+        // Constant fields keep their value everywhere
+        let density = bag
+            .get_field("input_density")
+            .unwrap()
+            .as_f32_array(1.0)
+            .unwrap();
+        assert!(density.iter().all(|&v| (v - 0.5).abs() < 1e-6));
+
+        assert!(bag
+            .harmonize_grids("missing", Some(ResampleMethod::Nearest))
+            .is_err());
+    }
+
+    #[test]
+    fn harmonize_grids_offset() {
+        // Reference grid of 1 mm cells from x = 10 to 14
+        let bbox = BoundingBox {
+            min_x: 10.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 14.,
+            max_y: 1.,
+            max_z: 1.,
+        };
+        // Fields of 0.5 mm cells from x = 8 to 16, with values matching x
+        let mut other_box = bbox;
+        other_box.min_x = 8.;
+        other_box.max_x = 16.;
+
+        let ramp = |x: usize| 8.25 + 0.5 * x as f32;
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "output_geometry",
+            ParamField::new_u8(bbox, ndarray::Array3::from_elem((1, 1, 4), 255)),
+        );
+        bag.add_field(
+            "ramp",
+            ParamField::new_f32(
+                other_box,
+                ndarray::Array3::from_shape_fn((1, 1, 16), |(_k, _j, i)| ramp(i)),
+            ),
+        );
+        bag.add_field(
+            "feature",
+            ParamField::new_u8(
+                other_box,
+                ndarray::Array3::from_shape_fn((1, 1, 16), |(_k, _j, i)| (i % 3) as u8),
+            ),
+        );
+        bag.add_field(
+            "tensor",
+            ParamField::new_tensor(
+                other_box,
+                ndarray::Array4::from_shape_fn((1, 1, 16, 6), |(_k, _j, i, c)| {
+                    if i < 8 {
+                        0.
+                    } else {
+                        ramp(i) * c as f32
+                    }
+                }),
+            ),
+        );
+        bag.add_field(
+            "dir",
+            ParamField::new_vec3(other_box, ndarray::Array4::zeros((1, 1, 16, 3))),
+        );
+
+        bag.harmonize_grids("output_geometry", None).unwrap();
+
+        // Cell centers at x = 10.5, 11.5, 12.5 and 13.5
+        let ramp = bag
+            .get_field("ramp")
+            .unwrap()
+            .as_f32_array(1.0)
+            .unwrap()
+            .into_owned();
+        assert!(ramp.all_close(&ndarray::arr3(&[[[10.5, 11.5, 12.5, 13.5]]]), 1e-5));
+
+        // Categories are not interpolated
+        let feature = bag.get_field("feature").unwrap().as_u8().unwrap();
+        assert!(feature.iter().all(|v| *v < 3));
+        assert_eq!(feature[(0, 0, 0)], (5 % 3) as u8);
+
+        // Tensors keep their components and zero vectors stay zero
+        let tensor = bag.get_field("tensor").unwrap().as_vec3().unwrap();
+        assert_eq!(tensor.dim(), (1, 1, 4, 6));
+        assert_eq!(tensor[(0, 0, 0, 1)], 0.);
+        assert!((tensor[(0, 0, 3, 2)] - 2. * 13.5).abs() < 1e-4);
+        let dir = bag.get_field("dir").unwrap().as_vec3().unwrap();
+        assert!(dir.iter().all(|v| *v == 0.));
+    }
+
+    #[test]
+    fn downsample_fields_methods() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 2.,
+            max_z: 2.,
+        };
+        // Three cells of the window hold 1, the other five hold 4
+        let values = |a: f32, b: f32| {
+            ndarray::Array3::from_shape_fn((2, 2, 2), |(k, j, i)| {
+                if k == 0 && (j == 0 || i == 0) {
+                    a
+                } else {
+                    b
+                }
+            })
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "output_geometry",
+            ParamField::new_u8(bbox, values(255., 0.).mapv(|v| v as u8)),
+        );
+        bag.add_field(
+            "output_feature",
+            ParamField::new_u8(bbox, values(1., 4.).mapv(|v| v as u8)),
+        );
+        bag.add_field("density", ParamField::new_f32(bbox, values(1., 4.)));
+
+        bag.downsample_fields(2);
+
+        let byte = |name| bag.get_field(name).unwrap().as_u8().unwrap()[(0, 0, 0)];
+        assert_eq!(byte("output_geometry"), 0);
+        assert_eq!(byte("output_feature"), 4);
+        let density = bag.get_field("density").unwrap().as_f32_array(1.0).unwrap();
+        assert!((density[(0, 0, 0)] - 23. / 8.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn check_boxes() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 4.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "input_geometry",
+            ParamField::new_u8(bbox, ndarray::Array3::zeros((2, 2, 2))),
+        );
+        bag.add_field(
+            "output_geometry",
+            ParamField::new_u8(bbox, ndarray::Array3::zeros((2, 2, 2))),
+        );
+        // Other grids are written separately
+        bag.add_field(
+            "input_density",
+            ParamField::new_f32(bbox, ndarray::Array3::zeros((3, 3, 3))),
+        );
+        assert!(bag.check_boxes().is_ok());
+
+        // Moving all fields keeps them consistent
+        let offset = nalgebra::Vector3::new(1., 0., 0.);
+        bag.translate_fields(-offset);
+        assert!(bag.check_boxes().is_ok());
+        bag.translate_fields(offset);
+        assert_eq!(bag.get_field("input_density").unwrap().field_box_mm, bbox);
+
+        let mut shifted = bbox;
+        shifted.translate(offset);
+        bag.add_field(
+            "output_tool",
+            ParamField::new_u8(shifted, ndarray::Array3::zeros((2, 2, 2))),
+        );
+
+        let error = bag.check_boxes().unwrap_err().to_string();
+        assert!(error.contains("output_tool"));
+        assert!(!error.contains("output_geometry ("));
+    }
+
+    #[test]
+    fn pad_fields() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 4.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "mean",
+            ParamField::new_f32(bbox, ndarray::Array3::from_elem((2, 2, 2), 2.0)),
+        );
+        bag.add_field(
+            "dir",
+            ParamField::new_vec3(bbox, ndarray::Array4::ones((2, 2, 2, 3))),
+        );
+
+        bag.pad_fields(1);
+
+        // One cell is 1mm wide in X, 2mm in Y and 0.5mm in Z
+        let padded_box = BoundingBox {
+            min_x: -1.,
+            min_y: -2.,
+            min_z: -0.5,
+            max_x: 3.,
+            max_y: 6.,
+            max_z: 1.5,
+        };
+
+        let mean = bag.get_field("mean").unwrap();
+        assert_eq!(mean.dim(), (4, 4, 4, 0));
+        assert_eq!(mean.field_box_mm, padded_box);
+
+        let mean = mean.as_f32_array(1.0).unwrap();
+        for ((k, j, i), v) in mean.indexed_iter() {
+            let interior = (1..3).contains(&k) && (1..3).contains(&j) && (1..3).contains(&i);
+            assert_eq!(*v, if interior { 2.0 } else { 0.0 });
+        }
+
+        let dir = bag.get_field("dir").unwrap();
+        assert_eq!(dir.dim(), (4, 4, 4, 3));
+        assert_eq!(dir.field_box_mm, padded_box);
+        let dir = dir.as_vec3().unwrap();
+        assert_eq!(dir[(0, 0, 0, 0)], 0.0);
+        assert_eq!(dir[(3, 2, 1, 2)], 0.0);
+        assert_eq!(dir[(1, 2, 1, 2)], 1.0);
+    }
+
+    /// Shape and values of a dataset
+    type DatasetValues = (Vec<usize>, Vec<f64>);
+
+    fn dataset_values(file: &hdf5::File, path: &str) -> DatasetValues {
+        let dataset = file.dataset(path).unwrap();
+        let dtype = dataset.dtype().unwrap();
+
+        let values = if dtype.is::<u8>() {
+            let raw = dataset.read_raw::<u8>().unwrap();
+            raw.into_iter().map(f64::from).collect()
+        } else if dtype.is::<f32>() {
+            let raw = dataset.read_raw::<f32>().unwrap();
+            raw.into_iter().map(f64::from).collect()
+        } else if dtype.is::<VarLenUnicode>() {
+            // Compare strings by their bytes
+            let raw = dataset.read_raw::<VarLenUnicode>().unwrap();
+            raw.iter()
+                .flat_map(|s| s.as_str().bytes().map(f64::from).collect::<Vec<_>>())
+                .collect()
+        } else {
+            dataset.read_raw::<f64>().unwrap()
+        };
+
+        (dataset.shape(), values)
+    }
+
+    fn all_datasets(file: &hdf5::File) -> Vec<(String, DatasetValues)> {
+        let mut result = Vec::new();
+
+        for group in file.member_names().unwrap() {
+            for name in file.group(&group).unwrap().member_names().unwrap() {
+                let path = format!("/{}/{}", group, name);
+                if group == "fields" {
+                    let field_group = file.group(&path).unwrap();
+                    for part in field_group.member_names().unwrap() {
+                        let path = format!("{}/{}", path, part);
+                        result.push((path.clone(), dataset_values(file, &path)));
+                    }
+                } else {
+                    result.push((path.clone(), dataset_values(file, &path)));
+                }
+            }
+        }
+
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+
+    /// Bag with fields of each storage, arrays and parameters
+    fn round_trip_bag() -> ParamBag {
+        let bbox = BoundingBox {
+            min_x: -1.,
+            min_y: 0.,
+            min_z: 0.5,
+            max_x: 3.,
+            max_y: 3.,
+            max_z: 2.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "geometry",
+            ParamField::new_u8(
+                bbox,
+                Array3::from_shape_fn((2, 3, 4), |(k, j, i)| (k * 12 + j * 4 + i) as u8),
+            ),
+        );
+        bag.add_field(
+            "mean",
+            ParamField::new_f32(bbox, Array3::from_elem((2, 3, 4), 0.25)),
+        );
+        bag.add_field(
+            "dir",
+            ParamField::new_vec3(
+                bbox,
+                Array4::from_shape_fn((2, 3, 4, 3), |(k, j, i, c)| (k + j + i + c) as f32),
+            ),
+        );
+
+        let mut theta = ParamArray::from_val(0, Param::Float(0.5));
+        theta.add_param(2, Param::Float(1.5));
+        bag.param_arrays.insert("infill_theta".to_owned(), theta);
+        let mut flags = ParamArray::from_val(0, Param::Bool(true));
+        flags.add_param(1, Param::Bool(false));
+        bag.param_arrays.insert("flags".to_owned(), flags);
+
+        bag.params.insert("enabled".to_owned(), Param::Bool(true));
+        bag.params.insert("speed".to_owned(), Param::Float(40.));
+        bag.params
+            .insert("name".to_owned(), Param::String("part".to_owned()));
+
+        bag
+    }
+
+    #[test]
+    fn hdf5_round_trip() {
+        let bag = round_trip_bag();
+
+        let dir = std::env::temp_dir();
+        let first_path = dir.join(format!(
+            "icesl2voxel_test_round_trip_1_{}.h5",
+            std::process::id()
+        ));
+        let second_path = dir.join(format!(
+            "icesl2voxel_test_round_trip_2_{}.h5",
+            std::process::id()
+        ));
+
+        bag.write_hdf5(
+            &hdf5::File::create(&first_path).unwrap(),
+            &Hdf5Options::default(),
+        )
+        .unwrap();
+        let first = hdf5::File::open(&first_path).unwrap();
+        assert_eq!(
+            first
+                .attr("format_version")
+                .unwrap()
+                .read_scalar::<u32>()
+                .unwrap(),
+            HDF5_FORMAT_VERSION
+        );
+        assert!(first
+            .attr("generator")
+            .unwrap()
+            .read_scalar::<VarLenUnicode>()
+            .unwrap()
+            .as_str()
+            .starts_with("icesl2voxel "));
+
+        let read = ParamBag::read_hdf5(&first).unwrap();
+
+        assert_eq!(read.param_fields.len(), 3);
+        assert_eq!(read.param_arrays.len(), 2);
+        assert!(read.params["enabled"].as_bool());
+        assert_eq!(read.params["speed"].as_float(), 40.);
+        match &read.params["name"] {
+            Param::String(name) => assert_eq!(name, "part"),
+            other => panic!("unexpected parameter {:?}", other),
+        }
+
+        read.write_hdf5(
+            &hdf5::File::create(&second_path).unwrap(),
+            &Hdf5Options::default(),
+        )
+        .unwrap();
+        let second = hdf5::File::open(&second_path).unwrap();
+
+        let first_datasets = all_datasets(&first);
+        // Bounding boxes are attributes, so fields only have a data dataset
+        assert_eq!(first_datasets.len(), 8);
+        assert_eq!(first_datasets, all_datasets(&second));
+    }
+
+    #[test]
+    fn hdf5_empty_points() {
+        let mut bag = ParamBag::new();
+        bag.add_points("retractions", Vec::new());
+
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_empty_points_{}.h5",
+            std::process::id()
+        ));
+
+        // Compressed datasets can't be empty
+        assert!(bag
+            .write_hdf5(&hdf5::File::create(&path).unwrap(), &Hdf5Options::default())
+            .is_err());
+
+        bag.write_hdf5(
+            &hdf5::File::create(&path).unwrap(),
+            &Hdf5Options {
+                compression: 0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let read = ParamBag::read_hdf5(&hdf5::File::open(&path).unwrap()).unwrap();
+        assert!(read.param_points["retractions"].is_empty());
+    }
+
+    #[test]
+    fn integer_params() {
+        let xml = r#"<?xml version="1.0"?>
+            <params>
+                <count value="3"/>
+                <ratio value="3.0"/>
+                <large value="1e3"/>
+                <enabled value="True"/>
+                <name value="abc"/>
+                <shells_0 value="2"/>
+                <shells_1 value="4"/>
+                <speed_0 value="20"/>
+                <speed_1 value="22.5"/>
+            </params>"#;
+
+        let bag = ParamBag::parse(&mut xml.as_bytes()).unwrap();
+
+        assert!(matches!(bag.params["count"], Param::Int(3)));
+        assert!(matches!(bag.params["ratio"], Param::Float(_)));
+        assert!(matches!(bag.params["large"], Param::Float(_)));
+        assert!(matches!(bag.params["enabled"], Param::Bool(true)));
+        assert!(matches!(bag.params["name"], Param::String(_)));
+        assert_eq!(bag.params["count"].as_float(), 3.);
+
+        assert_eq!(bag.param_arrays["shells"].xdmf_type(), Some(("Int", 8)));
+        assert_eq!(bag.param_arrays["shells"].csv_value(1).unwrap(), "4");
+        // A float value promotes the whole array
+        assert_eq!(bag.param_arrays["speed"].xdmf_type(), Some(("Float", 8)));
+        assert_eq!(
+            &bag.param_arrays["speed"].as_f64_slice().unwrap()[..],
+            &[20., 22.5]
+        );
+
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_integer_params_{}.h5",
+            std::process::id()
+        ));
+        bag.write_hdf5(&hdf5::File::create(&path).unwrap(), &Hdf5Options::default())
+            .unwrap();
+        let file = hdf5::File::open(&path).unwrap();
+
+        for name in &["parameters/count", "arrays/shells"] {
+            assert!(file.dataset(name).unwrap().dtype().unwrap().is::<i64>());
+        }
+
+        let read = ParamBag::read_hdf5(&file).unwrap();
+        assert_eq!(read.params["count"].as_int(), 3);
+        assert_eq!(read.param_arrays["shells"].xdmf_type(), Some(("Int", 8)));
+        assert_eq!(read.param_arrays["shells"].csv_value(0).unwrap(), "2");
+    }
+
+    #[test]
+    fn string_params_round_trip() {
+        let names = ["matériau", "PLA ✓", ""];
+
+        for (fixed, expected) in &[(false, names), (true, ["mat?riau", "PLA ?", ""])] {
+            let mut bag = ParamBag::new();
+            let mut materials = ParamArray::from_val(0, Param::String(names[0].to_owned()));
+            materials.add_param(1, Param::String(names[1].to_owned()));
+            materials.add_param(2, Param::String(names[2].to_owned()));
+            bag.add_array("material", materials);
+            bag.params
+                .insert("name".to_owned(), Param::String(names[1].to_owned()));
+
+            let path = std::env::temp_dir().join(format!(
+                "icesl2voxel_test_string_params_{}_{}.h5",
+                fixed,
+                std::process::id()
+            ));
+            bag.write_hdf5(
+                &hdf5::File::create(&path).unwrap(),
+                &Hdf5Options {
+                    fixed_length_strings: *fixed,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let file = hdf5::File::open(&path).unwrap();
+
+            let dataset = file.dataset("arrays/material").unwrap();
+            let values: Vec<String> = if *fixed {
+                dataset
+                    .read_raw::<crate::param::FixedString>()
+                    .unwrap()
+                    .iter()
+                    .map(|s| s.as_str().to_owned())
+                    .collect()
+            } else {
+                dataset
+                    .read_raw::<VarLenUnicode>()
+                    .unwrap()
+                    .iter()
+                    .map(|s| s.as_str().to_owned())
+                    .collect()
+            };
+            assert_eq!(values, expected);
+
+            let read = ParamBag::read_hdf5(&file).unwrap();
+            for (idx, name) in expected.iter().enumerate() {
+                assert_eq!(
+                    read.param_arrays["material"].csv_value(idx).unwrap(),
+                    csv_escape(name)
+                );
+            }
+            match &read.params["name"] {
+                Param::String(name) => assert_eq!(name, expected[1]),
+                other => panic!("unexpected parameter {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_gzip_xml() {
+        use std::io::Write;
+
+        let xml = r#"<?xml version="1.0"?>
+            <params>
+                <speed value="40.5"/>
+                <name value="part"/>
+                <shells_0 value="2"/>
+                <shells_1 value="3"/>
+            </params>"#;
+        let mut encoder = libflate::gzip::Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(xml.as_bytes()).unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_params_{}.xml.gz",
+            std::process::id()
+        ));
+        std::fs::write(&path, encoder.finish().into_result().unwrap()).unwrap();
+
+        let plain = ParamBag::parse(&mut xml.as_bytes()).unwrap();
+        let gzip = ParamBag::parse(&mut crate::utils::open_input(&path).unwrap()).unwrap();
+
+        let csv = |bag: &ParamBag| {
+            let mut out = Vec::new();
+            bag.write_csv_summary(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(csv(&plain), csv(&gzip));
+        assert_eq!(gzip.params.len(), 2);
+    }
+
+    #[test]
+    fn write_csv_summary() {
+        let mut bag = ParamBag::new();
+
+        let mut theta = ParamArray::from_val(0, Param::Float(0.5));
+        theta.add_param(1, Param::Float(-1.25));
+        theta.add_param(2, Param::Float(3.));
+        bag.param_arrays.insert("infill_theta".to_owned(), theta);
+
+        let mut flags = ParamArray::from_val(0, Param::Bool(true));
+        flags.add_param(1, Param::Bool(false));
+        bag.param_arrays.insert("flags".to_owned(), flags);
+
+        bag.params.insert("speed".to_owned(), Param::Float(40.));
+        bag.params.insert(
+            "name".to_owned(),
+            Param::String("part \"A\", v2".to_owned()),
+        );
+
+        let mut out = Vec::new();
+        bag.write_csv_summary(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "flags,infill_theta\n\
+             true,0.5\n\
+             false,-1.25\n\
+             ,3\n\
+             \n\
+             name,value\n\
+             name,\"part \"\"A\"\", v2\"\n\
+             speed,40\n"
+        );
+
+        let mut out = Vec::new();
+        bag.param_arrays["flags"]
+            .write_csv("flags", &mut out)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "flags\ntrue\nfalse\n");
+    }
+
+    #[test]
+    fn assemble_spherical_vec3_radius() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 1.,
+            max_y: 1.,
+            max_z: 1.,
+        };
+
+        let mut radius = ndarray::Array4::zeros((1, 1, 1, 3));
+        radius[(0, 0, 0, 0)] = 3.;
+        radius[(0, 0, 0, 1)] = 4.;
+
+        let mut bag = ParamBag::new();
+        bag.add_field("radius", ParamField::new_vec3(bbox, radius));
+        bag.add_field(
+            "theta",
+            ParamField::new_f32(bbox, ndarray::Array3::zeros((1, 1, 1))),
+        );
+        bag.add_field(
+            "phi",
+            ParamField::new_f32(bbox, ndarray::Array3::from_elem((1, 1, 1), 90.)),
+        );
+
+        let dir = bag
+            .assemble_spherical("dir", &["radius", "theta", "phi"])
+            .unwrap()
+            .as_vec3()
+            .unwrap();
+        assert!(dir[(0, 0, 0, 0)].abs() < 1e-5);
+        assert!(dir[(0, 0, 0, 1)].abs() < 1e-5);
+        assert!((dir[(0, 0, 0, 2)] - 5.).abs() < 1e-5);
+
+        assert!(bag
+            .assemble_spherical("dir", &["theta", "radius", "phi"])
+            .is_err());
+    }
+
+    #[test]
+    fn assemble_cartesian() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 2.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "fx",
+            ParamField::new_f32(bbox, ndarray::Array3::from_elem((1, 2, 2), 3.)),
+        );
+        bag.add_field(
+            "fy",
+            ParamField::new_u8(bbox, ndarray::Array3::from_elem((1, 2, 2), 0)),
+        );
+        bag.add_field(
+            "fz",
+            ParamField::new_f32(bbox, ndarray::Array3::from_elem((1, 2, 2), 4.)),
+        );
+
+        let dir = bag
+            .assemble_cartesian("dir", &["fx", "fy", "fz"], false)
+            .unwrap();
+        assert_eq!(dir.field_box_mm, bbox);
+        assert!(dir
+            .as_vec3()
+            .unwrap()
+            .lanes(Axis(3))
+            .into_iter()
+            .all(|v| v.to_vec() == vec![3., 0., 4.]));
+
+        let dir = bag
+            .assemble_cartesian("dir", &["fx", "fy", "fz"], true)
+            .unwrap();
+        assert!(dir
+            .as_vec3()
+            .unwrap()
+            .lanes(Axis(3))
+            .into_iter()
+            .all(|v| (v[0] - 0.6).abs() < 1e-6 && v[1] == 0. && (v[2] - 0.8).abs() < 1e-6));
+
+        // Missing Z component
+        let dir = bag.assemble_cartesian("dir", &["fz", "fx"], false).unwrap();
+        assert!(dir
+            .as_vec3()
+            .unwrap()
+            .lanes(Axis(3))
+            .into_iter()
+            .all(|v| v.to_vec() == vec![4., 3., 0.]));
+
+        assert!(bag.assemble_cartesian("dir2", &["fx"], false).is_err());
+        assert!(bag
+            .assemble_cartesian("dir2", &["fx", "fy", "dir"], false)
+            .is_err());
+        assert!(bag
+            .assemble_cartesian("dir2", &["fx", "fy", "missing"], false)
+            .is_err());
+    }
+
+    #[test]
+    fn apply_op() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 1.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "input_geometry",
+            ParamField::new_u8(bbox, ndarray::arr3(&[[[255, 255]]])),
+        );
+        bag.add_field(
+            "output_geometry",
+            ParamField::new_u8(bbox, ndarray::arr3(&[[[255, 0]]])),
+        );
+        bag.add_field(
+            "length",
+            ParamField::new_f32(bbox, ndarray::arr3(&[[[1.0, 2.0]]])).with_unit("mm"),
+        );
+
+        let gap = bag
+            .apply_op(
+                "coverage_gap",
+                FieldOp::Sub,
+                "input_geometry",
+                &"output_geometry".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(gap.field_box_mm, bbox);
+        assert_eq!(
+            *gap.as_f32_array(1.0).unwrap(),
+            ndarray::arr3(&[[[0.0f32, 1.0]]])
+        );
+
+        let mask = bag
+            .apply_op(
+                "gap_mask",
+                FieldOp::Threshold,
+                "coverage_gap",
+                &"0.5".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(*mask.as_u8().unwrap(), ndarray::arr3(&[[[0, 255]]]));
+        assert_eq!(mask.unit, None);
+        assert_eq!(
+            mask.description.as_deref(),
+            Some("255 where coverage_gap >= 0.5, 0 elsewhere")
+        );
+
+        let scaled = bag
+            .apply_op("scaled", FieldOp::Mul, "length", &"2".parse().unwrap())
+            .unwrap();
+        assert_eq!(scaled.unit.as_deref(), Some("mm"));
+        assert_eq!(scaled.description.as_deref(), Some("length * 2"));
+
+        assert!(bag
+            .apply_op(
+                "missing",
+                FieldOp::Add,
+                "input_geometry",
+                &FieldOperand::Field("missing".to_owned())
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn masked_stats() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 4.,
+            max_y: 1.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "input_geometry",
+            ParamField::new_u8(bbox, ndarray::arr3(&[[[255, 255, 0, 0]]])),
+        );
+        bag.add_field(
+            "input_percentage",
+            ParamField::new_f32(bbox, ndarray::arr3(&[[[20., 20., 80., 80.]]])),
+        );
+        bag.add_field(
+            "input_coverage",
+            ParamField::new_u8(bbox, ndarray::arr3(&[[[0, 255, 255, 255]]])),
+        );
+
+        bag.masked_stats("input_percentage", "input_geometry", "infill")
+            .unwrap();
+        for (name, value) in &[
+            ("infill_count", 2.),
+            ("infill_mean", 20.),
+            ("infill_std", 0.),
+            ("infill_min", 20.),
+            ("infill_max", 20.),
+        ] {
+            assert_eq!(bag.params[*name].as_float(), *value, "{}", name);
+        }
+
+        // Bytes are mapped to [0, 1]
+        bag.masked_stats("input_coverage", "input_geometry", "coverage")
+            .unwrap();
+        assert_eq!(bag.params["coverage_mean"].as_float(), 0.5);
+        assert_eq!(bag.params["coverage_std"].as_float(), 0.5);
+        assert_eq!(bag.params["coverage_min"].as_float(), 0.);
+
+        bag.add_field(
+            "empty",
+            ParamField::new_u8(bbox, ndarray::Array3::zeros((1, 1, 4))),
+        );
+        assert!(bag
+            .masked_stats("input_percentage", "empty", "empty")
+            .is_err());
+    }
+
+    #[test]
+    fn write_xdmf_series() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 4.,
+            max_y: 3.,
+            max_z: 2.,
+        };
+
+        let step = |value: f32| {
+            let mut bag = ParamBag::new();
+            bag.add_field(
+                "mean",
+                ParamField::new_f32(bbox, ndarray::Array3::from_elem((2, 3, 4), value)),
+            );
+            bag
+        };
+
+        let (first, second) = (step(0.), step(1.));
+        let mut out = Vec::new();
+        ParamBag::write_xdmf_series(
+            &[(0., "step0.h5", &first), (2.5, "step1.h5", &second)],
+            nalgebra::Vector3::zeros(),
+            &mut out,
+            false,
+            &[],
+        )
+        .unwrap();
+
+        let mut collection_type = None;
+        let mut times = Vec::new();
+        let mut references = Vec::new();
+        let mut in_hdf5_item = false;
+
+        for e in EventReader::new(&out[..]) {
+            match e.unwrap() {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } => {
+                    let attr = |key: &str| {
+                        attributes
+                            .iter()
+                            .find(|a| a.name.local_name == key)
+                            .map(|a| a.value.clone())
+                    };
+
+                    match name.local_name.as_str() {
+                        "Grid" if attr("CollectionType").is_some() => {
+                            collection_type = attr("CollectionType")
+                        }
+                        "Time" => times.push(attr("Value").unwrap()),
+                        "DataItem" => in_hdf5_item = attr("Format").as_deref() == Some("HDF5"),
+                        _ => {}
+                    }
+                }
+                XmlEvent::Characters(text) if in_hdf5_item => {
+                    references.push(text.trim().to_owned());
+                }
+                XmlEvent::EndElement { .. } => in_hdf5_item = false,
+                _ => {}
+            }
+        }
+
+        assert_eq!(collection_type.as_deref(), Some("Temporal"));
+        assert_eq!(times, vec!["0", "2.5"]);
+        assert_eq!(
+            references,
+            vec!["step0.h5:/fields/mean/data", "step1.h5:/fields/mean/data"]
+        );
+
+        // Different grids can't be part of the same series
+        let mut coarse = ParamBag::new();
+        coarse.add_field(
+            "mean",
+            ParamField::new_f32(bbox, ndarray::Array3::zeros((1, 1, 1))),
+        );
+        assert!(ParamBag::write_xdmf_series(
+            &[(0., "step0.h5", &first), (1., "coarse.h5", &coarse)],
+            nalgebra::Vector3::zeros(),
+            &mut Vec::new(),
+            false,
+            &[],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn write_xdmf_tensor_attributes() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 4.,
+            max_y: 3.,
+            max_z: 2.,
+        };
+
+        // Tensors share the storage of vector fields
+        let mean = ParamField::new_f32(bbox, ndarray::Array3::zeros((2, 3, 4)));
+        let components = |n| mean.derive_vec3_from_field(ndarray::Array4::zeros((2, 3, 4, n)));
+
+        let mut bag = ParamBag::new();
+        bag.add_field("dir", components(3));
+        bag.add_field("orientation_tensor", components(6));
+        bag.add_field("full_tensor", components(9));
+        // No XDMF attribute type has 2 components
+        bag.add_field("pair", components(2));
+        bag.add_field("mean", mean);
+
+        let mut out = Vec::new();
+        bag.write_xdmf(nalgebra::Vector3::zeros(), "test.h5", &mut out, true, &[])
+            .unwrap();
+
+        let mut attributes = Vec::new();
+        let mut current = None;
+        for e in EventReader::new(&out[..]) {
+            if let XmlEvent::StartElement {
+                name,
+                attributes: attrs,
+                ..
+            } = e.unwrap()
+            {
+                let attr = |key: &str| {
+                    attrs
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                        .unwrap()
+                };
+
+                if name.local_name == "Attribute" {
+                    current = Some((attr("Name"), attr("AttributeType")));
+                } else if name.local_name == "DataItem" {
+                    if let Some((name, kind)) = current.take() {
+                        attributes.push((name, kind, attr("Dimensions")));
+                    }
+                }
+            }
+        }
+
+        attributes.sort();
+        let expected: Vec<_> = [
+            ("dir", "Vector", "2 3 4 3"),
+            ("full_tensor", "Tensor", "2 3 4 9"),
+            ("mean", "Scalar", "2 3 4"),
+            ("orientation_tensor", "Tensor6", "2 3 4 6"),
+        ]
+        .iter()
+        .map(|(a, b, c)| ((*a).to_owned(), (*b).to_owned(), (*c).to_owned()))
+        .collect();
+        assert_eq!(attributes, expected);
+    }
+
+    #[test]
+    fn write_xdmf_field_information() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 1.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "dir_length",
+            ParamField::new_f32(bbox, ndarray::Array3::zeros((1, 1, 2)))
+                .with_unit("mm")
+                .with_description("longest ray, \"free\" <length>"),
+        );
+        bag.add_field(
+            "geometry",
+            ParamField::new_u8(bbox, ndarray::Array3::zeros((1, 1, 2))),
+        );
+
+        let mut out = Vec::new();
+        bag.write_xdmf(nalgebra::Vector3::zeros(), "test.h5", &mut out, true, &[])
+            .unwrap();
+
+        let mut information = Vec::new();
+        let mut current = None;
+        for e in EventReader::new(&out[..]) {
+            if let XmlEvent::StartElement {
+                name, attributes, ..
+            } = e.unwrap()
+            {
+                let attr = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                        .unwrap()
+                };
+
+                if name.local_name == "Attribute" {
+                    current = Some(attr("Name"));
+                } else if name.local_name == "Information" {
+                    information.push((current.clone().unwrap(), attr("Name"), attr("Value")));
+                }
+            }
+        }
+
+        assert_eq!(
+            information,
+            vec![
+                ("dir_length".to_owned(), "unit".to_owned(), "mm".to_owned()),
+                (
+                    "dir_length".to_owned(),
+                    "description".to_owned(),
+                    "longest ray, \"free\" <length>".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn write_xdmf_array_filter() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 4.,
+            max_y: 3.,
+            max_z: 2.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "mean",
+            ParamField::new_f32(bbox, ndarray::Array3::zeros((2, 3, 4))),
+        );
+
+        for name in &["infill_theta", "infill_phi"] {
+            let mut array = ParamArray::from_val(0, Param::Float(0.5));
+            array.add_param(1, Param::Float(1.5));
+            bag.param_arrays.insert((*name).to_owned(), array);
+        }
+
+        let attributes = |array_filter: &[String]| {
+            let mut out = Vec::new();
+            bag.write_xdmf(
+                nalgebra::Vector3::zeros(),
+                "test.h5",
+                &mut out,
+                true,
+                array_filter,
+            )
+            .unwrap();
+
+            let mut names = Vec::new();
+            for e in EventReader::new(&out[..]) {
+                if let XmlEvent::StartElement {
+                    name, attributes, ..
+                } = e.unwrap()
+                {
+                    if name.local_name == "Attribute" {
+                        names.extend(
+                            attributes
+                                .iter()
+                                .find(|a| a.name.local_name == "Name")
+                                .map(|a| a.value.clone()),
+                        );
+                    }
+                }
+            }
+
+            names
+        };
+
+        assert_eq!(attributes(&[]), vec!["mean", "infill_phi", "infill_theta"]);
+        assert_eq!(
+            attributes(&["infill_phi".to_owned()]),
+            vec!["mean", "infill_phi"]
+        );
+    }
+
+    #[test]
+    fn write_xdmf_points() {
+        let bbox = BoundingBox {
+            min_x: 10.,
+            min_y: 10.,
+            min_z: 0.,
+            max_x: 14.,
+            max_y: 13.,
+            max_z: 2.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "mean",
+            ParamField::new_f32(bbox, ndarray::Array3::zeros((2, 3, 4))),
+        );
+        bag.add_points("seams", vec![[12., 11., 0.5, 0.], [13., 12., 1.5, 1.]]);
+        bag.add_points("retractions", Vec::new());
+
+        let mut out = Vec::new();
+        bag.write_xdmf(nalgebra::Vector3::zeros(), "test.h5", &mut out, false, &[])
+            .unwrap();
+
+        let mut topologies = Vec::new();
+        let mut functions = Vec::new();
+        for e in EventReader::new(&out[..]) {
+            if let XmlEvent::StartElement {
+                name, attributes, ..
+            } = e.unwrap()
+            {
+                let attr = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                };
+
+                match name.local_name.as_str() {
+                    "Topology" => topologies.extend(attr("TopologyType")),
+                    "DataItem" => functions.extend(attr("Function")),
+                    _ => {}
+                }
+            }
+        }
+
+        // Empty point sets are skipped, points are centered like the fields
+        assert_eq!(topologies, vec!["3DCoRectMesh", "Polyvertex"]);
+        assert_eq!(functions, vec!["$0 - 12", "$0 - 11.5", "$0 - 1"]);
+    }
+
+    #[test]
+    fn write_vtk_header() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 4.,
+            max_y: 3.,
+            max_z: 2.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "geometry",
+            ParamField::new_u8(bbox, ndarray::Array3::zeros((2, 3, 4))),
+        );
+        bag.add_field(
+            "mean",
+            ParamField::new_f32(bbox, ndarray::Array3::ones((2, 3, 4))),
+        );
+        bag.add_field(
+            "dir",
+            ParamField::new_vec3(bbox, ndarray::Array4::ones((2, 3, 4, 3))),
+        );
+        // Different grid, not written
+        bag.add_field(
+            "coarse",
+            ParamField::new_u8(bbox, ndarray::Array3::zeros((1, 1, 1))),
+        );
+
+        let mut out = Vec::new();
+        bag.write_vtk(nalgebra::Vector3::zeros(), &mut out).unwrap();
+
+        // Only parse the XML header, appended data is not valid XML
+        let header_end = out
+            .windows(b"<AppendedData".len())
+            .position(|w| w == b"<AppendedData")
+            .unwrap();
+        let header = [&out[..header_end], b"</VTKFile>"].concat();
+
+        let mut extent = None;
+        let mut arrays = Vec::new();
+
+        for e in EventReader::new(&header[..]) {
+            if let XmlEvent::StartElement {
+                name, attributes, ..
+            } = e.unwrap()
+            {
+                let attr = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                };
+
+                match name.local_name.as_str() {
+                    "ImageData" => extent = attr("WholeExtent"),
+                    "DataArray" => arrays.push((
+                        attr("Name").unwrap(),
+                        attr("type").unwrap(),
+                        attr("NumberOfComponents").unwrap(),
+                    )),
+                    _ => {}
+                }
+            }
+        }
+
+        assert_eq!(extent.as_deref(), Some("0 4 0 3 0 2"));
+        assert_eq!(
+            arrays,
+            vec![
+                ("dir".to_owned(), "Float32".to_owned(), "3".to_owned()),
+                ("geometry".to_owned(), "UInt8".to_owned(), "1".to_owned()),
+                ("mean".to_owned(), "Float32".to_owned(), "1".to_owned()),
+            ]
+        );
+
+        // 3 arrays with their u64 headers
+        let data_len = 3 * 8 + 24 + 24 * 4 + 24 * 3 * 4;
+        assert!(out.len() > header_end + data_len);
+    }
 }
@@ -1,9 +1,14 @@
 use std::borrow::Cow;
+use std::io::Write;
+use std::path::Path;
 
+use hdf5::types::VarLenUnicode;
 use ndarray::par_azip;
 use ndarray::prelude::*;
+use ndarray_stats::QuantileExt;
 use serde_derive::{Deserialize, Serialize};
 
+use super::param::to_var_len_string;
 use super::param_array::ParamArray;
 use super::utils::BoundingBox;
 
@@ -69,7 +74,262 @@ macro_rules! pad_array {
     }};
 }
 
+macro_rules! write_slabs {
+    (3, $type:ty, $array:expr, $path:ident, $file:ident, $options:ident) => {{
+        let array = $array;
+        let (nz, ny, nx) = array.dim();
+        let depth = $options.chunk_depth.clamp(1, nz.max(1));
+
+        let dataset = $options
+            .new_dataset::<$type>($file)
+            .chunk((depth, ny, nx))
+            .create(&$path, (nz, ny, nx))?;
+
+        // Write one chunk at a time, only non-standard views are copied
+        for k in (0..nz).step_by(depth) {
+            let slab = s![k..(k + depth).min(nz), .., ..];
+            dataset.write_slice(array.slice(slab).as_standard_layout().view(), slab)?;
+        }
+
+        dataset
+    }};
+    (4, $type:ty, $array:expr, $path:ident, $file:ident, $options:ident) => {{
+        let array = $array;
+        let (nz, ny, nx, nc) = array.dim();
+        let depth = $options.chunk_depth.clamp(1, nz.max(1));
+
+        let dataset = $options
+            .new_dataset::<$type>($file)
+            .chunk((depth, ny, nx, nc))
+            .create(&$path, (nz, ny, nx, nc))?;
+
+        for k in (0..nz).step_by(depth) {
+            let slab = s![k..(k + depth).min(nz), .., .., ..];
+            dataset.write_slice(array.slice(slab).as_standard_layout().view(), slab)?;
+        }
+
+        dataset
+    }};
+}
+
+macro_rules! slice_array {
+    (3, $array:ident, $k:ident, $j:ident, $i:ident) => {
+        $array
+            .slice(s![$k.clone(), $j.clone(), $i.clone()])
+            .to_owned()
+    };
+    (4, $array:ident, $k:ident, $j:ident, $i:ident) => {
+        $array
+            .slice(s![$k.clone(), $j.clone(), $i.clone(), ..])
+            .to_owned()
+    };
+}
+
+/// Layout and filters of datasets in HDF5 files
+#[derive(Debug, Clone, Copy)]
+pub struct Hdf5Options {
+    /// Number of Z layers in each chunk
+    pub chunk_depth: usize,
+    /// Gzip compression level, from 0 to 9. 0 disables compression.
+    pub compression: u8,
+    /// Enable the shuffle filter, which improves compression of multi-byte values
+    pub shuffle: bool,
+    /// Also write bounding boxes as datasets next to the field data, as in format version 1
+    pub legacy_layout: bool,
+    /// Write strings as fixed-length, null-padded ASCII instead of variable-length UTF-8
+    pub fixed_length_strings: bool,
+}
+
+impl Default for Hdf5Options {
+    fn default() -> Self {
+        Self {
+            chunk_depth: 1,
+            compression: 6,
+            shuffle: false,
+            legacy_layout: false,
+            fixed_length_strings: false,
+        }
+    }
+}
+
+impl Hdf5Options {
+    /// Dataset builder with the configured filters
+    pub fn new_dataset<T: hdf5::H5Type>(&self, file: &hdf5::File) -> hdf5::DatasetBuilder<T> {
+        let mut builder = file.new_dataset::<T>();
+
+        if self.compression > 0 {
+            builder.gzip(self.compression);
+        }
+
+        builder.shuffle(self.shuffle);
+        builder
+    }
+}
+
+/// Method for combining cells when downsampling fields
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Downsample {
+    /// Mean of the cells in the window, renormalized for direction fields
+    Mean,
+    /// Full if at least half the cells in the window are over 50%, empty otherwise. Only applies
+    /// to byte fields, others use the mean.
+    MajorityThreshold,
+    /// Most frequent value in the window, the smallest one for ties, for categorical data. Only
+    /// applies to byte fields, others use the mean.
+    Mode,
+}
+
+/// Most frequent of `values`, the smallest one for ties
+fn byte_mode<'a>(values: impl Iterator<Item = &'a u8>) -> u8 {
+    let mut counts = [0usize; 256];
+    for v in values {
+        counts[*v as usize] += 1;
+    }
+
+    // max_by_key returns the last maximum, scan from the largest value
+    (0..=255u8)
+        .rev()
+        .max_by_key(|v| counts[*v as usize])
+        .unwrap()
+}
+
+/// Range of source cells covered by the downsampled cell `idx`
+fn downsample_window(idx: usize, factor: usize, len: usize) -> std::ops::Range<usize> {
+    idx * factor..((idx + 1) * factor).min(len)
+}
+
+fn downsample_dim(dim: (usize, usize, usize), factor: usize) -> (usize, usize, usize) {
+    (
+        (dim.0 + factor - 1) / factor,
+        (dim.1 + factor - 1) / factor,
+        (dim.2 + factor - 1) / factor,
+    )
+}
+
 impl FieldStorage {
+    /// Whether the field holds directions: 3-component vectors are renormalized when averaged,
+    /// unlike tensors and other multi-component fields
+    fn is_direction(&self) -> bool {
+        match self {
+            Self::Vec3(array) => array.dim().3 == 3,
+            _ => false,
+        }
+    }
+
+    /// Scalar values of a vector field, `None` for other storages or out of range components.
+    /// Magnitudes are only defined for 3-component vectors.
+    fn scalarize(&self, method: VectorScalarization) -> Option<ndarray::Array3<f32>> {
+        match (self, method) {
+            (Self::Vec3(array), VectorScalarization::Magnitude) if self.is_direction() => {
+                Some(array.map_axis(Axis(3), |v| {
+                    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+                }))
+            }
+            (Self::Vec3(array), VectorScalarization::Component(c)) if c < 3 => {
+                Some(array.index_axis(Axis(3), c).to_owned())
+            }
+            _ => None,
+        }
+    }
+
+    fn downsample(&self, factor: usize, method: Downsample) -> Self {
+        let d = self.dim();
+        let out_dim = downsample_dim((d.0, d.1, d.2), factor);
+
+        let window = |k: usize, j: usize, i: usize| {
+            (
+                downsample_window(k, factor, d.0),
+                downsample_window(j, factor, d.1),
+                downsample_window(i, factor, d.2),
+            )
+        };
+
+        match self {
+            FieldStorage::Byte(array) => {
+                FieldStorage::Byte(Array3::from_shape_fn(out_dim, |(k, j, i)| {
+                    let (wk, wj, wi) = window(k, j, i);
+                    let w = array.slice(s![wk, wj, wi]);
+
+                    match method {
+                        Downsample::Mean => (w.iter().map(|x| *x as f32).sum::<f32>()
+                            / w.len() as f32)
+                            .round() as u8,
+                        Downsample::MajorityThreshold => {
+                            if 2 * w.iter().filter(|x| **x >= 128).count() >= w.len() {
+                                255
+                            } else {
+                                0
+                            }
+                        }
+                        Downsample::Mode => byte_mode(w.iter()),
+                    }
+                }))
+            }
+            FieldStorage::ByteVec4(array) => FieldStorage::ByteVec4(Array4::from_shape_fn(
+                (out_dim.0, out_dim.1, out_dim.2, d.3),
+                |(k, j, i, c)| {
+                    let (wk, wj, wi) = window(k, j, i);
+                    let w = array.slice(s![wk, wj, wi, c]);
+                    match method {
+                        Downsample::Mode => byte_mode(w.iter()),
+                        _ => (w.iter().map(|x| *x as f32).sum::<f32>() / w.len() as f32).round()
+                            as u8,
+                    }
+                },
+            )),
+            FieldStorage::Float(array) => {
+                FieldStorage::Float(Array3::from_shape_fn(out_dim, |(k, j, i)| {
+                    let (wk, wj, wi) = window(k, j, i);
+                    array.slice(s![wk, wj, wi]).mean().unwrap()
+                }))
+            }
+            FieldStorage::Vec3(array) => {
+                let mut out = Array4::zeros((out_dim.0, out_dim.1, out_dim.2, d.3));
+                let direction = self.is_direction();
+
+                par_azip!((index (k, j, i), mut v in out.lanes_mut(Axis(3))) {
+                    let (wk, wj, wi) = window(k, j, i);
+                    let w = array.slice(s![wk, wj, wi, ..]);
+
+                    // Sum vectors in the window then renormalize directions, or average the
+                    // components of other fields
+                    let mut count = 0;
+                    for lane in w.lanes(Axis(3)) {
+                        v += &lane;
+                        count += 1;
+                    }
+
+                    if direction {
+                        let norm = v.dot(&v).sqrt();
+                        if norm > 0.0 {
+                            v /= norm;
+                        }
+                    } else if count > 0 {
+                        v /= count as f32;
+                    }
+                });
+
+                FieldStorage::Vec3(out)
+            }
+        }
+    }
+
+    fn slice(
+        &self,
+        k: std::ops::Range<usize>,
+        j: std::ops::Range<usize>,
+        i: std::ops::Range<usize>,
+    ) -> Self {
+        match self {
+            FieldStorage::Byte(array) => FieldStorage::Byte(slice_array!(3, array, k, j, i)),
+            FieldStorage::ByteVec4(array) => {
+                FieldStorage::ByteVec4(slice_array!(4, array, k, j, i))
+            }
+            FieldStorage::Float(array) => FieldStorage::Float(slice_array!(3, array, k, j, i)),
+            FieldStorage::Vec3(array) => FieldStorage::Vec3(slice_array!(4, array, k, j, i)),
+        }
+    }
+
     fn pad(&mut self, one_size_pad: usize) {
         match self {
             FieldStorage::Byte(array) => {
@@ -114,59 +374,381 @@ impl FieldStorage {
         &self,
         path: &str,
         file: &hdf5::File,
-        std_layout: &mut ndarray::Array3<u8>,
-    ) -> Result<(), hdf5::Error> {
+        options: &Hdf5Options,
+    ) -> Result<hdf5::Dataset, hdf5::Error> {
+        Ok(match self {
+            Self::Byte(array) => write_slabs!(3, u8, array, path, file, options),
+            Self::ByteVec4(array) => {
+                write_slabs!(3, u8, array.index_axis(Axis(3), 0), path, file, options)
+            }
+            Self::Float(array) => write_slabs!(3, f32, array, path, file, options),
+            Self::Vec3(array) => write_slabs!(4, f32, array, path, file, options),
+        })
+    }
+
+    /// Infer the storage type from the dataset type and rank. ByteVec4 fields are written as
+    /// their first channel, so they are read back as Byte fields.
+    fn read_hdf5(dataset: &hdf5::Dataset) -> Result<Self, failure::Error> {
+        let shape = dataset.shape();
+        let dtype = dataset.dtype()?;
+
+        match shape[..] {
+            [z, y, x] if dtype.is::<u8>() => Ok(Self::Byte(Array3::from_shape_vec(
+                (z, y, x),
+                dataset.read_raw()?,
+            )?)),
+            [z, y, x] if dtype.is::<f32>() => Ok(Self::Float(Array3::from_shape_vec(
+                (z, y, x),
+                dataset.read_raw()?,
+            )?)),
+            [z, y, x, c] if dtype.is::<f32>() => Ok(Self::Vec3(Array4::from_shape_vec(
+                (z, y, x, c),
+                dataset.read_raw()?,
+            )?)),
+            _ => Err(failure::err_msg(format!(
+                "unsupported field dataset of shape {:?}",
+                shape
+            ))),
+        }
+    }
+
+    fn xdmf_type(&self) -> Option<(&'static str, usize, AttributeType)> {
+        match self {
+            Self::Byte(_) => Some(("UInt", 1, AttributeType::Scalar)),
+            Self::ByteVec4(_) => Some(("UInt", 1, AttributeType::Scalar)),
+            Self::Float(_) => Some(("Float", 4, AttributeType::Scalar)),
+            Self::Vec3(array) => {
+                AttributeType::from_components(array.dim().3).map(|kind| ("Float", 4, kind))
+            }
+        }
+    }
+
+    fn nrrd_type(&self) -> &'static str {
+        match self {
+            Self::Byte(_) => "uchar",
+            Self::ByteVec4(_) => "uchar",
+            Self::Float(_) => "float",
+            Self::Vec3(_) => "float",
+        }
+    }
+
+    fn vtk_type(&self) -> (&'static str, usize) {
+        match self {
+            Self::Byte(_) => ("UInt8", 1),
+            Self::ByteVec4(_) => ("UInt8", 1),
+            Self::Float(_) => ("Float32", 1),
+            Self::Vec3(array) => ("Float32", array.dim().3),
+        }
+    }
+
+    fn raw_len(&self) -> usize {
+        let dim = self.dim();
+        let cells = dim.0 * dim.1 * dim.2;
+
+        match self {
+            Self::Byte(_) => cells,
+            Self::ByteVec4(_) => cells,
+            Self::Float(_) => cells * std::mem::size_of::<f32>(),
+            Self::Vec3(_) => cells * dim.3 * std::mem::size_of::<f32>(),
+        }
+    }
+
+    fn write_raw(&self, dest: &mut dyn std::io::Write) -> std::io::Result<()> {
         match self {
             Self::Byte(array) => {
-                file.new_dataset::<u8>()
-                    .gzip(6)
-                    .create(&path, array.dim())?
-                    .write(array.view())?;
+                for x in array.iter() {
+                    dest.write_all(&[*x])?;
+                }
             }
             Self::ByteVec4(array) => {
-                let field = array.index_axis(Axis(3), 0);
-                if std_layout.shape() != field.shape() {
-                    let d = array.dim();
-                    *std_layout = ndarray::Array3::zeros((d.0, d.1, d.2));
+                // Only the first channel is meaningful, as for HDF5
+                for x in array.index_axis(Axis(3), 0).iter() {
+                    dest.write_all(&[*x])?;
                 }
-
-                std_layout.assign(&field);
-
-                let dim: (usize, usize, usize) = std_layout.dim().into();
-                let dataset = file.new_dataset::<u8>().gzip(6).create(&path, dim)?;
-                dataset.write(std_layout.view())?;
             }
             Self::Float(array) => {
-                file.new_dataset::<f32>()
-                    .gzip(6)
-                    .create(&path, array.dim())?
-                    .write(array.view())?;
+                for x in array.iter() {
+                    dest.write_all(&x.to_le_bytes())?;
+                }
             }
             Self::Vec3(array) => {
-                file.new_dataset::<f32>()
-                    .gzip(6)
-                    .create(&path, array.dim())?
-                    .write(array.view())?;
+                for x in array.iter() {
+                    dest.write_all(&x.to_le_bytes())?;
+                }
             }
         }
 
         Ok(())
     }
+}
+
+/// Kind of XDMF attribute a field is written as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeType {
+    Scalar,
+    Vector,
+    /// Symmetric 3x3 tensor, as its 6 upper triangular components xx, xy, xz, yy, yz, zz
+    Tensor6,
+    /// Full 3x3 tensor
+    Tensor,
+}
+
+impl AttributeType {
+    /// Attribute type of cells with `components` values, `None` if XDMF has no matching type
+    pub fn from_components(components: usize) -> Option<Self> {
+        match components {
+            1 => Some(Self::Scalar),
+            3 => Some(Self::Vector),
+            6 => Some(Self::Tensor6),
+            9 => Some(Self::Tensor),
+            _ => None,
+        }
+    }
+
+    pub fn components(self) -> usize {
+        match self {
+            Self::Scalar => 1,
+            Self::Vector => 3,
+            Self::Tensor6 => 6,
+            Self::Tensor => 9,
+        }
+    }
 
-    fn xdmf_type(&self) -> Option<(&'static str, usize, usize)> {
+    /// Value of the AttributeType XDMF attribute
+    pub fn xdmf_name(self) -> &'static str {
         match self {
-            Self::Byte(_) => Some(("UInt", 1, 1)),
-            Self::ByteVec4(_) => Some(("UInt", 1, 1)),
-            Self::Float(_) => Some(("Float", 4, 1)),
-            Self::Vec3(array) => Some(("Float", 4, array.dim().3)),
+            Self::Scalar => "Scalar",
+            Self::Vector => "Vector",
+            Self::Tensor6 => "Tensor6",
+            Self::Tensor => "Tensor",
+        }
+    }
+}
+
+/// Interpolation method for resampling fields
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleMethod {
+    /// Value of the closest cell, for categorical data
+    Nearest,
+    /// Trilinear interpolation between cell centers
+    Trilinear,
+}
+
+impl std::str::FromStr for ResampleMethod {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Self::Nearest),
+            "trilinear" => Ok(Self::Trilinear),
+            other => Err(failure::err_msg(format!(
+                "unknown resampling method: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Mapping of per-layer array values onto the Z axis of a field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArrayToFieldMode {
+    /// Value of the layer containing the cell, for quantities which apply to a whole layer
+    Step,
+    /// Linear interpolation between layer centers
+    Linear,
+}
+
+impl std::str::FromStr for ArrayToFieldMode {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "step" => Ok(Self::Step),
+            "linear" => Ok(Self::Linear),
+            other => Err(failure::err_msg(format!(
+                "unknown array to field mode: {}, expected step or linear",
+                other
+            ))),
+        }
+    }
+}
+
+/// Element-wise operation between a field and another field or a scalar
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldOp {
+    Add,
+    Sub,
+    Mul,
+    /// 255 where the left-hand side is greater than or equal to the right-hand side, 0 elsewhere
+    Threshold,
+    /// Left-hand side where the right-hand side is non-zero, 0 elsewhere
+    Mask,
+}
+
+impl std::str::FromStr for FieldOp {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(Self::Add),
+            "sub" => Ok(Self::Sub),
+            "mul" => Ok(Self::Mul),
+            "threshold" => Ok(Self::Threshold),
+            "mask" => Ok(Self::Mask),
+            other => Err(failure::err_msg(format!(
+                "unknown field operation: {}, expected add, sub, mul, threshold or mask",
+                other
+            ))),
         }
     }
 }
 
+/// Right-hand side of a field operation
+#[derive(Debug, Clone, Copy)]
+pub enum FieldOpRhs<'a> {
+    Field(&'a ParamField),
+    Scalar(f32),
+}
+
+/// Scalar derived from each vector of a vector field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorScalarization {
+    /// Euclidean norm of the vector
+    Magnitude,
+    /// Single component, 0 to 2 for X to Z
+    Component(usize),
+}
+
+impl std::str::FromStr for VectorScalarization {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "magnitude" => Ok(Self::Magnitude),
+            "x" => Ok(Self::Component(0)),
+            "y" => Ok(Self::Component(1)),
+            "z" => Ok(Self::Component(2)),
+            other => Err(failure::err_msg(format!(
+                "unknown vector scalarization: {}, expected magnitude, x, y or z",
+                other
+            ))),
+        }
+    }
+}
+
+/// Value of the cell containing `p`, in array coordinates, clamped to the array dimensions
+fn nearest<T>(
+    p: &nalgebra::Vector3<f32>,
+    dim: (usize, usize, usize),
+    fetch: impl Fn(usize, usize, usize) -> T,
+) -> T {
+    let axis = |v: f32, n: usize| (v.floor() as isize).max(0).min(n as isize - 1) as usize;
+    fetch(axis(p.z, dim.0), axis(p.y, dim.1), axis(p.x, dim.2))
+}
+
+/// Trilinear interpolation at `p`, in array coordinates (cell `i` spans `i..i + 1`), of the values
+/// returned by `fetch` for (z, y, x) indices in an array of dimensions `dim`. Points outside of the
+/// cell centers are clamped to the border values.
+fn trilinear<T>(
+    p: &nalgebra::Vector3<f32>,
+    dim: (usize, usize, usize),
+    fetch: impl Fn(usize, usize, usize) -> T,
+) -> T
+where
+    T: std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T>,
+{
+    // Neighbouring cell centers and interpolation factor along one axis
+    let axis = |v: f32, n: usize| {
+        let v = v - 0.5;
+        let lo = (v.floor() as isize).max(0).min(n as isize - 1) as usize;
+        let hi = (v.ceil() as isize).max(0).min(n as isize - 1) as usize;
+        let a = if lo == hi { 0.0 } else { v - v.floor() };
+        (lo, hi, a)
+    };
+
+    let (x1, x2, ax) = axis(p.x, dim.2);
+    let (y1, y2, ay) = axis(p.y, dim.1);
+    let (z1, z2, az) = axis(p.z, dim.0);
+
+    let lerp_x = |z, y| fetch(z, y, x1) * (1.0 - ax) + fetch(z, y, x2) * ax;
+
+    let c0 = lerp_x(z1, y1) * (1.0 - ay) + lerp_x(z1, y2) * ay;
+    let c1 = lerp_x(z2, y1) * (1.0 - ay) + lerp_x(z2, y2) * ay;
+
+    c0 * (1.0 - az) + c1 * az
+}
+
+/// Map `t` in [0, 1] to a blue-cyan-green-yellow-red ramp
+fn false_color(t: f32) -> image::Rgb<u8> {
+    const STOPS: [[f32; 3]; 5] = [
+        [0., 0., 255.],
+        [0., 255., 255.],
+        [0., 255., 0.],
+        [255., 255., 0.],
+        [255., 0., 0.],
+    ];
+
+    let t = if t.is_nan() { 0.0 } else { t.max(0.0).min(1.0) };
+    let x = t * (STOPS.len() - 1) as f32;
+    let idx = (x.floor() as usize).min(STOPS.len() - 2);
+    let a = x - idx as f32;
+
+    let mut rgb = [0u8; 3];
+    for (c, v) in rgb.iter_mut().enumerate() {
+        *v = (STOPS[idx][c] * (1.0 - a) + STOPS[idx + 1][c] * a).round() as u8;
+    }
+
+    image::Rgb(rgb)
+}
+
+/// Summary statistics of the values of a field
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct FieldStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Fraction of the cells with a non-zero value
+    pub nonzero_fraction: f64,
+}
+
+impl FieldStats {
+    fn new(values: ndarray::Array3<f64>) -> Self {
+        let nonzero = values.iter().filter(|v| **v != 0.0).count();
+
+        Self {
+            min: *values.min_skipnan(),
+            max: *values.max_skipnan(),
+            mean: values.mean().unwrap_or(std::f64::NAN),
+            nonzero_fraction: nonzero as f64 / values.len().max(1) as f64,
+        }
+    }
+}
+
+/// String attribute `name` of `dataset`, `None` if the attribute doesn't exist
+fn read_string_attr(dataset: &hdf5::Dataset, name: &str) -> Result<Option<String>, failure::Error> {
+    if !dataset.attr_names()?.iter().any(|attr| attr == name) {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        dataset
+            .attr(name)?
+            .read_scalar::<VarLenUnicode>()?
+            .as_str()
+            .to_owned(),
+    ))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ParamField {
     pub field_box_mm: BoundingBox<f32>,
     field: FieldStorage,
+    /// Unit of the field values, e.g. `mm`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    /// Meaning of the field values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 impl ParamField {
@@ -190,29 +772,59 @@ impl ParamField {
         Self::attr(attributes, name).value.parse::<T>().unwrap()
     }
 
-    pub fn new_u8(field_box_mm: BoundingBox<f32>, storage: ndarray::Array3<u8>) -> Self {
+    /// Field without unit nor description
+    fn from_storage(field_box_mm: BoundingBox<f32>, field: FieldStorage) -> Self {
         Self {
             field_box_mm,
-            field: FieldStorage::Byte(storage),
+            field,
+            unit: None,
+            description: None,
         }
     }
 
-    pub fn new_vec3(field_box_mm: BoundingBox<f32>, storage: ndarray::Array4<f32>) -> Self {
-        assert!(storage.dim().3 == 3);
+    /// Copy the unit and description of `other`, for fields holding the same quantity
+    fn with_metadata_of(self, other: &ParamField) -> Self {
+        Self {
+            unit: other.unit.clone(),
+            description: other.description.clone(),
+            ..self
+        }
+    }
 
+    pub fn with_unit(self, unit: impl Into<String>) -> Self {
         Self {
-            field_box_mm,
-            field: FieldStorage::Vec3(storage),
+            unit: Some(unit.into()),
+            ..self
         }
     }
 
-    pub fn new_f32(field_box_mm: BoundingBox<f32>, storage: ndarray::Array3<f32>) -> Self {
+    pub fn with_description(self, description: impl Into<String>) -> Self {
         Self {
-            field_box_mm,
-            field: FieldStorage::Float(storage),
+            description: Some(description.into()),
+            ..self
         }
     }
 
+    pub fn new_u8(field_box_mm: BoundingBox<f32>, storage: ndarray::Array3<u8>) -> Self {
+        Self::from_storage(field_box_mm, FieldStorage::Byte(storage))
+    }
+
+    pub fn new_vec3(field_box_mm: BoundingBox<f32>, storage: ndarray::Array4<f32>) -> Self {
+        assert!(storage.dim().3 == 3);
+
+        Self::from_storage(field_box_mm, FieldStorage::Vec3(storage))
+    }
+
+    /// Field of any number of float components per cell, such as the 6 unique components of a
+    /// symmetric tensor. It shares the storage of vector fields.
+    pub fn new_tensor(field_box_mm: BoundingBox<f32>, storage: ndarray::Array4<f32>) -> Self {
+        Self::from_storage(field_box_mm, FieldStorage::Vec3(storage))
+    }
+
+    pub fn new_f32(field_box_mm: BoundingBox<f32>, storage: ndarray::Array3<f32>) -> Self {
+        Self::from_storage(field_box_mm, FieldStorage::Float(storage))
+    }
+
     pub fn from_attr(
         attributes: &[xml::attribute::OwnedAttribute],
     ) -> Result<Self, failure::Error> {
@@ -223,8 +835,8 @@ impl ParamField {
         );
 
         // Parse field parameters
-        let mut field = ParamField {
-            field_box_mm: BoundingBox {
+        let mut field = ParamField::from_storage(
+            BoundingBox {
                 min_x: Self::attr_into(attributes, "field_box_mm_min_x"),
                 min_y: Self::attr_into(attributes, "field_box_mm_min_y"),
                 min_z: Self::attr_into(attributes, "field_box_mm_min_z"),
@@ -234,10 +846,8 @@ impl ParamField {
             },
             // Allocate array
             // Big endian order: fastest dimension varying last
-            field: FieldStorage::ByteVec4(ndarray::Array4::zeros((
-                field_sz, field_sy, field_sx, 4,
-            ))),
-        };
+            FieldStorage::ByteVec4(ndarray::Array4::zeros((field_sz, field_sy, field_sx, 4))),
+        );
 
         // Parse field data
         let mut attr_value_cursor = std::io::Cursor::new(&Self::attr(attributes, "field").value);
@@ -260,56 +870,352 @@ impl ParamField {
         self.field.dim()
     }
 
-    /// Pad the represented field by `pad` layers on each side. The bouding box will be resized
-    /// accordingly.
+    /// Pad the represented field by `pad` layers of zeros on each side. The bounding box is grown
+    /// by `pad` cells on each side so the cell size is preserved.
     pub fn pad(&mut self, pad: usize) {
         // Get previous array dimensions
         let original_dim = self.dim();
+        let size = self.field_box_mm.size();
 
         // Pad storage
         self.field.pad(pad);
 
         // Pad bounding box. Arguments are X, Y, Z
         self.field_box_mm.pad_all(nalgebra::Vector3::new(
-            pad as f32 / original_dim.2 as f32,
-            pad as f32 / original_dim.1 as f32,
-            pad as f32 / original_dim.0 as f32,
+            pad as f32 * size.x / original_dim.2 as f32,
+            pad as f32 * size.y / original_dim.1 as f32,
+            pad as f32 * size.z / original_dim.0 as f32,
         ));
     }
 
+    /// Reduce the resolution of the field by `factor` along each axis. Windows on the upper
+    /// boundaries may be partial if the dimensions aren't divisible by `factor`. The bounding box
+    /// is preserved.
+    pub fn downsample(&self, factor: usize, method: Downsample) -> ParamField {
+        Self::from_storage(self.field_box_mm, self.field.downsample(factor, method))
+            .with_metadata_of(self)
+    }
+
+    /// Crop the field to the cells overlapping `bbox`. The resulting bounding box is aligned on
+    /// the cells of the original field. Fails if `bbox` doesn't overlap the field.
+    pub fn crop(&self, bbox: &BoundingBox<f32>) -> Result<ParamField, failure::Error> {
+        let bbox = self.field_box_mm.intersection(bbox).ok_or_else(|| {
+            failure::err_msg("crop region doesn't intersect the field bounding box")
+        })?;
+
+        let dim = self.dim();
+        let size = self.field_box_mm.size();
+
+        let range = |min: f32, max: f32, box_min: f32, box_size: f32, count: usize| {
+            let cell = box_size / count as f32;
+            let start = ((min - box_min) / cell).floor().max(0.0).min(count as f32) as usize;
+            let end = ((max - box_min) / cell).ceil().max(0.0).min(count as f32) as usize;
+            let end = end.max(start);
+
+            (
+                start..end,
+                box_min + start as f32 * cell,
+                box_min + end as f32 * cell,
+            )
+        };
+
+        let (i, min_x, max_x) = range(
+            bbox.min_x,
+            bbox.max_x,
+            self.field_box_mm.min_x,
+            size.x,
+            dim.2,
+        );
+        let (j, min_y, max_y) = range(
+            bbox.min_y,
+            bbox.max_y,
+            self.field_box_mm.min_y,
+            size.y,
+            dim.1,
+        );
+        let (k, min_z, max_z) = range(
+            bbox.min_z,
+            bbox.max_z,
+            self.field_box_mm.min_z,
+            size.z,
+            dim.0,
+        );
+
+        Ok(Self::from_storage(
+            BoundingBox {
+                min_x,
+                min_y,
+                min_z,
+                max_x,
+                max_y,
+                max_z,
+            },
+            self.field.slice(k, j, i),
+        )
+        .with_metadata_of(self))
+    }
+
     pub fn write_hdf5(
         &self,
         path: &str,
         file: &hdf5::File,
-        std_layout: &mut ndarray::Array3<u8>,
+        options: &Hdf5Options,
     ) -> Result<(), hdf5::Error> {
-        self.field
-            .write_hdf5(&format!("{}/data", path), file, std_layout)?;
-
-        // Bounding box
-        file.new_dataset::<f32>()
-            .create(&format!("{}/bounding_box_min", path), (3,))?
-            .write(&[
-                self.field_box_mm.min_x,
-                self.field_box_mm.min_y,
-                self.field_box_mm.min_z,
-            ])?;
-        file.new_dataset::<f32>()
-            .create(&format!("{}/bounding_box_max", path), (3,))?
-            .write(&[
-                self.field_box_mm.max_x,
-                self.field_box_mm.max_y,
-                self.field_box_mm.max_z,
-            ])?;
+        let data = self
+            .field
+            .write_hdf5(&format!("{}/data", path), file, options)?;
+
+        let bbox = &self.field_box_mm;
+        let size = bbox.size();
+        let dim = self.dim();
+
+        // Grid metadata, in X, Y, Z order
+        let min = [bbox.min_x, bbox.min_y, bbox.min_z];
+        let max = [bbox.max_x, bbox.max_y, bbox.max_z];
+
+        data.new_attr::<f32>()
+            .create("bounding_box_min", (3,))?
+            .write(&min)?;
+        data.new_attr::<f32>()
+            .create("bounding_box_max", (3,))?
+            .write(&max)?;
+        data.new_attr::<f32>().create("spacing", (3,))?.write(&[
+            size.x / dim.2 as f32,
+            size.y / dim.1 as f32,
+            size.z / dim.0 as f32,
+        ])?;
+        data.new_attr::<u64>().create("dims", (3,))?.write(&[
+            dim.2 as u64,
+            dim.1 as u64,
+            dim.0 as u64,
+        ])?;
+
+        // Optional metadata, omitted when not set
+        for (name, value) in &[("unit", &self.unit), ("description", &self.description)] {
+            if let Some(value) = value {
+                data.new_attr::<VarLenUnicode>()
+                    .create(*name, ())?
+                    .write_scalar(&to_var_len_string(value))?;
+            }
+        }
+
+        if options.legacy_layout {
+            options
+                .new_dataset::<f32>(file)
+                .create(&format!("{}/bounding_box_min", path), (3,))?
+                .write(&min)?;
+            options
+                .new_dataset::<f32>(file)
+                .create(&format!("{}/bounding_box_max", path), (3,))?
+                .write(&max)?;
+        }
 
         Ok(())
     }
 
-    /// Returns (item_type, precision, components)
-    pub fn xdmf_type(&self) -> Option<(&'static str, usize, usize)> {
+    /// Read a field written by `write_hdf5`, with or without the legacy layout
+    pub fn read_hdf5(path: &str, file: &hdf5::File) -> Result<Self, failure::Error> {
+        let data = file.dataset(&format!("{}/data", path))?;
+        let field = FieldStorage::read_hdf5(&data)?;
+
+        // Bounding box, from the data attributes or the legacy datasets
+        let (min, max) = match (data.attr("bounding_box_min"), data.attr("bounding_box_max")) {
+            (Ok(min), Ok(max)) => (min.read_raw::<f32>()?, max.read_raw::<f32>()?),
+            _ => (
+                file.dataset(&format!("{}/bounding_box_min", path))?
+                    .read_raw::<f32>()?,
+                file.dataset(&format!("{}/bounding_box_max", path))?
+                    .read_raw::<f32>()?,
+            ),
+        };
+
+        match (&min[..], &max[..]) {
+            ([min_x, min_y, min_z], [max_x, max_y, max_z]) => Ok(Self {
+                field_box_mm: BoundingBox {
+                    min_x: *min_x,
+                    min_y: *min_y,
+                    min_z: *min_z,
+                    max_x: *max_x,
+                    max_y: *max_y,
+                    max_z: *max_z,
+                },
+                field,
+                unit: read_string_attr(&data, "unit")?,
+                description: read_string_attr(&data, "description")?,
+            }),
+            _ => Err(failure::err_msg(format!(
+                "invalid bounding box for field {}",
+                path
+            ))),
+        }
+    }
+
+    /// XDMF data type, precision and attribute type of the field, `None` if its number of
+    /// components has no XDMF attribute type
+    pub fn xdmf_type(&self) -> Option<(&'static str, usize, AttributeType)> {
         self.field.xdmf_type()
     }
 
+    /// Returns (data_type, components)
+    pub fn vtk_type(&self) -> (&'static str, usize) {
+        self.field.vtk_type()
+    }
+
+    /// Size in bytes of the data written by `write_raw`
+    pub fn raw_len(&self) -> usize {
+        self.field.raw_len()
+    }
+
+    /// Write the field data as little-endian values, in (z, y, x) order with vector components
+    /// interleaved
+    pub fn write_raw(&self, dest: &mut dyn std::io::Write) -> std::io::Result<()> {
+        self.field.write_raw(dest)
+    }
+
+    /// Write the field as a detached-header NRRD file. The header is written to `path` and the
+    /// raw data next to it, with the `raw` extension.
+    pub fn write_nrrd(&self, path: &Path) -> std::io::Result<()> {
+        let raw_path = path.with_extension("raw");
+        let dim = self.dim();
+        let size = self.field_box_mm.size();
+        let components = self.vtk_type().1;
+
+        let mut header = std::fs::File::create(path)?;
+        writeln!(header, "NRRD0004")?;
+        writeln!(
+            header,
+            "# Data is stored in (z, y, x) order, sizes are fastest axis first"
+        )?;
+        writeln!(header, "type: {}", self.field.nrrd_type())?;
+        writeln!(header, "encoding: raw")?;
+        writeln!(header, "endian: little")?;
+
+        if components == 1 {
+            writeln!(header, "dimension: 3")?;
+            writeln!(header, "sizes: {} {} {}", dim.2, dim.1, dim.0)?;
+            writeln!(header, "kinds: domain domain domain")?;
+            writeln!(
+                header,
+                "spacings: {} {} {}",
+                size.x / dim.2 as f32,
+                size.y / dim.1 as f32,
+                size.z / dim.0 as f32
+            )?;
+        } else {
+            writeln!(header, "dimension: 4")?;
+            writeln!(
+                header,
+                "sizes: {} {} {} {}",
+                components, dim.2, dim.1, dim.0
+            )?;
+            writeln!(header, "kinds: {}-vector domain domain domain", components)?;
+            writeln!(
+                header,
+                "spacings: nan {} {} {}",
+                size.x / dim.2 as f32,
+                size.y / dim.1 as f32,
+                size.z / dim.0 as f32
+            )?;
+        }
+
+        writeln!(
+            header,
+            "axis mins: {}{} {} {}",
+            if components == 1 { "" } else { "nan " },
+            self.field_box_mm.min_x,
+            self.field_box_mm.min_y,
+            self.field_box_mm.min_z
+        )?;
+        writeln!(
+            header,
+            "data file: {}",
+            raw_path.file_name().unwrap().to_string_lossy()
+        )?;
+
+        let mut raw = std::io::BufWriter::new(std::fs::File::create(&raw_path)?);
+        self.write_raw(&mut raw)
+    }
+
+    /// Write one PNG per Z layer to `dir` as `{prefix}_{k}.png`, with zero-padded indices and Y
+    /// pointing up. Byte fields are written in grayscale, float fields and the magnitude of vector
+    /// fields in false colors between `range`, which defaults to the range of the values.
+    pub fn write_png_stack(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        range: Option<(f32, f32)>,
+    ) -> Result<Vec<std::path::PathBuf>, failure::Error> {
+        std::fs::create_dir_all(dir)?;
+
+        let (zc, yc, xc, _) = self.dim();
+        let digits = (zc.max(2) - 1).to_string().len().max(4);
+        let path = |k: usize| dir.join(format!("{}_{:0width$}.png", prefix, k, width = digits));
+
+        let mut paths = Vec::with_capacity(zc);
+
+        // Only the first component of 4-component byte fields is written
+        let gray = match &self.field {
+            FieldStorage::Byte(array) => Some(array.view()),
+            FieldStorage::ByteVec4(array) => Some(array.index_axis(Axis(3), 0)),
+            _ => None,
+        };
+
+        if let Some(gray) = gray {
+            for (k, layer) in gray.outer_iter().enumerate() {
+                let img = image::GrayImage::from_fn(xc as u32, yc as u32, |x, y| {
+                    image::Luma([layer[(yc - 1 - y as usize, x as usize)]])
+                });
+                img.save(path(k))?;
+                paths.push(path(k));
+            }
+
+            return Ok(paths);
+        }
+
+        let values = match &self.field {
+            FieldStorage::Float(array) => Cow::Borrowed(array),
+            FieldStorage::Vec3(array) => {
+                Cow::Owned(array.map_axis(Axis(3), |v| v.iter().map(|c| c * c).sum::<f32>().sqrt()))
+            }
+            _ => unreachable!(),
+        };
+
+        let (min, max) = range.unwrap_or_else(|| {
+            values
+                .iter()
+                .filter(|v| v.is_finite())
+                .fold((std::f32::MAX, std::f32::MIN), |(min, max), &v| {
+                    (min.min(v), max.max(v))
+                })
+        });
+        let scale = if max > min { 1.0 / (max - min) } else { 0.0 };
+
+        for (k, layer) in values.outer_iter().enumerate() {
+            let img = image::RgbImage::from_fn(xc as u32, yc as u32, |x, y| {
+                false_color((layer[(yc - 1 - y as usize, x as usize)] - min) * scale)
+            });
+            img.save(path(k))?;
+            paths.push(path(k));
+        }
+
+        Ok(paths)
+    }
+
+    /// Statistics of the raw values of byte fields (first channel of RGBA fields), float fields
+    /// and the norm of vector and tensor fields
+    pub fn summary_stats(&self) -> FieldStats {
+        FieldStats::new(match &self.field {
+            FieldStorage::Byte(array) => array.mapv(f64::from),
+            FieldStorage::ByteVec4(array) => array.index_axis(Axis(3), 0).mapv(f64::from),
+            FieldStorage::Float(array) => array.mapv(f64::from),
+            // Norm of all the components, the magnitude for 3-component vectors
+            FieldStorage::Vec3(array) => array.map_axis(Axis(3), |v| f64::from(v.dot(&v)).sqrt()),
+        })
+    }
+
+    /// Values of the field as single precision floats. Byte fields are mapped from [0, 255] to
+    /// [0, `byte_scale`], using the first channel of RGBA fields, and vector fields are reduced to
+    /// their magnitude. `None` for tensor fields, which have no single scalar value.
     pub fn as_f32_array(&self, byte_scale: f32) -> Option<Cow<ndarray::Array3<f32>>> {
         match &self.field {
             FieldStorage::Float(array) => Some(Cow::Borrowed(array)),
@@ -331,7 +1237,31 @@ impl ParamField {
 
                 Some(Cow::Owned(mapped))
             }
-            _ => None,
+            FieldStorage::Vec3(_) => self
+                .field
+                .scalarize(VectorScalarization::Magnitude)
+                .map(Cow::Owned),
+        }
+    }
+
+    /// Scalar float field derived from each vector of a vector field
+    pub fn map_vec3_to_scalar(
+        &self,
+        method: VectorScalarization,
+    ) -> Result<ParamField, failure::Error> {
+        match &self.field {
+            FieldStorage::Vec3(_) => {
+                let data = self.field.scalarize(method).ok_or_else(|| {
+                    failure::err_msg(format!("invalid vector scalarization: {:?}", method))
+                })?;
+
+                // Magnitudes and components keep the unit of the vectors
+                Ok(Self {
+                    unit: self.unit.clone(),
+                    ..Self::from_storage(self.field_box_mm, FieldStorage::Float(data))
+                })
+            }
+            _ => Err(failure::err_msg("expected a vector field")),
         }
     }
 
@@ -350,39 +1280,148 @@ impl ParamField {
     }
 
     pub fn derive_vec3_from_field(&self, data: ndarray::Array4<f32>) -> Self {
-        Self {
-            field: FieldStorage::Vec3(data),
-            ..*self
-        }
+        Self::from_storage(self.field_box_mm, FieldStorage::Vec3(data))
     }
 
-    pub fn derive_from_array(&self, array: &ParamArray) -> Option<Self> {
+    /// Float field on the grid of this field, with the values of `array` spread over its
+    /// height: the array has one value per layer, and layers evenly divide the field along Z.
+    pub fn derive_from_array(&self, array: &ParamArray, mode: ArrayToFieldMode) -> Option<Self> {
         let dim = self.dim();
         let mut data = ndarray::Array3::zeros((dim.0, dim.1, dim.2));
         let src = array.as_f64_slice()?;
+        let last = src.len().checked_sub(1)?;
 
         for z in 0..dim.0 {
             // z in 0..1 range (cell middle)
-            let norm_z = (z as f32 + 0.5) / (dim.0 + 1) as f32;
+            let norm_z = (z as f32 + 0.5) / dim.0 as f32;
             // z in 0..src.len() range
-            let src_z = norm_z * (src.len() + 1) as f32 - 0.5;
-            // z idx in src
-            let src_idx = src_z.floor() as usize;
-            let src_idx_p1 = (src_idx + 1).min(src.len());
-            // Linear interpolation
-            let val = src[src_idx] as f32 * (1.0 - src_z.fract())
-                + src[src_idx_p1] as f32 * src_z.fract();
+            let src_z = norm_z * src.len() as f32;
+
+            let val = match mode {
+                ArrayToFieldMode::Step => src[(src_z.floor() as usize).min(last)] as f32,
+                ArrayToFieldMode::Linear => {
+                    // Layer values are at layer centers, and constant past the first and last
+                    let src_z = (src_z - 0.5).max(0.0).min(last as f32);
+                    let src_idx = src_z.floor() as usize;
+                    let src_idx_p1 = (src_idx + 1).min(last);
+                    let t = src_z - src_idx as f32;
+
+                    src[src_idx] as f32 * (1.0 - t) + src[src_idx_p1] as f32 * t
+                }
+            };
 
             data.index_axis_mut(Axis(0), z).fill(val);
         }
 
-        Some(Self {
-            field: FieldStorage::Float(data),
-            ..*self
+        Some(Self::from_storage(
+            self.field_box_mm,
+            FieldStorage::Float(data),
+        ))
+    }
+
+    fn op_values(&self) -> Result<Cow<ndarray::Array3<f32>>, failure::Error> {
+        match &self.field {
+            FieldStorage::Byte(_) | FieldStorage::Float(_) => Ok(self.as_f32_array(1.0).unwrap()),
+            _ => Err(failure::err_msg(
+                "field operations only support byte and float fields",
+            )),
+        }
+    }
+
+    /// Apply `op` between this field and `rhs`. Byte values are promoted to floats in [0, 1]:
+    /// arithmetic results are float fields, thresholds are byte masks and masking keeps the
+    /// storage of this field. Only byte and float fields are supported.
+    pub fn apply_op(&self, op: FieldOp, rhs: FieldOpRhs) -> Result<Self, failure::Error> {
+        let lhs = self.op_values()?;
+
+        // Products of two fields with units have the unit of both
+        let unit = match (op, rhs) {
+            (FieldOp::Threshold, _) => None,
+            (FieldOp::Mul, FieldOpRhs::Field(rhs)) => match (&self.unit, &rhs.unit) {
+                (Some(a), Some(b)) => Some(format!("{}*{}", a, b)),
+                (a, b) => a.clone().or_else(|| b.clone()),
+            },
+            _ => self.unit.clone(),
+        };
+
+        let rhs = match rhs {
+            FieldOpRhs::Field(rhs) => {
+                if !self.has_same_box(rhs) {
+                    return Err(failure::err_msg(format!(
+                        "operands have different grids ({:?} and {:?}), harmonize them first",
+                        self.dim(),
+                        rhs.dim()
+                    )));
+                }
+
+                rhs.op_values()?
+            }
+            FieldOpRhs::Scalar(value) => Cow::Owned(ndarray::Array3::from_elem(lhs.dim(), value)),
+        };
+
+        let field = match op {
+            FieldOp::Add => FieldStorage::Float(&*lhs + &*rhs),
+            FieldOp::Sub => FieldStorage::Float(&*lhs - &*rhs),
+            FieldOp::Mul => FieldStorage::Float(&*lhs * &*rhs),
+            FieldOp::Threshold => {
+                let mut mask = ndarray::Array3::zeros(lhs.dim());
+                azip!((m in &mut mask, a in &*lhs, b in &*rhs) *m = if *a >= *b { 255 } else { 0 });
+                FieldStorage::Byte(mask)
+            }
+            FieldOp::Mask => {
+                let mut field = self.field.clone();
+                match &mut field {
+                    FieldStorage::Byte(array) => {
+                        azip!((v in array, m in &*rhs) if *m == 0.0 { *v = 0 });
+                    }
+                    FieldStorage::Float(array) => {
+                        azip!((v in array, m in &*rhs) if *m == 0.0 { *v = 0.0 });
+                    }
+                    _ => unreachable!(),
+                }
+                field
+            }
+        };
+
+        Ok(Self {
+            unit,
+            ..Self::from_storage(self.field_box_mm, field)
         })
     }
 
-    pub fn resample(&self, mask: &ParamField) -> Self {
+    /// Resampling method matching the kind of data stored in the field: nearest for byte fields,
+    /// which may hold categories, and trilinear otherwise.
+    pub fn default_resample_method(&self) -> ResampleMethod {
+        match &self.field {
+            FieldStorage::Byte(_) | FieldStorage::ByteVec4(_) => ResampleMethod::Nearest,
+            FieldStorage::Float(_) | FieldStorage::Vec3(_) => ResampleMethod::Trilinear,
+        }
+    }
+
+    /// Resample the field onto the grid of `reference`, keeping every cell instead of masking
+    /// them with a geometry. Fields of 4-component bytes are rejected since `resample` only keeps
+    /// their first component.
+    pub fn resample_grid(
+        &self,
+        reference: &ParamField,
+        method: ResampleMethod,
+    ) -> Result<Self, failure::Error> {
+        if let FieldStorage::ByteVec4(_) = &self.field {
+            return Err(failure::err_msg(
+                "resampling 4-component byte fields is not supported",
+            ));
+        }
+
+        let d = reference.dim();
+        let mask = Self::new_u8(
+            reference.field_box_mm,
+            ndarray::Array3::from_elem((d.0, d.1, d.2), 255),
+        );
+
+        Ok(self.resample(&mask, method))
+    }
+
+    pub fn resample(&self, mask: &ParamField, method: ResampleMethod) -> Self {
         use nalgebra::Vector3;
 
         debug!("input field bounding box: {:?}", self.field_box_mm);
@@ -402,92 +1441,768 @@ impl ParamField {
             self.dim().0 as f32,
         ));
 
-        match &self.field {
+        let d = self.dim();
+        let dim = (d.0, d.1, d.2);
+
+        let out_min = mask.field_box_mm.min();
+        let in_min = self.field_box_mm.min();
+
+        // Convert output coordinates into point in input array
+        let to_input = |k: usize, j: usize, i: usize| {
+            let p = Vector3::new(i as f32 + 0.5, j as f32 + 0.5, k as f32 + 0.5); // Float array coordinates
+            let p = out_min + p.component_mul(&out_scale); // mm coordinates
+            (p - in_min).component_div(&in_scale) // input coordinates
+        };
+
+        macro_rules! sample {
+            ($p:expr, $fetch:expr) => {
+                match method {
+                    ResampleMethod::Nearest => nearest(&$p, dim, $fetch),
+                    ResampleMethod::Trilinear => trilinear(&$p, dim, $fetch),
+                }
+            };
+        }
+
+        let resampled = match &self.field {
+            FieldStorage::Byte(array) => {
+                let mut out = ndarray::Array3::<u8>::zeros(im.dim());
+
+                par_azip!((index (k, j, i), d in &mut out, m in im) {
+                    let c = sample!(to_input(k, j, i), |z, y, x| array[(z, y, x)] as f32);
+                    *d = (*m as f32 / 255.0 * c) as u8;
+                });
+
+                ParamField::new_u8(mask.field_box_mm, out)
+            }
             FieldStorage::ByteVec4(array) => {
                 let mut out = ndarray::Array3::<u8>::zeros(im.dim());
 
                 par_azip!((index (k, j, i), d in &mut out, m in im) {
-                    // Convert output coordinates into point in input array
-                    let p = Vector3::new(i as f32 + 0.5, j as f32 + 0.5, k as f32 + 0.5); // Float array coordinates
-                    let p = p.component_mul(&out_scale); // mm coordinates
-                    let p = p.component_div(&in_scale); // input coordinates
-
-                    // Tri-linear interpolation
-                    let px1 = (p.x.floor() as isize).max(0).min((array.dim().2 - 1) as isize) as usize;
-                    let py1 = (p.y.floor() as isize).max(0).min((array.dim().1 - 1) as isize) as usize;
-                    let pz1 = (p.z.floor() as isize).max(0).min((array.dim().0 - 1) as isize) as usize;
-                    let px2 = (p.x.ceil() as isize).max(0).min((array.dim().2 - 1) as isize) as usize;
-                    let py2 = (p.y.ceil() as isize).max(0).min((array.dim().1 - 1) as isize) as usize;
-                    let pz2 = (p.z.ceil() as isize).max(0).min((array.dim().0 - 1) as isize) as usize;
-                    let ax = p.x.fract();
-                    let ay = p.y.fract();
-                    let az = p.z.fract();
-
-                    let c00 = array[(pz1, py1, px1, 0)] as f32 * (1.0 - ax) + array[(pz1, py1, px2, 0)] as f32 * ax;
-                    let c01 = array[(pz2, py1, px1, 0)] as f32 * (1.0 - ax) + array[(pz2, py1, px2, 0)] as f32 * ax;
-                    let c10 = array[(pz1, py2, px1, 0)] as f32 * (1.0 - ax) + array[(pz1, py2, px2, 0)] as f32 * ax;
-                    let c11 = array[(pz2, py2, px1, 0)] as f32 * (1.0 - ax) + array[(pz1, py2, px2, 0)] as f32 * ax;
-
-                    let c0 = c00 * (1.0 - ay) + c10 * ay;
-                    let c1 = c01 * (1.0 - ay) + c11 * ay;
-
-                    *d = (*m as f32 / 255.0 * (c0 * (1.0 - az) + c1 * az)) as u8;
+                    let c = sample!(to_input(k, j, i), |z, y, x| array[(z, y, x, 0)] as f32);
+                    *d = (*m as f32 / 255.0 * c) as u8;
                 });
 
                 ParamField::new_u8(mask.field_box_mm, out)
             }
+            FieldStorage::Float(array) => {
+                let mut out = ndarray::Array3::<f32>::zeros(im.dim());
+
+                par_azip!((index (k, j, i), d in &mut out, m in im) {
+                    let c = sample!(to_input(k, j, i), |z, y, x| array[(z, y, x)]);
+                    *d = *m as f32 / 255.0 * c;
+                });
+
+                ParamField::new_f32(mask.field_box_mm, out)
+            }
             FieldStorage::Vec3(array) => {
                 let mut out =
-                    ndarray::Array4::<f32>::zeros((im.dim().0, im.dim().1, im.dim().2, 3));
+                    ndarray::Array4::<f32>::zeros((im.dim().0, im.dim().1, im.dim().2, d.3));
+                let direction = self.field.is_direction();
 
                 par_azip!((index (k, j, i), mut d in out.lanes_mut(Axis(3)), m in im) {
-                    // Convert output coordinates into point in input array
-                    let p = Vector3::new(i as f32 + 0.5, j as f32 + 0.5, k as f32 + 0.5); // Float array coordinates
-                    let p = p.component_mul(&out_scale); // mm coordinates
-                    let p = p.component_div(&in_scale); // input coordinates
-
-                    // Tri-linear interpolation
-                    let px1 = (p.x.floor() as isize).max(0).min((array.dim().2 - 1) as isize) as usize;
-                    let py1 = (p.y.floor() as isize).max(0).min((array.dim().1 - 1) as isize) as usize;
-                    let pz1 = (p.z.floor() as isize).max(0).min((array.dim().0 - 1) as isize) as usize;
-                    let px2 = (p.x.ceil() as isize).max(0).min((array.dim().2 - 1) as isize) as usize;
-                    let py2 = (p.y.ceil() as isize).max(0).min((array.dim().1 - 1) as isize) as usize;
-                    let pz2 = (p.z.ceil() as isize).max(0).min((array.dim().0 - 1) as isize) as usize;
-                    let ax = p.x.fract();
-                    let ay = p.y.fract();
-                    let az = p.z.fract();
-
-                    let c000: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz1, py1, px1, ..]).as_slice().unwrap()));
-                    let c001: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz2, py1, px1, ..]).as_slice().unwrap()));
-                    let c010: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz1, py2, px1, ..]).as_slice().unwrap()));
-                    let c011: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz2, py2, px1, ..]).as_slice().unwrap()));
-                    let c100: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz1, py1, px2, ..]).as_slice().unwrap()));
-                    let c101: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz2, py1, px2, ..]).as_slice().unwrap()));
-                    let c110: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz1, py2, px2, ..]).as_slice().unwrap()));
-                    let c111: Vector3<f32> = nalgebra::convert(Vector3::from_row_slice(array.slice(s![pz2, py2, px2, ..]).as_slice().unwrap()));
-
-                    let c00 = c000 * (1.0 - ax) + c100 * ax;
-                    let c01 = c001 * (1.0 - ax) + c101 * ax;
-                    let c10 = c010 * (1.0 - ax) + c110 * ax;
-                    let c11 = c011 * (1.0 - ax) + c111 * ax;
-
-                    let c0 = c00 * (1.0 - ay) + c10 * ay;
-                    let c1 = c01 * (1.0 - ay) + c11 * ay;
-
-                    // Normalize because we only resample direction vectors
-                    let c = *m as f32 / 255.0 * (c0 * (1.0 - az) + c1 * az).normalize();
-
-                    d[0] = c.x;
-                    d[1] = c.y;
-                    d[2] = c.z;
+                    let p = to_input(k, j, i);
+                    for (c, v) in d.iter_mut().enumerate() {
+                        *v = sample!(p, |z, y, x| array[(z, y, x, c)]);
+                    }
+
+                    // Directions are renormalized, zero vectors stay zero
+                    let norm = if direction { d.dot(&d).sqrt() } else { 1.0 };
+                    if norm > 0.0 {
+                        d *= *m as f32 / 255.0 / norm;
+                    }
                 });
 
-                Self {
-                    field_box_mm: mask.field_box_mm,
-                    field: FieldStorage::Vec3(out),
+                ParamField::new_tensor(mask.field_box_mm, out)
+            }
+        };
+
+        resampled.with_metadata_of(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_box() -> BoundingBox<f32> {
+        BoundingBox {
+            min_x: -2.,
+            min_y: -1.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 2.,
+            max_z: 1.,
+        }
+    }
+
+    fn write_and_read(field: &ParamField, name: &str) -> (String, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_{}_{}.nhdr",
+            name,
+            std::process::id()
+        ));
+        field.write_nrrd(&path).unwrap();
+
+        let header = std::fs::read_to_string(&path).unwrap();
+        let data = std::fs::read(path.with_extension("raw")).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("raw")).unwrap();
+
+        (header, data)
+    }
+
+    #[test]
+    fn map_vec3_to_scalar() {
+        let mut dirs = Array4::zeros((1, 1, 2, 3));
+        dirs[(0, 0, 0, 0)] = 2.;
+        dirs[(0, 0, 1, 0)] = 3.;
+        dirs[(0, 0, 1, 2)] = -4.;
+        let field = ParamField::new_vec3(test_box(), dirs);
+
+        let magnitude = field
+            .map_vec3_to_scalar(VectorScalarization::Magnitude)
+            .unwrap();
+        assert_eq!(magnitude.dim(), (1, 1, 2, 0));
+        assert_eq!(
+            *magnitude.as_f32_array(1.0).unwrap(),
+            ndarray::arr3(&[[[2., 5.]]])
+        );
+        assert_eq!(
+            *field.as_f32_array(1.0).unwrap(),
+            ndarray::arr3(&[[[2., 5.]]])
+        );
+
+        let z = field.map_vec3_to_scalar("z".parse().unwrap()).unwrap();
+        assert_eq!(*z.as_f32_array(1.0).unwrap(), ndarray::arr3(&[[[0., -4.]]]));
+
+        assert!(field
+            .map_vec3_to_scalar(VectorScalarization::Component(3))
+            .is_err());
+        assert!(magnitude
+            .map_vec3_to_scalar(VectorScalarization::Magnitude)
+            .is_err());
+
+        // Tensors have no magnitude
+        let tensor = ParamField::new_tensor(test_box(), Array4::ones((1, 1, 2, 6)));
+        assert!(tensor.as_f32_array(1.0).is_none());
+        assert!(tensor
+            .map_vec3_to_scalar(VectorScalarization::Magnitude)
+            .is_err());
+    }
+
+    #[test]
+    fn summary_stats() {
+        let stats =
+            ParamField::new_u8(test_box(), ndarray::arr3(&[[[0, 255], [51, 0]]])).summary_stats();
+        assert_eq!(
+            stats,
+            FieldStats {
+                min: 0.,
+                max: 255.,
+                mean: 76.5,
+                nonzero_fraction: 0.5,
+            }
+        );
+
+        let mut dirs = Array4::zeros((1, 1, 2, 3));
+        dirs[(0, 0, 0, 1)] = 3.;
+        dirs[(0, 0, 0, 2)] = 4.;
+        let stats = ParamField::new_vec3(test_box(), dirs).summary_stats();
+        assert_eq!((stats.min, stats.max, stats.mean), (0., 5., 2.5));
+        assert_eq!(stats.nonzero_fraction, 0.5);
+    }
+
+    #[test]
+    fn derive_from_array() {
+        let mask = ParamField::new_u8(test_box(), Array3::zeros((6, 2, 2)));
+        let array = ParamArray::from_f64(vec![0., 10., 20.]);
+
+        let layers = |mode| {
+            let field = mask.derive_from_array(&array, mode).unwrap();
+            let values = field.as_f32_array(1.0).unwrap();
+            assert!(values
+                .axis_iter(Axis(0))
+                .all(|layer| layer.iter().all(|v| *v == layer[[0, 0]])));
+            values
+                .index_axis(Axis(1), 0)
+                .index_axis(Axis(1), 0)
+                .to_vec()
+        };
+
+        assert_eq!(
+            layers(ArrayToFieldMode::Step),
+            vec![0., 0., 10., 10., 20., 20.]
+        );
+        assert_eq!(
+            layers(ArrayToFieldMode::Linear),
+            vec![0., 2.5, 7.5, 12.5, 17.5, 20.]
+        );
+
+        // Single values and more layers than cells
+        let single = ParamArray::from_f64(vec![5.]);
+        let many = ParamArray::from_f64((0..12).map(f64::from).collect());
+        for mode in &[ArrayToFieldMode::Step, ArrayToFieldMode::Linear] {
+            let field = mask.derive_from_array(&single, *mode).unwrap();
+            assert!(field.as_f32_array(1.0).unwrap().iter().all(|v| *v == 5.));
+            assert!(mask.derive_from_array(&many, *mode).is_some());
+        }
+
+        assert!(mask
+            .derive_from_array(&ParamArray::from_f64(vec![]), ArrayToFieldMode::Step)
+            .is_none());
+    }
+
+    #[test]
+    fn apply_op() {
+        let bytes = ParamField::new_u8(test_box(), ndarray::arr3(&[[[255, 0], [51, 255]]]));
+        let floats = ParamField::new_f32(test_box(), ndarray::arr3(&[[[0.5, 0.5], [2.0, -1.0]]]));
+
+        let values = |field: &ParamField| field.as_f32_array(1.0).unwrap().into_owned();
+
+        // Bytes are promoted to [0, 1] floats
+        let sum = floats
+            .apply_op(FieldOp::Add, FieldOpRhs::Field(&bytes))
+            .unwrap();
+        assert!(sum.as_u8().is_none());
+        assert!(values(&sum).all_close(&ndarray::arr3(&[[[1.5, 0.5], [2.2, 0.0]]]), 1e-6));
+
+        let diff = bytes
+            .apply_op(FieldOp::Sub, FieldOpRhs::Field(&bytes))
+            .unwrap();
+        assert_eq!(values(&diff), Array3::<f32>::zeros((1, 2, 2)));
+
+        let product = floats
+            .apply_op(FieldOp::Mul, FieldOpRhs::Scalar(2.0))
+            .unwrap();
+        assert_eq!(
+            values(&product),
+            ndarray::arr3(&[[[1.0, 1.0], [4.0, -2.0]]])
+        );
+
+        let threshold = floats
+            .apply_op(FieldOp::Threshold, FieldOpRhs::Scalar(0.5))
+            .unwrap();
+        assert_eq!(
+            *threshold.as_u8().unwrap(),
+            ndarray::arr3(&[[[255, 255], [255, 0]]])
+        );
+
+        let threshold = bytes
+            .apply_op(FieldOp::Threshold, FieldOpRhs::Field(&floats))
+            .unwrap();
+        assert_eq!(
+            *threshold.as_u8().unwrap(),
+            ndarray::arr3(&[[[255, 0], [0, 255]]])
+        );
+
+        // Masking keeps the storage of the left-hand side
+        let masked = floats
+            .apply_op(FieldOp::Mask, FieldOpRhs::Field(&bytes))
+            .unwrap();
+        assert_eq!(values(&masked), ndarray::arr3(&[[[0.5, 0.0], [2.0, -1.0]]]));
+        let masked = bytes
+            .apply_op(FieldOp::Mask, FieldOpRhs::Field(&floats))
+            .unwrap();
+        assert_eq!(masked.as_u8().unwrap(), bytes.as_u8().unwrap());
+
+        let other_grid = ParamField::new_f32(test_box(), Array3::zeros((2, 2, 2)));
+        assert!(floats
+            .apply_op(FieldOp::Add, FieldOpRhs::Field(&other_grid))
+            .is_err());
+        let vectors = ParamField::new_vec3(test_box(), Array4::zeros((1, 2, 2, 3)));
+        assert!(vectors
+            .apply_op(FieldOp::Add, FieldOpRhs::Scalar(1.0))
+            .is_err());
+    }
+
+    #[test]
+    fn downsample_mean() {
+        let array = Array3::from_shape_fn((4, 4, 4), |(k, j, i)| (16 * k + 4 * j + i) as f32);
+        let field = ParamField::new_f32(test_box(), array).downsample(2, Downsample::Mean);
+
+        assert_eq!(field.field_box_mm, test_box());
+        assert_eq!(
+            *field.as_f32_array(1.0).unwrap(),
+            Array3::from_shape_fn((2, 2, 2), |(k, j, i)| {
+                16. * (2. * k as f32 + 0.5) + 4. * (2. * j as f32 + 0.5) + (2. * i as f32 + 0.5)
+            })
+        );
+    }
+
+    #[test]
+    fn downsample_vectors() {
+        let dirs = Array4::from_shape_fn((2, 2, 2, 3), |(_k, j, _i, c)| {
+            [[1., 0., 0.], [0., 1., 0.]][j][c]
+        });
+        let dirs = ParamField::new_vec3(test_box(), dirs).downsample(2, Downsample::Mean);
+        let s = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(dirs.as_vec3().unwrap().all_close(
+            &Array4::from_shape_vec((1, 1, 1, 3), vec![s, s, 0.]).unwrap(),
+            1e-6
+        ));
+
+        // Tensor components are averaged, including all-zero windows
+        let tensor = Array4::from_shape_fn(
+            (2, 2, 4, 6),
+            |(_k, _j, i, c)| {
+                if i < 2 {
+                    0.0
+                } else {
+                    (c + i) as f32
                 }
+            },
+        );
+        let tensor = ParamField::new_tensor(test_box(), tensor).downsample(2, Downsample::Mean);
+        let tensor = tensor.as_vec3().unwrap();
+        assert_eq!(tensor.dim(), (1, 1, 2, 6));
+        for c in 0..6 {
+            assert_eq!(tensor[(0, 0, 0, c)], 0.0);
+            assert_eq!(tensor[(0, 0, 1, c)], c as f32 + 2.5);
+        }
+    }
+
+    #[test]
+    fn downsample_partial_windows() {
+        let array = Array3::from_shape_fn(
+            (3, 3, 3),
+            |(k, _j, i)| if k == 0 || i == 2 { 255 } else { 0 },
+        );
+        let field = ParamField::new_u8(test_box(), array);
+
+        let mean = field.downsample(2, Downsample::Mean);
+        let mean = mean.as_u8().unwrap();
+        assert_eq!(mean.dim(), (2, 2, 2));
+        // Half of the 2x2x2 window, all of the 2x2x1 window
+        assert_eq!(mean[(0, 0, 0)], 128);
+        assert_eq!(mean[(0, 0, 1)], 255);
+        // Partial window in Z only sees k = 2
+        assert_eq!(mean[(1, 1, 0)], 0);
+
+        let mask = field.downsample(2, Downsample::MajorityThreshold);
+        let mask = mask.as_u8().unwrap();
+        assert_eq!(mask[(0, 0, 0)], 255);
+        assert_eq!(mask[(1, 0, 0)], 0);
+        assert_eq!(mask[(1, 0, 1)], 255);
+    }
+
+    #[test]
+    fn downsample_mode() {
+        // Categories 1 and 3 only, their mean would be 2
+        let array = Array3::from_shape_fn((2, 2, 4), |(k, j, i)| {
+            if i < 2 {
+                if k + j == 0 {
+                    3
+                } else {
+                    1
+                }
+            } else if k == 0 {
+                1
+            } else {
+                3
+            }
+        });
+        let field = ParamField::new_u8(test_box(), array).downsample(2, Downsample::Mode);
+
+        // Majority of 1 in the first window, tie in the second
+        assert_eq!(*field.as_u8().unwrap(), ndarray::arr3(&[[[1, 1]]]));
+    }
+
+    #[test]
+    fn resample_linear_z() {
+        // Values only depend on z: 0 in the first layer, 200 in the second
+        let array = Array4::from_shape_fn((2, 2, 2, 4), |(k, _j, _i, _c)| (200 * k) as u8);
+        let field = ParamField::from_storage(test_box(), FieldStorage::ByteVec4(array));
+
+        // Sample in between cells in all directions
+        let mask = ParamField::new_u8(test_box(), Array3::from_elem((4, 3, 3), 255));
+        let resampled = field.resample(&mask, ResampleMethod::Trilinear);
+        let resampled = resampled.as_u8().unwrap();
+
+        for ((k, _j, _i), v) in resampled.indexed_iter() {
+            assert_eq!(*v, [0, 50, 150, 200][k]);
+        }
+    }
+
+    #[test]
+    fn resample_step_methods() {
+        // Step along X
+        let array = Array3::from_shape_fn((1, 1, 4), |(_k, _j, i)| if i < 2 { 0. } else { 1. });
+        let field = ParamField::new_f32(test_box(), array);
+        assert_eq!(field.default_resample_method(), ResampleMethod::Trilinear);
+
+        let mask = ParamField::new_u8(test_box(), Array3::from_elem((1, 1, 8), 255));
+
+        let nearest = field.resample(&mask, ResampleMethod::Nearest);
+        assert_eq!(
+            nearest.as_f32_array(1.0).unwrap().as_slice().unwrap(),
+            &[0., 0., 0., 0., 1., 1., 1., 1.]
+        );
+
+        let trilinear = field.resample(&mask, ResampleMethod::Trilinear);
+        assert_eq!(
+            trilinear.as_f32_array(1.0).unwrap().as_slice().unwrap(),
+            &[0., 0., 0., 0.25, 0.75, 1., 1., 1.]
+        );
+    }
+
+    #[test]
+    fn resample_grid_keeps_all_cells() {
+        let field = ParamField::new_f32(test_box(), Array3::from_elem((2, 2, 2), 0.5));
+        let reference = ParamField::new_u8(test_box(), Array3::zeros((3, 4, 5)));
+
+        let resampled = field
+            .resample_grid(&reference, ResampleMethod::Nearest)
+            .unwrap();
+        assert!(resampled.has_same_box(&reference));
+        assert!(resampled
+            .as_f32_array(1.0)
+            .unwrap()
+            .iter()
+            .all(|&v| v == 0.5));
+
+        let rgba = ParamField::from_storage(
+            test_box(),
+            FieldStorage::ByteVec4(Array4::zeros((2, 2, 2, 4))),
+        );
+        assert!(rgba
+            .resample_grid(&reference, ResampleMethod::Nearest)
+            .is_err());
+    }
+
+    #[test]
+    fn crop_field() {
+        let array = Array3::from_shape_fn((2, 3, 4), |(k, j, i)| (k * 12 + j * 4 + i) as f32);
+        let field = ParamField::new_f32(test_box(), array.clone());
+
+        // Cells are 1mm wide in X and Y, 0.5mm in Z
+        let cropped = field
+            .crop(&BoundingBox {
+                min_x: -1.5,
+                min_y: -10.,
+                min_z: 0.6,
+                max_x: 0.2,
+                max_y: 0.5,
+                max_z: 10.,
+            })
+            .unwrap();
+
+        assert_eq!(cropped.dim(), (1, 2, 3, 0));
+        assert_eq!(
+            cropped.field_box_mm,
+            BoundingBox {
+                min_x: -2.,
+                min_y: -1.,
+                min_z: 0.5,
+                max_x: 1.,
+                max_y: 1.,
+                max_z: 1.,
             }
-            _ => panic!("unsupported field storage type for resampling"),
+        );
+        assert_eq!(
+            *cropped.as_f32_array(1.0).unwrap(),
+            array.slice(s![1..2, 0..2, 0..3])
+        );
+
+        // Regions outside of the field are rejected
+        assert!(field
+            .crop(&BoundingBox {
+                min_x: 5.,
+                min_y: -1.,
+                min_z: 0.,
+                max_x: 6.,
+                max_y: 2.,
+                max_z: 1.,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn write_png_stack() {
+        let dir =
+            std::env::temp_dir().join(format!("icesl2voxel_test_png_stack_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Three layers of 1x2 cells, the bottom row holds the layer index
+        let array = Array3::from_shape_fn((3, 2, 1), |(k, j, _i)| (k * 100 + j) as u8);
+        let field = ParamField::new_u8(test_box(), array);
+        let paths = field.write_png_stack(&dir, "geometry", None).unwrap();
+
+        assert_eq!(paths.len(), 3);
+        for (k, path) in paths.iter().enumerate() {
+            assert_eq!(*path, dir.join(format!("geometry_{:04}.png", k)));
+
+            let img = image::open(path).unwrap().to_luma();
+            assert_eq!(img.dimensions(), (1, 2));
+            // Y points up
+            assert_eq!(img.get_pixel(0, 1)[0], (k * 100) as u8);
+            assert_eq!(img.get_pixel(0, 0)[0], (k * 100 + 1) as u8);
+        }
+
+        // Vector magnitudes in false colors, from blue to red
+        let array = Array4::from_shape_fn(
+            (3, 1, 1, 3),
+            |(k, _j, _i, c)| {
+                if c == 0 {
+                    k as f32
+                } else {
+                    0.
+                }
+            },
+        );
+        let field = ParamField::new_vec3(test_box(), array);
+        let paths = field.write_png_stack(&dir, "dir", Some((0., 2.))).unwrap();
+
+        let colors: Vec<_> = paths
+            .iter()
+            .map(|path| image::open(path).unwrap().to_rgb().get_pixel(0, 0).0)
+            .collect();
+        assert_eq!(colors, vec![[0, 0, 255], [0, 255, 0], [255, 0, 0]]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_nrrd_u8() {
+        let array = Array3::from_shape_fn((2, 3, 4), |(k, j, i)| (k * 12 + j * 4 + i) as u8);
+        let (header, data) = write_and_read(&ParamField::new_u8(test_box(), array.clone()), "u8");
+
+        assert!(header.contains("type: uchar\n"));
+        assert!(header.contains("sizes: 4 3 2\n"));
+        assert!(header.contains("spacings: 1 1 0.5\n"));
+        assert!(header.contains(&format!(
+            "data file: icesl2voxel_test_u8_{}.raw\n",
+            std::process::id()
+        )));
+        assert_eq!(data, array.iter().copied().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn write_nrrd_vec3() {
+        let array = Array4::from_shape_fn((2, 3, 4, 3), |(k, j, i, c)| {
+            (k * 100 + j * 10 + i) as f32 + c as f32 / 10.
+        });
+        let (header, data) =
+            write_and_read(&ParamField::new_vec3(test_box(), array.clone()), "vec3");
+
+        assert!(header.contains("type: float\n"));
+        assert!(header.contains("sizes: 3 4 3 2\n"));
+        assert!(header.contains("endian: little\n"));
+
+        let values: Vec<f32> = data
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(values, array.iter().copied().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn write_hdf5_chunked() {
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_chunked_{}.h5",
+            std::process::id()
+        ));
+        let file = hdf5::File::create(&path).unwrap();
+        let options = Hdf5Options {
+            chunk_depth: 4,
+            ..Default::default()
+        };
+
+        // Only the first channel is written
+        let channels = Array4::from_shape_fn((10, 24, 32, 4), |(k, j, i, c)| {
+            (k * 7 + j * 3 + i + c * 50) as u8
+        });
+        let infill = ParamField::from_storage(test_box(), FieldStorage::ByteVec4(channels.clone()));
+        infill
+            .write_hdf5("/fields/infill", &file, &options)
+            .unwrap();
+
+        let dir = ParamField::new_vec3(
+            test_box(),
+            Array4::from_shape_fn((10, 24, 32, 3), |(k, j, i, c)| {
+                (k * 1000 + j * 40 + i) as f32 + c as f32 * 0.25
+            }),
+        );
+        dir.write_hdf5("/fields/dir", &file, &options).unwrap();
+
+        let dataset = file.dataset("/fields/infill/data").unwrap();
+        assert_eq!(dataset.chunks(), Some(vec![4, 24, 32]));
+        let dataset = file.dataset("/fields/dir/data").unwrap();
+        assert_eq!(dataset.chunks(), Some(vec![4, 24, 32, 3]));
+
+        let read = ParamField::read_hdf5("/fields/infill", &file).unwrap();
+        assert_eq!(*read.as_u8().unwrap(), channels.index_axis(Axis(3), 0));
+
+        let read = ParamField::read_hdf5("/fields/dir", &file).unwrap();
+        assert_eq!(read.as_vec3().unwrap(), dir.as_vec3().unwrap());
+        assert_eq!(read.field_box_mm, test_box());
+    }
+
+    #[test]
+    fn write_hdf5_attributes() {
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_attributes_{}.h5",
+            std::process::id()
+        ));
+        let file = hdf5::File::create(&path).unwrap();
+        let field = ParamField::new_f32(test_box(), Array3::ones((2, 3, 4)));
+
+        field
+            .write_hdf5("/fields/current", &file, &Hdf5Options::default())
+            .unwrap();
+        field
+            .write_hdf5(
+                "/fields/legacy",
+                &file,
+                &Hdf5Options {
+                    legacy_layout: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let data = file.dataset("/fields/current/data").unwrap();
+        let attr = |name: &str| data.attr(name).unwrap().read_raw::<f32>().unwrap();
+        assert_eq!(attr("bounding_box_min"), vec![-2., -1., 0.]);
+        assert_eq!(attr("bounding_box_max"), vec![2., 2., 1.]);
+        assert_eq!(attr("spacing"), vec![1., 1., 0.5]);
+        assert_eq!(
+            data.attr("dims").unwrap().read_raw::<u64>().unwrap(),
+            vec![4, 3, 2]
+        );
+
+        assert!(file.dataset("/fields/current/bounding_box_min").is_err());
+        assert_eq!(
+            file.dataset("/fields/legacy/bounding_box_max")
+                .unwrap()
+                .read_raw::<f32>()
+                .unwrap(),
+            vec![2., 2., 1.]
+        );
+
+        // Both layouts can be read back
+        for name in &["current", "legacy"] {
+            let read = ParamField::read_hdf5(&format!("/fields/{}", name), &file).unwrap();
+            assert_eq!(read.field_box_mm, test_box());
+            assert_eq!(read.dim(), (2, 3, 4, 0));
+        }
+    }
+
+    #[test]
+    fn write_hdf5_metadata() {
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_metadata_{}.h5",
+            std::process::id()
+        ));
+        let file = hdf5::File::create(&path).unwrap();
+        let options = Hdf5Options::default();
+
+        let length = ParamField::new_f32(test_box(), Array3::ones((2, 3, 4)))
+            .with_unit("mm")
+            .with_description("length of the longest ray");
+        length
+            .write_hdf5("/fields/length", &file, &options)
+            .unwrap();
+        ParamField::new_u8(test_box(), Array3::zeros((2, 3, 4)))
+            .write_hdf5("/fields/mask", &file, &options)
+            .unwrap();
+
+        let data = file.dataset("/fields/length/data").unwrap();
+        assert_eq!(
+            data.attr("unit")
+                .unwrap()
+                .read_scalar::<VarLenUnicode>()
+                .unwrap()
+                .as_str(),
+            "mm"
+        );
+
+        let read = ParamField::read_hdf5("/fields/length", &file).unwrap();
+        assert_eq!(read.unit.as_deref(), Some("mm"));
+        assert_eq!(
+            read.description.as_deref(),
+            Some("length of the longest ray")
+        );
+
+        // Fields without metadata don't get the attributes
+        let data = file.dataset("/fields/mask/data").unwrap();
+        assert!(data.attr("unit").is_err());
+        assert!(data.attr("description").is_err());
+
+        let read = ParamField::read_hdf5("/fields/mask", &file).unwrap();
+        assert_eq!(read.unit, None);
+        assert_eq!(read.description, None);
+
+        // Resampled and cropped fields hold the same quantity, scaled ones keep the unit and
+        // thresholds drop it
+        assert_eq!(length.downsample(2, Downsample::Mean).unit, length.unit);
+        assert_eq!(
+            length.crop(&test_box()).unwrap().description,
+            length.description
+        );
+        assert_eq!(
+            length
+                .apply_op(FieldOp::Mul, FieldOpRhs::Scalar(2.0))
+                .unwrap()
+                .unit,
+            length.unit
+        );
+        assert_eq!(
+            length
+                .apply_op(FieldOp::Threshold, FieldOpRhs::Scalar(2.0))
+                .unwrap()
+                .unit,
+            None
+        );
+    }
+
+    #[test]
+    fn write_hdf5_compression() {
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_compression_{}.h5",
+            std::process::id()
+        ));
+        let file = hdf5::File::create(&path).unwrap();
+
+        // Mostly empty, so very compressible
+        let field = ParamField::new_f32(
+            test_box(),
+            Array3::from_shape_fn((8, 32, 32), |(k, j, i)| {
+                if (k + j + i) % 16 == 0 {
+                    (k * j) as f32 * 0.5
+                } else {
+                    0.
+                }
+            }),
+        );
+
+        let settings = [(0, false), (1, false), (6, false), (9, false), (6, true)];
+        for (compression, shuffle) in &settings {
+            field
+                .write_hdf5(
+                    &format!("/fields/level{}_{}", compression, shuffle),
+                    &file,
+                    &Hdf5Options {
+                        compression: *compression,
+                        shuffle: *shuffle,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        let storage_size = |name: &str| {
+            file.dataset(&format!("/fields/{}/data", name))
+                .unwrap()
+                .storage_size()
+        };
+        assert!(storage_size("level0_false") > storage_size("level6_false"));
+
+        for (compression, shuffle) in &settings {
+            let read =
+                ParamField::read_hdf5(&format!("/fields/level{}_{}", compression, shuffle), &file)
+                    .unwrap();
+            assert_eq!(read.as_f32_array(1.0), field.as_f32_array(1.0));
         }
     }
 }
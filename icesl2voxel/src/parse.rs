@@ -8,6 +8,12 @@ impl Parse<f64> for f64 {
     }
 }
 
+impl Parse<i64> for i64 {
+    fn parse(value: &str) -> Result<i64, failure::Error> {
+        value.parse::<i64>().map_err(|e| e.into())
+    }
+}
+
 impl Parse<bool> for bool {
     fn parse(value: &str) -> Result<bool, failure::Error> {
         match value {
@@ -1,8 +1,26 @@
-use ndarray::par_azip;
 use ndarray::prelude::*;
+use ndarray::{azip, par_azip};
 //use rand::{Rng, SeedableRng};
 
 use super::param_field::ParamField;
+use super::utils::{Progress, ProgressConfig};
+
+/// Options for computing output statistics
+#[derive(Debug, Clone, Copy)]
+pub struct StatsOptions<'a> {
+    /// Size of the neighborhood kernel in mm
+    pub kernel_size_mm: f32,
+    /// Standard deviation of the Gaussian smoothing in mm, defaults to half the kernel size. The
+    /// neighborhood is truncated at 3 sigma when this is smaller than the kernel
+    pub sigma_mm: Option<f32>,
+    /// Number of rays to sample directions in output geometry
+    pub dir_samples: usize,
+    /// Thickness in mm of each Z cell, for variable layer heights. Cells are assumed to be
+    /// uniform if `None` or if the count doesn't match the field
+    pub layer_thickness_mm: Option<&'a [f32]>,
+    /// Progress bar settings
+    pub progress: ProgressConfig,
+}
 
 pub struct OutputStats {
     pub mean_field: ParamField,
@@ -11,15 +29,98 @@ pub struct OutputStats {
     pub dir_length_field: ParamField,
     pub dir_change_field: ParamField,
     pub dir_correlation: Option<ParamField>,
+    pub dir_misalignment_deg: Option<ParamField>,
+    pub orientation_tensor: Option<ParamField>,
+    pub fractional_anisotropy: Option<ParamField>,
+}
+
+/// Number of unique components of a symmetric 3x3 tensor, stored as xx, xy, xz, yy, yz, zz to
+/// match the XDMF `Tensor6` attribute type
+pub const TENSOR_COMPONENTS: usize = 6;
+
+/// Unique components of the outer product of `dir` with itself
+pub fn outer_product(dir: &nalgebra::Vector3<f32>) -> [f32; TENSOR_COMPONENTS] {
+    [
+        dir.x * dir.x,
+        dir.x * dir.y,
+        dir.x * dir.z,
+        dir.y * dir.y,
+        dir.y * dir.z,
+        dir.z * dir.z,
+    ]
+}
+
+fn tensor_matrix(t: ArrayView1<f32>) -> nalgebra::Matrix3<f32> {
+    nalgebra::Matrix3::new(t[0], t[1], t[2], t[1], t[3], t[4], t[2], t[4], t[5])
+}
+
+/// Fractional anisotropy of a symmetric tensor given by its unique components: 0 for an
+/// isotropic tensor, 1 when all directions are along a single axis
+pub fn fractional_anisotropy(t: ArrayView1<f32>) -> f32 {
+    let eigenvalues = tensor_matrix(t).symmetric_eigenvalues();
+    let norm = eigenvalues.norm_squared();
+
+    if norm > 0.0 {
+        let mean = eigenvalues.sum() / 3.0;
+        (1.5 * eigenvalues.add_scalar(-mean).norm_squared() / norm).sqrt()
+    } else {
+        0.0
+    }
+}
+
+/// Value of the misalignment angle where either direction has zero length
+pub const NO_DIRECTION: f32 = -1.0;
+
+/// Angle in degrees between two undirected directions, in [0, 90], or `NO_DIRECTION` if either
+/// of them has zero length
+pub fn misalignment_deg(a: &nalgebra::Vector3<f32>, b: &nalgebra::Vector3<f32>) -> f32 {
+    let norm = a.norm() * b.norm();
+
+    if norm > 0.0 {
+        // The absolute value is already positive, clamp rounding errors above 1
+        (a.dot(b).abs() / norm).min(1.0).acos().to_degrees()
+    } else {
+        NO_DIRECTION
+    }
+}
+
+/// Smooth every component of the accumulated segment orientations `acc` and normalize them into
+/// orientation tensors with a unit trace. Returns the tensors and their fractional anisotropy,
+/// both zero outside of `mask`.
+pub fn orientation_tensor(
+    acc: &ndarray::Array4<f32>,
+    mask: &ndarray::Array3<u8>,
+    smooth: impl Fn(ArrayView3<f32>) -> ndarray::Array3<f32>,
+) -> (ndarray::Array4<f32>, ndarray::Array3<f32>) {
+    let mut tensor = ndarray::Array4::<f32>::zeros(acc.dim());
+    for (l, mut component) in tensor.axis_iter_mut(Axis(3)).enumerate() {
+        component.assign(&smooth(acc.index_axis(Axis(3), l)));
+    }
+
+    let mut anisotropy = ndarray::Array3::<f32>::zeros(mask.dim());
+    par_azip!((mut t in tensor.lanes_mut(Axis(3)), fa in &mut anisotropy, m in mask) {
+        let trace = t[0] + t[3] + t[5];
+
+        if *m > 0 && trace > 0.0 {
+            t.map_inplace(|x| *x /= trace);
+            *fa = fractional_anisotropy(t.view());
+        } else {
+            t.fill(0.0);
+        }
+    });
+
+    (tensor, anisotropy)
 }
 
 pub fn compute_output_stats(
     voxelized_field: &ParamField,
     input_mask: &ParamField,
     input_dir: Option<&ParamField>,
-    kernel_size_mm: f32,
-    dir_samples: usize,
+    orientation: Option<&ParamField>,
+    options: &StatsOptions,
 ) -> Result<OutputStats, failure::Error> {
+    let dir_samples = options.dir_samples;
+
     let vx = voxelized_field.as_u8().unwrap();
     let im = input_mask.as_u8().unwrap();
 
@@ -32,12 +133,50 @@ pub fn compute_output_stats(
         dim.0 as f32 / size.z,
     );
 
-    let kernel_size_mm = kernel_size_mm / 2.0;
-    let kernel_offset_mm = nalgebra::Vector3::new(kernel_size_mm, kernel_size_mm, kernel_size_mm);
+    let kernel_size_mm = options.kernel_size_mm / 2.0;
+    let sigma = options.sigma_mm.unwrap_or(kernel_size_mm);
+    if sigma.is_nan() || sigma <= 0.0 {
+        return Err(failure::err_msg(format!(
+            "the smoothing sigma must be positive, got {}",
+            sigma
+        )));
+    }
+    let kernel_radius_mm = kernel_size_mm.min(3.0 * sigma);
+    let kernel_offset_mm =
+        nalgebra::Vector3::new(kernel_radius_mm, kernel_radius_mm, kernel_radius_mm);
 
     let cell_count = 2. * kernel_offset_mm.component_mul(&scale);
     debug!("kernel size in cells: {:?}", cell_count);
 
+    // Height of the center of each Z cell in mm, for non-uniform layers
+    let cell_z: Option<Vec<f32>> = match options.layer_thickness_mm {
+        Some(thickness) if thickness.len() == dim.0 => Some(
+            thickness
+                .iter()
+                .scan(0.0, |z, t| {
+                    let center = *z + t / 2.0;
+                    *z += t;
+                    Some(center)
+                })
+                .collect(),
+        ),
+        Some(thickness) => {
+            warn!(
+                "{} layer thicknesses for {} cells along Z, assuming uniform layers",
+                thickness.len(),
+                dim.0
+            );
+            None
+        }
+        None => None,
+    };
+
+    // Distance between the centers of two Z cells in mm
+    let z_distance = |z: usize, k: usize| match &cell_z {
+        Some(cell_z) => cell_z[z] - cell_z[k],
+        None => (z as f32 - k as f32) / scale.z,
+    };
+
     // Coordinate transform
     let transform = |k: usize, j: usize, i: usize| {
         let center = nalgebra::Vector3::new(i as f32 + 0.5, j as f32 + 0.5, k as f32 + 0.5)
@@ -45,8 +184,25 @@ pub fn compute_output_stats(
         let kernel_min = (center - kernel_offset_mm).component_mul(&scale);
         let kernel_max = (center + kernel_offset_mm).component_mul(&scale);
 
-        let z_min = kernel_min.z.ceil().min((dim.0 - 1) as f32).max(0.) as usize;
-        let z_max = kernel_max.z.floor().min((dim.0 - 1) as f32).max(0.) as usize;
+        let (z_min, z_max) = if let Some(cell_z) = &cell_z {
+            // Cells whose center is within the kernel radius, the current cell always is
+            let z_min = (0..k)
+                .rev()
+                .take_while(|&z| cell_z[k] - cell_z[z] <= kernel_radius_mm)
+                .last()
+                .unwrap_or(k);
+            let z_max = (k + 1..dim.0)
+                .take_while(|&z| cell_z[z] - cell_z[k] <= kernel_radius_mm)
+                .last()
+                .unwrap_or(k);
+
+            (z_min, z_max)
+        } else {
+            (
+                kernel_min.z.ceil().min((dim.0 - 1) as f32).max(0.) as usize,
+                kernel_max.z.floor().min((dim.0 - 1) as f32).max(0.) as usize,
+            )
+        };
         let y_min = kernel_min.y.ceil().min((dim.1 - 1) as f32).max(0.) as usize;
         let y_max = kernel_max.y.floor().min((dim.1 - 1) as f32).max(0.) as usize;
         let x_min = kernel_min.x.ceil().min((dim.2 - 1) as f32).max(0.) as usize;
@@ -149,7 +305,7 @@ pub fn compute_output_stats(
                 }
             }
 
-            for _ in 0..(dir_samples.max(dirs.len()) - dirs.len()) {
+            for _ in 0..dir_samples.saturating_sub(dirs.len()) {
                 let theta = rtheta.next().unwrap() * 2.0 * std::f64::consts::PI;
                 let phi = rphi.next().unwrap() * std::f64::consts::PI;
                 //let theta = rng.gen_range(-std::f32::consts::PI, std::f32::consts::PI);
@@ -177,27 +333,11 @@ pub fn compute_output_stats(
 
     // Raytrace direction
     if dir_samples > 0 {
-        let steps = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
-        let total = dim.2 * dim.1 * dim.0;
-
-        let thd = std::thread::spawn({
-            let steps = steps.clone();
-            let dir_samples = dir_samples as u64;
-            move || {
-                let mut pb = pbr::ProgressBar::new(dir_samples * total as u64);
-                pb.message("raytracing direction: ");
-                let mut last = 0;
-
-                while last != total {
-                    let q = steps.load(std::sync::atomic::Ordering::Relaxed);
-                    pb.add((q - last) as u64 * dir_samples);
-                    last = q;
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                }
-
-                pb.finish();
-            }
-        });
+        let progress = Progress::new(
+            &options.progress,
+            "raytracing direction: ",
+            dir_samples * dim.2 * dim.1 * dim.0,
+        );
 
         par_azip!((index (k, j, i),
                 mut ddir in dir_field.lanes_mut(Axis(3)),
@@ -214,40 +354,54 @@ pub fn compute_output_stats(
                 *ddch = last_change;
             }
 
-            steps.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            progress.add(dir_samples);
         });
-
-        thd.join().unwrap();
     }
 
-    let dir_correlation = if let Some(input_dir) = input_dir {
+    let (dir_correlation, dir_misalignment_deg) = if let Some(input_dir) = input_dir {
         let input_dir = input_dir.as_vec3().unwrap();
         let mut dir_correlation = ndarray::Array3::<f32>::zeros(dim);
+        let mut dir_misalignment_deg = ndarray::Array3::<f32>::from_elem(dim, NO_DIRECTION);
 
         par_azip!((ddc in &mut dir_correlation,
+            ddm in &mut dir_misalignment_deg,
             in_dir in input_dir.lanes(Axis(3)),
-            out_dir in dir_field.lanes(Axis(3))) {
+            out_dir in dir_field.lanes(Axis(3)),
+            m in im) {
             *ddc = (in_dir[0] * out_dir[0]
                    + in_dir[1] * out_dir[1]
                    + in_dir[2] * out_dir[2]).abs();
+
+            if *m > 0 {
+                *ddm = misalignment_deg(
+                    &nalgebra::Vector3::new(in_dir[0], in_dir[1], in_dir[2]),
+                    &nalgebra::Vector3::new(out_dir[0], out_dir[1], out_dir[2]),
+                );
+            }
         });
 
-        Some(ParamField::new_f32(
-            voxelized_field.field_box_mm,
-            dir_correlation,
-        ))
+        (
+            Some(
+                ParamField::new_f32(voxelized_field.field_box_mm, dir_correlation)
+                    .with_description("absolute cosine between the input and output directions"),
+            ),
+            Some(
+                ParamField::new_f32(voxelized_field.field_box_mm, dir_misalignment_deg)
+                    .with_unit("deg")
+                    .with_description("angle between the input and output directions"),
+            ),
+        )
     } else {
-        None
+        (None, None)
     };
 
     let mut mean_field_a = ndarray::Array3::<f32>::zeros(dim);
     let mut mean_field_b = ndarray::Array3::<f32>::zeros(dim);
     let mut mean_field_confidence_f = ndarray::Array3::<f32>::ones(dim);
 
-    let gauss = |x: usize, i: usize, s: f32| {
-        let sigma = kernel_size_mm;
-        (-0.5 * ((x as f32 - i as f32) / (s * sigma)).powf(2.0)).exp()
-    };
+    let gauss =
+        |x: usize, i: usize, s: f32| (-0.5 * ((x as f32 - i as f32) / (s * sigma)).powf(2.0)).exp();
+    let gauss_z = |z: usize, k: usize| (-0.5 * (z_distance(z, k) / sigma).powf(2.0)).exp();
 
     // Seed A buffer with input
     par_azip!((o in &mut mean_field_a, i in vx, m in im) {
@@ -268,7 +422,7 @@ pub fn compute_output_stats(
             let mut count = 0.0f32;
 
             for z in min.z..=max.z {
-                let w = gauss(z, k, scale.z);
+                let w = gauss_z(z, k);
                 mean += src[(z, j, i)] * w;
                 sum += w;
                 count += if im[(z, j, i)] > 0 { w } else { 0.0 };
@@ -338,15 +492,491 @@ pub fn compute_output_stats(
         *oc = m * *ic;
     });
 
+    let (orientation_tensor, fractional_anisotropy) = if let Some(orientation) = orientation {
+        let acc = orientation
+            .as_vec3()
+            .filter(|acc| acc.dim() == (dim.0, dim.1, dim.2, TENSOR_COMPONENTS))
+            .ok_or_else(|| {
+                failure::err_msg("orientation field doesn't match the voxelized geometry")
+            })?;
+
+        // Same separable Gaussian kernel as the mean field
+        let smooth = |src: ArrayView3<f32>| {
+            let mut a = ndarray::Array3::<f32>::zeros(dim);
+            let mut b = ndarray::Array3::<f32>::zeros(dim);
+
+            par_azip!((index (k, j, i), o in &mut a) {
+                let (min, max) = transform(k, j, i);
+                let (mut acc, mut sum) = (0.0f32, 0.0f32);
+
+                for z in min.z..=max.z {
+                    let w = gauss_z(z, k);
+                    acc += src[(z, j, i)] * w;
+                    sum += w;
+                }
+
+                *o = acc / sum;
+            });
+
+            par_azip!((index (k, j, i), o in &mut b) {
+                let (min, max) = transform(k, j, i);
+                let (mut acc, mut sum) = (0.0f32, 0.0f32);
+
+                for y in min.y..=max.y {
+                    let w = gauss(y, j, scale.y);
+                    acc += a[(k, y, i)] * w;
+                    sum += w;
+                }
+
+                *o = acc / sum;
+            });
+
+            par_azip!((index (k, j, i), o in &mut a) {
+                let (min, max) = transform(k, j, i);
+                let (mut acc, mut sum) = (0.0f32, 0.0f32);
+
+                for x in min.x..=max.x {
+                    let w = gauss(x, i, scale.x);
+                    acc += b[(k, j, x)] * w;
+                    sum += w;
+                }
+
+                *o = acc / sum;
+            });
+
+            a
+        };
+
+        let (tensor, anisotropy) = orientation_tensor(acc, im, smooth);
+
+        (
+            Some(
+                ParamField::new_tensor(voxelized_field.field_box_mm, tensor).with_description(
+                    "smoothed orientation tensor of the extrusions, as xx, xy, xz, yy, yz, zz",
+                ),
+            ),
+            Some(
+                ParamField::new_f32(voxelized_field.field_box_mm, anisotropy)
+                    .with_description("fractional anisotropy of the orientation tensor, 0 to 1"),
+            ),
+        )
+    } else {
+        (None, None)
+    };
+
+    let bbox = voxelized_field.field_box_mm;
+
     Ok(OutputStats {
-        mean_field: ParamField::new_f32(voxelized_field.field_box_mm, mean_field_a),
-        mean_field_confidence: ParamField::new_f32(
-            voxelized_field.field_box_mm,
-            mean_field_confidence,
-        ),
-        dir_field: ParamField::new_vec3(voxelized_field.field_box_mm, dir_field),
-        dir_length_field: ParamField::new_f32(voxelized_field.field_box_mm, dir_length_field),
-        dir_change_field: ParamField::new_f32(voxelized_field.field_box_mm, dir_change_field),
+        mean_field: ParamField::new_f32(bbox, mean_field_a)
+            .with_description("smoothed fraction of printed material, 0 to 1"),
+        mean_field_confidence: ParamField::new_f32(bbox, mean_field_confidence)
+            .with_description("weight of the smoothing kernel inside the input geometry"),
+        dir_field: ParamField::new_vec3(bbox, dir_field)
+            .with_description("unit direction of the longest ray inside printed material"),
+        dir_length_field: ParamField::new_f32(bbox, dir_length_field)
+            .with_unit("mm")
+            .with_description("length of the longest ray inside printed material"),
+        dir_change_field: ParamField::new_f32(bbox, dir_change_field)
+            .with_unit("mm")
+            .with_description("length gained by the last improvement of the longest ray"),
         dir_correlation,
+        dir_misalignment_deg,
+        orientation_tensor,
+        fractional_anisotropy,
     })
 }
+
+/// Threshold above which a byte mask cell is considered filled
+const MASK_THRESHOLD: u8 = 128;
+
+/// Coverage of the input geometry by extrusions in a single Z slab
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerStats {
+    pub layer: usize,
+    /// Number of cells inside the input geometry
+    pub in_cells: usize,
+    /// Number of cells inside the input geometry covered by extrusions
+    pub covered_cells: usize,
+    /// Fraction of the input geometry cells not covered by extrusions
+    pub porosity: f64,
+}
+
+impl LayerStats {
+    /// Fraction of the input geometry cells covered by extrusions
+    pub fn coverage(&self) -> f64 {
+        if self.in_cells > 0 {
+            self.covered_cells as f64 / self.in_cells as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Compute the per-layer coverage of the input geometry mask by the voxelized output geometry
+pub fn layer_report(
+    output_geometry: &ParamField,
+    input_geometry: &ParamField,
+) -> Result<Vec<LayerStats>, failure::Error> {
+    let out = output_geometry
+        .as_u8()
+        .ok_or_else(|| failure::err_msg("output geometry is not a byte field"))?;
+    let inp = input_geometry
+        .as_u8()
+        .ok_or_else(|| failure::err_msg("input geometry is not a byte field"))?;
+
+    if out.dim() != inp.dim() {
+        return Err(failure::err_msg(format!(
+            "output geometry dimensions {:?} don't match input geometry dimensions {:?}",
+            out.dim(),
+            inp.dim()
+        )));
+    }
+
+    Ok(out
+        .outer_iter()
+        .zip(inp.outer_iter())
+        .enumerate()
+        .map(|(layer, (out_layer, in_layer))| {
+            let mut in_cells = 0;
+            let mut covered_cells = 0;
+
+            azip!((o in &out_layer, i in &in_layer) {
+                if *i >= MASK_THRESHOLD {
+                    in_cells += 1;
+
+                    if *o >= MASK_THRESHOLD {
+                        covered_cells += 1;
+                    }
+                }
+            });
+
+            LayerStats {
+                layer,
+                in_cells,
+                covered_cells,
+                porosity: if in_cells > 0 {
+                    1.0 - covered_cells as f64 / in_cells as f64
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::BoundingBox;
+
+    /// Accumulate segments of the given directions and coverage weights in the cell `idx`
+    fn accumulate(
+        acc: &mut ndarray::Array4<f32>,
+        idx: (usize, usize, usize),
+        segments: &[(nalgebra::Vector3<f32>, f32)],
+    ) {
+        for (dir, w) in segments {
+            for (l, o) in outer_product(&dir.normalize()).iter().enumerate() {
+                acc[(idx.0, idx.1, idx.2, l)] += w * o;
+            }
+        }
+    }
+
+    #[test]
+    fn orientation_tensor_unidirectional() {
+        let axis = nalgebra::Vector3::new(1.0, 1.0, 0.0).normalize();
+
+        let mut acc = ndarray::Array4::<f32>::zeros((2, 2, 2, TENSOR_COMPONENTS));
+        accumulate(&mut acc, (0, 0, 0), &[(axis, 1.0), (-axis, 0.5)]);
+        accumulate(&mut acc, (1, 1, 0), &[(axis, 0.25), (2.0 * axis, 0.75)]);
+        accumulate(
+            &mut acc,
+            (1, 1, 1),
+            &[
+                (nalgebra::Vector3::new(1.0, 0.0, 0.0), 1.0),
+                (nalgebra::Vector3::new(0.0, 1.0, 0.0), 1.0),
+            ],
+        );
+
+        let mut mask = ndarray::Array3::<u8>::from_elem((2, 2, 2), 255);
+        mask[(1, 1, 1)] = 0;
+
+        let (tensor, anisotropy) = orientation_tensor(&acc, &mask, |src| src.to_owned());
+        for idx in &[(0, 0, 0), (1, 1, 0)] {
+            assert!((anisotropy[*idx] - 1.0).abs() < 1e-4);
+
+            let t = tensor.slice(s![idx.0, idx.1, idx.2, ..]);
+            assert!((t[0] + t[3] + t[5] - 1.0).abs() < 1e-5);
+
+            let eigen = tensor_matrix(t).symmetric_eigen();
+            let principal = eigen.eigenvalues.imax();
+            assert!((eigen.eigenvalues[principal] - 1.0).abs() < 1e-4);
+            assert!((eigen.eigenvectors.column(principal).dot(&axis).abs() - 1.0).abs() < 1e-4);
+        }
+
+        // Empty and masked cells have no orientation
+        assert_eq!(anisotropy[(0, 1, 0)], 0.0);
+        assert_eq!(anisotropy[(1, 1, 1)], 0.0);
+        assert!(tensor.slice(s![1, 1, 1, ..]).iter().all(|t| *t == 0.0));
+    }
+
+    #[test]
+    fn orientation_fields_from_accumulator() {
+        let bbox = BoundingBox {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 4.0,
+            max_y: 4.0,
+            max_z: 1.0,
+        };
+
+        // Every cell is crossed by extrusions along X
+        let mut acc = ndarray::Array4::<f32>::zeros((1, 4, 4, TENSOR_COMPONENTS));
+        for j in 0..4 {
+            for i in 0..4 {
+                accumulate(&mut acc, (0, j, i), &[(nalgebra::Vector3::x(), 1.0)]);
+            }
+        }
+        let filled = ndarray::Array3::<u8>::from_elem((1, 4, 4), 255);
+
+        let stats = compute_output_stats(
+            &ParamField::new_u8(bbox, filled.clone()),
+            &ParamField::new_u8(bbox, filled),
+            None,
+            Some(&ParamField::new_tensor(bbox, acc)),
+            &StatsOptions {
+                kernel_size_mm: 2.0,
+                sigma_mm: None,
+                dir_samples: 8,
+                layer_thickness_mm: None,
+                progress: ProgressConfig { quiet: true },
+            },
+        )
+        .unwrap();
+
+        let tensor = stats.orientation_tensor.unwrap();
+        let tensor = tensor.as_vec3().unwrap();
+        assert_eq!(tensor.dim(), (1, 4, 4, TENSOR_COMPONENTS));
+        assert!(tensor
+            .lanes(Axis(3))
+            .into_iter()
+            .all(|t| (t[0] - 1.0).abs() < 1e-5 && t.iter().skip(1).all(|x| x.abs() < 1e-5)));
+
+        let anisotropy = stats.fractional_anisotropy.unwrap();
+        assert!(anisotropy
+            .as_f32_array(1.0)
+            .unwrap()
+            .iter()
+            .all(|fa| (fa - 1.0).abs() < 1e-4));
+    }
+
+    #[test]
+    fn non_positive_sigma() {
+        let bbox = BoundingBox {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 4.0,
+            max_y: 4.0,
+            max_z: 1.0,
+        };
+        let filled = ParamField::new_u8(bbox, ndarray::Array3::<u8>::from_elem((1, 4, 4), 255));
+
+        for sigma in &[0.0, -1.0, f32::NAN] {
+            assert!(compute_output_stats(
+                &filled,
+                &filled,
+                None,
+                None,
+                &StatsOptions {
+                    kernel_size_mm: 2.0,
+                    sigma_mm: Some(*sigma),
+                    dir_samples: 8,
+                    layer_thickness_mm: None,
+                    progress: ProgressConfig { quiet: true },
+                },
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn layer_report_porosity() {
+        let bbox = BoundingBox {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 2.0,
+            max_y: 2.0,
+            max_z: 2.0,
+        };
+
+        // Fully covered first layer, second layer with one of its three input cells covered
+        let input = ndarray::arr3(&[[[255, 255], [255, 255]], [[255, 255], [255, 0]]]);
+        let output = ndarray::arr3(&[[[255, 200], [128, 255]], [[0, 127], [255, 255]]]);
+
+        let report = layer_report(
+            &ParamField::new_u8(bbox, output),
+            &ParamField::new_u8(bbox, input),
+        )
+        .unwrap();
+
+        assert_eq!(
+            report,
+            vec![
+                LayerStats {
+                    layer: 0,
+                    in_cells: 4,
+                    covered_cells: 4,
+                    porosity: 0.0,
+                },
+                LayerStats {
+                    layer: 1,
+                    in_cells: 3,
+                    covered_cells: 1,
+                    porosity: 1.0 - 1.0 / 3.0,
+                },
+            ]
+        );
+        assert_eq!(report[0].coverage(), 1.0);
+        assert_eq!(report[1].coverage(), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn layer_report_mismatched_dims() {
+        let bbox = BoundingBox {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 1.0,
+            max_y: 1.0,
+            max_z: 1.0,
+        };
+
+        assert!(layer_report(
+            &ParamField::new_u8(bbox, ndarray::Array3::zeros((2, 1, 1))),
+            &ParamField::new_u8(bbox, ndarray::Array3::zeros((1, 1, 1))),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn smoothing_sigma_distinct_from_kernel() {
+        // One cell per mm along X
+        let bbox = BoundingBox {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 41.0,
+            max_y: 1.0,
+            max_z: 1.0,
+        };
+
+        let mut delta = ndarray::Array3::<u8>::zeros((1, 1, 41));
+        delta[(0, 0, 20)] = 255;
+        let mask = ndarray::Array3::<u8>::from_elem((1, 1, 41), 255);
+
+        let sigma = 2.0f32;
+        let stats = compute_output_stats(
+            &ParamField::new_u8(bbox, delta),
+            &ParamField::new_u8(bbox, mask),
+            None,
+            None,
+            &StatsOptions {
+                kernel_size_mm: 20.0,
+                sigma_mm: Some(sigma),
+                dir_samples: 0,
+                layer_thickness_mm: None,
+                progress: ProgressConfig { quiet: true },
+            },
+        )
+        .unwrap();
+
+        let mean = stats.mean_field.as_f32_array(1.0).unwrap();
+        let center = mean[(0, 0, 20)];
+        assert!(center > 0.0);
+
+        // Gaussian profile within the truncated neighborhood
+        for d in -5..=5isize {
+            let expected = (-0.5 * (d as f32 / sigma).powi(2)).exp();
+            let actual = mean[(0, 0, (20 + d) as usize)] / center;
+            assert!(
+                (actual - expected).abs() < 1e-5,
+                "offset {}: {} != {}",
+                d,
+                actual,
+                expected
+            );
+        }
+
+        // Nothing past 3 sigma, even though the kernel is wider
+        for i in (0..13).chain(28..41) {
+            assert_eq!(mean[(0, 0, i)], 0.0);
+        }
+    }
+
+    #[test]
+    fn fractional_anisotropy_planar() {
+        let t = ndarray::arr1(&[0.5, 0.0, 0.0, 0.5, 0.0, 0.0]);
+        assert!((fractional_anisotropy(t.view()) - 0.5f32.sqrt()).abs() < 1e-5);
+
+        let t = ndarray::arr1(&[1.0, 0.0, 0.0, 1.0, 0.0, 1.0]);
+        assert!(fractional_anisotropy(t.view()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn misalignment_angles() {
+        let x = nalgebra::Vector3::new(1.0, 0.0, 0.0);
+
+        assert!(misalignment_deg(&x, &nalgebra::Vector3::new(2.0, 0.0, 0.0)).abs() < 1e-4);
+        assert!(misalignment_deg(&x, &nalgebra::Vector3::new(-1.0, 0.0, 0.0)).abs() < 1e-4);
+        assert!((misalignment_deg(&x, &nalgebra::Vector3::new(0.0, 0.0, 1.0)) - 90.0).abs() < 1e-4);
+        assert!((misalignment_deg(&x, &nalgebra::Vector3::new(1.0, 1.0, 0.0)) - 45.0).abs() < 1e-4);
+        assert_eq!(
+            misalignment_deg(&x, &nalgebra::Vector3::zeros()),
+            NO_DIRECTION
+        );
+    }
+
+    #[test]
+    fn channel_direction_off_origin() {
+        // Empty channel along Y in a filled slab, one cell per mm
+        let bbox = BoundingBox {
+            min_x: 0.0,
+            min_y: 0.0,
+            min_z: 0.0,
+            max_x: 9.0,
+            max_y: 9.0,
+            max_z: 1.0,
+        };
+
+        let mut output = ndarray::Array3::<u8>::from_elem((1, 9, 9), 255);
+        for j in 0..9 {
+            output[(0, j, 6)] = 0;
+        }
+        let mask = ndarray::Array3::<u8>::from_elem((1, 9, 9), 255);
+
+        let stats = compute_output_stats(
+            &ParamField::new_u8(bbox, output),
+            &ParamField::new_u8(bbox, mask),
+            None,
+            None,
+            &StatsOptions {
+                kernel_size_mm: 4.0,
+                sigma_mm: None,
+                dir_samples: 32,
+                layer_thickness_mm: None,
+                progress: ProgressConfig { quiet: true },
+            },
+        )
+        .unwrap();
+
+        let dir = stats.dir_field.as_vec3().unwrap();
+        for j in 1..8 {
+            assert_eq!(dir[(0, j, 6, 0)], 0.0);
+            assert_eq!(dir[(0, j, 6, 1)].abs(), 1.0);
+            assert_eq!(dir[(0, j, 6, 2)], 0.0);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use serde_derive::Serialize;
+
+use super::param::Param;
+use super::param_bag::ParamBag;
+use super::param_field::FieldStats;
+use super::utils::BoundingBox;
+
+/// Duration of one step of the conversion pipeline
+#[derive(Debug, Clone, Serialize)]
+pub struct StageTiming {
+    pub stage: String,
+    pub ms: f64,
+}
+
+impl StageTiming {
+    /// Time elapsed in `stage` since `start`
+    pub fn since(stage: impl Into<String>, start: Instant) -> Self {
+        Self {
+            stage: stage.into(),
+            ms: start.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldSummary {
+    /// Cell counts as (z, y, x, components), with 0 components for scalar fields
+    pub dim: (usize, usize, usize, usize),
+    pub field_box_mm: BoundingBox<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(flatten)]
+    pub stats: FieldStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArraySummary {
+    pub len: usize,
+    /// Smallest and largest values, `None` for string arrays
+    pub range: Option<(f64, f64)>,
+}
+
+/// Machine-readable summary of a conversion run, written by `--summary`
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    /// Command line arguments of the run
+    pub options: Vec<String>,
+    /// Bounding box of the input geometry, if any
+    pub geometry_box_mm: Option<BoundingBox<f32>>,
+    pub fields: BTreeMap<String, FieldSummary>,
+    pub arrays: BTreeMap<String, ArraySummary>,
+    pub parameters: BTreeMap<String, serde_json::Value>,
+    /// Pipeline stages in the order they ran
+    pub stages: Vec<StageTiming>,
+}
+
+impl RunSummary {
+    pub fn new(
+        param_bag: &ParamBag,
+        geometry_box_mm: Option<BoundingBox<f32>>,
+        stages: Vec<StageTiming>,
+        options: Vec<String>,
+    ) -> Self {
+        Self {
+            options,
+            geometry_box_mm,
+            fields: param_bag
+                .fields()
+                .map(|(name, field)| {
+                    (
+                        name.to_owned(),
+                        FieldSummary {
+                            dim: field.dim(),
+                            field_box_mm: field.field_box_mm,
+                            unit: field.unit.clone(),
+                            description: field.description.clone(),
+                            stats: field.summary_stats(),
+                        },
+                    )
+                })
+                .collect(),
+            arrays: param_bag
+                .arrays()
+                .map(|(name, array)| {
+                    (
+                        name.to_owned(),
+                        ArraySummary {
+                            len: array.len(),
+                            range: array.range(),
+                        },
+                    )
+                })
+                .collect(),
+            parameters: param_bag
+                .params()
+                .map(|(name, param)| {
+                    let value = match param {
+                        Param::Bool(value) => serde_json::Value::from(*value),
+                        Param::Int(value) => serde_json::Value::from(*value),
+                        Param::Float(value) => serde_json::Value::from(*value),
+                        Param::String(value) => serde_json::Value::from(value.as_str()),
+                    };
+
+                    (name.to_owned(), value)
+                })
+                .collect(),
+            stages,
+        }
+    }
+
+    pub fn write_json(&self, dest: &mut dyn std::io::Write) -> Result<(), failure::Error> {
+        serde_json::to_writer_pretty(dest, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::param_array::ParamArray;
+    use crate::param_field::ParamField;
+
+    #[test]
+    fn run_summary_json() {
+        let bbox = BoundingBox {
+            min_x: 0.,
+            min_y: 0.,
+            min_z: 0.,
+            max_x: 2.,
+            max_y: 1.,
+            max_z: 1.,
+        };
+
+        let mut bag = ParamBag::new();
+        bag.add_field(
+            "output_geometry",
+            ParamField::new_u8(bbox, ndarray::arr3(&[[[255, 0]]]))
+                .with_description("printed material"),
+        );
+        bag.add_array(
+            "layer_porosity",
+            ParamArray::from_f64(vec![0.25, 0.05, 0.5]),
+        );
+        bag.masked_stats("output_geometry", "output_geometry", "geometry")
+            .unwrap();
+
+        let summary = RunSummary::new(
+            &bag,
+            Some(bbox),
+            vec![StageTiming {
+                stage: "voxelize gcode".to_owned(),
+                ms: 12.5,
+            }],
+            vec!["icesl2voxel".to_owned(), "--summary".to_owned()],
+        );
+
+        let mut out = Vec::new();
+        summary.write_json(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        let geometry = &json["fields"]["output_geometry"];
+        assert_eq!(geometry["dim"], serde_json::json!([1, 1, 2, 0]));
+        assert_eq!(geometry["max"], 255.0);
+        assert_eq!(geometry["mean"], 127.5);
+        assert_eq!(geometry["nonzero_fraction"], 0.5);
+        assert_eq!(geometry["field_box_mm"]["max_x"], 2.0);
+        assert_eq!(geometry["description"], "printed material");
+        assert!(geometry.get("unit").is_none());
+
+        let porosity = &json["arrays"]["layer_porosity"];
+        assert_eq!(porosity["len"], 3);
+        assert_eq!(porosity["range"], serde_json::json!([0.05, 0.5]));
+
+        assert_eq!(json["parameters"]["geometry_count"], 1.0);
+        assert_eq!(json["parameters"]["geometry_mean"], 1.0);
+
+        assert_eq!(json["geometry_box_mm"]["max_y"], 1.0);
+        assert_eq!(json["stages"][0]["stage"], "voxelize gcode");
+        assert_eq!(json["options"][1], "--summary");
+    }
+}
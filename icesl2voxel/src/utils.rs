@@ -1,3 +1,10 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
@@ -35,6 +42,40 @@ impl<T: num_traits::Num + num_traits::NumCast + std::fmt::Debug + Copy + 'static
         nalgebra::Vector3::new(self.max_x, self.max_y, self.max_z)
     }
 
+    /// Intersection of both bounding boxes, or None if they don't overlap
+    pub fn intersection(&self, other: &Self) -> Option<Self>
+    where
+        T: PartialOrd,
+    {
+        let max = |a: T, b: T| if a > b { a } else { b };
+        let min = |a: T, b: T| if a < b { a } else { b };
+
+        let result = Self {
+            min_x: max(self.min_x, other.min_x),
+            min_y: max(self.min_y, other.min_y),
+            min_z: max(self.min_z, other.min_z),
+            max_x: min(self.max_x, other.max_x),
+            max_y: min(self.max_y, other.max_y),
+            max_z: min(self.max_z, other.max_z),
+        };
+
+        if result.min_x < result.max_x && result.min_y < result.max_y && result.min_z < result.max_z
+        {
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    pub fn translate(&mut self, offset: nalgebra::Vector3<T>) {
+        self.min_x = self.min_x + offset.x;
+        self.min_y = self.min_y + offset.y;
+        self.min_z = self.min_z + offset.z;
+        self.max_x = self.max_x + offset.x;
+        self.max_y = self.max_y + offset.y;
+        self.max_z = self.max_z + offset.z;
+    }
+
     pub fn pad_all(&mut self, padding: nalgebra::Vector3<T>) {
         self.min_x = self.min_x - padding.x;
         self.min_y = self.min_y - padding.y;
@@ -45,6 +86,29 @@ impl<T: num_traits::Num + num_traits::NumCast + std::fmt::Debug + Copy + 'static
     }
 }
 
+impl std::str::FromStr for BoundingBox<f32> {
+    type Err = failure::Error;
+
+    /// Parse a bounding box from min_x,min_y,min_z,max_x,max_y,max_z
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Result<Vec<f32>, _> = s.split(',').map(str::parse).collect();
+
+        match parts?[..] {
+            [min_x, min_y, min_z, max_x, max_y, max_z] => Ok(Self {
+                min_x,
+                min_y,
+                min_z,
+                max_x,
+                max_y,
+                max_z,
+            }),
+            _ => Err(failure::err_msg(
+                "expected min_x,min_y,min_z,max_x,max_y,max_z",
+            )),
+        }
+    }
+}
+
 impl<'a, T, U> From<T> for BoundingBox<U>
 where
     T: IntoIterator<Item = (&'a nalgebra::Vector3<U>, &'a nalgebra::Vector3<U>)>,
@@ -84,3 +148,225 @@ where
         }
     }
 }
+
+/// Quote a CSV cell if it contains separators, quotes or line breaks
+pub fn csv_escape(value: &str) -> Cow<str> {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reader which prefixes I/O errors with the path of the file being read, so errors reported
+/// while parsing, e.g. from truncated archives, name the faulty input
+struct PathReader<R> {
+    inner: R,
+    path: PathBuf,
+}
+
+impl<R: Read> Read for PathReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner
+            .read(buf)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+/// True if `path` has a `.gz` extension
+pub fn is_gzip_path(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "gz")
+}
+
+/// Path without its `.gz` extension, to look up the extension of the compressed file
+pub fn strip_gzip_extension(path: &Path) -> Cow<Path> {
+    if is_gzip_path(path) {
+        Cow::Owned(path.with_extension(""))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+/// Buffered reader over the file at `path`, transparently decompressed if it has a `.gz`
+/// extension or starts with the gzip magic bytes
+pub fn open_input(path: &Path) -> Result<Box<dyn BufRead>, failure::Error> {
+    let mut reader = BufReader::new(PathReader {
+        inner: File::open(path)
+            .map_err(|e| failure::err_msg(format!("{}: {}", path.display(), e)))?,
+        path: path.to_owned(),
+    });
+
+    if is_gzip_path(path) || reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        let decoder = libflate::gzip::Decoder::new(reader)
+            .map_err(|e| failure::err_msg(format!("{}: {}", path.display(), e)))?;
+
+        Ok(Box::new(BufReader::new(PathReader {
+            inner: decoder,
+            path: path.to_owned(),
+        })))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Progress reporting settings for long-running passes
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProgressConfig {
+    /// Do not display any progress bar
+    pub quiet: bool,
+}
+
+/// Progress bar driven by an atomic counter, displayed from a background thread so worker
+/// threads only have to bump the counter
+pub struct Progress {
+    steps: Arc<AtomicUsize>,
+    done: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Progress {
+    pub fn new(config: &ProgressConfig, message: &str, total: usize) -> Self {
+        let steps = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let thread = if config.quiet {
+            None
+        } else {
+            Some(std::thread::spawn({
+                let steps = steps.clone();
+                let done = done.clone();
+                let message = message.to_owned();
+
+                move || {
+                    let mut pb = pbr::ProgressBar::new(total as u64);
+                    pb.message(&message);
+                    let mut last = 0;
+
+                    // The done flag is raised when the progress is dropped early, e.g. on error
+                    while last < total && !done.load(Ordering::Relaxed) {
+                        let q = steps.load(Ordering::Relaxed);
+                        pb.add((q - last) as u64);
+                        last = q;
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                    }
+
+                    let q = steps.load(Ordering::Relaxed);
+                    pb.add((q - last) as u64);
+
+                    if q < total {
+                        pb.finish_print("aborted");
+                    } else {
+                        pb.finish();
+                    }
+                }
+            }))
+        };
+
+        Self {
+            steps,
+            done,
+            thread,
+        }
+    }
+
+    /// Record `n` completed steps
+    pub fn add(&self, n: usize) {
+        self.steps.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Number of completed steps so far
+    pub fn count(&self) -> usize {
+        self.steps.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_gzip_input() {
+        use std::io::Write;
+
+        let src = "; <layer>\nG1 X10 Y10 E1\n; </layer>\n".repeat(100);
+        let mut encoder = libflate::gzip::Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(src.as_bytes()).unwrap();
+        let compressed = encoder.finish().into_result().unwrap();
+
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!(
+            "icesl2voxel_test_open_input_{}.gcode",
+            std::process::id()
+        ));
+        let gzip = dir.join(format!(
+            "icesl2voxel_test_open_input_{}.gcode.gz",
+            std::process::id()
+        ));
+        // Compressed files are also detected from their contents
+        let renamed = dir.join(format!(
+            "icesl2voxel_test_open_input_gz_{}.gcode",
+            std::process::id()
+        ));
+        let truncated = dir.join(format!(
+            "icesl2voxel_test_open_input_truncated_{}.gcode.gz",
+            std::process::id()
+        ));
+        std::fs::write(&plain, &src).unwrap();
+        std::fs::write(&gzip, &compressed).unwrap();
+        std::fs::write(&renamed, &compressed).unwrap();
+        std::fs::write(&truncated, &compressed[..compressed.len() / 2]).unwrap();
+
+        for path in &[&plain, &gzip, &renamed] {
+            let mut read = String::new();
+            open_input(path).unwrap().read_to_string(&mut read).unwrap();
+            assert_eq!(read, src);
+        }
+
+        let mut read = String::new();
+        let error = open_input(&truncated)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap_err();
+        assert!(error.to_string().contains(&truncated.display().to_string()));
+
+        assert_eq!(strip_gzip_extension(&gzip).extension().unwrap(), "gcode");
+    }
+
+    fn fake_work(config: &ProgressConfig, total: usize, stop_at: usize) -> usize {
+        let progress = Progress::new(config, "working: ", total);
+
+        (0..total)
+            .take_while(|i| *i < stop_at)
+            .for_each(|_| progress.add(1));
+
+        progress.count()
+    }
+
+    #[test]
+    fn progress_counts_steps() {
+        let config = ProgressConfig { quiet: true };
+        assert_eq!(fake_work(&config, 100, 100), 100);
+
+        let config = ProgressConfig { quiet: false };
+        assert_eq!(fake_work(&config, 100, 100), 100);
+    }
+
+    #[test]
+    fn progress_finishes_on_early_exit() {
+        // Dropping the progress before all steps are done must not hang the display thread
+        let config = ProgressConfig { quiet: false };
+        assert_eq!(fake_work(&config, 100, 40), 40);
+    }
+}
@@ -1,8 +1,8 @@
-use std::path::Path;
-use std::str::FromStr;
+use std::io::BufRead;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use gcode::*;
-use itertools::Itertools;
 use lazy_static::lazy_static;
 use ndarray::par_azip;
 use ndarray::prelude::*;
@@ -10,14 +10,24 @@ use rand::{Rng, SeedableRng};
 use regex::Regex;
 
 use super::param_field::ParamField;
-use super::utils::BoundingBox;
+use super::stats::{outer_product, TENSOR_COMPONENTS};
+use super::utils::{open_input, BoundingBox, Progress, ProgressConfig};
 
+mod context;
 mod shaders;
+mod slicer;
+
+pub use context::GlBackend;
+use context::HeadlessGl;
+use slicer::LayerMarker;
+pub use slicer::Slicer;
 
 #[derive(Debug, Clone)]
 struct Segment {
     start: nalgebra::Vector3<f32>,
     end: nalgebra::Vector3<f32>,
+    /// Length of filament pushed per mm along the segment
+    extrusion: f32,
     state: State,
 }
 
@@ -27,198 +37,951 @@ struct State {
     f: f32,
     line: usize,
     layer: Option<usize>,
+    tool: usize,
+    feature: Feature,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+/// Type of the printed feature, as announced by slicer comments. Codes start at 1 since 0 is
+/// the background of the feature field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Feature {
+    Unknown = 1,
+    Travel = 2,
+    Perimeter = 3,
+    Infill = 4,
+    Bridge = 5,
+}
+
+/// Names of the feature field values, indexed by code
+pub const FEATURE_NAMES: [&str; 6] = [
+    "background",
+    "unknown",
+    "travel",
+    "perimeter",
+    "infill",
+    "bridge",
+];
+
+impl Default for Feature {
+    fn default() -> Self {
+        Feature::Unknown
+    }
+}
+
+impl Feature {
+    /// Classify a feature label from a slicer comment by keyword, e.g. `perimeter`,
+    /// `WALL-OUTER` or `Bridge infill`
+    fn classify(label: &str) -> Self {
+        let label = label.to_lowercase();
+        let has = |keywords: &[&str]| keywords.iter().any(|k| label.contains(k));
+
+        if has(&["bridge"]) {
+            Feature::Bridge
+        } else if has(&["perimeter", "wall", "shell"]) {
+            Feature::Perimeter
+        } else if has(&["infill", "fill", "skin"]) {
+            Feature::Infill
+        } else if has(&["travel"]) {
+            Feature::Travel
+        } else {
+            Feature::Unknown
+        }
+    }
+}
+
+fn set_tool_value(values: &mut Vec<Option<f32>>, tool: usize, value: f32) {
+    if values.len() <= tool {
+        values.resize(tool + 1, None);
+    }
+
+    values[tool] = Some(value);
+}
+
+/// Value for the given tool, falling back to the first tool that has one
+fn tool_value(values: &[Option<f32>], tool: usize) -> Option<f32> {
+    values
+        .get(tool)
+        .copied()
+        .flatten()
+        .or_else(|| values.iter().copied().flatten().next())
+}
+
+#[derive(Debug, Clone, Default)]
 struct GlobalState {
-    nozzle_diameter: f32,
+    nozzle_diameters: Vec<Option<f32>>,
+    filament_diameters: Vec<Option<f32>>,
+}
+
+impl GlobalState {
+    fn set_nozzle_diameter(&mut self, tool: usize, diameter: f32) {
+        set_tool_value(&mut self.nozzle_diameters, tool, diameter);
+    }
+
+    fn set_filament_diameter(&mut self, tool: usize, diameter: f32) {
+        set_tool_value(&mut self.filament_diameters, tool, diameter);
+    }
+
+    /// Nozzle diameter for the given tool, falling back to the first tool if it was not specified
+    fn nozzle_diameter(&self, tool: usize) -> f32 {
+        tool_value(&self.nozzle_diameters, tool).unwrap_or(0.0)
+    }
+
+    /// Filament diameter for the given tool, same as above
+    fn filament_diameter(&self, tool: usize) -> Option<f32> {
+        tool_value(&self.filament_diameters, tool)
+    }
+
+    fn max_nozzle_diameter(&self) -> f32 {
+        self.nozzle_diameters
+            .iter()
+            .copied()
+            .flatten()
+            .fold(0.0, f32::max)
+    }
+
+    fn min_nozzle_diameter(&self) -> f32 {
+        self.nozzle_diameters
+            .iter()
+            .copied()
+            .flatten()
+            .fold(std::f32::MAX, f32::min)
+    }
+}
+
+/// Width of the extruded beads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtrusionModel {
+    /// Beads are as wide as the nozzle
+    Nozzle,
+    /// Beads are as wide as the volume of extruded filament, given the layer height
+    Volumetric,
+}
+
+impl std::str::FromStr for ExtrusionModel {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nozzle" => Ok(Self::Nozzle),
+            "volumetric" => Ok(Self::Volumetric),
+            _ => Err(failure::err_msg("expected nozzle or volumetric")),
+        }
+    }
+}
+
+/// Range of volumetric bead widths, relative to the nozzle diameter
+const MIN_WIDTH_FACTOR: f32 = 0.25;
+const MAX_WIDTH_FACTOR: f32 = 3.0;
+
+/// Width of a bead of the given cross-section area and height, modeled as a rectangle with
+/// semicircular sides
+fn bead_width(area: f32, height: f32) -> f32 {
+    area / height + height * (1.0 - std::f32::consts::FRAC_PI_4)
+}
+
+/// Options for voxelizing gcode files
+#[derive(Debug, Clone)]
+pub struct GcodeOptions {
+    /// Number of samples per voxel
+    pub samples: usize,
+    /// Sampling factor in the XY plane
+    pub xy_sampling_factor: f32,
+    /// Maximum chord deviation in mm when tessellating arcs
+    pub arc_tolerance: f32,
+    /// Width of the extruded beads
+    pub extrusion_model: ExtrusionModel,
+    /// Compute the field of tool indices
+    pub extract_tool_field: bool,
+    /// Compute the feedrate and fan speed fields
+    pub extract_motion_fields: bool,
+    /// Compute the field of accumulated segment orientations
+    pub extract_orientation_field: bool,
+    /// Compute the field of dominant feature types, see `FEATURE_NAMES`
+    pub extract_feature_field: bool,
+    /// Record the positions of retractions and seams
+    pub extract_seams: bool,
+    /// Regex matching feature comments, where the first group that matched holds the feature
+    /// label. Defaults to `FEATURE_REGEX`
+    pub feature_regex: Option<Regex>,
+    /// Slicer that generated the gcode, detected from its first lines if `None`
+    pub slicer: Option<Slicer>,
+    /// Size of the voxels in mm, overrides the default of one cell per layer. Extrusions then
+    /// cover the Z cells within the thickness of their layer
+    pub voxel_size_mm: Option<f32>,
+    /// Number of cells along X, Y and Z, overrides `voxel_size_mm`
+    pub resolution: Option<[usize; 3]>,
+    /// Printer bounding box to use instead of the bounds of the extruded segments
+    pub bbox_override: Option<BoundingBox<f32>>,
+    /// Region of the printer bounding box to voxelize
+    pub crop: Option<BoundingBox<f32>>,
+    /// Voxelize the geometry on the GPU, falling back to the CPU if no context can be created
+    pub gpu: bool,
+    /// Backend for the GPU context, detected from the environment if `None`
+    pub gl_backend: Option<GlBackend>,
+    /// Progress bar settings
+    pub progress: ProgressConfig,
+}
+
+impl Default for GcodeOptions {
+    /// Same defaults as the command line, without any extra field
+    fn default() -> Self {
+        Self {
+            samples: 4,
+            xy_sampling_factor: 1.0,
+            arc_tolerance: 0.05,
+            extrusion_model: ExtrusionModel::Nozzle,
+            extract_tool_field: false,
+            extract_motion_fields: false,
+            extract_orientation_field: false,
+            extract_feature_field: false,
+            extract_seams: false,
+            feature_regex: None,
+            slicer: None,
+            voxel_size_mm: None,
+            resolution: None,
+            bbox_override: None,
+            crop: None,
+            gpu: false,
+            gl_backend: None,
+            progress: ProgressConfig::default(),
+        }
+    }
+}
+
+pub struct VoxelizedGcode {
+    pub geometry: ParamField,
+    pub tool: Option<ParamField>,
+    pub feedrate: Option<ParamField>,
+    pub fan: Option<ParamField>,
+    /// Sum of the outer products of segment directions weighted by coverage, see
+    /// `stats::outer_product`
+    pub orientation: Option<ParamField>,
+    /// Code of the feature with the largest coverage in each voxel
+    pub feature: Option<ParamField>,
+    /// Height of each layer in mm, from its first extruding move
+    pub layer_z: Vec<f32>,
+    /// Thickness of each layer in mm, the first one is measured from Z = 0
+    pub layer_thickness: Vec<f32>,
+    /// Positions of the retractions as (x, y, z, layer), the layer being -1 outside of layers
+    pub retractions: Option<Vec<[f32; 4]>>,
+    /// Starts of the closed extrusion loops, same as above
+    pub seams: Option<Vec<[f32; 4]>>,
 }
 
 lazy_static! {
-    static ref PARAMETER_REGEX: Regex = Regex::new(r"^; ([a-z0-9_]*) :\s*(.*)$").unwrap();
+    /// Feature comments of IceSL (`; perimeter`) and of Cura or PrusaSlicer (`;TYPE:Perimeter`)
+    pub static ref FEATURE_REGEX: Regex =
+        Regex::new(r"^;\s*(?:TYPE:(.+)|(?i:(perimeter|infill|bridge|travel)))\s*$").unwrap();
 }
 
-pub fn voxelize_gcode(
-    path: &Path,
-    samples: usize,
-    xy_sampling_factor: f32,
-) -> Result<ParamField, failure::Error> {
-    // Parse gcode
-    let gcode_src = std::fs::read_to_string(path)?;
-    let mut gcode_lines = gcode_src.lines().enumerate();
-    let gcode = gcode::parse(&gcode_src);
+fn find_arg(part: &GCode, letter: char) -> Option<f32> {
+    part.arguments()
+        .iter()
+        .find(|arg| arg.letter == letter)
+        .map(|arg| arg.value)
+}
+
+/// Compute the center of an arc given in radius form (G2/G3 with R). A positive radius selects
+/// the shorter arc, a negative radius the longer one.
+fn arc_center_from_radius(
+    start: nalgebra::Vector2<f32>,
+    end: nalgebra::Vector2<f32>,
+    r: f32,
+    clockwise: bool,
+) -> Option<nalgebra::Vector2<f32>> {
+    let d = end - start;
+    let len = d.norm();
+
+    if len == 0.0 || r.abs() < len / 2.0 {
+        return None;
+    }
+
+    let h = (r * r - len * len / 4.0).sqrt();
+    let e = if clockwise ^ (r < 0.0) { -1.0 } else { 1.0 };
+    let s = nalgebra::Vector2::new(-d.y, d.x) / len;
+
+    Some((start + end) / 2.0 + e * h * s)
+}
+
+/// Tessellate an arc from `start` to `end` around `center` into points separated by chords which
+/// deviate at most `tolerance` from the arc. The returned points exclude `start` and end exactly
+/// on `end`. A full circle is produced when `start == end`.
+fn tessellate_arc(
+    start: nalgebra::Vector2<f32>,
+    end: nalgebra::Vector2<f32>,
+    center: nalgebra::Vector2<f32>,
+    clockwise: bool,
+    tolerance: f32,
+) -> Vec<nalgebra::Vector2<f32>> {
+    use std::f32::consts::PI;
+    const EPS: f32 = 1e-6;
+
+    let r = (start - center).norm();
+    if r < EPS {
+        return vec![end];
+    }
+
+    let a0 = (start.y - center.y).atan2(start.x - center.x);
+    let a1 = (end.y - center.y).atan2(end.x - center.x);
+
+    let mut sweep = a1 - a0;
+    if clockwise {
+        while sweep >= -EPS {
+            sweep -= 2.0 * PI;
+        }
+    } else {
+        while sweep <= EPS {
+            sweep += 2.0 * PI;
+        }
+    }
 
-    let mut current_x = None;
-    let mut current_y = None;
-    let mut current_z = None;
+    // Maximum angle so the sagitta of each chord stays below the tolerance
+    let max_angle = 2.0 * (1.0 - tolerance / r).clamp(-1.0, 1.0).acos();
+    let count = if max_angle > 0.0 {
+        (sweep.abs() / max_angle).ceil().max(1.0) as usize
+    } else {
+        1
+    };
+
+    let mut points: Vec<_> = (1..count)
+        .map(|i| {
+            let a = a0 + sweep * i as f32 / count as f32;
+            center + r * nalgebra::Vector2::new(a.cos(), a.sin())
+        })
+        .collect();
+    points.push(end);
+    points
+}
+
+/// Everything but the segments of a gcode file
+struct GcodeSummary {
+    global_state: GlobalState,
+    layer_count: usize,
+    /// Height of each layer, from its first extruding move or the position at its marker
+    layer_z: Vec<Option<f32>>,
+    /// Bounding box of the segments, except those of the first layer
+    bbox: BoundingBox<f32>,
+    segment_count: usize,
+    /// Positions of the retractions as (x, y, z, layer)
+    retractions: Vec<[f32; 4]>,
+    /// Starts of the closed extrusion loops as (x, y, z, layer)
+    seams: Vec<[f32; 4]>,
+}
+
+struct ParsedGcode {
+    segments: Vec<Segment>,
+    summary: GcodeSummary,
+}
+
+/// Parse gcode, keeping all the extruded segments in memory
+fn parse_gcode(
+    gcode_src: &str,
+    arc_tolerance: f32,
+    slicer: Slicer,
+    feature_regex: &Regex,
+) -> Result<ParsedGcode, failure::Error> {
+    let mut segments = Vec::new();
+    let summary = parse_gcode_stream(
+        gcode_src.as_bytes(),
+        arc_tolerance,
+        slicer,
+        feature_regex,
+        |seg| segments.push(seg),
+    )?;
+
+    Ok(ParsedGcode { segments, summary })
+}
+
+/// Parse gcode line by line, passing the extruded segments to `on_segment` as they are read
+fn parse_gcode_stream(
+    reader: impl BufRead,
+    arc_tolerance: f32,
+    slicer: Slicer,
+    feature_regex: &Regex,
+    mut on_segment: impl FnMut(Segment),
+) -> Result<GcodeSummary, failure::Error> {
+    // Physical position of the X, Y and Z axes, unknown until first set
+    let mut current_pos: [Option<f32>; 3] = [None; 3];
+    // Difference between physical and logical coordinates, set by G92
+    let mut offsets = nalgebra::Vector3::<f32>::zeros();
+    // Logical extruder position
+    let mut current_e = 0.0f32;
+
+    // Positioning modes (G90/G91 for all axes, M82/M83 for the extruder)
+    let mut relative_xyz = false;
+    let mut relative_e = false;
 
     let mut current_state = State::default();
     let mut global_state = GlobalState::default();
-    let mut segments = Vec::new();
 
-    let mut gcode_current_line: isize = -1;
     let mut current_layer = 0;
+    let mut layer_z = Vec::new();
+    let mut layer_z_from_move = false;
+
+    // Bounds of the segments, the first layer is skipped because of the supports
+    let mut bbox_min = nalgebra::Vector3::repeat(std::f32::MAX);
+    let mut bbox_max = nalgebra::Vector3::repeat(std::f32::MIN);
+    let mut segment_count = 0;
+
+    // Retraction positions and loop starts
+    let mut retractions = Vec::new();
+    let mut seams = Vec::new();
+    let mut chain_start: Option<nalgebra::Vector3<f32>> = None;
+    let mut chain_length = 0.0f32;
+
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        current_state.line = line_idx;
+
+        if !line.starts_with(';') {
+            // Not a comment
+        } else if let Some(marker) = slicer.layer_marker(&line) {
+            // Slicers without end markers only announce the start of the next layer
+            if current_state.layer.is_some() {
+                current_state.layer = None;
+                current_layer += 1;
+            }
 
-    for part in gcode {
-        current_state.line = part.span().line;
-
-        while gcode_current_line != current_state.line as isize {
-            if let Some((line_idx, line)) = gcode_lines.next() {
-                if line == "; <layer>" {
-                    current_state.layer = Some(current_layer);
-                } else if line == "; </layer>" {
-                    current_state.layer = None;
-                    current_layer += 1;
-                } else if let Some(captures) = PARAMETER_REGEX.captures(line) {
-                    match captures.get(1).map(|m| m.as_str()) {
-                        Some("nozzle_diameter_mm_0") => {
-                            global_state.nozzle_diameter =
-                                f32::from_str(captures.get(2).unwrap().as_str())?;
-                        }
-                        _ => {}
-                    }
-                }
-
-                gcode_current_line = line_idx as isize;
+            if marker == LayerMarker::Start {
+                current_state.layer = Some(current_layer);
+                layer_z.push(current_pos[2]);
+                layer_z_from_move = false;
+            }
+        } else if slicer.parse_parameter(&line, &mut global_state)? {
+            // Header parameter
+        } else if let Some(captures) = feature_regex.captures(&line) {
+            if let Some(label) = captures.iter().skip(1).find_map(|m| m) {
+                current_state.feature = Feature::classify(label.as_str().trim());
             }
         }
 
-        match part.mnemonic() {
-            Mnemonic::General => {
-                match part.major_number() {
-                    1 => {
-                        let x_arg = part
-                            .arguments()
-                            .iter()
-                            .find(|arg| arg.letter == 'X')
-                            .map(|arg| arg.value);
-                        let y_arg = part
-                            .arguments()
-                            .iter()
-                            .find(|arg| arg.letter == 'Y')
-                            .map(|arg| arg.value);
-                        let z_arg = part
-                            .arguments()
-                            .iter()
-                            .find(|arg| arg.letter == 'Z')
-                            .map(|arg| arg.value);
-                        let e_arg = part
-                            .arguments()
-                            .iter()
-                            .find(|arg| arg.letter == 'E')
-                            .map(|arg| arg.value);
-                        let f_arg = part
-                            .arguments()
-                            .iter()
-                            .find(|arg| arg.letter == 'F')
-                            .map(|arg| arg.value);
-
-                        if let (Some(current_x), Some(current_y), Some(current_z)) =
-                            (current_x, current_y, current_z)
-                        {
-                            let new_x = x_arg.unwrap_or(current_x);
-                            let new_y = y_arg.unwrap_or(current_y);
-                            let new_z = z_arg.unwrap_or(current_z);
+        for part in gcode::parse(&line) {
+            match part.mnemonic() {
+                Mnemonic::General => {
+                    match part.major_number() {
+                        major @ 0..=3 => {
+                            let axis_args = [
+                                find_arg(&part, 'X'),
+                                find_arg(&part, 'Y'),
+                                find_arg(&part, 'Z'),
+                            ];
+                            let e_arg = find_arg(&part, 'E');
+                            let f_arg = find_arg(&part, 'F');
 
                             // Update filament speed
                             current_state.f = f_arg.unwrap_or(current_state.f);
 
-                            if let Some(e) = e_arg {
-                                if e > 0.0 {
+                            // Resolve the physical target position
+                            let mut target_pos = current_pos;
+                            for (axis, arg) in axis_args.iter().enumerate() {
+                                if let Some(value) = arg {
+                                    target_pos[axis] = if relative_xyz {
+                                        current_pos[axis].map(|c| c + value)
+                                    } else {
+                                        Some(value + offsets[axis])
+                                    };
+                                }
+                            }
+
+                            // Amount of filament pushed by this move
+                            let extruded = if let Some(e) = e_arg {
+                                let delta = if relative_e { e } else { e - current_e };
+                                current_e += delta;
+                                delta
+                            } else {
+                                0.0
+                            };
+
+                            // Layer of the annotations, -1 outside of layers
+                            let annotation_layer =
+                                current_state.layer.map(|l| l as f32).unwrap_or(-1.0);
+
+                            if extruded < 0.0 {
+                                if let [Some(x), Some(y), Some(z)] = current_pos {
+                                    retractions.push([x, y, z, annotation_layer]);
+                                }
+                            }
+
+                            // Extrusion chains end with retractions and travel moves
+                            if extruded <= 0.0 && (extruded < 0.0 || target_pos != current_pos) {
+                                chain_start = None;
+                            }
+
+                            if let (
+                                [Some(current_x), Some(current_y), Some(current_z)],
+                                [Some(new_x), Some(new_y), Some(new_z)],
+                            ) = (current_pos, target_pos)
+                            {
+                                if extruded > 0.0 {
+                                    let start =
+                                        nalgebra::Vector3::new(current_x, current_y, current_z);
+                                    let end = nalgebra::Vector3::new(new_x, new_y, new_z);
+
+                                    if chain_start.is_none() {
+                                        chain_start = Some(start);
+                                        chain_length = 0.0;
+                                    }
+
+                                    // Points along the move, excluding the start point
+                                    let path = if major <= 1 {
+                                        vec![end]
+                                    } else {
+                                        let center = if let Some(r) = find_arg(&part, 'R') {
+                                            arc_center_from_radius(
+                                                start.xy(),
+                                                end.xy(),
+                                                r,
+                                                major == 2,
+                                            )
+                                        } else {
+                                            Some(
+                                                start.xy()
+                                                    + nalgebra::Vector2::new(
+                                                        find_arg(&part, 'I').unwrap_or(0.0),
+                                                        find_arg(&part, 'J').unwrap_or(0.0),
+                                                    ),
+                                            )
+                                        };
+
+                                        if let Some(center) = center {
+                                            let points = tessellate_arc(
+                                                start.xy(),
+                                                end.xy(),
+                                                center,
+                                                major == 2,
+                                                arc_tolerance,
+                                            );
+
+                                            // Helical moves are flattened at the layer height,
+                                            // only horizontal segments can be rasterized
+                                            points
+                                                .into_iter()
+                                                .map(|p| nalgebra::Vector3::new(p.x, p.y, start.z))
+                                                .collect()
+                                        } else {
+                                            warn!(
+                                                "invalid arc radius on line {}, using a straight line",
+                                                current_state.line + 1
+                                            );
+                                            vec![nalgebra::Vector3::new(end.x, end.y, start.z)]
+                                        }
+                                    };
+
+                                    if current_state.layer.is_some() && !layer_z_from_move {
+                                        *layer_z.last_mut().unwrap() = Some(start.z);
+                                        layer_z_from_move = true;
+                                    }
+
+                                    // Filament is pushed evenly along the move
+                                    let length = path
+                                        .iter()
+                                        .fold((start, 0.0), |(last, length), point| {
+                                            (*point, length + (point - last).norm())
+                                        })
+                                        .1;
+                                    let extrusion =
+                                        if length > 0.0 { extruded / length } else { 0.0 };
+
                                     // We are extruding a segment
-                                    segments.push(Segment {
-                                        start: nalgebra::Vector3::new(
-                                            current_x, current_y, current_z,
-                                        ),
-                                        end: nalgebra::Vector3::new(new_x, new_y, new_z),
-                                        state: current_state,
-                                    })
+                                    let mut last = start;
+                                    for point in path {
+                                        if current_state.layer.map(|l| l > 0).unwrap_or(false) {
+                                            for pt in [last, point].iter() {
+                                                bbox_min = bbox_min.zip_map(pt, f32::min);
+                                                bbox_max = bbox_max.zip_map(pt, f32::max);
+                                            }
+                                        }
+
+                                        segment_count += 1;
+                                        on_segment(Segment {
+                                            start: last,
+                                            end: point,
+                                            extrusion,
+                                            state: current_state,
+                                        });
+
+                                        chain_length += (point - last).norm();
+                                        last = point;
+                                    }
+
+                                    // A chain back at its start is a closed loop, which starts
+                                    // at the seam
+                                    if let Some(seam) = chain_start {
+                                        if chain_length > SEAM_EPSILON_MM
+                                            && (last - seam).norm() < SEAM_EPSILON_MM
+                                        {
+                                            seams.push([seam.x, seam.y, seam.z, annotation_layer]);
+                                            chain_start = None;
+                                        }
+                                    }
                                 }
                             }
+
+                            current_pos = target_pos;
+                        }
+                        90 => {
+                            relative_xyz = false;
+                            relative_e = false;
+                        }
+                        91 => {
+                            relative_xyz = true;
+                            relative_e = true;
                         }
+                        92 => {
+                            let axis_args = [
+                                find_arg(&part, 'X'),
+                                find_arg(&part, 'Y'),
+                                find_arg(&part, 'Z'),
+                            ];
+                            let e_arg = find_arg(&part, 'E');
+
+                            // Without arguments, all axes are reset to zero
+                            let reset_all =
+                                axis_args.iter().all(Option::is_none) && e_arg.is_none();
+
+                            for (axis, arg) in axis_args.iter().enumerate() {
+                                let value = if reset_all { Some(0.0) } else { *arg };
+
+                                if let Some(value) = value {
+                                    // Setting the logical position doesn't move the head
+                                    if let Some(current) = current_pos[axis] {
+                                        offsets[axis] = current - value;
+                                    } else {
+                                        current_pos[axis] = Some(value);
+                                        offsets[axis] = 0.0;
+                                    }
+                                }
+                            }
 
-                        current_x = x_arg.map_or_else(|| current_x, |x| Some(x));
-                        current_y = y_arg.map_or_else(|| current_y, |y| Some(y));
-                        current_z = z_arg.map_or_else(|| current_z, |z| Some(z));
+                            if reset_all {
+                                current_e = 0.0;
+                            } else if let Some(e) = e_arg {
+                                current_e = e;
+                            }
+                        }
+                        _ => {}
                     }
-                    _ => {}
                 }
-            }
-            Mnemonic::Miscellaneous => match part.major_number() {
-                106 => {
-                    current_state.fan = part
-                        .arguments()
-                        .iter()
-                        .find(|arg| arg.letter == 'S')
-                        .map(|arg| arg.value as u8)
-                        .unwrap_or(0);
+                Mnemonic::Miscellaneous => match part.major_number() {
+                    82 => {
+                        relative_e = false;
+                    }
+                    83 => {
+                        relative_e = true;
+                    }
+                    106 => {
+                        current_state.fan = find_arg(&part, 'S').map(|s| s as u8).unwrap_or(0);
+                    }
+                    107 => {
+                        current_state.fan = 0;
+                    }
+                    _ => {}
+                },
+                Mnemonic::ToolChange => {
+                    current_state.tool = part.major_number() as usize;
                 }
                 _ => {}
-            },
-            _ => {}
+            }
         }
     }
 
-    // Skip the first layer because of the supports, but extend it after
-    let printer_bbox = BoundingBox::from(
-        &mut segments
-            .iter()
-            .filter(|seg| seg.state.layer.map(|l| l > 0).unwrap_or(false))
-            .map(|seg| (&seg.start, &seg.end))
-            .into_iter(),
-    );
+    // Count the last layer even if it isn't terminated
+    if current_state.layer.is_some() {
+        current_layer += 1;
+    }
 
-    let printer_bbox = BoundingBox {
-        min_x: printer_bbox.min_x - global_state.nozzle_diameter / 2.0,
-        min_y: printer_bbox.min_y - global_state.nozzle_diameter / 2.0,
-        min_z: printer_bbox.min_z - 2.0 * global_state.nozzle_diameter / 2.0,
-        max_x: printer_bbox.max_x + global_state.nozzle_diameter / 2.0,
-        max_y: printer_bbox.max_y + global_state.nozzle_diameter / 2.0,
-        max_z: printer_bbox.max_z + global_state.nozzle_diameter / 2.0,
-    };
+    Ok(GcodeSummary {
+        global_state,
+        layer_count: current_layer,
+        layer_z,
+        bbox: BoundingBox {
+            min_x: bbox_min.x,
+            min_y: bbox_min.y,
+            min_z: bbox_min.z,
+            max_x: bbox_max.x,
+            max_y: bbox_max.y,
+            max_z: bbox_max.z,
+        },
+        segment_count,
+        retractions,
+        seams,
+    })
+}
 
-    let bbox_min = printer_bbox.min();
-    let bbox_size = printer_bbox.size();
+/// Resolve the height and thickness of each layer. Layers without a known height keep the height
+/// of the previous one, and heights that don't increase are reported.
+fn layer_heights(layer_z: &[Option<f32>]) -> (Vec<f32>, Vec<f32>) {
+    let mut heights = Vec::with_capacity(layer_z.len());
+    let mut thickness = Vec::with_capacity(layer_z.len());
+    let mut last = 0.0f32;
 
-    debug!(
-        "extracted {} line segments from gcode over {} layers",
-        segments.len(),
-        current_layer
-    );
-    debug!("printing bounding box: {:?}", printer_bbox);
+    for (layer, z) in layer_z.iter().enumerate() {
+        let z = z.unwrap_or(last);
 
-    // One cell per layer
-    let zc = current_layer;
-    let xc = ((bbox_size.x / bbox_size.z) * zc as f32 * xy_sampling_factor).ceil() as usize;
-    let yc = ((bbox_size.y / bbox_size.z) * zc as f32 * xy_sampling_factor).ceil() as usize;
-    debug!("computed optimal voxel grid size: {}x{}x{}", xc, yc, zc);
+        if z <= last && layer > 0 {
+            warn!(
+                "layer {} at Z = {} mm is not above the previous layer at Z = {} mm",
+                layer, z, last
+            );
+        }
 
-    let c = nalgebra::Vector3::new(xc as f32, yc as f32, zc as f32);
+        heights.push(z);
+        thickness.push(z - last);
+        last = z;
+    }
+
+    (heights, thickness)
+}
 
-    // Turn segment list into list of per-layer segments
-    let mut segarray: ndarray::Array1<Vec<&Segment>> = ndarray::Array1::default((zc,));
-    for (key, iter) in &segments
+/// Height of the extrusions of a layer: its thickness, up to the nozzle diameter. Layers that
+/// aren't above the previous one are assumed to be as thick as the nozzle.
+fn extrusion_height(thickness: f32, nozzle_diameter: f32) -> f32 {
+    if thickness > 0.0 {
+        thickness.min(nozzle_diameter)
+    } else {
+        nozzle_diameter
+    }
+}
+
+/// Range of the layers whose extrusions intersect each Z cell, given the bottom of the grid and
+/// the height of a cell. The ranges may include extra layers so their bounds never decrease.
+fn cell_layer_ranges(
+    zc: usize,
+    min_z: f32,
+    cell_height: f32,
+    layer_z: &[f32],
+    layer_thickness: &[f32],
+    nozzle_diameter: f32,
+) -> Vec<Range<usize>> {
+    // First and last + 1 layers intersecting each cell
+    let bounds: Vec<_> = (0..zc)
+        .map(|k| {
+            let (bottom, top) = (
+                min_z + k as f32 * cell_height,
+                min_z + (k + 1) as f32 * cell_height,
+            );
+
+            let mut layers = layer_z
+                .iter()
+                .zip(layer_thickness.iter())
+                .enumerate()
+                .filter(|(_l, (z, t))| {
+                    **z > bottom && **z - extrusion_height(**t, nozzle_diameter) < top
+                })
+                .map(|(l, _)| l);
+
+            layers
+                .next()
+                .map(|first| (first, layers.next_back().unwrap_or(first) + 1))
+        })
+        .collect();
+
+    // Layers are read in order when streaming, so the bounds of the ranges can't decrease
+    let mut starts = vec![0; zc];
+    let mut start = std::usize::MAX;
+    for k in (0..zc).rev() {
+        if let Some((first, _)) = bounds[k] {
+            start = start.min(first);
+        }
+        starts[k] = start;
+    }
+
+    let mut end = 0;
+    bounds
         .iter()
-        .filter(|seg| seg.state.layer.is_some())
-        .group_by(|seg| seg.state.layer.unwrap())
-    {
-        segarray[key].extend(iter);
+        .zip(starts)
+        .map(|(bounds, start)| {
+            if let Some((_, last)) = bounds {
+                end = end.max(*last);
+            }
+            start.min(end)..end
+        })
+        .collect()
+}
+
+pub fn voxelize_gcode(
+    path: &Path,
+    options: &GcodeOptions,
+) -> Result<VoxelizedGcode, failure::Error> {
+    let feature_regex = options.feature_regex.as_ref().unwrap_or(&FEATURE_REGEX);
+    let open = || open_input(path);
+
+    let slicer = if let Some(slicer) = options.slicer {
+        slicer
+    } else {
+        let head = open()?
+            .lines()
+            .take(slicer::DETECT_LINES)
+            .collect::<Result<Vec<_>, _>>()?;
+        let slicer = Slicer::detect(head.iter().map(String::as_str))?;
+        debug!("detected slicer: {:?}", slicer);
+        slicer
+    };
+
+    // The first pass only finds the bounds and layers, segments are rasterized on the second
+    let summary = parse_gcode_stream(
+        open()?,
+        options.arc_tolerance,
+        slicer,
+        feature_regex,
+        |_| {},
+    )?;
+
+    voxelize_segments(
+        summary,
+        |on_segment| {
+            parse_gcode_stream(
+                open()?,
+                options.arc_tolerance,
+                slicer,
+                feature_regex,
+                on_segment,
+            )?;
+            Ok(())
+        },
+        options,
+    )
+}
+
+fn voxelize_parsed_gcode(
+    parsed: ParsedGcode,
+    options: &GcodeOptions,
+) -> Result<VoxelizedGcode, failure::Error> {
+    let ParsedGcode { segments, summary } = parsed;
+
+    voxelize_segments(
+        summary,
+        |on_segment| {
+            segments.into_iter().for_each(on_segment);
+            Ok(())
+        },
+        options,
+    )
+}
+
+/// Distance under which an extrusion chain is considered back at its start
+const SEAM_EPSILON_MM: f32 = 0.01;
+
+/// Number of Z cells rasterized at once when streaming segments. Only the segments of the layers
+/// sampled by these cells are kept in memory.
+const STREAM_BATCH_CELLS: usize = 64;
+
+/// Grid parameters shared by all cells when rasterizing gcode segments
+struct GcodeGrid<'a> {
+    options: &'a GcodeOptions,
+    global_state: &'a GlobalState,
+    /// Thickness of each layer
+    layer_thickness: &'a [f32],
+    /// Extrusions span several Z cells, otherwise each Z cell holds a single layer
+    vertical_extrusions: bool,
+    bbox_min: nalgebra::Vector3<f32>,
+    bbox_size: nalgebra::Vector3<f32>,
+    c: nalgebra::Vector3<f32>,
+    cell_scale: nalgebra::Vector2<f32>,
+    xc: usize,
+    yc: usize,
+}
+
+/// Slices of the voxelized fields for one Z cell, empty if the field isn't requested
+struct CellSlices<'a> {
+    vx: ArrayViewMut2<'a, u8>,
+    tool: ArrayViewMut2<'a, u8>,
+    motion: ArrayViewMut3<'a, f32>,
+    orientation: ArrayViewMut3<'a, f32>,
+    feature: ArrayViewMut3<'a, f32>,
+}
+
+impl<'a> GcodeGrid<'a> {
+    /// Height of the extrusion of `seg`
+    fn segment_height(&self, seg: &Segment) -> f32 {
+        let nozzle_diameter = self.global_state.nozzle_diameter(seg.state.tool);
+
+        match seg.state.layer {
+            Some(layer) => extrusion_height(self.layer_thickness[layer], nozzle_diameter),
+            None => nozzle_diameter,
+        }
     }
 
-    // Allocate voxel grid
-    let mut vx = ndarray::Array3::<u8>::zeros((zc, yc, xc));
+    /// Width of the extrusion of `seg`
+    fn segment_width(&self, seg: &Segment) -> f32 {
+        let nozzle_diameter = self.global_state.nozzle_diameter(seg.state.tool);
+
+        match self.options.extrusion_model {
+            ExtrusionModel::Nozzle => nozzle_diameter,
+            ExtrusionModel::Volumetric => {
+                // Checked before rasterizing
+                let filament_diameter =
+                    self.global_state.filament_diameter(seg.state.tool).unwrap();
+                let filament_area = std::f32::consts::PI * filament_diameter.powi(2) / 4.0;
+
+                bead_width(seg.extrusion * filament_area, self.segment_height(seg))
+                    .max(MIN_WIDTH_FACTOR * nozzle_diameter)
+                    .min(MAX_WIDTH_FACTOR * nozzle_diameter)
+            }
+        }
+    }
+
+    /// Fraction of the height of the Z cell `k` covered by the extrusion of `seg`
+    fn z_coverage(&self, k: usize, seg: &Segment) -> f32 {
+        match seg.state.layer {
+            Some(_) if self.vertical_extrusions => {
+                let cell_height = self.bbox_size.z / self.c.z;
+                let bottom = self.bbox_min.z + k as f32 * cell_height;
+                let height = self.segment_height(seg);
+
+                let overlap =
+                    seg.start.z.min(bottom + cell_height) - (seg.start.z - height).max(bottom);
+                (overlap / cell_height).max(0.0)
+            }
+            _ => 1.0,
+        }
+    }
 
-    let nozzle_dimensions =
-        c.xy().component_div(&bbox_size.xy()) * global_state.nozzle_diameter / 2.0;
+    /// Rasterize the segments of the layers intersecting the Z cell `k`
+    fn rasterize_cell<'s>(
+        &self,
+        k: usize,
+        layer_segs: impl Iterator<Item = &'s Segment>,
+        cell: CellSlices,
+    ) {
+        let CellSlices {
+            vx: mut vx_layer,
+            tool: mut tool_layer,
+            motion: mut motion_layer,
+            orientation: mut orientation_layer,
+            feature: mut feature_layer,
+        } = cell;
+
+        let options = self.options;
+        let (bbox_min, bbox_size, c, cell_scale) =
+            (self.bbox_min, self.bbox_size, self.c, self.cell_scale);
+        let (xc, yc) = (self.xc, self.yc);
+        let samples = options.samples;
 
-    par_azip!((index k, mut vx_layer in vx.outer_iter_mut(), layer_segs in &segarray) {
         for seg in layer_segs {
             // We only process horizontal segments in the current layer
             assert!(seg.start.z == seg.end.z);
 
+            // Partial coverage of the cells at the top and bottom of the extrusion
+            let z_coverage = self.z_coverage(k, seg);
+            if z_coverage <= 0.0 {
+                continue;
+            }
+
+            let orientation = outer_product(&(seg.end - seg.start).normalize());
+
+            let nozzle_dimensions = cell_scale * self.segment_width(seg) / 2.0;
+
             // Convert end and start point into voxel coordinates
-            let start = (seg.start - bbox_min).component_div(&bbox_size).component_mul(&c).xy();
-            let end = (seg.end - bbox_min).component_div(&bbox_size).component_mul(&c).xy();
+            let start = (seg.start - bbox_min)
+                .component_div(&bbox_size)
+                .component_mul(&c)
+                .xy();
+            let end = (seg.end - bbox_min)
+                .component_div(&bbox_size)
+                .component_mul(&c)
+                .xy();
 
             let d = end - start;
 
@@ -232,44 +995,57 @@ pub fn voxelize_gcode(
                 start.y - nozzle_dimensions.y
             } else {
                 end.y - nozzle_dimensions.y
-            }.floor() as isize).max(0).min((yc - 1) as isize) as usize;
+            }
+            .floor() as isize)
+                .max(0)
+                .min((yc - 1) as isize) as usize;
 
             let j_max = (if start.y < end.y {
                 end.y + nozzle_dimensions.y
             } else {
                 start.y + nozzle_dimensions.y
-            }.ceil() as isize).max(0).min((yc - 1) as isize) as usize;
+            }
+            .ceil() as isize)
+                .max(0)
+                .min((yc - 1) as isize) as usize;
 
             let i_min = (if start.x < end.x {
                 start.x - nozzle_dimensions.x
             } else {
                 end.x - nozzle_dimensions.x
-            }.floor() as isize).max(0).min((xc - 1) as isize) as usize;
+            }
+            .floor() as isize)
+                .max(0)
+                .min((xc - 1) as isize) as usize;
 
             let i_max = (if start.x < end.x {
                 end.x + nozzle_dimensions.x
             } else {
                 start.x + nozzle_dimensions.x
-            }.ceil() as isize).max(0).min((xc - 1) as isize) as usize;
+            }
+            .ceil() as isize)
+                .max(0)
+                .min((xc - 1) as isize) as usize;
 
             for j in j_min..=j_max {
                 for i in i_min..=i_max {
-                    let v = vx_layer.get_mut((j, i)).ok_or_else(|| failure::err_msg(format!("out of bounds: ({}, {})", i, j))).unwrap();
+                    let v = vx_layer
+                        .get_mut((j, i))
+                        .ok_or_else(|| failure::err_msg(format!("out of bounds: ({}, {})", i, j)))
+                        .unwrap();
 
                     let x = i as f32 + 0.5;
                     let y = j as f32 + 0.5;
 
                     let mut in_samples = 0;
-                    let mut rnd = rand::rngs::SmallRng::seed_from_u64((k * yc * xc + j * xc + i) as u64);
+                    let mut rnd =
+                        rand::rngs::SmallRng::seed_from_u64((k * yc * xc + j * xc + i) as u64);
 
                     for l in 0..samples {
                         let (x, y) = if l == 0 {
                             (x, y) // middle for first sample
                         } else {
-                            (
-                                x + rnd.gen_range(-0.5, 0.5),
-                                y + rnd.gen_range(-0.5, 0.5),
-                            )
+                            (x + rnd.gen_range(-0.5, 0.5), y + rnd.gen_range(-0.5, 0.5))
                         };
 
                         // Sample location
@@ -286,7 +1062,10 @@ pub fn voxelize_gcode(
                             // Outside start of segment
                             (p - start).component_div(&nozzle_dimensions).norm() < 1.0
                         } else {
-                            ((p - proj).dot(&normal_vec) * normal_vec).component_div(&nozzle_dimensions).norm() < 1.0
+                            ((p - proj).dot(&normal_vec) * normal_vec)
+                                .component_div(&nozzle_dimensions)
+                                .norm()
+                                < 1.0
                         };
 
                         if is_in {
@@ -294,20 +1073,644 @@ pub fn voxelize_gcode(
                         }
                     }
 
-                    *v = v.saturating_add(((in_samples as f32 / samples as f32) * 255.0) as u8);
-                }
-            }
-        }
-    });
+                    let w = in_samples as f32 / samples as f32 * z_coverage;
+                    *v = v.saturating_add((w * 255.0) as u8);
+
+                    // Last tool to extrude in this voxel wins
+                    if in_samples > 0 && options.extract_tool_field {
+                        tool_layer[(j, i)] = seg.state.tool as u8;
+                    }
+
+                    // Weight motion parameters by segment coverage
+                    if in_samples > 0 && options.extract_motion_fields {
+                        motion_layer[(j, i, 0)] += w * seg.state.f;
+                        motion_layer[(j, i, 1)] += w * seg.state.fan as f32;
+                        motion_layer[(j, i, 2)] += w;
+                    }
+
+                    // Weight segment orientations by segment coverage
+                    if in_samples > 0 && options.extract_orientation_field {
+                        for (l, o) in orientation.iter().enumerate() {
+                            orientation_layer[(j, i, l)] += w * o;
+                        }
+                    }
+
+                    // Weight feature types by segment coverage
+                    if in_samples > 0 && options.extract_feature_field {
+                        feature_layer[(j, i, seg.state.feature as usize)] += w;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rasterizes Z cells as soon as the layers they sample have been read, so only a bounded number
+/// of layers is held in memory
+struct LayerStream<'a> {
+    grid: GcodeGrid<'a>,
+    /// Range of the gcode layers sampled by each Z cell
+    cell_layers: &'a [Range<usize>],
+    /// Segments of the layers from `first_layer` on
+    pending: Vec<Vec<Segment>>,
+    first_layer: usize,
+    /// Layers before this one have been read completely
+    done_layers: usize,
+    /// First Z cell not rasterized yet
+    next_cell: usize,
+    vx: ndarray::Array3<u8>,
+    tool_field: ndarray::Array3<u8>,
+    motion_acc: ndarray::Array4<f32>,
+    orientation_acc: ndarray::Array4<f32>,
+    feature_acc: ndarray::Array4<f32>,
+    progress: Progress,
+}
+
+impl<'a> LayerStream<'a> {
+    fn push(&mut self, seg: Segment) {
+        let layer = match seg.state.layer {
+            Some(layer) => layer,
+            None => return,
+        };
+
+        // Layers are read in order, so the previous ones are complete
+        if layer > self.done_layers {
+            self.done_layers = layer;
+            self.flush(false);
+        }
+
+        // Skip the layers that aren't sampled by the remaining cells
+        let end_layer = self.cell_layers.last().unwrap().end;
+        if layer < self.first_layer || layer >= end_layer {
+            return;
+        }
+
+        let idx = layer - self.first_layer;
+        if self.pending.len() <= idx {
+            self.pending.resize_with(idx + 1, Vec::new);
+        }
+
+        self.pending[idx].push(seg);
+    }
+
+    /// Rasterize the cells whose layers are complete, once there are enough of them or if `force`
+    fn flush(&mut self, force: bool) {
+        let (k0, done_layers) = (self.next_cell, self.done_layers);
+        let k1 = self.cell_layers[k0..]
+            .iter()
+            .position(|layers| layers.end > done_layers)
+            .map(|p| k0 + p)
+            .unwrap_or_else(|| self.cell_layers.len());
+
+        if k1 == k0 || (k1 - k0 < STREAM_BATCH_CELLS && !force) {
+            return;
+        }
+
+        {
+            let (grid, pending, first_layer, cell_layers, progress) = (
+                &self.grid,
+                &self.pending,
+                self.first_layer,
+                self.cell_layers,
+                &self.progress,
+            );
+
+            let mut vx = self.vx.slice_mut(s![k0..k1, .., ..]);
+            let mut tool_field = self.tool_field.slice_mut(s![k0..k1, .., ..]);
+            let mut motion_acc = self.motion_acc.slice_mut(s![k0..k1, .., .., ..]);
+            let mut orientation_acc = self.orientation_acc.slice_mut(s![k0..k1, .., .., ..]);
+            let mut feature_acc = self.feature_acc.slice_mut(s![k0..k1, .., .., ..]);
+
+            par_azip!((index k,
+                    vx_layer in vx.outer_iter_mut(),
+                    tool_layer in tool_field.outer_iter_mut(),
+                    motion_layer in motion_acc.outer_iter_mut(),
+                    orientation_layer in orientation_acc.outer_iter_mut(),
+                    feature_layer in feature_acc.outer_iter_mut()) {
+                let k = k0 + k;
+                let layers = &cell_layers[k];
+                let layer_segs = pending
+                    .get(layers.start - first_layer..(layers.end - first_layer).min(pending.len()))
+                    .unwrap_or(&[])
+                    .iter()
+                    .flatten();
+
+                grid.rasterize_cell(k, layer_segs, CellSlices {
+                    vx: vx_layer,
+                    tool: tool_layer,
+                    motion: motion_layer,
+                    orientation: orientation_layer,
+                    feature: feature_layer,
+                });
+
+                progress.add(1);
+            });
+        }
+
+        // Drop the layers that the remaining cells don't sample
+        self.next_cell = k1;
+        let needed = self
+            .cell_layers
+            .get(k1)
+            .map(|layers| layers.start)
+            .unwrap_or(done_layers);
+        let drained = (needed - self.first_layer).min(self.pending.len());
+        self.pending.drain(..drained);
+        self.first_layer = needed;
+    }
+}
+
+fn voxelize_segments(
+    summary: GcodeSummary,
+    replay: impl FnOnce(&mut dyn FnMut(Segment)) -> Result<(), failure::Error>,
+    options: &GcodeOptions,
+) -> Result<VoxelizedGcode, failure::Error> {
+    let GcodeSummary {
+        global_state,
+        layer_count: current_layer,
+        layer_z,
+        bbox: printer_bbox,
+        segment_count,
+        retractions,
+        seams,
+    } = summary;
+
+    if current_layer == 0 {
+        return Err(failure::err_msg("no layers found in gcode"));
+    }
+
+    if global_state.max_nozzle_diameter() == 0.0 {
+        return Err(failure::err_msg(
+            "no nozzle diameter found in gcode, check the --slicer setting",
+        ));
+    }
+
+    if options.extrusion_model == ExtrusionModel::Volumetric
+        && global_state.filament_diameter(0).is_none()
+    {
+        return Err(failure::err_msg(
+            "no filament diameter found in gcode, required by the volumetric extrusion model",
+        ));
+    }
+
+    let (layer_z, layer_thickness) = layer_heights(&layer_z);
+
+    let (retractions, seams) = if options.extract_seams {
+        (Some(retractions), Some(seams))
+    } else {
+        (None, None)
+    };
+
+    // The first layer was skipped because of the supports, but extend it after
+    let nozzle_diameter = global_state.max_nozzle_diameter();
+    let printer_bbox = BoundingBox {
+        min_x: printer_bbox.min_x - nozzle_diameter / 2.0,
+        min_y: printer_bbox.min_y - nozzle_diameter / 2.0,
+        min_z: printer_bbox.min_z - 2.0 * nozzle_diameter / 2.0,
+        max_x: printer_bbox.max_x + nozzle_diameter / 2.0,
+        max_y: printer_bbox.max_y + nozzle_diameter / 2.0,
+        max_z: printer_bbox.max_z + nozzle_diameter / 2.0,
+    };
+
+    // The layers are assumed to span the height of the overridden box
+    let printer_bbox = if let Some(bbox_override) = options.bbox_override {
+        debug!(
+            "overriding printing bounding box {:?} with {:?}",
+            printer_bbox, bbox_override
+        );
+        bbox_override
+    } else {
+        printer_bbox
+    };
+
+    // Height covered by each layer, before cropping
+    let full_min_z = printer_bbox.min_z;
+    let layer_height = printer_bbox.size().z / current_layer as f32;
+
+    let printer_bbox = if let Some(crop) = &options.crop {
+        printer_bbox
+            .intersection(crop)
+            .ok_or_else(|| failure::err_msg("crop region doesn't intersect the printed geometry"))?
+    } else {
+        printer_bbox
+    };
+
+    let bbox_min = printer_bbox.min();
+    let bbox_size = printer_bbox.size();
+
+    debug!(
+        "extracted {} line segments from gcode over {} layers",
+        segment_count, current_layer
+    );
+    debug!("printing bounding box: {:?}", printer_bbox);
+
+    let (xc, yc, zc) = if let Some([xc, yc, zc]) = options.resolution {
+        (xc, yc, zc)
+    } else if let Some(voxel_size) = options.voxel_size_mm {
+        (
+            (bbox_size.x / voxel_size).ceil() as usize,
+            (bbox_size.y / voxel_size).ceil() as usize,
+            (bbox_size.z / voxel_size).ceil() as usize,
+        )
+    } else {
+        // One cell per layer
+        let zc = if options.crop.is_some() {
+            (bbox_size.z / layer_height - 1e-3).ceil() as usize
+        } else {
+            current_layer
+        };
+
+        (
+            (bbox_size.x / layer_height * options.xy_sampling_factor).ceil() as usize,
+            (bbox_size.y / layer_height * options.xy_sampling_factor).ceil() as usize,
+            zc,
+        )
+    };
+    debug!("computed voxel grid size: {}x{}x{}", xc, yc, zc);
+
+    if xc == 0 || yc == 0 || zc == 0 {
+        return Err(failure::err_msg(format!(
+            "invalid voxel grid size: {}x{}x{}",
+            xc, yc, zc
+        )));
+    }
+
+    let c = nalgebra::Vector3::new(xc as f32, yc as f32, zc as f32);
+
+    // Allocate voxel grid
+    let vx = ndarray::Array3::<u8>::zeros((zc, yc, xc));
+
+    // The tool grid is only allocated if requested, but still has one (empty) slice per layer
+    let tool_field = ndarray::Array3::<u8>::zeros(if options.extract_tool_field {
+        (zc, yc, xc)
+    } else {
+        (zc, 0, 0)
+    });
+
+    // Accumulators for the weighted feedrate, fan speed and total weight, same as above
+    let motion_acc = ndarray::Array4::<f32>::zeros(if options.extract_motion_fields {
+        (zc, yc, xc, 3)
+    } else {
+        (zc, 0, 0, 3)
+    });
+
+    // Accumulators for the weighted segment direction outer products, same as above
+    let orientation_acc = ndarray::Array4::<f32>::zeros(if options.extract_orientation_field {
+        (zc, yc, xc, TENSOR_COMPONENTS)
+    } else {
+        (zc, 0, 0, TENSOR_COMPONENTS)
+    });
+
+    // Accumulators for the coverage of each feature type, same as above
+    let feature_acc = ndarray::Array4::<f32>::zeros(if options.extract_feature_field {
+        (zc, yc, xc, FEATURE_NAMES.len())
+    } else {
+        (zc, 0, 0, FEATURE_NAMES.len())
+    });
+
+    // Extrusions span the thickness of their layer if the Z cells don't follow the layers
+    let vertical_extrusions =
+        (options.resolution.is_some() || options.voxel_size_mm.is_some()) && zc != current_layer;
+
+    // Range of the gcode layers sampled by each Z cell. With one cell per layer, layers span the
+    // same height and each cell samples the layer at its center.
+    let cell_layers: Vec<_> = if vertical_extrusions {
+        cell_layer_ranges(
+            zc,
+            bbox_min.z,
+            bbox_size.z / zc as f32,
+            &layer_z,
+            &layer_thickness,
+            global_state.max_nozzle_diameter(),
+        )
+    } else {
+        (0..zc)
+            .map(|k| {
+                let z = bbox_min.z + (k as f32 + 0.5) * bbox_size.z / zc as f32;
+                let layer = (((z - full_min_z) / layer_height) as usize).min(current_layer - 1);
+                layer..layer + 1
+            })
+            .collect()
+    };
+
+    let cell_scale = c.xy().component_div(&bbox_size.xy());
+
+    // The GPU path needs all the segments at once, they are rasterized on the CPU if it fails
+    let mut replay = Some(replay);
+    let mut gpu_layers = None;
+
+    if options.gpu {
+        if options.extract_tool_field
+            || options.extract_motion_fields
+            || options.extract_orientation_field
+            || options.extract_feature_field
+        {
+            warn!("GPU voxelization only computes the geometry, using the CPU instead");
+        } else if vertical_extrusions {
+            warn!("GPU voxelization needs one Z cell per layer, using the CPU instead");
+        } else if options.extrusion_model != ExtrusionModel::Nozzle {
+            warn!("GPU voxelization only models nozzle-wide extrusions, using the CPU instead");
+        } else {
+            let mut layers = vec![Vec::new(); current_layer];
+            replay.take().unwrap()(&mut |seg: Segment| {
+                if let Some(layer) = seg.state.layer {
+                    layers[layer].push(seg);
+                }
+            })?;
+
+            let cell_layers: Vec<_> = cell_layers.iter().map(|layers| layers.start).collect();
+
+            match voxelize_gcode_gpu(
+                &layers,
+                &cell_layers,
+                &global_state,
+                &printer_bbox,
+                (xc, yc, zc),
+                options.samples,
+                options.gl_backend,
+            ) {
+                Ok(vx) => {
+                    return Ok(VoxelizedGcode {
+                        geometry: ParamField::new_u8(printer_bbox, vx),
+                        tool: None,
+                        feedrate: None,
+                        fan: None,
+                        orientation: None,
+                        feature: None,
+                        layer_z,
+                        layer_thickness,
+                        retractions,
+                        seams,
+                    })
+                }
+                Err(error) => {
+                    warn!("GPU voxelization failed, using the CPU instead: {}", error);
+                    gpu_layers = Some(layers);
+                }
+            }
+        }
+    }
+
+    // Thin extrusions can fall between cell samples if the nozzle is smaller than a cell
+    let nozzle_footprint = cell_scale * global_state.min_nozzle_diameter();
+    if nozzle_footprint.x < 1.0 || nozzle_footprint.y < 1.0 {
+        warn!(
+            "nozzle footprint is smaller than one cell ({:.2}x{:.2} cells), increase the XY sampling factor",
+            nozzle_footprint.x, nozzle_footprint.y
+        );
+    }
+
+    let mut stream = LayerStream {
+        grid: GcodeGrid {
+            options,
+            global_state: &global_state,
+            layer_thickness: &layer_thickness,
+            vertical_extrusions,
+            bbox_min,
+            bbox_size,
+            c,
+            cell_scale,
+            xc,
+            yc,
+        },
+        cell_layers: &cell_layers,
+        pending: Vec::new(),
+        first_layer: cell_layers[0].start,
+        done_layers: 0,
+        next_cell: 0,
+        vx,
+        tool_field,
+        motion_acc,
+        orientation_acc,
+        feature_acc,
+        progress: Progress::new(&options.progress, "voxelizing layers: ", zc),
+    };
+
+    match replay {
+        Some(replay) => replay(&mut |seg| stream.push(seg))?,
+        None => gpu_layers
+            .into_iter()
+            .flatten()
+            .flatten()
+            .for_each(|seg| stream.push(seg)),
+    }
+
+    stream.done_layers = current_layer;
+    stream.flush(true);
+
+    let LayerStream {
+        vx,
+        tool_field,
+        motion_acc,
+        orientation_acc,
+        feature_acc,
+        progress,
+        ..
+    } = stream;
+
+    drop(progress);
+
+    let (feedrate, fan) = if options.extract_motion_fields {
+        let mut feedrate = ndarray::Array3::<f32>::zeros((zc, yc, xc));
+        let mut fan = ndarray::Array3::<f32>::zeros((zc, yc, xc));
+
+        par_azip!((f in &mut feedrate, p in &mut fan, acc in motion_acc.lanes(Axis(3))) {
+            if acc[2] > 0.0 {
+                *f = acc[0] / acc[2];
+                *p = acc[1] / acc[2];
+            }
+        });
+
+        (
+            Some(ParamField::new_f32(printer_bbox, feedrate)),
+            Some(ParamField::new_f32(printer_bbox, fan)),
+        )
+    } else {
+        (None, None)
+    };
+
+    let feature = if options.extract_feature_field {
+        let mut feature = ndarray::Array3::<u8>::zeros((zc, yc, xc));
+
+        // Dominant feature, the background code is never accumulated
+        par_azip!((f in &mut feature, acc in feature_acc.lanes(Axis(3))) {
+            let (code, w) = acc
+                .iter()
+                .enumerate()
+                .fold((0, 0.0), |(c, w), (l, v)| if *v > w { (l, *v) } else { (c, w) });
+
+            if w > 0.0 {
+                *f = code as u8;
+            }
+        });
+
+        Some(ParamField::new_u8(printer_bbox, feature))
+    } else {
+        None
+    };
+
+    Ok(VoxelizedGcode {
+        geometry: ParamField::new_u8(printer_bbox, vx),
+        tool: if options.extract_tool_field {
+            Some(ParamField::new_u8(printer_bbox, tool_field))
+        } else {
+            None
+        },
+        feedrate,
+        fan,
+        orientation: if options.extract_orientation_field {
+            Some(ParamField::new_tensor(printer_bbox, orientation_acc))
+        } else {
+            None
+        },
+        feature,
+        layer_z,
+        layer_thickness,
+        retractions,
+        seams,
+    })
+}
+
+use tinygl::gl;
+use tinygl::prelude::*;
+
+/// Workgroup size of the gcode compute shader along X and Y
+const GCODE_LOCAL_SIZE: usize = 8;
+
+/// Rasterize the segments of each gcode layer on the GPU. `cell_layers` holds the index of the
+/// layer sampled by each Z cell. The result matches the CPU path, except for the positions of the
+/// random samples.
+fn voxelize_gcode_gpu(
+    layers: &[Vec<Segment>],
+    cell_layers: &[usize],
+    global_state: &GlobalState,
+    bbox: &BoundingBox<f32>,
+    dims: (usize, usize, usize),
+    samples: usize,
+    gl_backend: Option<GlBackend>,
+) -> Result<ndarray::Array3<u8>, failure::Error> {
+    let (xc, yc, zc) = dims;
+    let mut vx = ndarray::Array3::<u8>::zeros((zc, yc, xc));
+
+    let bbox_min = bbox.min();
+    let bbox_size = bbox.size();
+    let c = nalgebra::Vector3::new(xc as f32, yc as f32, zc as f32);
+    let cell_scale = c.xy().component_div(&bbox_size.xy());
+
+    // Start, end and half nozzle size of the segments in voxel coordinates, layer by layer
+    let mut segments: Vec<[f32; 8]> = Vec::new();
+    let mut layer_ranges = Vec::with_capacity(layers.len());
+
+    for layer in layers {
+        layer_ranges.push([segments.len() as u32, layer.len() as u32]);
+
+        for seg in layer {
+            let start = (seg.start - bbox_min)
+                .component_div(&bbox_size)
+                .component_mul(&c);
+            let end = (seg.end - bbox_min)
+                .component_div(&bbox_size)
+                .component_mul(&c);
+            let nozzle = cell_scale * global_state.nozzle_diameter(seg.state.tool) / 2.0;
+
+            segments.push([start.x, start.y, end.x, end.y, nozzle.x, nozzle.y, 0.0, 0.0]);
+        }
+    }
+
+    if segments.is_empty() {
+        return Ok(vx);
+    }
+
+    let cell_ranges: Vec<_> = cell_layers.iter().map(|l| layer_ranges[*l]).collect();
+
+    let HeadlessGl { gl, _handle } = HeadlessGl::new(gl_backend)?;
+
+    let prog = shaders::GcodeProgram::build(&gl)
+        .map_err(|emsg| failure::err_msg(format!("failed to build program: {}", emsg)))?;
+
+    // Upload segments and their ranges for each Z cell
+    let upload = |bytes: &[u8], binding: u32| {
+        let buffer = tinygl::wrappers::Buffer::new(&gl)
+            .map_err(|_| failure::err_msg("failed to create storage buffer"))?;
+
+        unsafe {
+            buffer.bind(&gl, gl::SHADER_STORAGE_BUFFER);
+            gl.buffer_data_u8_slice(gl::SHADER_STORAGE_BUFFER, bytes, gl::STATIC_DRAW);
+            gl.bind_buffer_base(gl::SHADER_STORAGE_BUFFER, binding, Some(&buffer));
+        }
+
+        Ok::<_, failure::Error>(buffer)
+    };
+
+    let _segments_buffer = upload(
+        unsafe {
+            std::slice::from_raw_parts(
+                segments.as_ptr() as *const u8,
+                std::mem::size_of_val(&segments[..]),
+            )
+        },
+        0,
+    )?;
+    let _ranges_buffer = upload(
+        unsafe {
+            std::slice::from_raw_parts(
+                cell_ranges.as_ptr() as *const u8,
+                std::mem::size_of_val(&cell_ranges[..]),
+            )
+        },
+        1,
+    )?;
+
+    // Coverage texture
+    let geometry_texture = tinygl::wrappers::GlRefHandle::new(
+        &gl,
+        tinygl::wrappers::Texture::new(&gl).map_err(|emsg| {
+            failure::err_msg(format!("failed to create geometry texture: {}", emsg))
+        })?,
+    );
+
+    unsafe {
+        geometry_texture.bind(&gl, gl::TEXTURE_3D);
+        gl.tex_storage_3d(gl::TEXTURE_3D, 1, gl::R8, xc as i32, yc as i32, zc as i32);
+
+        prog.use_program(&gl);
+        prog.set_u_samples(&gl, samples as i32);
+
+        gl.bind_image_texture(
+            prog.get_u_geometry_binding(),
+            Some(&geometry_texture),
+            0,
+            true,
+            0,
+            gl::WRITE_ONLY,
+            gl::R8,
+        );
+
+        gl.dispatch_compute(
+            ((xc + GCODE_LOCAL_SIZE - 1) / GCODE_LOCAL_SIZE) as u32,
+            ((yc + GCODE_LOCAL_SIZE - 1) / GCODE_LOCAL_SIZE) as u32,
+            zc as u32,
+        );
+
+        gl.memory_barrier(gl::TEXTURE_UPDATE_BARRIER_BIT);
 
-    Ok(ParamField::new_u8(printer_bbox, vx))
-}
+        // Fetch coverage, rows are tightly packed
+        gl.pixel_store_i32(gl::PACK_ALIGNMENT, 1);
+        gl.get_tex_image_u8_slice(
+            gl::TEXTURE_3D,
+            0,
+            gl::RED,
+            gl::UNSIGNED_BYTE,
+            Some(vx.as_slice().unwrap()),
+        );
 
-use glutin::event_loop::EventLoop;
-use glutin::ContextBuilder;
+        gl.check_last_error().map_err(|emsg| {
+            failure::err_msg(format!("failed to voxelize gcode on the GPU: {}", emsg))
+        })?;
+    }
 
-use tinygl::gl;
-use tinygl::prelude::*;
+    Ok(vx)
+}
 
 fn render_axis(
     mesh_bbox: &BoundingBox<f32>,
@@ -460,13 +1863,49 @@ fn render_axis(
     }
 }
 
-fn write_depth_img(
-    buf: &ndarray::Array2<f32>,
-    dest: impl AsRef<Path>,
-) -> Result<(), failure::Error> {
+/// Destination of the depth images exported while voxelizing meshes
+#[derive(Debug, Clone)]
+pub struct DepthImages {
+    /// Directory the images are written to
+    pub dir: PathBuf,
+    /// Prefix of the image file names
+    pub prefix: String,
+}
+
+impl DepthImages {
+    /// Images prefixed with the file stem of `output`, written to `dir` or next to `output`
+    pub fn for_output(output: &Path, dir: Option<&Path>) -> Self {
+        Self {
+            dir: dir
+                .or_else(|| output.parent())
+                .map(Path::to_owned)
+                .unwrap_or_default(),
+            prefix: output
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Path of the image with the given name, e.g. `zplus`
+    pub fn path(&self, name: &str) -> PathBuf {
+        if self.prefix.is_empty() {
+            self.dir.join(format!("{}.png", name))
+        } else {
+            self.dir.join(format!("{}_{}.png", self.prefix, name))
+        }
+    }
+
+    /// Create the destination directory if it doesn't exist yet
+    pub fn create_dir(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+    }
+}
+
+fn depth_img(buf: &ndarray::Array2<f32>) -> image::GrayImage {
     let (height, width) = buf.dim();
 
-    let img = image::GrayImage::from_fn(width as u32, height as u32, |x, y| {
+    image::GrayImage::from_fn(width as u32, height as u32, |x, y| {
         let mut v = buf.get((y as usize, x as usize)).unwrap() * 255.0;
         if v < 0.0 {
             v = 0.0;
@@ -475,9 +1914,38 @@ fn write_depth_img(
             v = 255.0;
         }
         image::Luma([v as u8])
-    });
+    })
+}
+
+fn write_depth_img(
+    buf: &ndarray::Array2<f32>,
+    dest: impl AsRef<Path>,
+) -> Result<(), failure::Error> {
+    depth_img(buf).save(dest.as_ref())?;
+    Ok(())
+}
+
+/// Write the depth images as a single sheet, with the 3 closest point images on the first row and
+/// the 3 furthest point images on the second
+fn write_contact_sheet(
+    bufs: &[&ndarray::Array2<f32>; 6],
+    dest: impl AsRef<Path>,
+) -> Result<(), failure::Error> {
+    let panels: Vec<_> = bufs.iter().map(|buf| depth_img(buf)).collect();
+    let panel_width = panels.iter().map(|panel| panel.width()).max().unwrap();
+    let panel_height = panels.iter().map(|panel| panel.height()).max().unwrap();
+
+    let mut sheet = image::GrayImage::new(3 * panel_width, 2 * panel_height);
+    for (idx, panel) in panels.iter().enumerate() {
+        image::imageops::replace(
+            &mut sheet,
+            panel,
+            (idx % 3) as u32 * panel_width,
+            (idx / 3) as u32 * panel_height,
+        );
+    }
 
-    img.save(dest.as_ref())?;
+    sheet.save(dest.as_ref())?;
     Ok(())
 }
 
@@ -485,28 +1953,14 @@ pub fn voxelize_mesh(
     mesh: &stl_io::IndexedMesh,
     mesh_bbox: &BoundingBox<f32>,
     printed_field: &ParamField,
-    export_depth_images: bool,
+    depth_images: Option<&DepthImages>,
+    gl_backend: Option<GlBackend>,
 ) -> Result<ParamField, failure::Error> {
-    let el = EventLoop::new();
-    let sz = glutin::dpi::PhysicalSize::new(128, 128);
-    let headless_context = ContextBuilder::new()
-        .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (4, 6)))
-        .with_gl_profile(glutin::GlProfile::Core)
-        .with_gl_debug_flag(true)
-        .build_headless(&el, sz)?;
-
-    let (gl, _headless_context) = unsafe {
-        let headless_context = headless_context
-            .make_current()
-            .map_err(|_| failure::err_msg("failed to make context current"))?;
+    if let Some(depth_images) = depth_images {
+        depth_images.create_dir()?;
+    }
 
-        (
-            tinygl::Context::from_loader_function(|s| {
-                headless_context.get_proc_address(s) as *const _
-            }),
-            headless_context,
-        )
-    };
+    let HeadlessGl { gl, _handle } = HeadlessGl::new(gl_backend)?;
 
     // VAO
     let _vao = unsafe {
@@ -619,9 +2073,9 @@ pub fn voxelize_mesh(
         )?
     };
 
-    if export_depth_images {
-        write_depth_img(&zplus, "zplus.png")?;
-        write_depth_img(&zminus, "zminus.png")?;
+    if let Some(depth_images) = depth_images {
+        write_depth_img(&zplus, depth_images.path("zplus"))?;
+        write_depth_img(&zminus, depth_images.path("zminus"))?;
     }
 
     let get_tran = |rot: nalgebra::Matrix4<f32>| rot * nalgebra::Matrix4::new_translation(&-center);
@@ -646,9 +2100,9 @@ pub fn voxelize_mesh(
         )?
     };
 
-    if export_depth_images {
-        write_depth_img(&yplus, "yplus.png")?;
-        write_depth_img(&yminus, "yminus.png")?;
+    if let Some(depth_images) = depth_images {
+        write_depth_img(&yplus, depth_images.path("yplus"))?;
+        write_depth_img(&yminus, depth_images.path("yminus"))?;
     }
 
     // Render X axis
@@ -671,9 +2125,13 @@ pub fn voxelize_mesh(
         )?
     };
 
-    if export_depth_images {
-        write_depth_img(&xplus, "xplus.png")?;
-        write_depth_img(&xminus, "xminus.png")?;
+    if let Some(depth_images) = depth_images {
+        write_depth_img(&xplus, depth_images.path("xplus"))?;
+        write_depth_img(&xminus, depth_images.path("xminus"))?;
+        write_contact_sheet(
+            &[&zplus, &yplus, &xplus, &zminus, &yminus, &xminus],
+            depth_images.path("depth"),
+        )?;
     }
 
     // Compute visibility from depth buffers
@@ -716,3 +2174,1000 @@ pub fn voxelize_mesh(
 
     Ok(ParamField::new_u8(printed_field.field_box_mm, vis))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tessellate_quarter_arc() {
+        let tolerance = 0.05;
+        let start = nalgebra::Vector2::new(10.0, 0.0);
+        let end = nalgebra::Vector2::new(0.0, 10.0);
+        let center = nalgebra::Vector2::new(0.0, 0.0);
+
+        let points = tessellate_arc(start, end, center, false, tolerance);
+
+        assert!(points.len() > 1);
+        assert_eq!(*points.last().unwrap(), end);
+
+        let mut last = start;
+        for p in &points {
+            // Points lie on the circle
+            assert!((p.norm() - 10.0).abs() < 1e-4);
+            // Counter-clockwise quarter: stays in the first quadrant
+            assert!(p.x >= -1e-4 && p.y >= -1e-4);
+            // Chord midpoints stay within tolerance of the arc
+            assert!(10.0 - ((last + p) / 2.0).norm() <= tolerance + 1e-4);
+            last = *p;
+        }
+    }
+
+    #[test]
+    fn tessellate_clockwise_and_full_arcs() {
+        let start = nalgebra::Vector2::new(1.0, 0.0);
+        let end = nalgebra::Vector2::new(0.0, 1.0);
+        let center = nalgebra::Vector2::new(0.0, 0.0);
+
+        // Clockwise from (1, 0) to (0, 1) goes the long way around
+        let ccw = tessellate_arc(start, end, center, false, 0.001);
+        let cw = tessellate_arc(start, end, center, true, 0.001);
+        assert!(cw.len() > 2 * ccw.len());
+        assert!(cw.iter().any(|p| p.y < -0.5));
+
+        // Full circle when start == end
+        let full = tessellate_arc(start, start, center, false, 0.001);
+        assert_eq!(*full.last().unwrap(), start);
+        assert!(full.iter().any(|p| p.x < -0.9));
+    }
+
+    fn segment_ends(src: &str) -> Vec<([f32; 3], [f32; 3])> {
+        parse_gcode(src, 0.05, Slicer::IceSL, &FEATURE_REGEX)
+            .unwrap()
+            .segments
+            .iter()
+            .map(|seg| {
+                (
+                    [seg.start.x, seg.start.y, seg.start.z],
+                    [seg.end.x, seg.end.y, seg.end.z],
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_absolute_extrusion() {
+        // The last move retracts filament: E decreases in absolute mode
+        let segs = segment_ends("G1 X0 Y0 Z0.2 E1\nG1 X10 E2\nG1 X10 Y10 E1.5\n");
+        assert_eq!(segs, vec![([0., 0., 0.2], [10., 0., 0.2])]);
+    }
+
+    #[test]
+    fn parse_relative_positioning() {
+        let segs = segment_ends("G0 X1 Y1 Z0.2\nG91\nG1 X10 E1\nG1 Y10 E1\nG90\nG1 X0 Y0 E3\n");
+        assert_eq!(
+            segs,
+            vec![
+                ([1., 1., 0.2], [11., 1., 0.2]),
+                ([11., 1., 0.2], [11., 11., 0.2]),
+                ([11., 11., 0.2], [0., 0., 0.2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_relative_extruder() {
+        let segs = segment_ends("M83\nG1 X0 Y0 Z0.2\nG1 X10 E0.5\nG1 X20 E0.5\nG1 X30 E-1\n");
+        assert_eq!(
+            segs,
+            vec![
+                ([0., 0., 0.2], [10., 0., 0.2]),
+                ([10., 0., 0.2], [20., 0., 0.2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_coordinate_reset() {
+        // Resetting E doesn't turn the next move into a retraction
+        let segs = segment_ends("G1 X0 Y0 Z0.2 E5\nG92 E0\nG1 X10 E1\n");
+        assert_eq!(segs, vec![([0., 0., 0.2], [10., 0., 0.2])]);
+
+        // Resetting X shifts subsequent absolute coordinates without motion
+        let segs = segment_ends("G1 X5 Y0 Z0.2\nG92 X0\nG1 X10 E1\n");
+        assert_eq!(segs, vec![([5., 0., 0.2], [15., 0., 0.2])]);
+    }
+
+    #[test]
+    fn voxelize_two_tools() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\n; nozzle_diameter_mm_1 : 0.8\n");
+        for z in &[0.2, 0.4] {
+            src.push_str("; <layer>\n");
+            src.push_str(&format!("T0\nG0 X0 Y0 Z{}\nG1 X10 E1\nG92 E0\n", z));
+            src.push_str(&format!("T1\nG0 X0 Y5 Z{}\nG1 X10 E1\nG92 E0\n", z));
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let parsed = parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap();
+        assert_eq!(parsed.summary.global_state.nozzle_diameter(0), 0.4);
+        assert_eq!(parsed.summary.global_state.nozzle_diameter(1), 0.8);
+        assert_eq!(parsed.summary.global_state.nozzle_diameter(2), 0.4);
+        assert!(parsed.segments.iter().any(|seg| seg.state.tool == 1));
+
+        let result = voxelize_parsed_gcode(
+            parsed,
+            &GcodeOptions {
+                xy_sampling_factor: 4.0,
+                extract_tool_field: true,
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let geometry = result.geometry.as_u8().unwrap();
+        let tool = result.tool.as_ref().unwrap();
+        let tool_data = tool.as_u8().unwrap();
+        assert_eq!(geometry.dim(), tool_data.dim());
+
+        // Voxel index of a point on the top layer
+        let (_, yc, xc) = tool_data.dim();
+        let bbox = tool.field_box_mm;
+        let index = |x: f32, y: f32| {
+            (
+                1,
+                ((y - bbox.min_y) / (bbox.max_y - bbox.min_y) * yc as f32) as usize,
+                ((x - bbox.min_x) / (bbox.max_x - bbox.min_x) * xc as f32) as usize,
+            )
+        };
+
+        assert!(geometry[index(5.0, 0.0)] > 0);
+        assert_eq!(tool_data[index(5.0, 0.0)], 0);
+        assert!(geometry[index(5.0, 5.0)] > 0);
+        assert_eq!(tool_data[index(5.0, 5.0)], 1);
+        // Wider nozzle for the second tool
+        assert!(geometry[index(5.0, 5.3)] > 0);
+        assert_eq!(tool_data[index(5.0, 5.3)], 1);
+        assert_eq!(geometry[index(5.0, -0.3)], 0);
+        assert_eq!(geometry[index(5.0, 2.5)], 0);
+    }
+
+    #[test]
+    fn voxelize_motion_fields() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\n");
+        for z in &[0.2, 0.4] {
+            src.push_str("; <layer>\n");
+            // Back and forth on the same line at two different feedrates
+            src.push_str(&format!(
+                "G92 E0\nG0 X0 Y0 Z{}\nG1 X10 E1 F1200\nG1 X0 E2 F600\n",
+                z
+            ));
+            src.push_str("M106 S255\nG0 X0 Y5\nG1 X10 E3 F3000\nM107\n");
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let result = voxelize_parsed_gcode(
+            parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &GcodeOptions {
+                xy_sampling_factor: 4.0,
+                extract_motion_fields: true,
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(result.tool.is_none());
+        let feedrate = result.feedrate.as_ref().unwrap();
+        let feedrate_data = feedrate.as_f32().unwrap();
+        let fan_data = result.fan.as_ref().unwrap().as_f32().unwrap();
+
+        let (_, yc, xc) = feedrate_data.dim();
+        let bbox = feedrate.field_box_mm;
+        let index = |x: f32, y: f32| {
+            (
+                1,
+                ((y - bbox.min_y) / (bbox.max_y - bbox.min_y) * yc as f32) as usize,
+                ((x - bbox.min_x) / (bbox.max_x - bbox.min_x) * xc as f32) as usize,
+            )
+        };
+
+        assert!((feedrate_data[index(5.0, 0.0)] - 900.0).abs() < 1e-2);
+        assert_eq!(fan_data[index(5.0, 0.0)], 0.0);
+        assert!((feedrate_data[index(5.0, 5.0)] - 3000.0).abs() < 1e-2);
+        assert!((fan_data[index(5.0, 5.0)] - 255.0).abs() < 1e-2);
+        assert_eq!(feedrate_data[index(5.0, 2.5)], 0.0);
+    }
+
+    #[test]
+    fn voxelize_orientation_field() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.4] {
+            src.push_str("; <layer>\n");
+            // One line along X, one along Y
+            src.push_str(&format!("G0 X0 Y0 Z{}\nG1 X10 E1\n", z));
+            src.push_str("G0 X10 Y5\nG1 Y10 E1\n");
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let result = voxelize_parsed_gcode(
+            parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &GcodeOptions {
+                xy_sampling_factor: 4.0,
+                extract_orientation_field: true,
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let orientation = result.orientation.as_ref().unwrap();
+        let orientation_data = orientation.as_vec3().unwrap();
+        let (_, yc, xc, components) = orientation_data.dim();
+        assert_eq!(components, TENSOR_COMPONENTS);
+
+        let bbox = orientation.field_box_mm;
+        let index = |x: f32, y: f32, l: usize| {
+            (
+                1,
+                ((y - bbox.min_y) / (bbox.max_y - bbox.min_y) * yc as f32) as usize,
+                ((x - bbox.min_x) / (bbox.max_x - bbox.min_x) * xc as f32) as usize,
+                l,
+            )
+        };
+
+        // xx only along the first line, yy only along the second
+        assert!(orientation_data[index(5.0, 0.0, 0)] > 0.0);
+        assert_eq!(orientation_data[index(5.0, 0.0, 3)], 0.0);
+        assert_eq!(orientation_data[index(10.0, 7.5, 0)], 0.0);
+        assert!(orientation_data[index(10.0, 7.5, 3)] > 0.0);
+
+        for l in [1, 2, 4, 5].iter().copied() {
+            assert_eq!(orientation_data[index(5.0, 0.0, l)], 0.0);
+            assert_eq!(orientation_data[index(10.0, 7.5, l)], 0.0);
+        }
+    }
+
+    #[test]
+    fn voxelize_feature_field() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.4] {
+            src.push_str("; <layer>\n");
+            src.push_str(&format!("; perimeter\nG0 X0 Y0 Z{}\nG1 X10 E1\n", z));
+            src.push_str(";TYPE:Bridge infill\nG0 X10 Y5\nG1 Y10 E1\n");
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let result = voxelize_parsed_gcode(
+            parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &GcodeOptions {
+                xy_sampling_factor: 4.0,
+                extract_feature_field: true,
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let feature = result.feature.as_ref().unwrap();
+        let feature_data = feature.as_u8().unwrap();
+        let (_, yc, xc) = feature_data.dim();
+
+        let bbox = feature.field_box_mm;
+        let index = |x: f32, y: f32| {
+            (
+                1,
+                ((y - bbox.min_y) / (bbox.max_y - bbox.min_y) * yc as f32) as usize,
+                ((x - bbox.min_x) / (bbox.max_x - bbox.min_x) * xc as f32) as usize,
+            )
+        };
+
+        assert_eq!(feature_data[index(5.0, 0.0)], Feature::Perimeter as u8);
+        assert_eq!(feature_data[index(10.0, 7.5)], Feature::Bridge as u8);
+        assert_eq!(feature_data[index(5.0, 7.5)], 0);
+        assert_eq!(FEATURE_NAMES[Feature::Bridge as usize], "bridge");
+    }
+
+    #[test]
+    fn variable_layer_heights() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.5, 0.7, 1.0] {
+            // The Z move follows the layer marker
+            src.push_str(&format!(
+                "; <layer>\nG0 X0 Y0 Z{}\nG1 X10 E1\n; </layer>\n",
+                z
+            ));
+        }
+
+        let parsed = parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap();
+        let (layer_z, layer_thickness) = layer_heights(&parsed.summary.layer_z);
+
+        let expected_z = [0.2, 0.5, 0.7, 1.0];
+        let expected_thickness = [0.2, 0.3, 0.2, 0.3];
+        assert_eq!(layer_z.len(), 4);
+        for l in 0..4 {
+            assert!((layer_z[l] - expected_z[l]).abs() < 1e-5);
+            assert!((layer_thickness[l] - expected_thickness[l]).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn parse_slicer_dialects() {
+        for (src, expected_z, expected_feature) in &[
+            (
+                include_str!("../fixtures/icesl_header.gcode"),
+                &[0.2][..],
+                Feature::Perimeter,
+            ),
+            (
+                include_str!("../fixtures/cura_header.gcode"),
+                &[0.27, 0.37][..],
+                Feature::Infill,
+            ),
+            (
+                include_str!("../fixtures/prusa_header.gcode"),
+                &[0.2, 0.4][..],
+                Feature::Infill,
+            ),
+        ] {
+            let slicer = Slicer::detect(src.lines()).unwrap();
+            let parsed = parse_gcode(src, 0.05, slicer, &FEATURE_REGEX).unwrap();
+            let (layer_z, _) = layer_heights(&parsed.summary.layer_z);
+
+            assert_eq!(parsed.summary.layer_count, expected_z.len(), "{:?}", slicer);
+            for (z, expected) in layer_z.iter().zip(expected_z.iter()) {
+                assert!((z - expected).abs() < 1e-5, "{:?}", slicer);
+            }
+            assert_eq!(parsed.summary.global_state.nozzle_diameter(0), 0.4);
+
+            let last = parsed.segments.last().unwrap();
+            assert_eq!(last.state.layer, Some(expected_z.len() - 1));
+            assert_eq!(last.state.feature as u8, *expected_feature as u8);
+        }
+    }
+
+    #[test]
+    fn extract_seams_square_perimeters() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.4, 0.6] {
+            src.push_str("; <layer>\n");
+            src.push_str(&format!("G0 X0 Y0 Z{}\n", z));
+            // Square perimeter, back to its start
+            src.push_str("G1 X10 E1\nG1 Y10 E1\nG1 X0 E1\nG1 Y0 E1\n");
+            // Open line of infill after a retraction and travel
+            src.push_str("G1 E-1\nG0 X2 Y2\nG1 E1\nG1 X8 Y8 E1\n");
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let options = GcodeOptions {
+            extract_seams: true,
+            ..stream_options(None)
+        };
+        let result = voxelize_parsed_gcode(
+            parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &options,
+        )
+        .unwrap();
+
+        let seams = result.seams.unwrap();
+        assert_eq!(seams.len(), 3);
+        for (layer, seam) in seams.iter().enumerate() {
+            assert_eq!(seam[..2], [0.0, 0.0]);
+            assert_eq!(seam[3], layer as f32);
+        }
+
+        let retractions = result.retractions.unwrap();
+        assert_eq!(retractions.len(), 3);
+        assert_eq!(retractions[1][..2], [0.0, 0.0]);
+        assert_eq!(retractions[1][3], 1.0);
+    }
+
+    #[test]
+    fn layer_heights_out_of_order() {
+        let (layer_z, layer_thickness) = layer_heights(&[Some(0.2), None, Some(0.1)]);
+        assert_eq!(layer_z, vec![0.2, 0.2, 0.1]);
+        assert!(layer_thickness[1] == 0.0 && layer_thickness[2] < 0.0);
+    }
+
+    #[test]
+    fn voxelize_gpu_matches_cpu() {
+        // Only run where an OpenGL 4.6 context is available
+        if let Err(error) = HeadlessGl::new(None) {
+            eprintln!("skipping GPU voxelization test: {}", error);
+            return;
+        }
+
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.4, 0.6] {
+            src.push_str("; <layer>\n");
+            src.push_str(&format!(
+                "G0 X0 Y0 Z{}\nG1 X10 E1\nG1 Y5 E1\nG1 X2 Y1 E1\n",
+                z
+            ));
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let voxelize = |gpu| {
+            voxelize_parsed_gcode(
+                parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+                &GcodeOptions {
+                    // The random sample positions differ between both paths
+                    samples: 1,
+                    xy_sampling_factor: 4.0,
+                    gpu,
+                    progress: ProgressConfig { quiet: true },
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .geometry
+        };
+
+        let cpu = voxelize(false);
+        let gpu = voxelize(true);
+        let cpu = cpu.as_u8().unwrap();
+        let gpu = gpu.as_u8().unwrap();
+
+        assert_eq!(cpu.dim(), gpu.dim());
+        assert!(cpu.iter().any(|v| *v > 0));
+        for (c, g) in cpu.iter().zip(gpu.iter()) {
+            assert!((*c as i32 - *g as i32).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn voxelize_mesh_egl() {
+        // Only run where an EGL surfaceless context is available, which doesn't need a display
+        if let Err(error) = HeadlessGl::new(Some(GlBackend::Egl)) {
+            eprintln!("skipping EGL voxelization test: {}", error);
+            return;
+        }
+
+        let mesh = crate::geometry::read_obj(
+            &mut "v 0 0 0\nv 4 0 0\nv 4 4 0\nv 0 4 0\nv 0 0 4\nv 4 0 4\nv 4 4 4\nv 0 4 4\n\
+                  f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 2 3 7 6\nf 3 4 8 7\nf 4 1 5 8\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mesh_bbox = crate::geometry::get_bounding_box(&mesh);
+
+        // Printed field around the cube
+        let printed_field = ParamField::new_u8(
+            BoundingBox {
+                min_x: -2.0,
+                min_y: -2.0,
+                min_z: -2.0,
+                max_x: 6.0,
+                max_y: 6.0,
+                max_z: 6.0,
+            },
+            ndarray::Array3::zeros((16, 16, 16)),
+        );
+
+        let mask = voxelize_mesh(
+            &mesh,
+            &mesh_bbox,
+            &printed_field,
+            None,
+            Some(GlBackend::Egl),
+        )
+        .unwrap();
+        let mask = mask.as_u8().unwrap();
+
+        assert_eq!(mask.dim(), (16, 16, 16));
+        assert!(mask.iter().any(|v| *v > 0));
+    }
+
+    #[test]
+    fn depth_image_paths() {
+        let depth_images = DepthImages::for_output(Path::new("results/part.h5"), None);
+        assert_eq!(
+            depth_images.path("zplus"),
+            Path::new("results").join("part_zplus.png")
+        );
+
+        let depth_images = DepthImages::for_output(Path::new("part.h5"), Some(Path::new("depth")));
+        assert_eq!(
+            depth_images.path("xminus"),
+            Path::new("depth").join("part_xminus.png")
+        );
+    }
+
+    #[test]
+    fn write_depth_images_to_new_dir() {
+        let dir = std::env::temp_dir()
+            .join("icesl2voxel_test_depth")
+            .join(format!("{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let depth_images = DepthImages::for_output(Path::new("part.h5"), Some(&dir));
+        depth_images.create_dir().unwrap();
+        assert!(dir.is_dir());
+
+        let wide = ndarray::Array2::from_elem((2, 4), 0.5f32);
+        let tall = ndarray::Array2::from_elem((3, 2), 1.0f32);
+        write_depth_img(&wide, depth_images.path("zplus")).unwrap();
+        write_contact_sheet(
+            &[&wide, &tall, &wide, &tall, &wide, &tall],
+            depth_images.path("depth"),
+        )
+        .unwrap();
+
+        assert!(dir.join("part_zplus.png").is_file());
+
+        let sheet = image::open(dir.join("part_depth.png")).unwrap().to_luma();
+        assert_eq!(sheet.dimensions(), (12, 6));
+        assert_eq!(sheet.get_pixel(0, 0)[0], 127);
+        assert_eq!(sheet.get_pixel(4, 0)[0], 255);
+        // Padding below the shorter panels
+        assert_eq!(sheet.get_pixel(0, 2)[0], 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn voxelize_fine_z_resolution() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for (z, y) in &[(0.2, 0.0), (0.4, 0.0), (0.6, 5.0)] {
+            src.push_str("; <layer>\n");
+            src.push_str(&format!("G0 X0 Y{y} Z{z}\nG1 X10 E1\n", y = y, z = z));
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let parsed = parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap();
+        assert_eq!(parsed.summary.layer_count, 3);
+
+        let result = voxelize_parsed_gcode(
+            parsed,
+            &GcodeOptions {
+                resolution: Some([40, 20, 12]),
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let geometry = result.geometry.as_u8().unwrap();
+        assert_eq!(geometry.dim(), (12, 20, 40));
+
+        let bbox = result.geometry.field_box_mm;
+        let index = |k: usize, x: f32, y: f32| {
+            (
+                k,
+                ((y - bbox.min_y) / (bbox.max_y - bbox.min_y) * 20.) as usize,
+                ((x - bbox.min_x) / (bbox.max_x - bbox.min_x) * 40.) as usize,
+            )
+        };
+
+        // Cells are 1/15 mm high from Z = 0, extrusions span the 0.2 mm below their layer
+        for k in 0..12 {
+            assert_eq!(
+                geometry[index(k, 5.0, 0.0)] > 0,
+                (0..6).contains(&k),
+                "cell {}",
+                k
+            );
+            assert_eq!(
+                geometry[index(k, 5.0, 5.0)] > 0,
+                (6..9).contains(&k),
+                "cell {}",
+                k
+            );
+        }
+    }
+
+    #[test]
+    fn voxelize_vertical_extrusions() {
+        // Lines as wide as the 1 mm cells, alternating between both rows
+        let mut src = String::from("; nozzle_diameter_mm_0 : 1.0\nM83\n");
+        for (z, y) in &[(0.2, -0.5), (0.4, 0.5), (0.6, -0.5)] {
+            src.push_str(&format!(
+                "; <layer>\nG0 X0 Y{} Z{}\nG1 X10 E1\n; </layer>\n",
+                y, z
+            ));
+        }
+
+        // Four Z cells per 0.2 mm layer, offset by half a cell from the layers
+        let bbox_override = BoundingBox {
+            min_x: 0.0,
+            min_y: -1.0,
+            min_z: 0.025,
+            max_x: 10.0,
+            max_y: 1.0,
+            max_z: 0.625,
+        };
+
+        let result = voxelize_parsed_gcode(
+            parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &GcodeOptions {
+                resolution: Some([10, 2, 12]),
+                bbox_override: Some(bbox_override),
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let geometry = result.geometry.as_u8().unwrap();
+        assert_eq!(geometry.dim(), (12, 2, 10));
+
+        // Three full cells per layer, and half of the cells on its boundaries
+        let expected: [[u8; 12]; 2] = [
+            [255, 255, 255, 127, 0, 0, 0, 127, 255, 255, 255, 127],
+            [0, 0, 0, 127, 255, 255, 255, 127, 0, 0, 0, 0],
+        ];
+
+        for (j, row) in expected.iter().enumerate() {
+            for (k, value) in row.iter().enumerate() {
+                let actual = geometry[(k, j, 5)];
+                assert!(
+                    (actual as i32 - *value as i32).abs() <= 1,
+                    "cell ({}, {}): {} != {}",
+                    k,
+                    j,
+                    actual,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bead_widths() {
+        // Round beads as wide as they are high
+        let area = std::f32::consts::PI * 0.2f32.powi(2) / 4.0;
+        assert!((bead_width(area, 0.2) - 0.2).abs() < 1e-6);
+
+        // Rectangle with semicircular sides
+        let area = (0.45 - 0.2) * 0.2 + std::f32::consts::PI * 0.1f32.powi(2);
+        assert!((bead_width(area, 0.2) - 0.45).abs() < 1e-6);
+    }
+
+    #[test]
+    fn voxelize_volumetric_width() {
+        let voxelize = |e: f32, extrusion_model| {
+            let src = format!(
+                "; nozzle_diameter_mm_0 : 0.4\n\
+                 ; filament_diameter_mm_0 : 1.75\n\
+                 M83\n\
+                 ; <layer>\nG0 X0 Y0 Z0.2\nG1 X10 E{}\n; </layer>\n",
+                e
+            );
+
+            voxelize_parsed_gcode(
+                parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+                &GcodeOptions {
+                    extrusion_model,
+                    // Rows of 0.1 mm, sampled at their centers
+                    samples: 1,
+                    resolution: Some([10, 20, 1]),
+                    bbox_override: Some(BoundingBox {
+                        min_x: 0.0,
+                        min_y: -1.0,
+                        min_z: 0.0,
+                        max_x: 10.0,
+                        max_y: 1.0,
+                        max_z: 0.2,
+                    }),
+                    ..stream_options(None)
+                },
+            )
+            .map(|result| result.geometry.as_u8().unwrap().clone())
+        };
+
+        let covered_rows = |geometry: &ndarray::Array3<u8>| {
+            geometry
+                .slice(s![0, .., 5])
+                .iter()
+                .filter(|v| **v > 0)
+                .count()
+        };
+
+        // Filament for 0.8 mm wide and 0.2 mm high beads over 10 mm
+        let filament_area = std::f32::consts::PI * 1.75f32.powi(2) / 4.0;
+        let e = ((0.8 - 0.2) * 0.2 + std::f32::consts::PI * 0.1f32.powi(2)) / filament_area * 10.0;
+
+        let volumetric = voxelize(e, ExtrusionModel::Volumetric).unwrap();
+        assert_eq!(covered_rows(&volumetric), 8);
+
+        // Over-extrusion is limited to three times the nozzle diameter
+        let clamped = voxelize(100.0, ExtrusionModel::Volumetric).unwrap();
+        assert_eq!(covered_rows(&clamped), 12);
+
+        // The nozzle model doesn't depend on the extruded filament
+        let nozzle = voxelize(e, ExtrusionModel::Nozzle).unwrap();
+        assert_eq!(covered_rows(&nozzle), 4);
+        assert_eq!(nozzle, voxelize(2.0 * e, ExtrusionModel::Nozzle).unwrap());
+    }
+
+    #[test]
+    fn voxelize_volumetric_needs_filament_diameter() {
+        let src =
+            "; nozzle_diameter_mm_0 : 0.4\nM83\n; <layer>\nG0 X0 Y0 Z0.2\nG1 X10 E1\n; </layer>\n";
+        let result = voxelize_parsed_gcode(
+            parse_gcode(src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &GcodeOptions {
+                extrusion_model: ExtrusionModel::Volumetric,
+                ..stream_options(None)
+            },
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn voxelize_bbox_override() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.4] {
+            src.push_str(&format!(
+                "; <layer>\nG0 X0 Y0.5 Z{}\nG1 X10 E1\n; </layer>\n",
+                z
+            ));
+        }
+
+        let bbox_override = BoundingBox {
+            min_x: -10.,
+            min_y: -10.,
+            min_z: 0.,
+            max_x: 20.,
+            max_y: 10.,
+            max_z: 0.6,
+        };
+        let options = GcodeOptions {
+            bbox_override: Some(bbox_override),
+            ..stream_options(Some([30, 20, 2]))
+        };
+        let result = voxelize_parsed_gcode(
+            parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+            &options,
+        )
+        .unwrap();
+
+        // One cell per mm in X and Y, the line runs through the centers of row 10
+        assert_eq!(result.geometry.field_box_mm, bbox_override);
+        let geometry = result.geometry.as_u8().unwrap();
+        assert!(geometry[(1, 10, 15)] > 0);
+        assert_eq!(geometry[(1, 10, 5)], 0);
+    }
+
+    fn stream_options(resolution: Option<[usize; 3]>) -> GcodeOptions {
+        GcodeOptions {
+            extract_tool_field: true,
+            extract_motion_fields: true,
+            resolution,
+            progress: ProgressConfig { quiet: true },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn voxelize_gcode_streaming_matches_memory() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for (l, z) in [0.2, 0.4, 0.6, 0.8].iter().enumerate() {
+            src.push_str("; <layer>\n");
+            src.push_str(&format!(
+                "G0 X0 Y{} Z{}\nG1 X10 E1 F{}\n",
+                l,
+                z,
+                900 * (l + 1)
+            ));
+            src.push_str(&format!("T{}\nG0 X{} Y0\nG1 Y5 E1\n", l % 2, l * 2));
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_streaming_{}.gcode",
+            std::process::id()
+        ));
+        std::fs::write(&path, &src).unwrap();
+
+        // More Z cells than a single batch, so layers are dropped while streaming
+        for resolution in &[None, Some([16, 16, 4 * STREAM_BATCH_CELLS + 3])] {
+            let options = stream_options(*resolution);
+            let streamed = voxelize_gcode(&path, &options).unwrap();
+            let in_memory = voxelize_parsed_gcode(
+                parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+                &options,
+            )
+            .unwrap();
+
+            assert_eq!(streamed.geometry.as_u8(), in_memory.geometry.as_u8());
+            assert_eq!(
+                streamed.tool.unwrap().as_u8(),
+                in_memory.tool.unwrap().as_u8()
+            );
+            assert_eq!(
+                streamed.feedrate.unwrap().as_f32(),
+                in_memory.feedrate.unwrap().as_f32()
+            );
+            assert_eq!(streamed.layer_z, in_memory.layer_z);
+            assert!(streamed.geometry.as_u8().unwrap().iter().any(|v| *v > 0));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn voxelize_gzip_gcode() {
+        use std::io::Write;
+
+        let src = include_str!("../fixtures/prusa_header.gcode");
+        let mut encoder = libflate::gzip::Encoder::new(Vec::new()).unwrap();
+        encoder.write_all(src.as_bytes()).unwrap();
+
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!("icesl2voxel_test_{}.gcode", std::process::id()));
+        let gzip = dir.join(format!("icesl2voxel_test_{}.gcode.gz", std::process::id()));
+        std::fs::write(&plain, src).unwrap();
+        std::fs::write(&gzip, encoder.finish().into_result().unwrap()).unwrap();
+
+        let options = stream_options(None);
+        let plain_result = voxelize_gcode(&plain, &options).unwrap();
+        let gzip_result = voxelize_gcode(&gzip, &options).unwrap();
+
+        assert_eq!(plain_result.geometry.as_u8(), gzip_result.geometry.as_u8());
+        assert_eq!(
+            plain_result.geometry.field_box_mm,
+            gzip_result.geometry.field_box_mm
+        );
+        assert_eq!(plain_result.layer_z, gzip_result.layer_z);
+
+        std::fs::remove_file(&plain).unwrap();
+        std::fs::remove_file(&gzip).unwrap();
+    }
+
+    #[test]
+    fn voxelize_large_gcode_bounded_memory() {
+        // Generates a few hundred MB of gcode, only run on request
+        if std::env::var_os("ICESL2VOXEL_LARGE_GCODE_TEST").is_none() {
+            eprintln!("skipping large gcode test, set ICESL2VOXEL_LARGE_GCODE_TEST to run it");
+            return;
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "icesl2voxel_test_large_{}.gcode",
+            std::process::id()
+        ));
+
+        {
+            use std::io::Write;
+
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+            writeln!(out, "; nozzle_diameter_mm_0 : 0.4\nM83").unwrap();
+
+            for l in 0..1000 {
+                writeln!(out, "; <layer>\nG0 X0 Y0 Z{:.1}", 0.2 * (l + 1) as f32).unwrap();
+                for s in 0..10000 {
+                    writeln!(out, "G1 X{:.2} Y{} E0.01", (s % 100) as f32 * 0.5, s / 100).unwrap();
+                }
+                writeln!(out, "; </layer>").unwrap();
+            }
+        }
+
+        let size = std::fs::metadata(&path).unwrap().len();
+        let options = GcodeOptions {
+            xy_sampling_factor: 0.25,
+            extract_tool_field: false,
+            extract_motion_fields: false,
+            ..stream_options(None)
+        };
+        let result = voxelize_gcode(&path, &options).unwrap();
+        assert_eq!(result.layer_z.len(), 1000);
+        std::fs::remove_file(&path).unwrap();
+
+        // Peak resident memory stays well below the size of the gcode
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            let peak_kb: u64 = status
+                .lines()
+                .find(|line| line.starts_with("VmHWM:"))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|kb| kb.parse().ok())
+                .unwrap();
+
+            assert!(
+                peak_kb * 1024 < size,
+                "peak memory {} kB for {} bytes of gcode",
+                peak_kb,
+                size
+            );
+        }
+    }
+
+    #[test]
+    fn voxelize_xy_sampling_factor() {
+        let mut src = String::from("; nozzle_diameter_mm_0 : 0.4\nM83\n");
+        for z in &[0.2, 0.4] {
+            src.push_str("; <layer>\n");
+            // Solid 10x4mm rectangle made of adjacent lines
+            for l in 0..10 {
+                src.push_str(&format!("G0 X0 Y{} Z{}\nG1 X10 E1\n", l as f32 * 0.4, z));
+            }
+            src.push_str("; </layer>\n");
+        }
+        src.push_str("M107\n");
+
+        let voxelize = |xy_sampling_factor| {
+            voxelize_parsed_gcode(
+                parse_gcode(&src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap(),
+                &GcodeOptions {
+                    samples: 16,
+                    xy_sampling_factor,
+                    progress: ProgressConfig { quiet: true },
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .geometry
+        };
+
+        // Covered volume in mm^3
+        let volume = |field: &ParamField| {
+            let data = field.as_u8().unwrap();
+            let (zc, yc, xc) = data.dim();
+            let size = field.field_box_mm.size();
+            let cell_volume = size.x * size.y * size.z / (xc * yc * zc) as f32;
+            data.iter().map(|v| *v as f32 / 255.0).sum::<f32>() * cell_volume
+        };
+
+        let base = voxelize(1.0);
+        let fine = voxelize(2.0);
+
+        let (bz, by, bx) = base.as_u8().unwrap().dim();
+        let (fz, fy, fx) = fine.as_u8().unwrap().dim();
+        assert_eq!(fz, bz);
+        assert!(fy == 2 * by || fy + 1 == 2 * by);
+        assert!(fx == 2 * bx || fx + 1 == 2 * bx);
+
+        let (base_volume, fine_volume) = (volume(&base), volume(&fine));
+        assert!(
+            (fine_volume - base_volume).abs() / base_volume < 0.05,
+            "{} vs. {}",
+            base_volume,
+            fine_volume
+        );
+    }
+
+    #[test]
+    fn voxelize_helical_arc() {
+        let src = "; nozzle_diameter_mm_0 : 0.4\nM83\n; <layer>\n\
+                   G0 X10 Y0 Z0.2\nG2 X0 Y10 Z0.4 I-10 J0 E2\n; </layer>\nM107\n";
+
+        let parsed = parse_gcode(src, 0.05, Slicer::IceSL, &FEATURE_REGEX).unwrap();
+        assert!(parsed.segments.len() > 1);
+        assert!(parsed
+            .segments
+            .iter()
+            .all(|seg| seg.start.z == 0.2 && seg.end.z == 0.2));
+
+        voxelize_parsed_gcode(
+            parsed,
+            &GcodeOptions {
+                progress: ProgressConfig { quiet: true },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn arc_center_radius_form() {
+        let start = nalgebra::Vector2::new(1.0, 0.0);
+        let end = nalgebra::Vector2::new(0.0, 1.0);
+
+        let center = arc_center_from_radius(start, end, 1.0, false).unwrap();
+        assert!(center.norm() < 1e-5);
+
+        let center = arc_center_from_radius(start, end, -1.0, false).unwrap();
+        assert!((center - nalgebra::Vector2::new(1.0, 1.0)).norm() < 1e-5);
+
+        assert!(arc_center_from_radius(start, end, 0.1, false).is_none());
+    }
+}
@@ -0,0 +1,211 @@
+use std::str::FromStr;
+
+use glutin::event_loop::EventLoop;
+use glutin::ContextBuilder;
+
+#[cfg(target_os = "linux")]
+use khronos_egl as egl;
+
+/// Backend used to create headless OpenGL contexts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlBackend {
+    /// EGL surfaceless context, which doesn't need a display server
+    Egl,
+    /// glutin headless context, which needs an X11 or Wayland display
+    Glutin,
+}
+
+impl GlBackend {
+    /// EGL on Linux when there is no display server to connect to, glutin otherwise
+    pub fn detect() -> Self {
+        if cfg!(target_os = "linux")
+            && std::env::var_os("DISPLAY").is_none()
+            && std::env::var_os("WAYLAND_DISPLAY").is_none()
+        {
+            Self::Egl
+        } else {
+            Self::Glutin
+        }
+    }
+}
+
+impl FromStr for GlBackend {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "egl" => Ok(Self::Egl),
+            "glutin" => Ok(Self::Glutin),
+            _ => Err(failure::err_msg("expected egl or glutin")),
+        }
+    }
+}
+
+/// Backend resources which must outlive the loaded OpenGL functions
+pub(super) enum ContextHandle {
+    Glutin {
+        _context: glutin::Context<glutin::PossiblyCurrent>,
+        _event_loop: EventLoop<()>,
+    },
+    #[cfg(target_os = "linux")]
+    Egl(EglContext),
+}
+
+/// Headless OpenGL 4.6 core context, current on the calling thread
+pub(super) struct HeadlessGl {
+    pub gl: tinygl::Context,
+    pub _handle: ContextHandle,
+}
+
+impl HeadlessGl {
+    /// Create a context with the given backend, or the detected one if `None`
+    pub fn new(backend: Option<GlBackend>) -> Result<Self, failure::Error> {
+        let backend = backend.unwrap_or_else(GlBackend::detect);
+        debug!("creating headless OpenGL context using {:?}", backend);
+
+        match backend {
+            GlBackend::Egl => Self::new_egl(),
+            GlBackend::Glutin => Self::new_glutin(),
+        }
+    }
+
+    fn new_glutin() -> Result<Self, failure::Error> {
+        // Voxelization doesn't need events, so it can run outside of the main thread
+        #[cfg(target_os = "linux")]
+        let el = {
+            use glutin::platform::unix::EventLoopExtUnix;
+            EventLoop::new_any_thread()
+        };
+        #[cfg(not(target_os = "linux"))]
+        let el = EventLoop::new();
+
+        let sz = glutin::dpi::PhysicalSize::new(128, 128);
+        let headless_context = ContextBuilder::new()
+            .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (4, 6)))
+            .with_gl_profile(glutin::GlProfile::Core)
+            .with_gl_debug_flag(true)
+            .build_headless(&el, sz)?;
+
+        unsafe {
+            let headless_context = headless_context
+                .make_current()
+                .map_err(|_| failure::err_msg("failed to make context current"))?;
+
+            Ok(Self {
+                gl: tinygl::Context::from_loader_function(|s| {
+                    headless_context.get_proc_address(s) as *const _
+                }),
+                _handle: ContextHandle::Glutin {
+                    _context: headless_context,
+                    _event_loop: el,
+                },
+            })
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn new_egl() -> Result<Self, failure::Error> {
+        let context = EglContext::new()?;
+
+        Ok(Self {
+            gl: unsafe {
+                tinygl::Context::from_loader_function(|s| {
+                    egl::get_proc_address(s)
+                        .map(|f| f as *const _)
+                        .unwrap_or(std::ptr::null())
+                })
+            },
+            _handle: ContextHandle::Egl(context),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new_egl() -> Result<Self, failure::Error> {
+        Err(failure::err_msg(
+            "the EGL backend is only available on Linux",
+        ))
+    }
+}
+
+/// EGL context without any surface, made current on creation
+#[cfg(target_os = "linux")]
+pub(super) struct EglContext {
+    display: egl::Display,
+    context: egl::Context,
+}
+
+#[cfg(target_os = "linux")]
+impl EglContext {
+    fn new() -> Result<Self, failure::Error> {
+        let egl_err = |what: &str, error: egl::Error| {
+            failure::err_msg(format!("failed to {}: {}", what, error))
+        };
+
+        let display = egl::get_display(egl::DEFAULT_DISPLAY)
+            .ok_or_else(|| failure::err_msg("no EGL display available"))?;
+        egl::initialize(display).map_err(|e| egl_err("initialize EGL", e))?;
+
+        let create = || {
+            let extensions = egl::query_string(Some(display), egl::EXTENSIONS)
+                .map_err(|e| egl_err("query EGL extensions", e))?;
+            if !extensions
+                .to_string_lossy()
+                .split_whitespace()
+                .any(|ext| ext == "EGL_KHR_surfaceless_context")
+            {
+                return Err(failure::err_msg(
+                    "EGL_KHR_surfaceless_context is not supported",
+                ));
+            }
+
+            egl::bind_api(egl::OPENGL_API).map_err(|e| egl_err("bind the OpenGL API", e))?;
+
+            let config = egl::choose_first_config(
+                display,
+                &[egl::RENDERABLE_TYPE, egl::OPENGL_BIT, egl::NONE],
+            )
+            .map_err(|e| egl_err("choose an EGL config", e))?
+            .ok_or_else(|| failure::err_msg("no EGL config supports OpenGL"))?;
+
+            let context = egl::create_context(
+                display,
+                config,
+                None,
+                &[
+                    egl::CONTEXT_MAJOR_VERSION,
+                    4,
+                    egl::CONTEXT_MINOR_VERSION,
+                    6,
+                    egl::CONTEXT_OPENGL_PROFILE_MASK,
+                    egl::CONTEXT_OPENGL_CORE_PROFILE_BIT,
+                    egl::NONE,
+                ],
+            )
+            .map_err(|e| egl_err("create the EGL context", e))?;
+
+            if let Err(e) = egl::make_current(display, None, None, Some(context)) {
+                egl::destroy_context(display, context).ok();
+                return Err(egl_err("make the EGL context current", e));
+            }
+
+            Ok(context)
+        };
+
+        match create() {
+            Ok(context) => Ok(Self { display, context }),
+            Err(error) => {
+                egl::terminate(display).ok();
+                Err(error)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for EglContext {
+    fn drop(&mut self) {
+        egl::make_current(self.display, None, None, None).ok();
+        egl::destroy_context(self.display, self.context).ok();
+        egl::terminate(self.display).ok();
+    }
+}
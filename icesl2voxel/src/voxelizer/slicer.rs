@@ -0,0 +1,258 @@
+use std::str::FromStr;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use super::GlobalState;
+
+/// Number of lines scanned for slicer markers at the start of a gcode file
+pub(super) const DETECT_LINES: usize = 200;
+
+lazy_static! {
+    static ref ICESL_PARAMETER_REGEX: Regex = Regex::new(r"^; ([a-z0-9_]*) :\s*(.*)$").unwrap();
+    static ref ICESL_NOZZLE_DIAMETER_REGEX: Regex =
+        Regex::new(r"^nozzle_diameter_mm_([0-9]+)$").unwrap();
+    static ref ICESL_FILAMENT_DIAMETER_REGEX: Regex =
+        Regex::new(r"^filament_diameter_mm_([0-9]+)$").unwrap();
+    static ref CURA_LAYER_REGEX: Regex = Regex::new(r"^;LAYER:-?[0-9]+$").unwrap();
+    static ref CURA_NOZZLE_DIAMETER_REGEX: Regex =
+        Regex::new(r"^;EXTRUDER_TRAIN\.([0-9]+)\.NOZZLE\.DIAMETER:\s*([0-9.]+)").unwrap();
+    static ref CURA_SETTING_NOZZLE_SIZE_REGEX: Regex =
+        Regex::new(r"machine_nozzle_size\s*=\s*([0-9.]+)").unwrap();
+    static ref CURA_SETTING_MATERIAL_DIAMETER_REGEX: Regex =
+        Regex::new(r"material_diameter\s*=\s*([0-9.]+)").unwrap();
+    static ref PRUSA_PARAMETER_REGEX: Regex = Regex::new(r"^; ([a-z0-9_]+) = (.*)$").unwrap();
+}
+
+/// Slicer that generated a gcode file, which sets how layers and parameters are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slicer {
+    /// `; <layer>` and `; </layer>` markers, `; name : value` parameters in the header
+    IceSL,
+    /// `;LAYER:n` markers, nozzle sizes in the header or the `;SETTING_3` block
+    Cura,
+    /// `;LAYER_CHANGE` markers, `; name = value` parameters at the end of the file
+    Prusa,
+}
+
+/// Layer boundary announced by a comment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum LayerMarker {
+    /// Start of a layer, which also ends the current one if there is no end marker
+    Start,
+    End,
+}
+
+impl Slicer {
+    /// Detect the slicer from the first lines of a gcode file
+    pub fn detect<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<Self, failure::Error> {
+        let mut first_comment = None;
+
+        for line in lines.into_iter().take(DETECT_LINES) {
+            let lower = line.to_lowercase();
+
+            if lower.starts_with(";generated with cura") || line.starts_with(";FLAVOR:") {
+                return Ok(Self::Cura);
+            } else if lower.starts_with("; generated by prusaslicer") {
+                return Ok(Self::Prusa);
+            } else if line == "; <layer>"
+                || lower.contains("icesl")
+                || ICESL_PARAMETER_REGEX
+                    .captures(line)
+                    .map(|captures| ICESL_NOZZLE_DIAMETER_REGEX.is_match(&captures[1]))
+                    .unwrap_or(false)
+            {
+                return Ok(Self::IceSL);
+            }
+
+            if first_comment.is_none() && line.starts_with(';') {
+                first_comment = Some(line.to_owned());
+            }
+        }
+
+        Err(failure::err_msg(format!(
+            "unknown slicer, no IceSL, Cura or PrusaSlicer markers in the first {} lines (first comment: {}), use --slicer to set it",
+            DETECT_LINES,
+            first_comment.as_deref().unwrap_or("none")
+        )))
+    }
+
+    /// Layer boundary announced by a comment line, if any
+    pub(super) fn layer_marker(self, line: &str) -> Option<LayerMarker> {
+        match self {
+            Self::IceSL => match line {
+                "; <layer>" => Some(LayerMarker::Start),
+                "; </layer>" => Some(LayerMarker::End),
+                _ => None,
+            },
+            Self::Cura if CURA_LAYER_REGEX.is_match(line) => Some(LayerMarker::Start),
+            Self::Prusa if line == ";LAYER_CHANGE" => Some(LayerMarker::Start),
+            _ => None,
+        }
+    }
+
+    /// Extract the parameters of a comment line into `global_state`. Returns true if the line
+    /// holds a parameter, even one that isn't used.
+    pub(super) fn parse_parameter(
+        self,
+        line: &str,
+        global_state: &mut GlobalState,
+    ) -> Result<bool, failure::Error> {
+        match self {
+            Self::IceSL => {
+                if let Some(captures) = ICESL_PARAMETER_REGEX.captures(line) {
+                    if let Some(nozzle) = ICESL_NOZZLE_DIAMETER_REGEX.captures(&captures[1]) {
+                        global_state.set_nozzle_diameter(
+                            usize::from_str(&nozzle[1])?,
+                            f32::from_str(&captures[2])?,
+                        );
+                    } else if let Some(filament) =
+                        ICESL_FILAMENT_DIAMETER_REGEX.captures(&captures[1])
+                    {
+                        global_state.set_filament_diameter(
+                            usize::from_str(&filament[1])?,
+                            f32::from_str(&captures[2])?,
+                        );
+                    }
+
+                    return Ok(true);
+                }
+            }
+            Self::Cura => {
+                if let Some(captures) = CURA_NOZZLE_DIAMETER_REGEX.captures(line) {
+                    global_state.set_nozzle_diameter(
+                        usize::from_str(&captures[1])?,
+                        f32::from_str(&captures[2])?,
+                    );
+
+                    return Ok(true);
+                } else if line.starts_with(";SETTING_3 ") {
+                    // Only the first extruder is listed by the header of non-Griffin flavors
+                    if let Some(captures) = CURA_SETTING_NOZZLE_SIZE_REGEX.captures(line) {
+                        if global_state.nozzle_diameters.is_empty() {
+                            global_state.set_nozzle_diameter(0, f32::from_str(&captures[1])?);
+                        }
+                    }
+
+                    // The material diameter is never in the header, and applies to all tools
+                    if let Some(captures) = CURA_SETTING_MATERIAL_DIAMETER_REGEX.captures(line) {
+                        if global_state.filament_diameters.is_empty() {
+                            global_state.set_filament_diameter(0, f32::from_str(&captures[1])?);
+                        }
+                    }
+
+                    return Ok(true);
+                }
+            }
+            Self::Prusa => {
+                if let Some(captures) = PRUSA_PARAMETER_REGEX.captures(line) {
+                    if &captures[1] == "nozzle_diameter" {
+                        for (tool, diameter) in captures[2].split(',').enumerate() {
+                            global_state.set_nozzle_diameter(tool, f32::from_str(diameter.trim())?);
+                        }
+                    } else if &captures[1] == "filament_diameter" {
+                        for (tool, diameter) in captures[2].split(',').enumerate() {
+                            global_state
+                                .set_filament_diameter(tool, f32::from_str(diameter.trim())?);
+                        }
+                    }
+
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+impl FromStr for Slicer {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "icesl" => Ok(Self::IceSL),
+            "cura" => Ok(Self::Cura),
+            "prusa" => Ok(Self::Prusa),
+            _ => Err(failure::err_msg("expected icesl, cura or prusa")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ICESL_HEADER: &str = include_str!("../../fixtures/icesl_header.gcode");
+    const CURA_HEADER: &str = include_str!("../../fixtures/cura_header.gcode");
+    const PRUSA_HEADER: &str = include_str!("../../fixtures/prusa_header.gcode");
+
+    fn parameters(slicer: Slicer, src: &str) -> GlobalState {
+        let mut global_state = GlobalState::default();
+        for line in src.lines() {
+            slicer.parse_parameter(line, &mut global_state).unwrap();
+        }
+        global_state
+    }
+
+    fn layer_markers(slicer: Slicer, src: &str) -> Vec<LayerMarker> {
+        src.lines()
+            .filter_map(|line| slicer.layer_marker(line))
+            .collect()
+    }
+
+    #[test]
+    fn detect_slicers() {
+        assert_eq!(Slicer::detect(ICESL_HEADER.lines()).unwrap(), Slicer::IceSL);
+        assert_eq!(Slicer::detect(CURA_HEADER.lines()).unwrap(), Slicer::Cura);
+        assert_eq!(Slicer::detect(PRUSA_HEADER.lines()).unwrap(), Slicer::Prusa);
+
+        let error = Slicer::detect(";generated by Slic3r\nG28\n".lines()).unwrap_err();
+        assert!(error.to_string().contains(";generated by Slic3r"));
+    }
+
+    #[test]
+    fn icesl_parameters() {
+        let global_state = parameters(Slicer::IceSL, ICESL_HEADER);
+        assert_eq!(global_state.nozzle_diameter(0), 0.4);
+        assert_eq!(global_state.nozzle_diameter(1), 0.6);
+        assert_eq!(global_state.filament_diameter(0), Some(1.75));
+        assert_eq!(global_state.filament_diameter(1), Some(1.75));
+        assert_eq!(
+            layer_markers(Slicer::IceSL, ICESL_HEADER),
+            vec![LayerMarker::Start, LayerMarker::End]
+        );
+    }
+
+    #[test]
+    fn cura_parameters() {
+        let global_state = parameters(Slicer::Cura, CURA_HEADER);
+        assert_eq!(global_state.nozzle_diameter(0), 0.4);
+        assert_eq!(global_state.nozzle_diameter(1), 0.8);
+        assert_eq!(global_state.filament_diameter(1), Some(2.85));
+        assert_eq!(
+            layer_markers(Slicer::Cura, CURA_HEADER),
+            vec![LayerMarker::Start, LayerMarker::Start]
+        );
+
+        // Settings block of single extruder flavors
+        let global_state = parameters(
+            Slicer::Cura,
+            ";SETTING_3 {\"global_quality\": \"[values]\\\\nmachine_nozzle_size = 0.6\\\\n\"}",
+        );
+        assert_eq!(global_state.nozzle_diameter(0), 0.6);
+    }
+
+    #[test]
+    fn prusa_parameters() {
+        let global_state = parameters(Slicer::Prusa, PRUSA_HEADER);
+        assert_eq!(global_state.nozzle_diameter(0), 0.4);
+        assert_eq!(global_state.nozzle_diameter(1), 0.25);
+        assert_eq!(global_state.filament_diameter(0), Some(1.75));
+        assert_eq!(global_state.filament_diameter(1), Some(2.85));
+        assert_eq!(
+            layer_markers(Slicer::Prusa, PRUSA_HEADER),
+            vec![LayerMarker::Start, LayerMarker::Start]
+        );
+    }
+}
@@ -19,7 +19,42 @@ layout(location = 6) uniform float u_FilterModulation;
 layout(location = 7) uniform float u_FilterModPower;
 layout(location = 8) uniform float u_IsotropyModulation;
 
+// Color lookup table applied to scalar display modes when u_UseLut is non-zero
+layout(location = 16, binding = 0) uniform sampler1D u_Lut;
+layout(location = 17) uniform int u_UseLut;
+
+// Write the derivatives of the phase to the extra attachment when non-zero
+layout(location = 18) uniform int u_EmitDerivatives;
+
+// Half of the viewport to draw in split-screen comparisons, SPLIT_NONE draws it all
+layout(location = 19) uniform int u_SplitSide;
+
+vec4 lut_color(float v) {
+    if (u_UseLut == 0)
+        return vec4(vec3(v), 1.0);
+
+    // Map [0, 1] to the centers of the first and last texels
+    float n = float(textureSize(u_Lut, 0));
+    return vec4(texture(u_Lut, (clamp(v, 0., 1.) * (n - 1.) + 0.5) / n).rgb, 1.0);
+}
+
 void main() {
+    if (u_SplitSide != SPLIT_NONE) {
+        // Viewport width in pixels, the divider is the middle column
+        float width = round(1. / dFdx(uv.x));
+        int column = int(uv.x * width);
+        int split = int(width) / 2;
+
+        if (column == split) {
+            o_PixColor = vec4(1.0);
+            o_PixExtra = vec4(0.0);
+            return;
+        }
+
+        if ((column < split) != (u_SplitSide == SPLIT_LEFT))
+            discard;
+    }
+
     vec2 gij = vec2(vec2(u_Grid.xy) * uv);
     vec2 gs = 32.0 / vec2(u_Grid.xy);
 
@@ -27,6 +62,8 @@ void main() {
     int gj = int(gij.y);
 
     vec2 kv = vec2(0.0);
+    // Derivatives of kv with respect to gij.x (xy) and gij.y (zw)
+    vec4 dkv = vec4(0.0);
     float s = 0.0;
 
     // Reference orientation at current pixel
@@ -58,6 +95,10 @@ void main() {
                 // evaluate
                 kv += phasor(gij - n.pos, n.phase, vec2(cos(n.angle), sin(n.angle)), n.frequency,
                              w, f, fm);
+                if (u_EmitDerivatives != 0) {
+                    dkv += phasor_grad(gij - n.pos, n.phase, vec2(cos(n.angle), sin(n.angle)),
+                                       n.frequency, w, f, fm);
+                }
                 s += phasor_state(gij - n.pos, n.state);
             }
         }
@@ -66,15 +107,33 @@ void main() {
     float ph = atan(kv.x, kv.y);
     float I = 0.5 * length(kv);
 
+    // Extra attachment layout:
+    //  DM_COMPLEX: isotropy, filter modulation, average kernel state, magnitude of kv
+    //  DM_KERNELS: zero, the kernel pass writes the kernel center and instance + 1 under the
+    //  ellipses
+    //  other modes: d/du, d/dv of the phase in turns (the DM_NOISE value), 0, 0 if
+    //  u_EmitDerivatives is set, zero otherwise
+    o_PixExtra = vec4(0.);
+    if (u_EmitDerivatives != 0) {
+        // d atan(y, x) = (x dy - y dx) / (x^2 + y^2), with y = kv.x and x = kv.y
+        vec2 dph = (kv.y * dkv.xz - kv.x * dkv.yw) / max(dot(kv, kv), 1e-12);
+        o_PixExtra.xy = dph * vec2(u_Grid.xy) / M_2PI;
+    }
+
     if (u_DisplayMode == DM_NOISE) {
-        o_PixColor = vec4(0.5 + 0.5 * sin(ph));
-        o_PixColor = vec4(vec3(mod(ph + M_PI, M_2PI) / M_2PI), 1.0);
+        o_PixColor = lut_color(mod(ph + M_PI, M_2PI) / M_2PI);
     } else if (u_DisplayMode == DM_COMPLEX) {
         // Complex conjugate
         o_PixColor = vec4(kv, atan(-w.y, w.x), f);
-        o_PixExtra = vec4(is, fm, s / K, 0.);
+        o_PixExtra = vec4(is, fm, s / K, length(kv));
     } else if (u_DisplayMode == DM_STATE) {
-        o_PixColor = vec4(vec3(pow(s / K, 1. / 2.2)), 1.0);
+        o_PixColor = lut_color(pow(s / K, 1. / 2.2));
+    } else if (u_DisplayMode == DM_THRESHOLD) {
+        o_PixColor = lut_color(step(0.5, 0.5 + 0.5 * sin(ph)));
+    } else if (u_DisplayMode == DM_KERNELS) {
+        // Dimmed noise, the kernels are drawn over it
+        o_PixColor = vec4(0.3 * lut_color(mod(ph + M_PI, M_2PI) / M_2PI).rgb, 1.0);
+        o_PixExtra = vec4(0.);
     } else {
         o_PixColor = vec4(1.0, 0.0, 1.0, 1.0);
     }
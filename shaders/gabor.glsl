@@ -4,13 +4,12 @@ layout(location = 10) uniform float u_NoiseBandwidth;
 layout(location = 11) uniform float u_FilterBandwidth;
 #endif
 
-vec2 phasor(vec2 x, float phi, vec2 wi, float fi
+// Gaussian falloff `be`, amplitude `ga` and frequency vector `k` of a kernel
+void phasor_shape(vec2 wi, float fi,
 #ifdef PREFILTERED
-            ,
-            vec2 w, float f, float fm
+                  vec2 w, float f, float fm,
 #endif
-) {
-    float gaus, osc;
+                  out float be, out float ga, out vec2 k) {
     float b = u_NoiseBandwidth * u_NoiseBandwidth * M_PI;
 
 #ifdef PREFILTERED
@@ -20,20 +19,67 @@ vec2 phasor(vec2 x, float phi, vec2 wi, float fi
         vec2 dfw = fi * wi - f * w;
         dfw *= fm;
 
-        gaus = exp(-b / (1. + fm * b / a) * dot(x, x)) * exp(-M_PI2 * dot(dfw, dfw) / (a + b));
-        osc = 2. * M_PI * dot(x, fi * wi + dfw / (1. + b / a)) + phi;
+        be = b / (1. + fm * b / a);
+        ga = exp(-M_PI2 * dot(dfw, dfw) / (a + b));
+        k = fi * wi + dfw / (1. + b / a);
 
     } else
 #endif
     {
         // Regular kernel
-        gaus = exp(-b * dot(x, x));
-        osc = 2. * M_PI * dot(x, fi * wi) + phi;
+        be = b;
+        ga = 1.;
+        k = fi * wi;
     }
+}
+
+vec2 phasor(vec2 x, float phi, vec2 wi, float fi
+#ifdef PREFILTERED
+            ,
+            vec2 w, float f, float fm
+#endif
+) {
+    float be, ga;
+    vec2 k;
+    phasor_shape(wi, fi,
+#ifdef PREFILTERED
+                 w, f, fm,
+#endif
+                 be, ga, k);
+
+    float gaus = ga * exp(-be * dot(x, x));
+    float osc = 2. * M_PI * dot(x, k) + phi;
 
     return gaus * vec2(cos(osc), sin(osc));
 }
 
+// Derivatives of phasor() with respect to x.x (xy) and x.y (zw), the orientation, frequency
+// and filter modulation at the evaluation point are treated as constants
+vec4 phasor_grad(vec2 x, float phi, vec2 wi, float fi
+#ifdef PREFILTERED
+                 ,
+                 vec2 w, float f, float fm
+#endif
+) {
+    float be, ga;
+    vec2 k;
+    phasor_shape(wi, fi,
+#ifdef PREFILTERED
+                 w, f, fm,
+#endif
+                 be, ga, k);
+
+    float gaus = ga * exp(-be * dot(x, x));
+    float osc = 2. * M_PI * dot(x, k) + phi;
+
+    vec2 p = gaus * vec2(cos(osc), sin(osc));
+    // i * p
+    vec2 ip = vec2(-p.y, p.x);
+
+    return vec4(-2. * be * x.x * p + 2. * M_PI * k.x * ip,
+                -2. * be * x.y * p + 2. * M_PI * k.y * ip);
+}
+
 float phasor_state(vec2 x, float state) {
     float b = u_NoiseBandwidth * u_NoiseBandwidth * M_PI;
     return (b * dot(x, x) < 2. ? 1. : 0.) * state;
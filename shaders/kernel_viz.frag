@@ -0,0 +1,31 @@
+#version 460 core
+
+#include "shared.h"
+
+layout(location = 0) in vec2 corner;
+layout(location = 1) in vec2 uv;
+layout(location = 2) flat in vec2 center;
+layout(location = 3) flat in float angle;
+layout(location = 4) flat in float state;
+layout(location = 5) flat in int instance;
+
+layout(location = 0) out vec4 o_PixColor;
+layout(location = 1) out vec4 o_PixExtra;
+
+// Half of the viewport to draw in split-screen comparisons, SPLIT_NONE draws it all
+layout(location = 19) uniform int u_SplitSide;
+
+void main() {
+    if (dot(corner, corner) > 1.)
+        discard;
+
+    if (u_SplitSide != SPLIT_NONE && (uv.x < 0.5) != (u_SplitSide == SPLIT_LEFT))
+        discard;
+
+    // Hue from the orientation, which is defined modulo pi
+    vec3 color = 0.5 + 0.5 * cos(2. * angle + vec3(0., 2. * M_PI / 3., 4. * M_PI / 3.));
+    o_PixColor = vec4(color, mix(0.4, 0.8, clamp(state, 0., 1.)));
+
+    // Opaque, so blending keeps the values of the last ellipse drawn
+    o_PixExtra = vec4(center, float(instance + 1), 1.);
+}
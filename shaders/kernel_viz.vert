@@ -0,0 +1,40 @@
+#version 460 core
+#extension GL_ARB_shader_image_load_store : enable
+
+#include "shared.h"
+
+// Position in the ellipse, in [-1, 1]
+layout(location = 0) out vec2 corner;
+// Position in the domain, in [0, 1]
+layout(location = 1) out vec2 uv;
+// Center of the kernel, in grid units
+layout(location = 2) flat out vec2 center;
+layout(location = 3) flat out float angle;
+layout(location = 4) flat out float state;
+layout(location = 5) flat out int instance;
+
+// One instance per kernel, drawn as a 4-vertex triangle strip
+void main() {
+    int cell = gl_InstanceID / K;
+    Kernel n = load_at_idx(gl_InstanceID, vec2(cell % u_Grid.x, cell / u_Grid.x));
+
+    // Frequency in periods per cell, as in the display pass
+    float f = n.frequency * 32.0 / float(u_Grid.x);
+
+    // Kernels of a cell share its area, the ellipses are narrower along the oscillation
+    // direction as the frequency increases
+    float r = 0.5 / sqrt(float(K));
+    vec2 radii = vec2(r * clamp(0.5 / max(f, 1e-3), 0.2, 1.0), r);
+
+    vec2 w = vec2(cos(n.angle), sin(n.angle));
+    corner = vec2(gl_VertexID & 1, (gl_VertexID >> 1) & 1) * 2. - 1.;
+    vec2 p = n.pos + corner.x * radii.x * w + corner.y * radii.y * vec2(-w.y, w.x);
+
+    uv = p / vec2(u_Grid.xy);
+    center = n.pos;
+    angle = n.angle;
+    state = n.state;
+    instance = gl_InstanceID;
+
+    gl_Position = vec4(uv * 2. - 1., 0., 1.);
+}
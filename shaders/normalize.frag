@@ -0,0 +1,16 @@
+#version 460 core
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 o_PixColor;
+
+layout(location = 0, binding = 0) uniform sampler2D u_Source;
+// Channels selected by u_ChannelMask are mapped to (value - u_Offset) * u_Scale
+layout(location = 1) uniform float u_Offset;
+layout(location = 2) uniform float u_Scale;
+layout(location = 3) uniform vec4 u_ChannelMask;
+
+void main() {
+    vec4 v = texelFetch(u_Source, ivec2(gl_FragCoord.xy), 0);
+    o_PixColor = mix(v, (v - u_Offset) * u_Scale, u_ChannelMask);
+}
@@ -1,9 +1,12 @@
 #define NFLOATS 6
 #define MAX_K 64
+#define KERNEL_SHARDS 4
 
 #define DM_NOISE 0
 #define DM_COMPLEX 1
 #define DM_STATE 2
+#define DM_THRESHOLD 3
+#define DM_KERNELS 4
 
 #define AM_STATIC 0
 #define AM_GAUSS 1
@@ -30,6 +33,23 @@
 #define OM_HYBRID (OM_OPTIMIZE_BIT | OM_AVERAGE_BIT)
 #define OM_COND_AVERAGE (OM_OPTIMIZE_BIT | OM_AVERAGE_BIT | OM_CONDITIONAL_BIT)
 
+#define OPT_PASS_IN_PLACE 0
+#define OPT_PASS_STAGE 1
+#define OPT_PASS_COMMIT 2
+
+#define NM_NONE 0
+#define NM_MINMAX 1
+#define NM_SIGMA 2
+
+#define RANGE_GROUP_SIZE 16
+#define RANGE_PASS_PARTIAL 0
+#define RANGE_PASS_FINAL 1
+#define RANGE_PARTIALS_BINDING 0
+
+#define SPLIT_NONE 0
+#define SPLIT_LEFT 1
+#define SPLIT_RIGHT 2
+
 #define M_PI 3.14159265358979323846
 #define M_2PI (2.0 * M_PI)
 #define M_PI2 (M_PI * M_PI)
@@ -53,7 +73,13 @@ struct Kernel {
 layout(location = 0) uniform ivec3 u_Grid;
 layout(location = 1) uniform int u_CellMode;
 layout(location = 2) uniform uint u_KernelCount;
+// Kernel data is split across consecutive bindings when it exceeds the texture buffer size
+// limit, with u_ShardTexels texels per shard
 layout(location = 3, binding = 0, r32f) coherent uniform imageBuffer u_Kernels;
+layout(location = 12, binding = 1, r32f) coherent uniform imageBuffer u_Kernels1;
+layout(location = 13, binding = 2, r32f) coherent uniform imageBuffer u_Kernels2;
+layout(location = 14, binding = 3, r32f) coherent uniform imageBuffer u_Kernels3;
+layout(location = 15) uniform int u_ShardTexels;
 
 vec3 gaussian(vec2 x, float b) {
     float a = exp(-M_PI * (b * b) * ((x.x * x.x) + (x.y * x.y)));
@@ -62,6 +88,42 @@ vec3 gaussian(vec2 x, float b) {
     return a * vec3(1., d.x, d.y);
 }
 
+float kernel_load(int i) {
+    int shard = i / u_ShardTexels;
+    int texel = i - shard * u_ShardTexels;
+
+    switch (shard) {
+    case 0:
+        return imageLoad(u_Kernels, texel).x;
+    case 1:
+        return imageLoad(u_Kernels1, texel).x;
+    case 2:
+        return imageLoad(u_Kernels2, texel).x;
+    default:
+        return imageLoad(u_Kernels3, texel).x;
+    }
+}
+
+void kernel_store(int i, float value) {
+    int shard = i / u_ShardTexels;
+    int texel = i - shard * u_ShardTexels;
+
+    switch (shard) {
+    case 0:
+        imageStore(u_Kernels, texel, vec4(value));
+        break;
+    case 1:
+        imageStore(u_Kernels1, texel, vec4(value));
+        break;
+    case 2:
+        imageStore(u_Kernels2, texel, vec4(value));
+        break;
+    default:
+        imageStore(u_Kernels3, texel, vec4(value));
+        break;
+    }
+}
+
 Kernel invalid_kernel() { return Kernel(vec2(-10.0), 0., 0., 0., 0.); }
 
 Kernel load_at_idx(int idx, vec2 pos_offset) {
@@ -69,32 +131,32 @@ Kernel load_at_idx(int idx, vec2 pos_offset) {
 
     return Kernel(
         pos_offset + vec2(
-            imageLoad(u_Kernels, idx + 0).x,
-            imageLoad(u_Kernels, idx + 1).x
+            kernel_load(idx + 0),
+            kernel_load(idx + 1)
         ),
-        imageLoad(u_Kernels, idx + 2).x,
-        imageLoad(u_Kernels, idx + 3).x,
-        imageLoad(u_Kernels, idx + 4).x,
-        imageLoad(u_Kernels, idx + 5).x
+        kernel_load(idx + 2),
+        kernel_load(idx + 3),
+        kernel_load(idx + 4),
+        kernel_load(idx + 5)
     );
 }
 
 void save_phase_at_idx(int idx, float phase) {
-    imageStore(u_Kernels, idx * NFLOATS + 3, vec4(phase));
+    kernel_store(idx * NFLOATS + 3, phase);
 }
 
 void save_state_at_idx(int idx, float state) {
-    imageStore(u_Kernels, idx * NFLOATS + 5, vec4(state));
+    kernel_store(idx * NFLOATS + 5, state);
 }
 
 void save_at_idx(int idx, Kernel k) {
     idx *= NFLOATS;
 
-    imageStore(u_Kernels, idx + 0, vec4(k.pos.x));
-    imageStore(u_Kernels, idx + 1, vec4(k.pos.y));
-    imageStore(u_Kernels, idx + 2, vec4(k.frequency));
-    imageStore(u_Kernels, idx + 3, vec4(k.phase));
-    imageStore(u_Kernels, idx + 4, vec4(k.angle));
-    imageStore(u_Kernels, idx + 5, vec4(k.state));
+    kernel_store(idx + 0, k.pos.x);
+    kernel_store(idx + 1, k.pos.y);
+    kernel_store(idx + 2, k.frequency);
+    kernel_store(idx + 3, k.phase);
+    kernel_store(idx + 4, k.angle);
+    kernel_store(idx + 5, k.state);
 }
 #endif
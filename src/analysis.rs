@@ -0,0 +1,56 @@
+//! CPU-side analysis of exported phasor fields
+
+use ndarray::Array2;
+
+/// Unwrap a wrapped phase field, indexed by `[row, column]`. The first column is unwrapped
+/// along the rows, then each row is unwrapped starting from its first value, so that
+/// neighboring values differ by less than pi in the unwrapping direction.
+pub fn unwrap_phase(phase: Array2<f32>) -> Array2<f32> {
+    let mut unwrapped = phase;
+    let (rows, cols) = unwrapped.dim();
+
+    // Value of `next` shifted by a multiple of 2pi to be closest to `previous`
+    let unwrap = |previous: f32, next: f32| {
+        let two_pi = 2.0 * std::f32::consts::PI;
+        next - two_pi * ((next - previous) / two_pi).round()
+    };
+
+    for i in 1..rows {
+        unwrapped[[i, 0]] = unwrap(unwrapped[[i - 1, 0]], unwrapped[[i, 0]]);
+    }
+
+    for i in 0..rows {
+        for j in 1..cols {
+            unwrapped[[i, j]] = unwrap(unwrapped[[i, j - 1]], unwrapped[[i, j]]);
+        }
+    }
+
+    unwrapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwrap_linear_ramp() {
+        let (a, b) = (0.7, -0.45);
+        let ramp = Array2::from_shape_fn((32, 48), |(i, j)| a * j as f32 + b * i as f32);
+        let wrapped = ramp.mapv(|v| v.sin().atan2(v.cos()));
+
+        let unwrapped = unwrap_phase(wrapped);
+
+        // The first value is already in [-pi, pi]
+        for ((i, j), v) in unwrapped.indexed_iter() {
+            let expected = ramp[[i, j]];
+            assert!(
+                (v - expected).abs() < 1e-3,
+                "[{}, {}]: {} != {}",
+                i,
+                j,
+                v,
+                expected
+            );
+        }
+    }
+}
@@ -1,135 +1,227 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::CString;
 use std::rc::Rc;
 
 use glutin::event_loop::EventLoop;
-use glutin::{Context, ContextBuilder, PossiblyCurrent};
 
-use super::{shared::Kernel, OptimizationMode, Params, State};
+use super::probe::HeadlessContext;
+use super::{
+    shared::Kernel, AngleMode, CellMode, DisplayMode, FrequencyMode, InvalidConstant, IsotropyMode,
+    NormalizeMode, OptimizationMode, OutputLayout, Params, State,
+};
 
-enum ApiContext {
-    Unintialized,
-    Ready(ApiState),
-}
+/// Handle to an optimizer instance, 0 is never a valid handle
+pub type PgHandle = i32;
 
-#[allow(dead_code)]
 struct ApiState {
-    el: EventLoop<()>,
-    context: Context<PossiblyCurrent>,
-    gl: Rc<tinygl::Context>,
     state: State,
+    gl: Rc<tinygl::Context>,
+    context: HeadlessContext,
     last_error: Option<CString>,
     grid_size: cgmath::Vector3<i32>,
     kernel_count: i32,
+    /// Parameters of the last optimization, used by `pg_render_to_fbo_h`
+    params: Params,
+    /// Layout of `buffer_main` and `buffer_extra`
+    output_layout: OutputLayout,
+    /// Size of the images in `buffer_main` and `buffer_extra`, None if they are not valid
+    output_size: Option<(i32, i32)>,
     buffer_main: Vec<f32>,
     buffer_extra: Vec<f32>,
     buffer_kernels: Vec<f32>,
 }
 
-impl ApiState {
-    #[cfg(target_os = "linux")]
-    fn get_event_loop() -> EventLoop<()> {
-        glutin::platform::unix::EventLoopExtUnix::new_any_thread()
-    }
-
-    #[cfg(not(target_os = "linux"))]
-    fn get_event_loop() -> EventLoop<()> {
-        EventLoop::new()
-    }
-
-    fn new() -> tinygl::Result<Self> {
-        let el = Self::get_event_loop();
-
-        let sz = glutin::dpi::PhysicalSize::new(512, 512);
-
-        let headless_context = ContextBuilder::new()
-            .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (4, 6)))
-            .with_gl_profile(glutin::GlProfile::Core)
-            .with_gl_debug_flag(true)
-            .build_headless(&el, sz)
-            .expect("failed to initialize context");
+#[cfg(target_os = "linux")]
+pub(crate) fn get_event_loop() -> EventLoop<()> {
+    glutin::platform::unix::EventLoopExtUnix::new_any_thread()
+}
 
-        let (gl, headless_context) = unsafe {
-            let headless_context = headless_context
-                .make_current()
-                .expect("failed to make context current");
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn get_event_loop() -> EventLoop<()> {
+    EventLoop::new()
+}
 
-            (
-                Rc::new(tinygl::Context::from_loader_function(|s| {
-                    headless_context.get_proc_address(s) as *const _
-                })),
-                headless_context,
-            )
-        };
+impl ApiState {
+    fn new() -> Result<Self, String> {
+        let context = HeadlessContext::with_debug(512, 512, true)?;
+        let gl = context.gl.clone();
 
         // Build an empty VAO for quad rendering
-        let vao = tinygl::wrappers::VertexArray::new(&gl)?;
+        let vao = tinygl::wrappers::VertexArray::new(&gl).map_err(|e| e.to_string())?;
         unsafe {
             vao.bind(&gl);
         }
 
-        let state = State::new(&gl)?;
+        let state = State::new(&gl).map_err(|e| e.to_string())?;
 
         Ok(Self {
-            el,
-            context: headless_context,
-            gl,
             state,
+            gl,
+            context,
             last_error: None,
             grid_size: cgmath::vec3(0, 0, 0),
             kernel_count: 0,
+            params: Params::default(),
+            output_layout: OutputLayout::default(),
+            output_size: None,
             buffer_main: Vec::new(),
             buffer_extra: Vec::new(),
             buffer_kernels: Vec::new(),
         })
     }
+
+    fn set_error(&mut self, error: impl Into<String>) {
+        let error = error.into();
+        warn!("{}", error);
+        self.last_error = CString::new(error).ok();
+    }
+
+    /// true if `ptr` points into the kernel buffer of this instance
+    fn owns_kernels(&self, ptr: *const f32) -> bool {
+        let range = self.buffer_kernels.as_ptr_range();
+        range.contains(&ptr)
+    }
+}
+
+/// Optimizer instances created through the C API
+#[derive(Default)]
+struct Registry {
+    states: HashMap<PgHandle, ApiState>,
+    last_handle: PgHandle,
+    /// Instance used by the functions without a handle
+    default_handle: Option<PgHandle>,
+    /// Error for calls with an invalid handle
+    last_error: Option<CString>,
 }
 
-impl ApiContext {
-    fn ensure_init(&mut self) -> &mut ApiState {
-        match self {
-            Self::Unintialized => {
-                crate::log::init();
-                *self = Self::Ready(ApiState::new().expect("failed to initialize api"));
+impl Registry {
+    fn create(&mut self) -> PgHandle {
+        // Configured by the PHASOR_LOG and PHASOR_LOG_FILE environment variables
+        crate::log::init();
+
+        match ApiState::new() {
+            Ok(state) => {
+                self.last_handle += 1;
+                self.states.insert(self.last_handle, state);
+                self.last_handle
+            }
+            Err(e) => {
+                self.set_error(format!("failed to initialize api: {}", e));
+                0
+            }
+        }
+    }
+
+    fn destroy(&mut self, handle: PgHandle) {
+        if let Some(mut state) = self.states.remove(&handle) {
+            // GL objects are deleted in their own context
+            if let Err(e) = state.context.make_current() {
+                warn!("failed to make context current: {}", e);
             }
-            _ => {}
+        } else {
+            self.set_error(format!("invalid handle: {}", handle));
         }
 
-        match self {
-            Self::Ready(state) => state,
-            _ => unreachable!(),
+        if self.default_handle == Some(handle) {
+            self.default_handle = None;
         }
     }
 
-    fn if_init(&mut self) -> Option<&mut ApiState> {
-        match self {
-            Self::Ready(state) => Some(state),
-            _ => None,
+    fn set_error(&mut self, error: String) {
+        warn!("{}", error);
+        self.last_error = CString::new(error).ok();
+    }
+
+    /// Instance for `handle`, with its context made current
+    fn get(&mut self, handle: PgHandle) -> Option<&mut ApiState> {
+        let state = match self.states.get_mut(&handle) {
+            Some(state) => state,
+            None => {
+                self.set_error(format!("invalid handle: {}", handle));
+                return None;
+            }
+        };
+
+        if let Err(e) = state.context.make_current() {
+            state.set_error(format!("failed to make context current: {}", e));
+            return None;
         }
+
+        Some(state)
     }
 
-    fn terminate(&mut self) {
-        *self = Self::Unintialized;
+    fn default_handle(&mut self) -> PgHandle {
+        match self.default_handle {
+            Some(handle) => handle,
+            None => {
+                let handle = self.create();
+                if handle != 0 {
+                    self.default_handle = Some(handle);
+                }
+                handle
+            }
+        }
     }
 }
 
-static mut CURRENT_CONTEXT: ApiContext = ApiContext::Unintialized;
+static mut REGISTRY: Option<Registry> = None;
+
+fn registry() -> &'static mut Registry {
+    unsafe { REGISTRY.get_or_insert_with(Default::default) }
+}
+
+/// Handle of the default instance, 0 if it hasn't been initialized
+fn default_handle() -> PgHandle {
+    registry().default_handle.unwrap_or(0)
+}
 
+#[no_mangle]
+pub extern "C" fn pg_create(hide_window: bool) -> PgHandle {
+    if !hide_window {
+        registry().set_error("phasor.rs doesn't support windowed library usage".to_owned());
+        return 0;
+    }
+
+    registry().create()
+}
+
+#[no_mangle]
+pub extern "C" fn pg_destroy(handle: PgHandle) {
+    registry().destroy(handle);
+}
+
+/// Create the default instance used by the functions without a handle. These fail with an
+/// invalid handle error, reported by `pg_get_error`, until it is called.
 #[no_mangle]
 pub extern "C" fn pg_init(hide_window: bool) {
     if !hide_window {
         panic!("phasor.rs doesn't support windowed library usage");
     }
 
-    unsafe { CURRENT_CONTEXT.ensure_init() };
+    let registry = registry();
+    if registry.default_handle() == 0 {
+        panic!(
+            "{}",
+            registry
+                .last_error
+                .as_ref()
+                .map(|e| e.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        );
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn pg_terminate() {
-    unsafe { CURRENT_CONTEXT.terminate() };
+    if let Some(handle) = registry().default_handle {
+        registry().destroy(handle);
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn pg_optimize_ex(
+pub extern "C" fn pg_optimize_ex_h(
+    handle: PgHandle,
     width: i32,
     height: i32,
     kernel_count: i32,
@@ -158,7 +250,141 @@ pub extern "C" fn pg_optimize_ex(
     display_mode: i32,
     init_kernels: bool,
 ) -> *const f32 {
-    let api_state = unsafe { CURRENT_CONTEXT.ensure_init() };
+    pg_optimize_ex2_h(
+        handle,
+        width,
+        height,
+        kernel_count,
+        seed,
+        iterations,
+        angle_mode,
+        angle_offset,
+        angle_bandwidth,
+        angle_range,
+        frequency_mode,
+        frequency_min,
+        frequency_max,
+        frequency_bandwidth,
+        noise_bandwidth,
+        filter_bandwidth,
+        filter_modulation,
+        filter_modpower,
+        isotropy_mode,
+        isotropy_min,
+        isotropy_max,
+        isotropy_bandwidth,
+        isotropy_modulation,
+        isotropy_power,
+        cell_mode,
+        opt_method,
+        display_mode,
+        init_kernels,
+        1,
+        super::shared::NM_NONE as i32,
+        0.0,
+    )
+}
+
+/// Same as `pg_optimize_ex_h`, rendering at `supersample` (1, 2 or 4) times the resolution and
+/// downsampling the result to reduce aliasing. The result is rescaled according to
+/// `normalize_mode` (`NM_NONE`, `NM_MINMAX` or `NM_SIGMA`), `normalize_sigma` is the number of
+/// standard deviations mapped to [0, 1] around the mean by `NM_SIGMA`.
+#[no_mangle]
+pub extern "C" fn pg_optimize_ex2_h(
+    handle: PgHandle,
+    width: i32,
+    height: i32,
+    kernel_count: i32,
+    seed: i32,
+    iterations: i32,
+    angle_mode: i32,
+    angle_offset: f32,
+    angle_bandwidth: f32,
+    angle_range: f32,
+    frequency_mode: i32,
+    frequency_min: f32,
+    frequency_max: f32,
+    frequency_bandwidth: f32,
+    noise_bandwidth: f32,
+    filter_bandwidth: f32,
+    filter_modulation: f32,
+    filter_modpower: f32,
+    isotropy_mode: i32,
+    isotropy_min: f32,
+    isotropy_max: f32,
+    isotropy_bandwidth: f32,
+    isotropy_modulation: f32,
+    isotropy_power: f32,
+    cell_mode: i32,
+    opt_method: i32,
+    display_mode: i32,
+    init_kernels: bool,
+    supersample: i32,
+    normalize_mode: i32,
+    normalize_sigma: f32,
+) -> *const f32 {
+    let api_state = match registry().get(handle) {
+        Some(api_state) => api_state,
+        None => return std::ptr::null(),
+    };
+
+    // Previous results are invalid until this call succeeds
+    api_state.output_size = None;
+
+    if width <= 0 || height <= 0 {
+        api_state.set_error(format!("invalid image size: {}x{}", width, height));
+        return std::ptr::null();
+    }
+
+    if kernel_count <= 0 || kernel_count > super::shared::MAX_K as i32 {
+        api_state.set_error(format!(
+            "invalid kernel count {}, expected 1 to {}",
+            kernel_count,
+            super::shared::MAX_K
+        ));
+        return std::ptr::null();
+    }
+
+    let normalize = match NormalizeMode::from_raw(normalize_mode, normalize_sigma) {
+        Some(normalize) => normalize,
+        None => {
+            api_state.set_error(format!("invalid normalization mode: {}", normalize_mode));
+            return std::ptr::null();
+        }
+    };
+
+    // Check the constants received from C
+    let modes = (|| {
+        Ok::<_, InvalidConstant>((
+            AngleMode::try_from(angle_mode)?,
+            FrequencyMode::try_from(frequency_mode)?,
+            IsotropyMode::try_from(isotropy_mode)?,
+            CellMode::try_from(cell_mode)?,
+            DisplayMode::try_from(display_mode)?,
+        ))
+    })();
+
+    let (angle_mode, frequency_mode, isotropy_mode, cell_mode, display_mode) = match modes {
+        Ok(modes) => modes,
+        Err(e) => {
+            api_state.set_error(e.to_string());
+            return std::ptr::null();
+        }
+    };
+
+    // The optimization method is only used when running iterations
+    let mode = if iterations > 0 {
+        match OptimizationMode::try_from(opt_method) {
+            Ok(mode) => mode,
+            Err(e) => {
+                api_state.set_error(e.to_string());
+                return std::ptr::null();
+            }
+        }
+    } else {
+        OptimizationMode::None
+    };
+
     let state = &mut api_state.state;
 
     let params = Params {
@@ -184,62 +410,337 @@ pub extern "C" fn pg_optimize_ex(
         filter_modulation,
         kernel_count: kernel_count as u32,
         grid_size: Params::compute_grid_size(noise_bandwidth),
+        in_place_optimize: false,
+        use_lut: false,
+        emit_derivatives: false,
     };
 
     // Remember grid size change
     api_state.grid_size = params.grid_size;
     api_state.kernel_count = params.kernel_count as i32;
-
-    let mode = OptimizationMode::from(opt_method);
+    api_state.params = params.clone();
 
     if init_kernels {
-        state.run_init(&api_state.gl, &params);
+        if let Err(e) = state.run_init(&api_state.gl, &params) {
+            api_state.set_error(e);
+            return std::ptr::null();
+        }
     }
 
     if iterations > 0 {
-        state.run_optimize(&api_state.gl, mode, iterations as u32, &params);
+        if let Err(e) = state.run_optimize(&api_state.gl, mode, iterations as u32, &params) {
+            api_state.set_error(e);
+            return std::ptr::null();
+        }
     }
 
-    // TODO: Errors could happen here
-    state.render_to_texture(
+    if let Err(e) = state.render_to_texture(
         &api_state.gl,
         width as u32,
         height as u32,
+        supersample.max(0) as u32,
+        normalize,
         display_mode,
         &params,
         &mut api_state.buffer_main,
         &mut api_state.buffer_extra,
-    );
+    ) {
+        api_state.set_error(e);
+        return std::ptr::null();
+    }
+
+    for buffer in &mut [&mut api_state.buffer_main, &mut api_state.buffer_extra] {
+        api_state
+            .output_layout
+            .apply(buffer, width as usize, height as usize);
+    }
 
     // No error occurred
     api_state.last_error = None;
+    api_state.output_size = Some((width, height));
 
     api_state.buffer_main.as_ptr()
 }
 
+/// Same as `pg_optimize_ex2_h`, for the default instance created by `pg_init`
 #[no_mangle]
-pub extern "C" fn pg_get_extra() -> *const f32 {
-    unsafe {
-        CURRENT_CONTEXT
-            .if_init()
-            .map(|api_state| api_state.buffer_extra.as_ptr())
-            .unwrap_or(std::ptr::null())
+pub extern "C" fn pg_optimize_ex2(
+    width: i32,
+    height: i32,
+    kernel_count: i32,
+    seed: i32,
+    iterations: i32,
+    angle_mode: i32,
+    angle_offset: f32,
+    angle_bandwidth: f32,
+    angle_range: f32,
+    frequency_mode: i32,
+    frequency_min: f32,
+    frequency_max: f32,
+    frequency_bandwidth: f32,
+    noise_bandwidth: f32,
+    filter_bandwidth: f32,
+    filter_modulation: f32,
+    filter_modpower: f32,
+    isotropy_mode: i32,
+    isotropy_min: f32,
+    isotropy_max: f32,
+    isotropy_bandwidth: f32,
+    isotropy_modulation: f32,
+    isotropy_power: f32,
+    cell_mode: i32,
+    opt_method: i32,
+    display_mode: i32,
+    init_kernels: bool,
+    supersample: i32,
+    normalize_mode: i32,
+    normalize_sigma: f32,
+) -> *const f32 {
+    pg_optimize_ex2_h(
+        default_handle(),
+        width,
+        height,
+        kernel_count,
+        seed,
+        iterations,
+        angle_mode,
+        angle_offset,
+        angle_bandwidth,
+        angle_range,
+        frequency_mode,
+        frequency_min,
+        frequency_max,
+        frequency_bandwidth,
+        noise_bandwidth,
+        filter_bandwidth,
+        filter_modulation,
+        filter_modpower,
+        isotropy_mode,
+        isotropy_min,
+        isotropy_max,
+        isotropy_bandwidth,
+        isotropy_modulation,
+        isotropy_power,
+        cell_mode,
+        opt_method,
+        display_mode,
+        init_kernels,
+        supersample,
+        normalize_mode,
+        normalize_sigma,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn pg_optimize_ex(
+    width: i32,
+    height: i32,
+    kernel_count: i32,
+    seed: i32,
+    iterations: i32,
+    angle_mode: i32,
+    angle_offset: f32,
+    angle_bandwidth: f32,
+    angle_range: f32,
+    frequency_mode: i32,
+    frequency_min: f32,
+    frequency_max: f32,
+    frequency_bandwidth: f32,
+    noise_bandwidth: f32,
+    filter_bandwidth: f32,
+    filter_modulation: f32,
+    filter_modpower: f32,
+    isotropy_mode: i32,
+    isotropy_min: f32,
+    isotropy_max: f32,
+    isotropy_bandwidth: f32,
+    isotropy_modulation: f32,
+    isotropy_power: f32,
+    cell_mode: i32,
+    opt_method: i32,
+    display_mode: i32,
+    init_kernels: bool,
+) -> *const f32 {
+    pg_optimize_ex_h(
+        default_handle(),
+        width,
+        height,
+        kernel_count,
+        seed,
+        iterations,
+        angle_mode,
+        angle_offset,
+        angle_bandwidth,
+        angle_range,
+        frequency_mode,
+        frequency_min,
+        frequency_max,
+        frequency_bandwidth,
+        noise_bandwidth,
+        filter_bandwidth,
+        filter_modulation,
+        filter_modpower,
+        isotropy_mode,
+        isotropy_min,
+        isotropy_max,
+        isotropy_bandwidth,
+        isotropy_modulation,
+        isotropy_power,
+        cell_mode,
+        opt_method,
+        display_mode,
+        init_kernels,
+    )
+}
+
+/// Set the memory layout of the images returned by the optimize functions and `pg_get_extra_h`
+/// for `handle`. By default, rows are ordered from the bottom of the image (OpenGL convention)
+/// and pixels have 4 float channels (RGBA). If `origin_top_left` is non-zero, rows are ordered
+/// from the top of the image. `channels` is 4 for RGBA pixels or 1 for the first channel only,
+/// tightly packed. Returns false if `channels` is invalid.
+#[no_mangle]
+pub extern "C" fn pg_set_output_layout_h(
+    handle: PgHandle,
+    origin_top_left: i32,
+    channels: i32,
+) -> bool {
+    let api_state = match registry().get(handle) {
+        Some(api_state) => api_state,
+        None => return false,
+    };
+
+    match OutputLayout::new(origin_top_left != 0, channels.max(0) as u32) {
+        Ok(layout) => {
+            api_state.output_layout = layout;
+            true
+        }
+        Err(e) => {
+            api_state.set_error(e);
+            false
+        }
     }
 }
 
+/// Same as `pg_set_output_layout_h`, for the default instance
 #[no_mangle]
-pub extern "C" fn pg_noise_kernel_width(
+pub extern "C" fn pg_set_output_layout(origin_top_left: i32, channels: i32) -> bool {
+    pg_set_output_layout_h(default_handle(), origin_top_left, channels)
+}
+
+/// Render the kernels of the last optimization of `handle` into the framebuffer `fbo` of its
+/// context (0 for the default framebuffer), within the given viewport. Returns false on error.
+#[no_mangle]
+pub extern "C" fn pg_render_to_fbo_h(
+    handle: PgHandle,
+    fbo: u32,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    display_mode: i32,
+) -> bool {
+    let api_state = match registry().get(handle) {
+        Some(api_state) => api_state,
+        None => return false,
+    };
+
+    if w <= 0 || h <= 0 {
+        api_state.set_error(format!("invalid viewport size: {}x{}", w, h));
+        return false;
+    }
+
+    let display_mode = match DisplayMode::try_from(display_mode) {
+        Ok(display_mode) => display_mode,
+        Err(e) => {
+            api_state.set_error(e.to_string());
+            return false;
+        }
+    };
+
+    if let Err(e) = api_state.state.run_display_to(
+        &api_state.gl,
+        if fbo == 0 { None } else { Some(fbo) },
+        (x, y, w, h),
+        &api_state.params,
+        display_mode,
+    ) {
+        api_state.set_error(e);
+        return false;
+    }
+
+    api_state.last_error = None;
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn pg_render_to_fbo(
+    fbo: u32,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+    display_mode: i32,
+) -> bool {
+    pg_render_to_fbo_h(default_handle(), fbo, x, y, w, h, display_mode)
+}
+
+#[no_mangle]
+pub extern "C" fn pg_get_extra_h(handle: PgHandle) -> *const f32 {
+    registry()
+        .get(handle)
+        .and_then(|api_state| {
+            api_state
+                .output_size
+                .map(|_| api_state.buffer_extra.as_ptr())
+        })
+        .unwrap_or(std::ptr::null())
+}
+
+/// Size of the image returned by `pg_get_extra_h`. Returns false, leaving `width` and `height`
+/// unchanged, if the last optimize call of `handle` failed or there was none.
+#[no_mangle]
+pub extern "C" fn pg_get_extra_dims_h(handle: PgHandle, width: &mut i32, height: &mut i32) -> bool {
+    match registry()
+        .get(handle)
+        .and_then(|api_state| api_state.output_size)
+    {
+        Some((w, h)) => {
+            *width = w;
+            *height = h;
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn pg_get_extra_dims(width: &mut i32, height: &mut i32) -> bool {
+    pg_get_extra_dims_h(default_handle(), width, height)
+}
+
+#[no_mangle]
+pub extern "C" fn pg_get_extra() -> *const f32 {
+    pg_get_extra_h(default_handle())
+}
+
+/// Horizontal grid size of the instance for `handle`, 0 if it is invalid
+fn grid_size_x(handle: PgHandle) -> i32 {
+    registry()
+        .states
+        .get(&handle)
+        .map(|api_state| api_state.grid_size.x)
+        .unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn pg_noise_kernel_width_h(
+    handle: PgHandle,
     width: i32,
     noise_bandwidth: f32,
     filter_bandwidth: f32,
 ) -> f32 {
     use std::f32::consts::PI;
-    let xsize = unsafe {
-        CURRENT_CONTEXT
-            .if_init()
-            .map(|api_state| api_state.grid_size.x)
-            .unwrap_or(0)
-    };
+    let xsize = grid_size_x(handle);
 
     let b = if filter_bandwidth > 0.0 {
         noise_bandwidth.powi(2) / (noise_bandwidth.powi(2) + filter_bandwidth.powi(2)).sqrt()
@@ -251,27 +752,45 @@ pub extern "C" fn pg_noise_kernel_width(
 }
 
 #[no_mangle]
-pub extern "C" fn pg_gauss_kernel_width(width: i32, bandwidth: f32) -> f32 {
+pub extern "C" fn pg_noise_kernel_width(
+    width: i32,
+    noise_bandwidth: f32,
+    filter_bandwidth: f32,
+) -> f32 {
+    pg_noise_kernel_width_h(default_handle(), width, noise_bandwidth, filter_bandwidth)
+}
+
+#[no_mangle]
+pub extern "C" fn pg_gauss_kernel_width_h(handle: PgHandle, width: i32, bandwidth: f32) -> f32 {
     use std::f32::consts::PI;
-    let xsize = unsafe {
-        CURRENT_CONTEXT
-            .if_init()
-            .map(|api_state| api_state.grid_size.x)
-            .unwrap_or(0)
-    };
+    let xsize = grid_size_x(handle);
 
     (-(0.05f32.ln()) / PI).sqrt() / bandwidth * xsize as f32 / width as f32
 }
 
 #[no_mangle]
-pub extern "C" fn pg_get_error() -> *const i8 {
-    unsafe {
-        CURRENT_CONTEXT
-            .if_init()
-            .and_then(|api_state| api_state.last_error.as_ref())
-            .map(|err| err.as_ptr())
-            .unwrap_or(std::ptr::null())
+pub extern "C" fn pg_gauss_kernel_width(width: i32, bandwidth: f32) -> f32 {
+    pg_gauss_kernel_width_h(default_handle(), width, bandwidth)
+}
+
+/// Last error of the instance for `handle`, or of the last call with an invalid handle
+#[no_mangle]
+pub extern "C" fn pg_get_error_h(handle: PgHandle) -> *const i8 {
+    let registry = registry();
+
+    match registry.states.get(&handle) {
+        Some(api_state) => api_state.last_error.as_ref(),
+        None => registry.last_error.as_ref(),
     }
+    .map(|err| err.as_ptr())
+    .unwrap_or(std::ptr::null())
+}
+
+/// Last error of the default instance, or of the last call with an invalid handle, such as a
+/// function without a handle called before `pg_init`
+#[no_mangle]
+pub extern "C" fn pg_get_error() -> *const i8 {
+    pg_get_error_h(default_handle())
 }
 
 #[no_mangle]
@@ -279,45 +798,106 @@ pub extern "C" fn pg_get_max_kernels() -> i32 {
     super::shared::MAX_K as i32
 }
 
+#[no_mangle]
+pub extern "C" fn pg_get_kernels_h(
+    handle: PgHandle,
+    grid_x: &mut i32,
+    grid_y: &mut i32,
+    kernel_count: &mut i32,
+) -> *const Kernel {
+    registry()
+        .get(handle)
+        .map(|api_state| {
+            *grid_x = api_state.grid_size.x;
+            *grid_y = api_state.grid_size.y;
+            *kernel_count = api_state.kernel_count;
+
+            // Allocate CPU-side buffer that's large enough
+            let target_size = std::mem::size_of::<Kernel>() / std::mem::size_of::<f32>()
+                * (*grid_x * *grid_y * *kernel_count) as usize;
+            if api_state.buffer_kernels.len() < target_size {
+                api_state.buffer_kernels.resize(target_size, 0.0);
+            }
+
+            // Copy data to CPU
+            api_state
+                .state
+                .read_kernels(&api_state.gl, &mut api_state.buffer_kernels[..target_size]);
+
+            api_state.buffer_kernels.as_ptr() as *const _
+        })
+        .unwrap_or(std::ptr::null())
+}
+
 #[no_mangle]
 pub extern "C" fn pg_get_kernels(
     grid_x: &mut i32,
     grid_y: &mut i32,
     kernel_count: &mut i32,
 ) -> *const Kernel {
-    unsafe {
-        CURRENT_CONTEXT
-            .if_init()
-            .and_then(|api_state| {
-                *grid_x = api_state.grid_size.x;
-                *grid_y = api_state.grid_size.y;
-                *kernel_count = api_state.kernel_count;
-
-                // Allocate CPU-side buffer that's large enough
-                let target_size = std::mem::size_of::<Kernel>() / std::mem::size_of::<f32>()
-                    * (*grid_x * *grid_y * *kernel_count) as usize;
-                if api_state.buffer_kernels.len() < target_size {
-                    api_state.buffer_kernels.resize(target_size, 0.0);
-                }
+    pg_get_kernels_h(default_handle(), grid_x, grid_y, kernel_count)
+}
+
+#[no_mangle]
+pub extern "C" fn pg_set_kernels_h(
+    handle: PgHandle,
+    kernels: *const Kernel,
+    grid_x: i32,
+    grid_y: i32,
+    kernel_count: i32,
+) -> bool {
+    let len = std::mem::size_of::<Kernel>() / std::mem::size_of::<f32>()
+        * (grid_x.max(0) * grid_y.max(0) * kernel_count.max(0)) as usize;
+
+    // Kernel data returned by pg_get_kernels_h for another instance is only valid for the size
+    // it was returned with
+    let registry = registry();
+    let misused = registry.states.iter().find_map(|(other, api_state)| {
+        let ptr = kernels as *const f32;
+        if api_state.owns_kernels(ptr)
+            && ptr.wrapping_add(len) > api_state.buffer_kernels.as_ptr_range().end
+        {
+            Some(*other)
+        } else {
+            None
+        }
+    });
 
-                // Bind buffer
-                let buf = api_state.state.kernels_buffer();
-                buf.bind(&api_state.gl, tinygl::gl::COPY_READ_BUFFER);
-                // Copy data to CPU
-                api_state.gl.get_buffer_sub_data(
-                    tinygl::gl::COPY_READ_BUFFER,
-                    0,
-                    std::slice::from_raw_parts_mut(
-                        api_state.buffer_kernels.as_mut_ptr() as *mut u8,
-                        target_size * std::mem::size_of::<f32>(),
-                    ),
-                );
-                // Unbind buffer
-                api_state.gl.bind_buffer(tinygl::gl::COPY_READ_BUFFER, None);
+    let api_state = match registry.get(handle) {
+        Some(api_state) => api_state,
+        None => return false,
+    };
 
-                Some(api_state.buffer_kernels.as_ptr() as *const _)
-            })
-            .unwrap_or(std::ptr::null())
+    if kernels.is_null() {
+        api_state.set_error("null kernel pointer");
+        return false;
+    }
+
+    if let Some(other) = misused {
+        api_state.set_error(format!(
+            "kernel data of handle {} is smaller than {}x{}x{} kernels",
+            other, grid_x, grid_y, kernel_count
+        ));
+        return false;
+    }
+
+    api_state.grid_size = cgmath::vec3(grid_x, grid_y, 1);
+    api_state.kernel_count = kernel_count;
+
+    // Copy data to GPU
+    let data = unsafe { std::slice::from_raw_parts(kernels as *const f32, len) };
+
+    match api_state.state.write_kernels(
+        &api_state.gl,
+        api_state.grid_size,
+        kernel_count as usize,
+        data,
+    ) {
+        Ok(()) => true,
+        Err(e) => {
+            api_state.set_error(e);
+            false
+        }
     }
 }
 
@@ -328,40 +908,234 @@ pub extern "C" fn pg_set_kernels(
     grid_y: i32,
     kernel_count: i32,
 ) -> bool {
-    unsafe {
-        CURRENT_CONTEXT
-            .if_init()
-            .and_then(|api_state| {
-                api_state.grid_size = cgmath::vec3(grid_x, grid_y, 1);
-                api_state.kernel_count = kernel_count;
-
-                // Bind buffer
-                let buf = api_state.state.kernels_buffer();
-                buf.bind(&api_state.gl, tinygl::gl::COPY_WRITE_BUFFER);
-                // Copy data to CPU
-                api_state.gl.buffer_data_u8_slice(
-                    tinygl::gl::COPY_WRITE_BUFFER,
-                    std::slice::from_raw_parts(
-                        kernels as *const u8,
-                        std::mem::size_of::<Kernel>() * (grid_x * grid_y * kernel_count) as usize,
-                    ),
-                    tinygl::gl::DYNAMIC_DRAW,
-                );
-                // Unbind buffer
-                api_state
-                    .gl
-                    .bind_buffer(tinygl::gl::COPY_WRITE_BUFFER, None);
-
-                Some(true)
-            })
-            .unwrap_or(false)
+    pg_set_kernels_h(default_handle(), kernels, grid_x, grid_y, kernel_count)
+}
+
+/// Replace the `count` kernels of the grid cell (`cell_x`, `cell_y`) of `handle` with
+/// `kernels`, leaving the other cells unchanged. `count` must be the kernel count of the last
+/// optimize call. Returns false on error.
+#[no_mangle]
+pub extern "C" fn pg_set_cell_kernels_h(
+    handle: PgHandle,
+    cell_x: i32,
+    cell_y: i32,
+    kernels: *const Kernel,
+    count: i32,
+) -> bool {
+    let api_state = match registry().get(handle) {
+        Some(api_state) => api_state,
+        None => return false,
+    };
+
+    if kernels.is_null() {
+        api_state.set_error("null kernel pointer");
+        return false;
+    }
+
+    let kernels = unsafe { std::slice::from_raw_parts(kernels, count.max(0) as usize) };
+
+    match api_state
+        .state
+        .write_kernel_cell(&api_state.gl, cgmath::vec2(cell_x, cell_y), kernels)
+    {
+        Ok(()) => true,
+        Err(e) => {
+            api_state.set_error(e);
+            false
+        }
     }
 }
 
+#[no_mangle]
+pub extern "C" fn pg_set_cell_kernels(
+    cell_x: i32,
+    cell_y: i32,
+    kernels: *const Kernel,
+    count: i32,
+) -> bool {
+    pg_set_cell_kernels_h(default_handle(), cell_x, cell_y, kernels, count)
+}
+
+/// Copy the kernels of the grid cell (`cell_x`, `cell_y`) of `handle` into `kernels`, which
+/// holds `count` kernels. `count` must be the kernel count of the last optimize call. Returns
+/// false on error.
+#[no_mangle]
+pub extern "C" fn pg_get_cell_kernels_h(
+    handle: PgHandle,
+    cell_x: i32,
+    cell_y: i32,
+    kernels: *mut Kernel,
+    count: i32,
+) -> bool {
+    let api_state = match registry().get(handle) {
+        Some(api_state) => api_state,
+        None => return false,
+    };
+
+    if kernels.is_null() {
+        api_state.set_error("null kernel pointer");
+        return false;
+    }
+
+    let cell = match api_state
+        .state
+        .read_kernel_cell(&api_state.gl, cgmath::vec2(cell_x, cell_y))
+    {
+        Ok(cell) => cell,
+        Err(e) => {
+            api_state.set_error(e);
+            return false;
+        }
+    };
+
+    if cell.len() != count.max(0) as usize {
+        api_state.set_error(format!(
+            "got room for {} kernels, cells have {} kernels",
+            count,
+            cell.len()
+        ));
+        return false;
+    }
+
+    unsafe { std::slice::from_raw_parts_mut(kernels, cell.len()) }.copy_from_slice(&cell);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn pg_get_cell_kernels(
+    cell_x: i32,
+    cell_y: i32,
+    kernels: *mut Kernel,
+    count: i32,
+) -> bool {
+    pg_get_cell_kernels_h(default_handle(), cell_x, cell_y, kernels, count)
+}
+
+/// GPU memory used by `handle`, in bytes, for its kernel storage and its render target, and
+/// grid size and kernel count of its kernel storage. Returns false if `handle` is invalid.
+#[no_mangle]
+pub extern "C" fn pg_get_allocation_info_h(
+    handle: PgHandle,
+    kernel_bytes: &mut u64,
+    render_target_bytes: &mut u64,
+    grid_x: &mut i32,
+    grid_y: &mut i32,
+    kernel_count: &mut i32,
+) -> bool {
+    registry()
+        .get(handle)
+        .map(|api_state| {
+            let info = api_state.state.allocation_info();
+            *kernel_bytes = info.kernel_bytes;
+            *render_target_bytes = info.render_target_bytes;
+            *grid_x = info.grid.x;
+            *grid_y = info.grid.y;
+            *kernel_count = info.kernel_count as i32;
+        })
+        .is_some()
+}
+
+#[no_mangle]
+pub extern "C" fn pg_get_allocation_info(
+    kernel_bytes: &mut u64,
+    render_target_bytes: &mut u64,
+    grid_x: &mut i32,
+    grid_y: &mut i32,
+    kernel_count: &mut i32,
+) -> bool {
+    pg_get_allocation_info_h(
+        default_handle(),
+        kernel_bytes,
+        render_target_bytes,
+        grid_x,
+        grid_y,
+        kernel_count,
+    )
+}
+
+/// Limit the GPU memory used by `handle` to `budget` bytes, 0 for no limit. Optimize calls
+/// that would exceed it fail with an error. Returns false if `handle` is invalid.
+#[no_mangle]
+pub extern "C" fn pg_set_memory_budget_h(handle: PgHandle, budget: u64) -> bool {
+    registry()
+        .get(handle)
+        .map(|api_state| {
+            api_state
+                .state
+                .set_memory_budget(if budget == 0 { None } else { Some(budget) })
+        })
+        .is_some()
+}
+
+#[no_mangle]
+pub extern "C" fn pg_set_memory_budget(budget: u64) -> bool {
+    pg_set_memory_budget_h(default_handle(), budget)
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    /// The API is not thread-safe, tests using it run one at a time
+    static API_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    fn lock_api() -> std::sync::MutexGuard<'static, ()> {
+        API_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Optimize and render a 64x64 image with the default parameters and the given seed
+    fn optimize(handle: PgHandle, seed: i32) -> Option<Vec<f32>> {
+        optimize_sized(handle, seed, 64)
+    }
+
+    /// Optimize and render a `size` by `size` image with the default parameters
+    fn optimize_sized(handle: PgHandle, seed: i32, size: i32) -> Option<Vec<f32>> {
+        let params = crate::Params::default();
+        let ptr = pg_optimize_ex_h(
+            handle,
+            size,
+            size,
+            16,
+            seed,
+            4,
+            params.angle_mode.as_raw(),
+            params.angle_offset,
+            params.angle_bandwidth,
+            params.angle_range,
+            params.frequency_mode.as_raw(),
+            params.min_frequency,
+            params.max_frequency,
+            params.frequency_bandwidth,
+            params.noise_bandwidth,
+            params.filter_bandwidth,
+            params.filter_modulation,
+            params.filter_mod_power,
+            params.isotropy_mode.as_raw(),
+            params.min_isotropy,
+            params.max_isotropy,
+            params.isotropy_bandwidth,
+            params.isotropy_modulation,
+            params.isotropy_power,
+            params.cell_mode.as_raw(),
+            crate::OptimizationMethod::Average.as_raw(),
+            crate::DisplayMode::Noise.as_raw(),
+            true,
+        );
+
+        if ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { std::slice::from_raw_parts(ptr, (size * size * 4) as usize) }.to_vec())
+        }
+    }
+
     #[test]
     fn pg_optimize_ex() {
+        if !crate::tests::gl_available() {
+            return;
+        }
+
+        let _lock = lock_api();
         super::pg_init(true);
 
         let params = crate::Params::default();
@@ -371,11 +1145,11 @@ mod tests {
             16,
             params.global_seed,
             4,
-            params.angle_mode,
+            params.angle_mode.as_raw(),
             params.angle_offset,
             params.angle_bandwidth,
             params.angle_range,
-            params.frequency_mode,
+            params.frequency_mode.as_raw(),
             params.min_frequency,
             params.max_frequency,
             params.frequency_bandwidth,
@@ -383,16 +1157,178 @@ mod tests {
             params.filter_bandwidth,
             params.filter_modulation,
             params.filter_mod_power,
-            params.isotropy_mode,
+            params.isotropy_mode.as_raw(),
             params.min_isotropy,
             params.max_isotropy,
             params.isotropy_bandwidth,
             params.isotropy_modulation,
             params.isotropy_power,
-            params.cell_mode,
-            crate::shared::OM_AVERAGE as i32,
-            crate::shared::DM_NOISE as i32,
+            params.cell_mode.as_raw(),
+            crate::OptimizationMethod::Average.as_raw(),
+            crate::DisplayMode::Noise.as_raw(),
             true,
         );
+
+        super::pg_terminate();
+    }
+
+    #[test]
+    fn default_instance_required() {
+        let _lock = lock_api();
+        assert!(!super::pg_set_output_layout(0, 4));
+
+        let error = unsafe { std::ffi::CStr::from_ptr(super::pg_get_error()) };
+        assert_eq!(error.to_str().unwrap(), "invalid handle: 0");
+    }
+
+    #[test]
+    fn independent_handles() {
+        if !crate::tests::gl_available() {
+            return;
+        }
+
+        let _lock = lock_api();
+        let first = pg_create(true);
+        let second = pg_create(true);
+        assert!(first != 0 && second != 0 && first != second);
+
+        let a = optimize(first, 1).unwrap();
+        let b = optimize(second, 2).unwrap();
+        assert!(a != b);
+
+        // Interleaved calls don't affect each other
+        assert!(optimize(first, 1).unwrap() == a);
+        assert!(optimize(second, 2).unwrap() == b);
+
+        pg_destroy(first);
+        pg_destroy(second);
+    }
+
+    #[test]
+    fn invalid_handles() {
+        let _lock = lock_api();
+
+        assert!(optimize(-1, 1).is_none());
+        assert!(!pg_get_error_h(-1).is_null());
+        assert!(pg_get_extra_h(-1).is_null());
+
+        let (mut x, mut y, mut k) = (0, 0, 0);
+        assert!(pg_get_kernels_h(-1, &mut x, &mut y, &mut k).is_null());
+        assert!(!pg_set_kernels_h(-1, std::ptr::null(), 1, 1, 1));
+    }
+
+    #[test]
+    fn stale_extra_after_failure() {
+        if !crate::tests::gl_available() {
+            return;
+        }
+
+        let _lock = lock_api();
+        let handle = pg_create(true);
+        let (mut w, mut h) = (0, 0);
+
+        // Nothing rendered yet
+        assert!(pg_get_extra_h(handle).is_null());
+        assert!(!pg_get_extra_dims_h(handle, &mut w, &mut h));
+
+        assert!(optimize_sized(handle, 1, 32).is_some());
+        assert!(!pg_get_extra_h(handle).is_null());
+        assert!(pg_get_extra_dims_h(handle, &mut w, &mut h));
+        assert_eq!((w, h), (32, 32));
+
+        // A failed call invalidates the previous results
+        assert!(optimize_sized(handle, 1, -1).is_none());
+        assert!(!pg_get_error_h(handle).is_null());
+        assert!(pg_get_extra_h(handle).is_null());
+        assert!(!pg_get_extra_dims_h(handle, &mut w, &mut h));
+        assert_eq!((w, h), (32, 32));
+
+        pg_destroy(handle);
+    }
+
+    #[test]
+    fn cell_kernels() {
+        if !crate::tests::gl_available() {
+            return;
+        }
+
+        let _lock = lock_api();
+        let handle = pg_create(true);
+        assert!(optimize(handle, 1).is_some());
+
+        let mut cell = vec![
+            Kernel {
+                x: 0.0,
+                y: 0.0,
+                frequency: 0.0,
+                phase: 0.0,
+                angle: 0.0,
+                state: 0.0,
+            };
+            16
+        ];
+        assert!(pg_get_cell_kernels_h(handle, 2, 3, cell.as_mut_ptr(), 16));
+
+        for kernel in &mut cell {
+            kernel.phase = 0.5;
+        }
+        assert!(pg_set_cell_kernels_h(handle, 2, 3, cell.as_ptr(), 16));
+
+        let mut read = cell.clone();
+        assert!(pg_get_cell_kernels_h(handle, 2, 3, read.as_mut_ptr(), 16));
+        assert!(read.iter().all(|kernel| kernel.phase == 0.5));
+
+        // Wrong kernel count and out of range cell
+        assert!(!pg_set_cell_kernels_h(handle, 2, 3, cell.as_ptr(), 8));
+        assert!(!pg_get_cell_kernels_h(handle, -1, 3, read.as_mut_ptr(), 16));
+        assert!(!pg_get_error_h(handle).is_null());
+
+        pg_destroy(handle);
+    }
+
+    #[test]
+    fn cell_kernels_after_set_kernels() {
+        if !crate::tests::gl_available() {
+            return;
+        }
+
+        let _lock = lock_api();
+        let handle = pg_create(true);
+        assert!(optimize(handle, 1).is_some());
+
+        // A 4x2 grid of 3 kernels, unlike the layout of the optimize call
+        let kernel = Kernel {
+            x: 0.0,
+            y: 0.0,
+            frequency: 1.0,
+            phase: 0.0,
+            angle: 0.0,
+            state: 0.0,
+        };
+        let kernels = vec![kernel; 4 * 2 * 3];
+        assert!(pg_set_kernels_h(handle, kernels.as_ptr(), 4, 2, 3));
+
+        let cell = vec![
+            Kernel {
+                phase: 0.5,
+                ..kernel
+            };
+            3
+        ];
+        assert!(pg_set_cell_kernels_h(handle, 3, 1, cell.as_ptr(), 3));
+
+        let (mut x, mut y, mut k) = (0, 0, 0);
+        let ptr = pg_get_kernels_h(handle, &mut x, &mut y, &mut k);
+        assert_eq!((x, y, k), (4, 2, 3));
+        let read = unsafe { std::slice::from_raw_parts(ptr, kernels.len()) };
+        for (i, kernel) in read.iter().enumerate() {
+            assert_eq!(kernel.phase, if i >= 21 { 0.5 } else { 0.0 });
+        }
+
+        // The previous layout no longer applies
+        assert!(!pg_set_cell_kernels_h(handle, 3, 1, cell.as_ptr(), 16));
+        assert!(!pg_set_cell_kernels_h(handle, 4, 1, cell.as_ptr(), 3));
+
+        pg_destroy(handle);
     }
 }
@@ -0,0 +1,132 @@
+use std::rc::Rc;
+use std::time::Instant;
+
+use tinygl::prelude::*;
+
+use super::probe::HeadlessContext;
+use super::{DisplayMode, NormalizeMode, OptimizationMode, Params, State};
+
+/// Exit code of `phasor bench` when no OpenGL context could be created
+pub const NO_CONTEXT_EXIT_CODE: i32 = 3;
+
+/// Kernel counts of the benchmark presets
+const KERNEL_COUNTS: &[u32] = &[16, 32];
+
+/// Noise bandwidths of the benchmark presets
+const NOISE_BANDWIDTHS: &[f32] = &[1.0, 1.692568750643269];
+
+/// Size of the rendered texture
+const RENDER_SIZE: u32 = 512;
+
+pub enum BenchError {
+    /// No OpenGL context could be created
+    NoContext(String),
+    /// The benchmark failed
+    Failed(String),
+}
+
+/// Timings of one benchmark preset, averaged over all iterations
+pub struct BenchResult {
+    pub kernel_count: u32,
+    pub noise_bandwidth: f32,
+    pub init_ms: f64,
+    pub optimize_ms: f64,
+    pub display_ms: f64,
+}
+
+impl BenchResult {
+    /// Identifier of the preset
+    pub fn preset(&self) -> String {
+        format!("k{}_b{:.2}", self.kernel_count, self.noise_bandwidth)
+    }
+
+    /// Single-line JSON representation of this result
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"preset": "{}", "kernel_count": {}, "noise_bandwidth": {}, "init_ms": {:.4}, "optimize_ms": {:.4}, "display_ms": {:.4}}}"#,
+            self.preset(),
+            self.kernel_count,
+            self.noise_bandwidth,
+            self.init_ms,
+            self.optimize_ms,
+            self.display_ms
+        )
+    }
+}
+
+/// Average duration in milliseconds of `iterations` runs of `f`, waiting for the GPU after each.
+/// Stops at the first error of `f`.
+fn time_ms<T>(
+    gl: &Rc<tinygl::Context>,
+    iterations: u32,
+    mut f: impl FnMut() -> Result<T, String>,
+) -> Result<f64, BenchError> {
+    let start = Instant::now();
+
+    for _ in 0..iterations {
+        f().map_err(BenchError::Failed)?;
+        unsafe { gl.finish() };
+    }
+
+    Ok(start.elapsed().as_secs_f64() * 1000.0 / iterations.max(1) as f64)
+}
+
+/// Time `iterations` init, optimization and display steps for each benchmark preset, in a
+/// headless context
+pub fn run(iterations: u32) -> Result<Vec<BenchResult>, BenchError> {
+    let context = HeadlessContext::new(RENDER_SIZE, RENDER_SIZE).map_err(BenchError::NoContext)?;
+    let gl = &context.gl;
+
+    // Build an empty VAO for quad rendering
+    let vao = tinygl::wrappers::VertexArray::new(gl)
+        .map_err(|e| BenchError::Failed(format!("failed to create VAO: {}", e)))?;
+    unsafe {
+        vao.bind(gl);
+    }
+
+    let mut state = State::new(gl)
+        .map_err(|e| BenchError::Failed(format!("failed to initialize state: {}", e)))?;
+
+    let mut buffer_main = Vec::new();
+    let mut buffer_extra = Vec::new();
+    let mut results = Vec::new();
+
+    for &kernel_count in KERNEL_COUNTS {
+        for &noise_bandwidth in NOISE_BANDWIDTHS {
+            let params = Params {
+                kernel_count,
+                noise_bandwidth,
+                grid_size: Params::compute_grid_size(noise_bandwidth),
+                ..Default::default()
+            };
+
+            let init_ms = time_ms(gl, iterations, || state.run_init(gl, &params))?;
+            let optimize_ms = time_ms(gl, iterations, || {
+                state.run_optimize(gl, OptimizationMode::Optimize, 1, &params)
+            })?;
+            let display_ms = time_ms(gl, iterations, || {
+                state.render_to_texture(
+                    gl,
+                    RENDER_SIZE,
+                    RENDER_SIZE,
+                    1,
+                    NormalizeMode::None,
+                    DisplayMode::Noise,
+                    &params,
+                    &mut buffer_main,
+                    &mut buffer_extra,
+                )
+            })?;
+
+            results.push(BenchResult {
+                kernel_count,
+                noise_bandwidth,
+                init_ms,
+                optimize_ms,
+                display_ms,
+            });
+        }
+    }
+
+    Ok(results)
+}
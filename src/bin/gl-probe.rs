@@ -0,0 +1,12 @@
+//! Print a description of the available OpenGL implementation as JSON, exits with 1 if no
+//! OpenGL 4.6 context could be created
+
+fn main() {
+    match phasor::probe::probe() {
+        Ok(info) => println!("{}", info.to_json()),
+        Err(e) => {
+            eprintln!("no OpenGL context available: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
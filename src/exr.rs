@@ -0,0 +1,104 @@
+//! Minimal OpenEXR writer for uncompressed single-part scanline images of 32-bit float
+//! channels
+
+use std::io::Write;
+use std::path::Path;
+
+/// OpenEXR magic number and version 2 flags (single-part scanline)
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+
+/// Pixel type of FLOAT channels
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+fn write_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+fn box2i(width: u32, height: u32) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// Encode a `width` by `height` image with the given named channels. Channel data is stored
+/// row by row, from the top row to the bottom one.
+pub fn encode(width: u32, height: u32, channels: &[(&str, &[f32])]) -> Vec<u8> {
+    let (w, h) = (width as usize, height as usize);
+    assert!(channels.iter().all(|(_, data)| data.len() == w * h));
+
+    // Channels must be sorted by name
+    let mut channels = channels.to_vec();
+    channels.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION);
+
+    // Header
+    let mut chlist = Vec::new();
+    for (name, _) in &channels {
+        chlist.extend_from_slice(name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // pLinear and reserved bytes, then x and y sampling
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    write_attribute(&mut out, "channels", "chlist", &chlist);
+    write_attribute(&mut out, "compression", "compression", &[0]);
+    write_attribute(&mut out, "dataWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut out, "displayWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut out, "lineOrder", "lineOrder", &[0]);
+    write_attribute(&mut out, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    write_attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    write_attribute(
+        &mut out,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    );
+    out.push(0);
+
+    // Offset table, one uncompressed scanline per chunk
+    let line_size = channels.len() * w * std::mem::size_of::<f32>();
+    let first_line = out.len() + h * std::mem::size_of::<u64>();
+    for y in 0..h {
+        let offset = first_line + y * (line_size + 2 * std::mem::size_of::<i32>());
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+    }
+
+    // Scanlines
+    for y in 0..h {
+        out.extend_from_slice(&(y as i32).to_le_bytes());
+        out.extend_from_slice(&(line_size as i32).to_le_bytes());
+
+        for (_, data) in &channels {
+            for v in &data[y * w..(y + 1) * w] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+    }
+
+    out
+}
+
+/// Write an image to `path`, see `encode`
+pub fn write(
+    path: &Path,
+    width: u32,
+    height: u32,
+    channels: &[(&str, &[f32])],
+) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(&encode(width, height, channels))
+}
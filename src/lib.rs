@@ -6,61 +6,350 @@ use std::rc::Rc;
 use tinygl::prelude::*;
 use tinygl::wrappers::GlHandle;
 
+pub mod analysis;
 pub mod api;
+pub mod bench;
+mod exr;
 pub mod log;
+mod normalize_mode;
+pub use normalize_mode::*;
 mod optimization_mode;
 pub use optimization_mode::*;
+mod output_layout;
+pub use output_layout::*;
+pub mod palette;
 mod params;
 pub use params::*;
+pub mod params_file;
+pub mod probe;
 pub mod shaders;
 pub mod shared;
+pub mod shared_types;
+pub use shared_types::{
+    AngleMode, CellMode, DisplayMode, FrequencyMode, InvalidConstant, IsotropyMode,
+    OptimizationMethod, SharedEnum,
+};
 mod texture_render_target;
 use texture_render_target::*;
 
+/// Size of the image reduced by `State::compute_range` before any image has been rendered
+pub const DEFAULT_RANGE_SIZE: u32 = 512;
+
+/// Environment variable lowering the texture buffer size limit, in texels
+pub const MAX_TEXTURE_BUFFER_SIZE_VAR: &str = "PHASOR_MAX_TEXTURE_BUFFER_SIZE";
+
+/// Texels of `kernels`, as stored in the kernel shards
+fn kernels_as_texels(kernels: &[shared::Kernel]) -> &[f32] {
+    unsafe {
+        std::slice::from_raw_parts(
+            kernels.as_ptr() as *const f32,
+            kernels.len() * shared_types::NFLOATS,
+        )
+    }
+}
+
+/// Allocated size of `shards`, in bytes
+fn shards_bytes(shards: &[KernelShard]) -> u64 {
+    shards.iter().map(KernelShard::bytes).sum()
+}
+
+/// Grow `shards` to hold at least `texels` texels, in shards of at most `capacity` texels
+fn alloc_shards(
+    gl: &Rc<tinygl::Context>,
+    shards: &mut Vec<KernelShard>,
+    texels: usize,
+    capacity: usize,
+) -> Result<(), String> {
+    while shards.len() < (texels + capacity - 1) / capacity {
+        shards.push(KernelShard::new(gl).map_err(|e| e.to_string())?);
+    }
+
+    for (i, shard) in shards.iter_mut().enumerate() {
+        shard.alloc(gl, texels.saturating_sub(i * capacity).min(capacity))?;
+    }
+
+    Ok(())
+}
+
+/// Bind `shards` to the image units starting at `binding`
+unsafe fn bind_shards(gl: &Rc<tinygl::Context>, shards: &[KernelShard], binding: u32) {
+    // Shard images use consecutive bindings, see shared.h
+    for (i, shard) in shards.iter().enumerate() {
+        gl.bind_image_texture(
+            binding + i as u32,
+            Some(&shard.texture),
+            0,
+            false,
+            0,
+            tinygl::gl::READ_WRITE,
+            tinygl::gl::R32F,
+        );
+    }
+}
+
+/// Part of the kernel storage, bound as a texture buffer
+struct KernelShard {
+    buffer: GlHandle<tinygl::wrappers::Buffer>,
+    texture: GlHandle<tinygl::wrappers::Texture>,
+    /// Allocated size, in texels
+    texels: usize,
+}
+
+impl KernelShard {
+    fn new(gl: &Rc<tinygl::Context>) -> tinygl::Result<Self> {
+        let shard = Self {
+            buffer: GlHandle::new(gl, tinygl::wrappers::Buffer::new(&gl)?),
+            texture: GlHandle::new(gl, tinygl::wrappers::Texture::new(&gl)?),
+            texels: 0,
+        };
+
+        // Setup texture for buffer storage
+        unsafe {
+            shard.texture.bind(gl, tinygl::gl::TEXTURE_BUFFER);
+            gl.tex_buffer(
+                tinygl::gl::TEXTURE_BUFFER,
+                tinygl::gl::R32F,
+                shard.buffer.name(),
+            );
+            gl.bind_texture(tinygl::gl::TEXTURE_BUFFER, None);
+        }
+
+        Ok(shard)
+    }
+
+    /// Allocated size, in bytes
+    fn bytes(&self) -> u64 {
+        (self.texels * std::mem::size_of::<f32>()) as u64
+    }
+
+    /// Grow the buffer storage to `texels` texels, discarding its contents
+    fn alloc(&mut self, gl: &Rc<tinygl::Context>, texels: usize) -> Result<(), String> {
+        if texels <= self.texels {
+            return Ok(());
+        }
+
+        // Setupinitialize buffer storage
+        unsafe {
+            self.buffer.bind(gl, tinygl::gl::TEXTURE_BUFFER);
+            gl.buffer_data_size(
+                tinygl::gl::TEXTURE_BUFFER,
+                (texels * std::mem::size_of::<f32>()) as i32,
+                tinygl::gl::DYNAMIC_DRAW,
+            );
+
+            // Check allocation errors
+            let error = gl.check_last_error();
+
+            gl.bind_buffer(tinygl::gl::TEXTURE_BUFFER, None);
+
+            // If there's an error, allocation was not successful
+            error.map_err(|e| e.to_string())?;
+        }
+
+        self.texels = texels;
+        Ok(())
+    }
+}
+
+/// GPU memory used by a `State`, see `State::allocation_info`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllocationInfo {
+    /// Kernel storage, including the staged updates and the comparison kernels
+    pub kernel_bytes: u64,
+    /// Render target of `State::render_to_texture`
+    pub render_target_bytes: u64,
+    /// Grid size of the last grid check
+    pub grid: cgmath::Vector3<i32>,
+    /// Kernels per cell of the last grid check
+    pub kernel_count: u32,
+}
+
+/// Optimization state and GPU passes, see `render_deterministic` for reproducible results
 pub struct State {
     display_program: GlHandle<shaders::DisplayProgram>,
     init_program: GlHandle<shaders::InitProgram>,
     opt_program: GlHandle<shaders::OptProgram>,
-    kernels: GlHandle<tinygl::wrappers::Buffer>,
-    kernel_texture: GlHandle<tinygl::wrappers::Texture>,
+    range_program: GlHandle<shaders::RangeProgram>,
+    normalize_program: GlHandle<shaders::NormalizeProgram>,
+    /// Ellipses of `DisplayMode::Kernels`
+    kernel_viz_program: GlHandle<shaders::KernelVizProgram>,
+    kernel_shards: Vec<KernelShard>,
+    /// Second kernel set of a split-screen comparison, see `start_comparison`
+    comparison_shards: Option<Vec<KernelShard>>,
+    /// Staged phase and state updates of the optimization pass, sharded like the kernels
+    next_kernels: Vec<KernelShard>,
+    /// Maximum size of a kernel shard, in texels
+    shard_capacity: usize,
     allocated_size: usize,
+    /// Kernels per cell, as of the last grid check
+    kernel_count: usize,
+    /// Grid size, as of the last grid check
+    grid_size: cgmath::Vector3<i32>,
     texture_render_target: Option<TextureRenderTarget>,
+    /// Color lookup table of the display pass, see `Params::use_lut`
+    color_lut: GlHandle<tinygl::wrappers::Texture>,
+    /// Partial ranges of the GPU min/max reduction
+    range_partials: GlHandle<tinygl::wrappers::Buffer>,
+    /// Allocated size of `range_partials`, in partial ranges
+    range_partials_len: usize,
+    /// Maximum GPU memory used by the kernels and the render target, in bytes
+    memory_budget: Option<u64>,
 }
 
 impl State {
-    pub fn new(gl: &Rc<tinygl::Context>) -> tinygl::Result<Self> {
+    pub fn new(gl: &Rc<tinygl::Context>) -> Result<Self, String> {
+        let mut limit =
+            unsafe { gl.get_parameter_i32(tinygl::gl::MAX_TEXTURE_BUFFER_SIZE) } as usize;
+
+        if let Ok(value) = std::env::var(MAX_TEXTURE_BUFFER_SIZE_VAR) {
+            match value.parse::<usize>() {
+                Ok(value) => limit = limit.min(value),
+                Err(e) => warn!("invalid {}: {}", MAX_TEXTURE_BUFFER_SIZE_VAR, e),
+            }
+        }
+
+        Self::with_texel_limit(gl, limit)
+    }
+
+    /// Build the state, splitting the kernel storage into shards of at most `limit` texels
+    pub fn with_texel_limit(gl: &Rc<tinygl::Context>, limit: usize) -> Result<Self, String> {
+        let mut state = Self::build(gl, limit).map_err(|e| e.to_string())?;
+
+        state.set_color_lut(gl, &palette::GRAYSCALE);
+
+        // Initialize grid
+        state.check_grid(gl, &Params::default())?;
+
+        Ok(state)
+    }
+
+    /// Programs and GL objects of the state, before allocating the grid
+    fn build(gl: &Rc<tinygl::Context>, limit: usize) -> tinygl::Result<Self> {
         // Build demo state
-        let mut state = Self {
+        Ok(Self {
             display_program: GlHandle::new(gl, shaders::DisplayProgram::build(&gl)?),
             init_program: GlHandle::new(gl, shaders::InitProgram::build(&gl)?),
             opt_program: GlHandle::new(gl, shaders::OptProgram::build(&gl)?),
-            kernels: GlHandle::new(gl, tinygl::wrappers::Buffer::new(&gl)?),
-            kernel_texture: GlHandle::new(gl, tinygl::wrappers::Texture::new(&gl)?),
+            range_program: GlHandle::new(gl, shaders::RangeProgram::build(&gl)?),
+            normalize_program: GlHandle::new(gl, shaders::NormalizeProgram::build(&gl)?),
+            kernel_viz_program: GlHandle::new(gl, shaders::KernelVizProgram::build(&gl)?),
+            kernel_shards: vec![KernelShard::new(gl)?],
+            comparison_shards: None,
+            next_kernels: vec![KernelShard::new(gl)?],
+            // Kernels never straddle two shards
+            shard_capacity: (limit / shared_types::NFLOATS).max(1) * shared_types::NFLOATS,
             allocated_size: 0,
+            kernel_count: 0,
+            grid_size: cgmath::vec3(0, 0, 0),
             texture_render_target: None,
-        };
+            color_lut: GlHandle::new(gl, tinygl::wrappers::Texture::new(&gl)?),
+            range_partials: GlHandle::new(gl, tinygl::wrappers::Buffer::new(&gl)?),
+            range_partials_len: 0,
+            memory_budget: None,
+        })
+    }
 
-        // Initialize grid
-        state.check_grid(gl, &Params::default())?;
+    /// Replace the color lookup table used by the display pass when `Params::use_lut` is set.
+    /// Scalar display values are linearly interpolated between `colors`, from 0 to 1.
+    pub fn set_color_lut(&mut self, gl: &Rc<tinygl::Context>, colors: &[[f32; 3]]) {
+        if colors.is_empty() {
+            warn!("ignoring empty color lookup table");
+            return;
+        }
 
-        // Setup texture for buffer storage
         unsafe {
-            state.kernel_texture.bind(gl, tinygl::gl::TEXTURE_BUFFER);
-            gl.tex_buffer(
-                tinygl::gl::TEXTURE_BUFFER,
-                tinygl::gl::R32F,
-                state.kernels.name(),
+            self.color_lut.bind(gl, tinygl::gl::TEXTURE_1D);
+            gl.tex_image_1d(
+                tinygl::gl::TEXTURE_1D,
+                0,
+                tinygl::gl::RGB32F as i32,
+                colors.len() as i32,
+                0,
+                tinygl::gl::RGB,
+                tinygl::gl::FLOAT,
+                Some(std::slice::from_raw_parts(
+                    colors.as_ptr() as *const u8,
+                    colors.len() * std::mem::size_of::<[f32; 3]>(),
+                )),
             );
-            gl.bind_texture(tinygl::gl::TEXTURE_BUFFER, None);
+
+            for (parameter, value) in &[
+                (tinygl::gl::TEXTURE_MIN_FILTER, tinygl::gl::LINEAR),
+                (tinygl::gl::TEXTURE_MAG_FILTER, tinygl::gl::LINEAR),
+                (tinygl::gl::TEXTURE_WRAP_S, tinygl::gl::CLAMP_TO_EDGE),
+            ] {
+                gl.tex_parameteri(tinygl::gl::TEXTURE_1D, *parameter, *value as i32);
+            }
+
+            gl.bind_texture(tinygl::gl::TEXTURE_1D, None);
         }
+    }
 
-        Ok(state)
+    /// GPU memory used by the kernel storage and the render target
+    pub fn allocation_info(&self) -> AllocationInfo {
+        AllocationInfo {
+            kernel_bytes: shards_bytes(&self.kernel_shards)
+                + shards_bytes(&self.next_kernels)
+                + self
+                    .comparison_shards
+                    .as_ref()
+                    .map(|shards| shards_bytes(shards))
+                    .unwrap_or(0),
+            render_target_bytes: self.render_target_bytes(),
+            grid: self.grid_size,
+            kernel_count: self.kernel_count as u32,
+        }
+    }
+
+    /// Limit the GPU memory used by the kernel storage, including the staged updates and the
+    /// comparison kernels, and the render target to `budget` bytes. Allocations that would
+    /// exceed it fail instead, memory that is already allocated is kept. None removes the
+    /// limit.
+    pub fn set_memory_budget(&mut self, budget: Option<u64>) {
+        self.memory_budget = budget;
+    }
+
+    /// Size of the render target, in bytes
+    fn render_target_bytes(&self) -> u64 {
+        self.texture_render_target
+            .as_ref()
+            .and_then(|trt| trt.size())
+            .map(|(width, height)| TextureRenderTarget::bytes(width, height))
+            .unwrap_or(0)
+    }
+
+    /// Check that `bytes` of `what` fit in the memory budget next to `other_bytes`
+    fn check_budget(&self, bytes: u64, other_bytes: u64, what: &str) -> Result<(), String> {
+        match self.memory_budget {
+            Some(budget) if bytes + other_bytes > budget => Err(format!(
+                "{} needs {}, exceeding the memory budget of {} ({} already used)",
+                what,
+                bytesize::ByteSize(bytes),
+                bytesize::ByteSize(budget),
+                bytesize::ByteSize(other_bytes)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of texels in each shard, as seen by the shaders
+    fn shard_texels(&self) -> i32 {
+        if self.kernel_shards.len() > 1 {
+            self.shard_capacity as i32
+        } else {
+            // Single buffer, all texels map to the first shard
+            std::i32::MAX
+        }
+    }
+
+    /// Bind the kernel shards to the image units starting at `binding`
+    unsafe fn bind_kernels(&self, gl: &Rc<tinygl::Context>, binding: u32) {
+        bind_shards(gl, &self.kernel_shards, binding);
     }
 
-    pub fn run_init(&mut self, gl: &Rc<tinygl::Context>, params: &Params) {
+    pub fn run_init(&mut self, gl: &Rc<tinygl::Context>, params: &Params) -> Result<(), String> {
         // Check grid status
-        self.check_grid(gl, params)
-            .expect("failed to allocate grid");
+        self.check_grid(gl, params)?;
 
         // Set params
         unsafe {
@@ -68,17 +357,12 @@ impl State {
         }
         params.apply_shared(gl, self.init_program.as_ref());
 
+        self.init_program
+            .set_u_shard_texels(gl, self.shard_texels());
+
         unsafe {
             // Bind kernel data
-            gl.bind_image_texture(
-                self.init_program.get_u_kernels_binding(),
-                Some(&self.kernel_texture),
-                0,
-                false,
-                0,
-                tinygl::gl::READ_WRITE,
-                tinygl::gl::R32F,
-            );
+            self.bind_kernels(gl, self.init_program.get_u_kernels_binding());
 
             // Dispatch program
             gl.dispatch_compute(
@@ -89,6 +373,8 @@ impl State {
 
             gl.memory_barrier(tinygl::gl::TEXTURE_FETCH_BARRIER_BIT);
         }
+
+        Ok(())
     }
 
     pub fn run_optimize(
@@ -97,20 +383,19 @@ impl State {
         mode: OptimizationMode,
         steps: u32,
         params: &Params,
-    ) {
+    ) -> Result<(), String> {
         if !mode.is_active() {
             warn!("invalid optimization mode: {:?}", mode);
-            return;
+            return Ok(());
         }
 
         if steps < 1 {
             warn!("invalid optimization step count: {:?}", steps);
-            return;
+            return Ok(());
         }
 
         // Check grid status
-        self.check_grid(gl, params)
-            .expect("failed to allocate grid");
+        self.check_grid(gl, params)?;
 
         // Run one optimization pass
         unsafe {
@@ -120,34 +405,58 @@ impl State {
         self.opt_program
             .set_u_noise_bandwidth(gl, params.noise_bandwidth);
         self.opt_program.set_u_opt_method(gl, mode.as_mode());
-        self.opt_program.set_u_opt_steps(gl, steps);
+        self.opt_program.set_u_shard_texels(gl, self.shard_texels());
+
+        let cells = (params.grid_size.x * params.grid_size.y * params.grid_size.z) as u32;
 
         unsafe {
             // Bind kernel data
-            gl.bind_image_texture(
-                self.opt_program.get_u_kernels_binding(),
-                Some(&self.kernel_texture),
-                0,
-                false,
-                0,
-                tinygl::gl::READ_WRITE,
-                tinygl::gl::R32F,
+            self.bind_kernels(gl, self.opt_program.get_u_kernels_binding());
+            bind_shards(
+                gl,
+                &self.next_kernels,
+                self.opt_program.get_u_next_kernels_binding(),
             );
+        }
 
-            gl.dispatch_compute(
-                (params.grid_size.x * params.grid_size.y * params.grid_size.z) as u32,
-                1,
-                1,
-            );
+        if params.in_place_optimize {
+            self.opt_program.set_u_opt_steps(gl, steps);
+            self.opt_program
+                .set_u_opt_pass(gl, shared::OPT_PASS_IN_PLACE as i32);
 
-            gl.memory_barrier(tinygl::gl::TEXTURE_FETCH_BARRIER_BIT);
+            unsafe {
+                gl.dispatch_compute(cells, 1, 1);
+                gl.memory_barrier(tinygl::gl::TEXTURE_FETCH_BARRIER_BIT);
+            }
+        } else {
+            self.opt_program.set_u_opt_steps(gl, 1);
+
+            for _ in 0..steps {
+                for pass in &[shared::OPT_PASS_STAGE, shared::OPT_PASS_COMMIT] {
+                    self.opt_program.set_u_opt_pass(gl, *pass as i32);
+
+                    unsafe {
+                        gl.dispatch_compute(cells, 1, 1);
+                        gl.memory_barrier(
+                            tinygl::gl::SHADER_IMAGE_ACCESS_BARRIER_BIT
+                                | tinygl::gl::TEXTURE_FETCH_BARRIER_BIT,
+                        );
+                    }
+                }
+            }
         }
+
+        Ok(())
     }
 
-    pub fn run_display(&mut self, gl: &Rc<tinygl::Context>, params: &Params, display_mode: i32) {
+    pub fn run_display(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        params: &Params,
+        display_mode: DisplayMode,
+    ) -> Result<(), String> {
         // Check grid status
-        self.check_grid(gl, params)
-            .expect("failed to allocate grid");
+        self.check_grid(gl, params)?;
 
         unsafe {
             self.display_program.use_program(gl);
@@ -163,135 +472,1811 @@ impl State {
             .set_u_noise_bandwidth(gl, params.noise_bandwidth);
         self.display_program
             .set_u_filter_bandwidth(gl, params.filter_bandwidth);
-        self.display_program.set_u_display_mode(gl, display_mode);
+        self.display_program
+            .set_u_display_mode(gl, display_mode.as_raw());
+        self.display_program
+            .set_u_use_lut(gl, if params.use_lut { 1 } else { 0 });
+        self.display_program
+            .set_u_emit_derivatives(gl, if params.emit_derivatives { 1 } else { 0 });
+        self.display_program
+            .set_u_shard_texels(gl, self.shard_texels());
 
         unsafe {
-            // Bind kernel data
-            gl.bind_image_texture(
-                self.display_program.get_u_kernels_binding(),
-                Some(&self.kernel_texture),
-                0,
-                false,
-                0,
-                tinygl::gl::READ_WRITE,
-                tinygl::gl::R32F,
-            );
-
-            // Draw current program
-            gl.draw_arrays(tinygl::gl::TRIANGLES, 0, 3);
+            // Bind color lookup table
+            gl.active_texture(tinygl::gl::TEXTURE0 + self.display_program.get_u_lut_binding());
+            self.color_lut.bind(gl, tinygl::gl::TEXTURE_1D);
         }
-    }
 
-    pub fn render_to_texture(
-        &mut self,
-        gl: &Rc<tinygl::Context>,
-        width: u32,
-        height: u32,
-        display_mode: i32,
-        params: &Params,
-        buffer_main: &mut Vec<f32>,
-        buffer_extra: &mut Vec<f32>,
-    ) {
-        // Prepare render target
-        let trt = {
-            if self.texture_render_target.is_none() {
-                self.texture_render_target = Some(
-                    TextureRenderTarget::new(gl, width, height)
-                        .expect("failed to create render target"),
-                );
+        // The left half shows the current kernels, the right half the comparison kernels
+        let sides: &[u32] = if self.comparison_shards.is_some() {
+            &[shared::SPLIT_LEFT, shared::SPLIT_RIGHT]
+        } else {
+            &[shared::SPLIT_NONE]
+        };
+
+        for side in sides {
+            if *side == shared::SPLIT_RIGHT {
+                self.swap_comparison();
             }
 
-            self.texture_render_target.as_mut().unwrap()
-        };
+            unsafe {
+                // The kernel pass of the previous side changed the current program
+                self.display_program.use_program(gl);
+            }
+            self.display_program.set_u_split_side(gl, *side as i32);
 
-        trt.alloc(gl, width, height);
+            unsafe {
+                // Bind kernel data
+                self.bind_kernels(gl, self.display_program.get_u_kernels_binding());
 
-        unsafe {
-            // Set target framebuffer
-            trt.framebuffer.bind(gl, tinygl::gl::FRAMEBUFFER);
+                // Draw current program
+                gl.draw_arrays(tinygl::gl::TRIANGLES, 0, 3);
+            }
 
-            // Set viewport
-            gl.viewport(0, 0, width as i32, height as i32);
+            if display_mode == DisplayMode::Kernels {
+                self.run_kernel_viz(gl, params, *side);
+            }
 
-            // Render
-            self.run_display(gl, params, display_mode);
+            if *side == shared::SPLIT_RIGHT {
+                self.swap_comparison();
+            }
+        }
 
-            // Render target
-            let trt = self.texture_render_target.as_mut().unwrap();
+        unsafe {
+            gl.bind_texture(tinygl::gl::TEXTURE_1D, None);
+            gl.active_texture(tinygl::gl::TEXTURE0);
+        }
 
-            // Get images
-            trt.texture_main.bind(gl, tinygl::gl::TEXTURE_2D);
-            buffer_main.resize(
-                width as usize * height as usize * std::mem::size_of::<f32>() * 4,
-                0.0,
-            );
-            gl.get_tex_image_u8_slice(
-                tinygl::gl::TEXTURE_2D,
-                0,
-                tinygl::gl::RGBA,
-                tinygl::gl::FLOAT,
-                Some(std::mem::transmute(&buffer_main[..])),
-            );
+        Ok(())
+    }
 
-            trt.texture_extra.bind(gl, tinygl::gl::TEXTURE_2D);
-            buffer_extra.resize(
-                width as usize * height as usize * std::mem::size_of::<f32>() * 4,
-                0.0,
-            );
-            gl.get_tex_image_u8_slice(
-                tinygl::gl::TEXTURE_2D,
-                0,
-                tinygl::gl::RGBA,
-                tinygl::gl::FLOAT,
-                Some(std::mem::transmute(&buffer_extra[..])),
-            );
+    /// Draw each kernel of the first layer as an ellipse oriented along its angle and narrowed
+    /// by its frequency, blended over the current framebuffer
+    fn run_kernel_viz(&self, gl: &Rc<tinygl::Context>, params: &Params, side: u32) {
+        unsafe {
+            self.kernel_viz_program.use_program(gl);
         }
 
-        // Cleanup
+        self.kernel_viz_program.set_u_grid(gl, params.grid_size);
+        self.kernel_viz_program
+            .set_u_kernel_count(gl, params.kernel_count);
+        self.kernel_viz_program
+            .set_u_shard_texels(gl, self.shard_texels());
+        self.kernel_viz_program.set_u_split_side(gl, side as i32);
+
+        let instances = params.grid_size.x * params.grid_size.y * params.kernel_count as i32;
+
         unsafe {
-            gl.bind_framebuffer(tinygl::gl::FRAMEBUFFER, None);
+            self.bind_kernels(gl, self.kernel_viz_program.get_u_kernels_binding());
+
+            gl.enable(tinygl::gl::BLEND);
+            gl.blend_func(tinygl::gl::SRC_ALPHA, tinygl::gl::ONE_MINUS_SRC_ALPHA);
+            gl.draw_arrays_instanced(tinygl::gl::TRIANGLE_STRIP, 0, 4, instances);
+            gl.disable(tinygl::gl::BLEND);
         }
     }
 
-    fn check_grid(&mut self, gl: &Rc<tinygl::Context>, params: &Params) -> tinygl::Result<()> {
-        let new_alloc_size = std::mem::size_of::<shared::Kernel>()
-            * (params.grid_size.x * params.grid_size.y * params.grid_size.z) as usize
-            * params.kernel_count as usize;
+    /// Start a split-screen comparison: the current kernels are copied into a second kernel
+    /// set, which `run_optimize_comparison` optimizes separately and `run_display` draws on the
+    /// right half of the viewport, with the current kernels on the left half. The comparison
+    /// is stopped if the kernel storage has to grow.
+    pub fn start_comparison(&mut self, gl: &Rc<tinygl::Context>) -> Result<(), String> {
+        // The comparison kernels copy the current kernels, replacing a running comparison
+        self.check_budget(
+            2 * shards_bytes(&self.kernel_shards) + shards_bytes(&self.next_kernels),
+            self.render_target_bytes(),
+            "comparison kernels",
+        )?;
 
-        if new_alloc_size > self.allocated_size {
-            info!(
-                "reallocating for grid_size: {:?}, kernel_count: {}, bytes: {}",
-                params.grid_size,
-                params.kernel_count,
-                bytesize::ByteSize(new_alloc_size as u64)
-            );
+        let mut shards = Vec::with_capacity(self.kernel_shards.len());
+
+        for shard in &self.kernel_shards {
+            let mut copy = KernelShard::new(gl).map_err(|e| e.to_string())?;
+            copy.alloc(gl, shard.texels)?;
 
-            // Setupinitialize buffer storage
             unsafe {
-                self.kernels.bind(gl, tinygl::gl::TEXTURE_BUFFER);
-                gl.buffer_data_size(
-                    tinygl::gl::TEXTURE_BUFFER,
-                    new_alloc_size as i32,
-                    tinygl::gl::DYNAMIC_DRAW,
+                shard.buffer.bind(gl, tinygl::gl::COPY_READ_BUFFER);
+                copy.buffer.bind(gl, tinygl::gl::COPY_WRITE_BUFFER);
+                gl.copy_buffer_sub_data(
+                    tinygl::gl::COPY_READ_BUFFER,
+                    tinygl::gl::COPY_WRITE_BUFFER,
+                    0,
+                    0,
+                    (shard.texels * std::mem::size_of::<f32>()) as i32,
                 );
+                gl.bind_buffer(tinygl::gl::COPY_READ_BUFFER, None);
+                gl.bind_buffer(tinygl::gl::COPY_WRITE_BUFFER, None);
+            }
+
+            shards.push(copy);
+        }
 
-                // Check allocation errors
-                let error = gl.check_last_error();
+        self.comparison_shards = Some(shards);
+        Ok(())
+    }
 
-                gl.bind_buffer(tinygl::gl::TEXTURE_BUFFER, None);
+    /// Stop the split-screen comparison, keeping the kernels of the left half
+    pub fn stop_comparison(&mut self) {
+        self.comparison_shards = None;
+    }
 
-                // If there's an error, allocation was not successful
-                error?;
-            }
+    /// true if a split-screen comparison is running
+    pub fn is_comparing(&self) -> bool {
+        self.comparison_shards.is_some()
+    }
+
+    /// Exchange the current and comparison kernel sets
+    fn swap_comparison(&mut self) {
+        if let Some(shards) = self.comparison_shards.as_mut() {
+            std::mem::swap(&mut self.kernel_shards, shards);
+        }
+    }
+
+    /// Run `steps` steps of `left` on the current kernels and, if a comparison is running,
+    /// `steps` steps of `right` on the comparison kernels
+    pub fn run_optimize_comparison(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        left: OptimizationMode,
+        right: OptimizationMode,
+        steps: u32,
+        params: &Params,
+    ) -> Result<(), String> {
+        // Grow the storage first, this stops the comparison if needed
+        self.check_grid(gl, params)?;
+
+        self.run_optimize(gl, left, steps, params)?;
 
-            // Updated allocated size
-            self.allocated_size = new_alloc_size;
+        if self.comparison_shards.is_some() {
+            self.swap_comparison();
+            let result = self.run_optimize(gl, right, steps, params);
+            self.swap_comparison();
+            result?;
         }
 
         Ok(())
     }
 
-    pub fn kernels_buffer(&self) -> &tinygl::wrappers::Buffer {
-        &self.kernels
+    /// Run the display pass into `framebuffer`, given by its GL name, or into the default
+    /// framebuffer if None, within `viewport` (x, y, width, height). Only the draw framebuffer
+    /// binding is changed, and it is restored afterwards. The viewport is left set to `viewport`.
+    /// The internal render target is not used.
+    pub fn run_display_to(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        framebuffer: Option<u32>,
+        viewport: (i32, i32, i32, i32),
+        params: &Params,
+        display_mode: DisplayMode,
+    ) -> Result<(), String> {
+        unsafe {
+            let previous = gl.get_parameter_i32(tinygl::gl::DRAW_FRAMEBUFFER_BINDING) as u32;
+
+            gl.bind_framebuffer(tinygl::gl::DRAW_FRAMEBUFFER, framebuffer);
+            gl.viewport(viewport.0, viewport.1, viewport.2, viewport.3);
+
+            let result = self.run_display(gl, params, display_mode);
+
+            gl.bind_framebuffer(
+                tinygl::gl::DRAW_FRAMEBUFFER,
+                if previous == 0 { None } else { Some(previous) },
+            );
+
+            result
+        }
+    }
+
+    /// Render the current kernels into `buffer_main` and `buffer_extra`, as `width` by `height`
+    /// RGBA images. The image is rendered at `supersample` (1, 2 or 4) times the resolution and
+    /// box-filtered down. The supersampling factor is reduced if the scaled image would exceed
+    /// the maximum texture size, the factor actually used is returned.
+    ///
+    /// `normalize` rescales `buffer_main` on the GPU according to the range of its first
+    /// channel, see `compute_range`. The color channels are rescaled in scalar display modes,
+    /// only the complex value is in `DisplayMode::Complex`.
+    ///
+    /// In `DisplayMode::Complex`, the first two channels of `buffer_main` hold the real and
+    /// imaginary parts of the phasor field, and `buffer_extra` holds the isotropy, the filter
+    /// modulation, the average kernel state and the magnitude of the field at each pixel. In
+    /// `DisplayMode::Kernels`, the first three channels of `buffer_extra` hold the center of the
+    /// ellipse drawn last at each pixel, in grid units, and the index of its kernel plus one.
+    /// They are zero outside of the ellipses. These values are only exact with a `supersample`
+    /// of 1, otherwise they are box-averaged like the colors, which mixes them at the edges of
+    /// the ellipses. In the other modes, if `Params::emit_derivatives` is set, the first two
+    /// channels of `buffer_extra` hold the derivatives of the phase in turns (the
+    /// `DisplayMode::Noise` value, ignoring wrapping) along the image width and height, per
+    /// image size. The other channels are zero.
+    pub fn render_to_texture(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        width: u32,
+        height: u32,
+        supersample: u32,
+        normalize: NormalizeMode,
+        display_mode: DisplayMode,
+        params: &Params,
+        buffer_main: &mut Vec<f32>,
+        buffer_extra: &mut Vec<f32>,
+    ) -> Result<u32, String> {
+        if ![1, 2, 4].contains(&supersample) {
+            return Err(format!(
+                "invalid supersampling factor {}, expected 1, 2 or 4",
+                supersample
+            ));
+        }
+
+        let max_size = unsafe {
+            gl.get_parameter_i32(tinygl::gl::MAX_TEXTURE_SIZE)
+                .min(gl.get_parameter_i32(tinygl::gl::MAX_RENDERBUFFER_SIZE))
+        } as u32;
+
+        if width.max(height) > max_size {
+            return Err(format!(
+                "{}x{} exceeds the maximum texture size of {}",
+                width, height, max_size
+            ));
+        }
+
+        let mut factor = supersample;
+        while width.max(height) * factor > max_size {
+            factor /= 2;
+        }
+
+        if factor != supersample {
+            warn!(
+                "reduced supersampling from {}x to {}x, {}x{} exceeds the maximum texture size of {}",
+                supersample,
+                factor,
+                width * supersample,
+                height * supersample,
+                max_size
+            );
+        }
+
+        self.render_display(gl, width * factor, height * factor, display_mode, params)?;
+
+        // Rescale the rendered image
+        let mut normalized = false;
+        if normalize != NormalizeMode::None {
+            let range = self.reduce_range(gl);
+
+            if let Some((offset, scale)) = normalize.transform(&range) {
+                self.run_normalize(gl, offset, scale, display_mode);
+                normalized = true;
+            }
+        }
+
+        unsafe {
+            // Render target
+            let trt = self.texture_render_target.as_mut().unwrap();
+
+            // The mipmap level matching the requested size is a box-filtered version of the
+            // supersampled image
+            let level = factor.trailing_zeros() as i32;
+
+            let texture_main = if normalized {
+                &trt.texture_normalized
+            } else {
+                &trt.texture_main
+            };
+
+            // Get images
+            for (texture, buffer) in &mut [
+                (texture_main, buffer_main),
+                (&trt.texture_extra, buffer_extra),
+            ] {
+                texture.bind(gl, tinygl::gl::TEXTURE_2D);
+                if level > 0 {
+                    gl.generate_mipmap(tinygl::gl::TEXTURE_2D);
+                }
+
+                buffer.resize(width as usize * height as usize * 4, 0.0);
+                gl.get_tex_image_u8_slice(
+                    tinygl::gl::TEXTURE_2D,
+                    level,
+                    tinygl::gl::RGBA,
+                    tinygl::gl::FLOAT,
+                    Some(std::slice::from_raw_parts(
+                        buffer.as_ptr() as *const u8,
+                        buffer.len() * std::mem::size_of::<f32>(),
+                    )),
+                );
+            }
+
+            gl.bind_texture(tinygl::gl::TEXTURE_2D, None);
+        }
+
+        Ok(factor)
+    }
+
+    /// Initialize the kernels, run `steps` optimization steps and render the result into
+    /// `buffer_main` and `buffer_extra` like `render_to_texture`, without supersampling or
+    /// normalization.
+    ///
+    /// # Determinism
+    ///
+    /// The same parameters and `global_seed` produce bit-identical kernels and rendered images
+    /// across runs and `State` instances on a given GPU and driver. Optimization steps read the
+    /// kernels of the previous step and stage their updates, which are committed in a separate
+    /// pass. `Params::in_place_optimize` is ignored: updating kernels in place is faster but
+    /// depends on the execution order of the work groups.
+    pub fn render_deterministic(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        width: u32,
+        height: u32,
+        steps: u32,
+        display_mode: DisplayMode,
+        params: &Params,
+        buffer_main: &mut Vec<f32>,
+        buffer_extra: &mut Vec<f32>,
+    ) -> Result<(), String> {
+        let params = Params {
+            in_place_optimize: false,
+            ..params.clone()
+        };
+
+        self.run_init(gl, &params)?;
+        if steps > 0 {
+            self.run_optimize(gl, OptimizationMode::Optimize, steps, &params)?;
+        }
+
+        self.render_to_texture(
+            gl,
+            width,
+            height,
+            1,
+            NormalizeMode::None,
+            display_mode,
+            &params,
+            buffer_main,
+            buffer_extra,
+        )
+        .map(|_| ())
+    }
+
+    /// Render the complex phasor field of the current kernels and save it to `path` as a
+    /// `width` by `height` float OpenEXR image. The `Re` and `Im` channels are the real and
+    /// imaginary parts of the `DisplayMode::Complex` output, `include_phase` adds a `Phase`
+    /// channel with `atan2(Im, Re)`. Rows are stored from top to bottom.
+    pub fn export_complex_field(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        width: u32,
+        height: u32,
+        params: &Params,
+        include_phase: bool,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), String> {
+        let mut image = Vec::new();
+        self.render_to_texture(
+            gl,
+            width,
+            height,
+            1,
+            NormalizeMode::None,
+            DisplayMode::Complex,
+            params,
+            &mut image,
+            &mut Vec::new(),
+        )?;
+
+        // Rendered rows are ordered bottom to top
+        let (mut re, mut im) = (Vec::with_capacity(image.len() / 4), Vec::new());
+        for row in image.chunks_exact(width as usize * 4).rev() {
+            for pixel in row.chunks_exact(4) {
+                re.push(pixel[0]);
+                im.push(pixel[1]);
+            }
+        }
+
+        let phase: Vec<_> = re.iter().zip(im.iter()).map(|(r, i)| i.atan2(*r)).collect();
+
+        let mut channels = vec![("Re", &re[..]), ("Im", &im[..])];
+        if include_phase {
+            channels.push(("Phase", &phase[..]));
+        }
+
+        let path = path.as_ref();
+        exr::write(path, width, height, &channels)
+            .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+    }
+
+    /// Minimum and maximum of the first channel of the image rendered with `display_mode`,
+    /// computed on the GPU. The image has the size of the last `render_to_texture` call, or
+    /// `DEFAULT_RANGE_SIZE` if there was none.
+    pub fn compute_range(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        params: &Params,
+        display_mode: DisplayMode,
+    ) -> Result<(f32, f32), String> {
+        let (width, height) = self
+            .texture_render_target
+            .as_ref()
+            .and_then(|trt| trt.size())
+            .unwrap_or((DEFAULT_RANGE_SIZE, DEFAULT_RANGE_SIZE));
+
+        self.render_display(gl, width, height, display_mode, params)?;
+
+        let range = self.reduce_range(gl);
+        Ok((range.min, range.max))
+    }
+
+    /// Render `display_mode` into the texture render target, resized to `width` by `height`
+    fn render_display(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        width: u32,
+        height: u32,
+        display_mode: DisplayMode,
+        params: &Params,
+    ) -> Result<(), String> {
+        // The render target may use what the kernels leave of the budget
+        let budget = self
+            .memory_budget
+            .map(|budget| budget.saturating_sub(self.allocation_info().kernel_bytes));
+
+        // Prepare render target
+        let trt = {
+            if self.texture_render_target.is_none() {
+                self.texture_render_target = Some(
+                    TextureRenderTarget::new(gl)
+                        .map_err(|e| format!("failed to create render target: {}", e))?,
+                );
+            }
+
+            self.texture_render_target.as_mut().unwrap()
+        };
+
+        trt.alloc(gl, width, height, budget)?;
+
+        unsafe {
+            // Set target framebuffer
+            trt.framebuffer.bind(gl, tinygl::gl::FRAMEBUFFER);
+
+            // Set viewport
+            gl.viewport(0, 0, width as i32, height as i32);
+
+            // Render
+            let result = self.run_display(gl, params, display_mode);
+
+            // Cleanup
+            gl.bind_framebuffer(tinygl::gl::FRAMEBUFFER, None);
+
+            result
+        }
+    }
+
+    /// Range of the first channel of the main render target texture, reduced on the GPU
+    fn reduce_range(&mut self, gl: &Rc<tinygl::Context>) -> ValueRange {
+        let trt = self.texture_render_target.as_ref().unwrap();
+        let (width, height) = trt.size().unwrap();
+
+        let group_size = shared::RANGE_GROUP_SIZE;
+        let groups_x = (width + group_size - 1) / group_size;
+        let groups_y = (height + group_size - 1) / group_size;
+        let partial_count = (groups_x * groups_y) as usize;
+
+        unsafe {
+            // Grow the partial range storage
+            if partial_count > self.range_partials_len {
+                self.range_partials
+                    .bind(gl, tinygl::gl::SHADER_STORAGE_BUFFER);
+                gl.buffer_data_size(
+                    tinygl::gl::SHADER_STORAGE_BUFFER,
+                    (partial_count * std::mem::size_of::<[f32; 4]>()) as i32,
+                    tinygl::gl::DYNAMIC_COPY,
+                );
+                gl.bind_buffer(tinygl::gl::SHADER_STORAGE_BUFFER, None);
+
+                self.range_partials_len = partial_count;
+            }
+
+            self.range_program.use_program(gl);
+
+            gl.bind_image_texture(
+                self.range_program.get_u_image_binding(),
+                Some(&trt.texture_main),
+                0,
+                false,
+                0,
+                tinygl::gl::READ_ONLY,
+                tinygl::gl::RGBA32F,
+            );
+            gl.bind_buffer_base(
+                tinygl::gl::SHADER_STORAGE_BUFFER,
+                shared::RANGE_PARTIALS_BINDING,
+                Some(&self.range_partials),
+            );
+        }
+
+        // Reduce each tile, then the tiles
+        self.range_program
+            .set_u_range_pass(gl, shared::RANGE_PASS_PARTIAL as i32);
+        unsafe {
+            gl.dispatch_compute(groups_x, groups_y, 1);
+            gl.memory_barrier(tinygl::gl::SHADER_STORAGE_BARRIER_BIT);
+        }
+
+        self.range_program
+            .set_u_range_pass(gl, shared::RANGE_PASS_FINAL as i32);
+        self.range_program
+            .set_u_partial_count(gl, partial_count as i32);
+
+        let mut sums = [0.0f32; 4];
+        unsafe {
+            gl.dispatch_compute(1, 1, 1);
+            gl.memory_barrier(tinygl::gl::BUFFER_UPDATE_BARRIER_BIT);
+
+            self.range_partials.bind(gl, tinygl::gl::COPY_READ_BUFFER);
+            gl.get_buffer_sub_data(
+                tinygl::gl::COPY_READ_BUFFER,
+                0,
+                std::slice::from_raw_parts_mut(
+                    sums.as_mut_ptr() as *mut u8,
+                    std::mem::size_of_val(&sums),
+                ),
+            );
+            gl.bind_buffer(tinygl::gl::COPY_READ_BUFFER, None);
+        }
+
+        ValueRange::from_sums(sums, width as usize * height as usize)
+    }
+
+    /// Map the main render target texture to `(value - offset) * scale` into the normalized
+    /// texture
+    fn run_normalize(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        offset: f32,
+        scale: f32,
+        display_mode: DisplayMode,
+    ) {
+        let trt = self.texture_render_target.as_ref().unwrap();
+
+        unsafe {
+            trt.normalize_framebuffer.bind(gl, tinygl::gl::FRAMEBUFFER);
+            self.normalize_program.use_program(gl);
+        }
+
+        self.normalize_program.set_u_offset(gl, offset);
+        self.normalize_program.set_u_scale(gl, scale);
+        self.normalize_program.set_u_channel_mask(
+            gl,
+            if display_mode == DisplayMode::Complex {
+                cgmath::vec4(1.0, 1.0, 0.0, 0.0)
+            } else {
+                cgmath::vec4(1.0, 1.0, 1.0, 0.0)
+            },
+        );
+
+        unsafe {
+            gl.active_texture(tinygl::gl::TEXTURE0 + self.normalize_program.get_u_source_binding());
+            trt.texture_main.bind(gl, tinygl::gl::TEXTURE_2D);
+
+            // The viewport already matches the render target
+            gl.draw_arrays(tinygl::gl::TRIANGLES, 0, 3);
+
+            gl.bind_texture(tinygl::gl::TEXTURE_2D, None);
+            gl.active_texture(tinygl::gl::TEXTURE0);
+            gl.bind_framebuffer(tinygl::gl::FRAMEBUFFER, None);
+        }
+    }
+
+    /// Render the current kernels as a `width` by `height` tangent-space normal map of the noise
+    /// phase and save it as a PNG file at `path`. Slopes are scaled by `strength`.
+    pub fn render_normal_map(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        width: u32,
+        height: u32,
+        strength: f32,
+        params: &Params,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), String> {
+        let params = Params {
+            emit_derivatives: true,
+            ..params.clone()
+        };
+
+        let mut image = Vec::new();
+        let mut derivatives = Vec::new();
+        self.render_to_texture(
+            gl,
+            width,
+            height,
+            1,
+            NormalizeMode::None,
+            DisplayMode::Noise,
+            &params,
+            &mut image,
+            &mut derivatives,
+        )?;
+
+        let path = path.as_ref();
+        let pixels = normal_map(&derivatives, width, height, strength);
+
+        let file = std::fs::File::create(path)
+            .map_err(|e| format!("failed to create {}: {}", path.display(), e))?;
+        let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+    }
+
+    /// Allocate the kernel storage for the grid of `params`. The passes do this as needed and
+    /// return the same error if it fails, e.g. when the memory budget is exceeded, calling this
+    /// first only moves the allocation earlier.
+    pub fn check_grid(&mut self, gl: &Rc<tinygl::Context>, params: &Params) -> Result<(), String> {
+        let kernels = (params.grid_size.x * params.grid_size.y * params.grid_size.z) as usize
+            * params.kernel_count as usize;
+
+        if kernels * std::mem::size_of::<shared::Kernel>() > self.allocated_size {
+            info!(
+                "reallocating for grid_size: {:?}, kernel_count: {}, bytes: {}",
+                params.grid_size,
+                params.kernel_count,
+                bytesize::ByteSize((kernels * std::mem::size_of::<shared::Kernel>()) as u64)
+            );
+        }
+
+        self.allocate(gl, kernels * shared_types::NFLOATS)?;
+
+        self.kernel_count = params.kernel_count as usize;
+        self.grid_size = params.grid_size;
+        Ok(())
+    }
+
+    /// Make sure the kernel storage holds at least `texels` texels, splitting it into shards if
+    /// it exceeds the texture buffer size limit
+    fn allocate(&mut self, gl: &Rc<tinygl::Context>, texels: usize) -> Result<(), String> {
+        let new_alloc_size = texels * std::mem::size_of::<f32>();
+        if new_alloc_size <= self.allocated_size {
+            return Ok(());
+        }
+
+        let shard_count = (texels + self.shard_capacity - 1) / self.shard_capacity;
+        if shard_count > shared::KERNEL_SHARDS as usize {
+            return Err(format!(
+                "kernel data needs {} texels, more than {} shards of {} texels",
+                texels,
+                shared::KERNEL_SHARDS,
+                self.shard_capacity
+            ));
+        }
+
+        // Two texels per kernel for the staged phase and state, which never need more shards
+        // than the kernels
+        let staged_texels = texels / shared_types::NFLOATS * 2;
+
+        // The comparison kernels are dropped by the reallocation
+        self.check_budget(
+            ((texels + staged_texels) * std::mem::size_of::<f32>()) as u64,
+            self.render_target_bytes(),
+            "kernel storage",
+        )?;
+
+        if self.comparison_shards.take().is_some() {
+            warn!("kernel storage reallocated, stopping comparison");
+        }
+
+        if shard_count > 1 {
+            info!(
+                "splitting kernel data into {} shards of {} texels",
+                shard_count, self.shard_capacity
+            );
+        }
+
+        alloc_shards(gl, &mut self.kernel_shards, texels, self.shard_capacity)?;
+        alloc_shards(
+            gl,
+            &mut self.next_kernels,
+            staged_texels,
+            self.shard_capacity,
+        )?;
+
+        // Updated allocated size
+        self.allocated_size = new_alloc_size;
+
+        Ok(())
+    }
+
+    /// Read the kernel data into `data`, which is truncated to the allocated size
+    pub fn read_kernels(&self, gl: &Rc<tinygl::Context>, data: &mut [f32]) {
+        for (shard, chunk) in self
+            .kernel_shards
+            .iter()
+            .zip(data.chunks_mut(self.shard_capacity))
+        {
+            let len = chunk.len().min(shard.texels);
+
+            unsafe {
+                shard.buffer.bind(gl, tinygl::gl::COPY_READ_BUFFER);
+                gl.get_buffer_sub_data(
+                    tinygl::gl::COPY_READ_BUFFER,
+                    0,
+                    std::slice::from_raw_parts_mut(
+                        chunk.as_mut_ptr() as *mut u8,
+                        len * std::mem::size_of::<f32>(),
+                    ),
+                );
+                gl.bind_buffer(tinygl::gl::COPY_READ_BUFFER, None);
+            }
+        }
+    }
+
+    /// Replace kernel `index` of the grid cell `cell` with `kernel`, uploading only that kernel.
+    /// The grid layout is the one of the last parameters passed to `run_init`, `run_optimize`
+    /// or `run_display`, or of the last `write_kernels` call.
+    pub fn update_kernel(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        cell: cgmath::Vector2<i32>,
+        index: usize,
+        kernel: shared::Kernel,
+    ) -> Result<(), String> {
+        let offset = self.cell_offset(cell, self.kernel_count)?;
+
+        if index >= self.kernel_count {
+            return Err(format!(
+                "kernel index {} out of range, cells have {} kernels",
+                index, self.kernel_count
+            ));
+        }
+
+        self.write_texels(
+            gl,
+            offset + index * shared_types::NFLOATS,
+            kernels_as_texels(std::slice::from_ref(&kernel)),
+        );
+        Ok(())
+    }
+
+    /// Replace the kernels of the grid cell `cell` with `kernels`, which must hold one kernel
+    /// per kernel of the cell. Only the data of that cell is uploaded. The grid layout is the
+    /// one of the last parameters passed to `run_init`, `run_optimize` or `run_display`, or of
+    /// the last `write_kernels` call.
+    pub fn write_kernel_cell(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        cell: cgmath::Vector2<i32>,
+        kernels: &[shared::Kernel],
+    ) -> Result<(), String> {
+        let offset = self.cell_offset(cell, kernels.len())?;
+        self.write_texels(gl, offset, kernels_as_texels(kernels));
+        Ok(())
+    }
+
+    /// Read the kernels of the grid cell `cell`, see `write_kernel_cell`
+    pub fn read_kernel_cell(
+        &self,
+        gl: &Rc<tinygl::Context>,
+        cell: cgmath::Vector2<i32>,
+    ) -> Result<Vec<shared::Kernel>, String> {
+        let offset = self.cell_offset(cell, self.kernel_count)?;
+
+        let mut data = vec![0.0; self.kernel_count * shared_types::NFLOATS];
+        self.read_texels(gl, offset, &mut data);
+
+        Ok(data
+            .chunks(shared_types::NFLOATS)
+            .map(|kernel| shared::Kernel {
+                x: kernel[0],
+                y: kernel[1],
+                frequency: kernel[2],
+                phase: kernel[3],
+                angle: kernel[4],
+                state: kernel[5],
+            })
+            .collect())
+    }
+
+    /// Offset of the first texel of the grid cell `cell`, checking that it exists and that
+    /// `count` is the number of kernels per cell
+    fn cell_offset(&self, cell: cgmath::Vector2<i32>, count: usize) -> Result<usize, String> {
+        if cell.x < 0 || cell.y < 0 || cell.x >= self.grid_size.x || cell.y >= self.grid_size.y {
+            return Err(format!(
+                "cell ({}, {}) out of range, the grid has {}x{} cells",
+                cell.x, cell.y, self.grid_size.x, self.grid_size.y
+            ));
+        }
+
+        if count != self.kernel_count {
+            return Err(format!(
+                "got {} kernels, cells have {} kernels",
+                count, self.kernel_count
+            ));
+        }
+
+        let linear = (cell.x + cell.y * self.grid_size.x) as usize;
+        Ok(linear * self.kernel_count * shared_types::NFLOATS)
+    }
+
+    /// Upload `data` to the kernel storage starting at texel `offset`, across shards if needed
+    fn write_texels(&self, gl: &Rc<tinygl::Context>, offset: usize, data: &[f32]) {
+        unsafe {
+            // Previous shader writes must complete before the update
+            gl.memory_barrier(tinygl::gl::BUFFER_UPDATE_BARRIER_BIT);
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let shard = &self.kernel_shards[(offset + done) / self.shard_capacity];
+            let local = (offset + done) % self.shard_capacity;
+            let chunk = &data[done..(done + self.shard_capacity - local).min(data.len())];
+
+            unsafe {
+                shard.buffer.bind(gl, tinygl::gl::COPY_WRITE_BUFFER);
+                gl.buffer_sub_data_u8_slice(
+                    tinygl::gl::COPY_WRITE_BUFFER,
+                    (local * std::mem::size_of::<f32>()) as i32,
+                    std::slice::from_raw_parts(
+                        chunk.as_ptr() as *const u8,
+                        chunk.len() * std::mem::size_of::<f32>(),
+                    ),
+                );
+                gl.bind_buffer(tinygl::gl::COPY_WRITE_BUFFER, None);
+            }
+
+            done += chunk.len();
+        }
+    }
+
+    /// Download the kernel storage starting at texel `offset` into `data`, across shards if
+    /// needed
+    fn read_texels(&self, gl: &Rc<tinygl::Context>, offset: usize, data: &mut [f32]) {
+        unsafe {
+            // Previous shader writes must complete before the download
+            gl.memory_barrier(tinygl::gl::BUFFER_UPDATE_BARRIER_BIT);
+        }
+
+        let mut done = 0;
+        while done < data.len() {
+            let shard = &self.kernel_shards[(offset + done) / self.shard_capacity];
+            let local = (offset + done) % self.shard_capacity;
+            let end = (done + self.shard_capacity - local).min(data.len());
+            let chunk = &mut data[done..end];
+
+            unsafe {
+                shard.buffer.bind(gl, tinygl::gl::COPY_READ_BUFFER);
+                gl.get_buffer_sub_data(
+                    tinygl::gl::COPY_READ_BUFFER,
+                    (local * std::mem::size_of::<f32>()) as i32,
+                    std::slice::from_raw_parts_mut(
+                        chunk.as_mut_ptr() as *mut u8,
+                        chunk.len() * std::mem::size_of::<f32>(),
+                    ),
+                );
+                gl.bind_buffer(tinygl::gl::COPY_READ_BUFFER, None);
+            }
+
+            done = end;
+        }
+    }
+
+    /// Replace the kernel data with `data`, laid out as `kernel_count` kernels per cell of a
+    /// `grid_size` grid, growing the storage if needed. The cell accessors then use this layout.
+    pub fn write_kernels(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        grid_size: cgmath::Vector3<i32>,
+        kernel_count: usize,
+        data: &[f32],
+    ) -> Result<(), String> {
+        let expected = (grid_size.x * grid_size.y * grid_size.z).max(0) as usize
+            * kernel_count
+            * shared_types::NFLOATS;
+        if data.len() != expected {
+            return Err(format!(
+                "got {} floats, a {}x{}x{} grid of {} kernels needs {}",
+                data.len(),
+                grid_size.x,
+                grid_size.y,
+                grid_size.z,
+                kernel_count,
+                expected
+            ));
+        }
+
+        self.allocate(gl, data.len())?;
+
+        for (shard, chunk) in self
+            .kernel_shards
+            .iter()
+            .zip(data.chunks(self.shard_capacity))
+        {
+            unsafe {
+                shard.buffer.bind(gl, tinygl::gl::COPY_WRITE_BUFFER);
+                gl.buffer_sub_data_u8_slice(
+                    tinygl::gl::COPY_WRITE_BUFFER,
+                    0,
+                    std::slice::from_raw_parts(
+                        chunk.as_ptr() as *const u8,
+                        chunk.len() * std::mem::size_of::<f32>(),
+                    ),
+                );
+                gl.bind_buffer(tinygl::gl::COPY_WRITE_BUFFER, None);
+            }
+        }
+
+        self.kernel_count = kernel_count;
+        self.grid_size = grid_size;
+        Ok(())
+    }
+}
+
+/// Convert derivatives per image size, as written to the extra attachment, into RGB8 normals.
+/// Rows of `derivatives` are ordered bottom to top, the returned rows are ordered top to bottom.
+fn normal_map(derivatives: &[f32], width: u32, height: u32, strength: f32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let mut pixels = Vec::with_capacity(width * height * 3);
+
+    for row in derivatives.chunks_exact(width * 4).rev() {
+        for pixel in row.chunks_exact(4) {
+            // Slopes per pixel
+            let dx = strength * pixel[0] / width as f32;
+            let dy = strength * pixel[1] / height as f32;
+            let norm = (dx * dx + dy * dy + 1.0).sqrt();
+
+            for c in &[-dx / norm, -dy / norm, 1.0 / norm] {
+                pixels.push(((0.5 + 0.5 * c) * 255.0).round() as u8);
+            }
+        }
+    }
+
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether GL is available, reporting skipped tests otherwise
+    pub(crate) fn gl_available() -> bool {
+        if !probe::gl_available() {
+            eprintln!("skipped: no GL");
+            return false;
+        }
+
+        true
+    }
+
+    /// Create a `width` by `height` headless context with a bound vertex array, or `None` if
+    /// GL is not available
+    fn gl_context(
+        width: u32,
+        height: u32,
+    ) -> Option<(probe::HeadlessContext, tinygl::wrappers::VertexArray)> {
+        if !gl_available() {
+            return None;
+        }
+
+        let context = probe::HeadlessContext::new(width, height).unwrap();
+        let vao = tinygl::wrappers::VertexArray::new(&context.gl).unwrap();
+        unsafe {
+            vao.bind(&context.gl);
+        }
+
+        Some((context, vao))
+    }
+
+    /// Render the default parameters, returning the rendered image and the kernel data
+    fn render(gl: &Rc<tinygl::Context>, state: &mut State) -> (Vec<f32>, Vec<f32>) {
+        let params = Params::default();
+        state.run_init(gl, &params).unwrap();
+        state
+            .run_optimize(gl, OptimizationMode::Optimize, 4, &params)
+            .unwrap();
+
+        let mut image = Vec::new();
+        let mut extra = Vec::new();
+        state
+            .render_to_texture(
+                gl,
+                128,
+                128,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Noise,
+                &params,
+                &mut image,
+                &mut extra,
+            )
+            .unwrap();
+
+        let mut kernels = vec![0.0; state.allocated_size / std::mem::size_of::<f32>()];
+        state.read_kernels(gl, &mut kernels);
+
+        (image, kernels)
+    }
+
+    #[test]
+    fn sharded_kernels() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let mut state = State::new(gl).unwrap();
+        let reference = render(gl, &mut state);
+        assert_eq!(state.kernel_shards.len(), 1);
+
+        // The default parameters need 32 * 32 * 16 * 6 = 98304 texels
+        let mut state = State::with_texel_limit(gl, 30000).unwrap();
+        let sharded = render(gl, &mut state);
+        assert_eq!(state.kernel_shards.len(), 4);
+        // and 32 * 32 * 16 * 2 = 32768 texels of staged updates
+        assert_eq!(state.next_kernels.len(), 2);
+
+        assert!(reference.0 == sharded.0);
+        assert!(reference.1 == sharded.1);
+    }
+
+    /// Average of the RGB channels of `image` over `block`x`block` pixel blocks
+    fn block_averages(image: &[f32], size: usize, block: usize) -> Vec<f32> {
+        let blocks = size / block;
+        let mut averages = vec![0.0; blocks * blocks];
+
+        for y in 0..size {
+            for x in 0..size {
+                let pixel = &image[(y * size + x) * 4..][..3];
+                averages[(y / block) * blocks + x / block] += pixel.iter().sum::<f32>();
+            }
+        }
+
+        for average in &mut averages {
+            *average /= (block * block * 3) as f32;
+        }
+
+        averages
+    }
+
+    #[test]
+    fn supersampled_render() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+        state
+            .run_optimize(gl, OptimizationMode::Optimize, 4, &params)
+            .unwrap();
+
+        let mut render = |supersample| {
+            let mut image = Vec::new();
+            let mut extra = Vec::new();
+            let factor = state
+                .render_to_texture(
+                    gl,
+                    128,
+                    128,
+                    supersample,
+                    NormalizeMode::None,
+                    DisplayMode::Noise,
+                    &params,
+                    &mut image,
+                    &mut extra,
+                )
+                .unwrap();
+
+            assert_eq!(factor, supersample);
+            assert_eq!(image.len(), 128 * 128 * 4);
+            assert_eq!(extra.len(), 128 * 128 * 4);
+            image
+        };
+
+        let naive = render(1);
+        let supersampled = render(4);
+        assert!(naive != supersampled);
+
+        // Supersampling only removes high frequencies
+        for (a, b) in block_averages(&naive, 128, 16)
+            .iter()
+            .zip(block_averages(&supersampled, 128, 16).iter())
+        {
+            assert!(
+                (a - b).abs() < 0.05,
+                "block averages {} and {} differ",
+                a,
+                b
+            );
+        }
+
+        assert!(state
+            .render_to_texture(
+                gl,
+                128,
+                128,
+                3,
+                NormalizeMode::None,
+                DisplayMode::Noise,
+                &params,
+                &mut Vec::new(),
+                &mut Vec::new(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn color_lut() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params {
+            use_lut: true,
+            ..Default::default()
+        };
+
+        let colors = [[1.0, 0.5, 0.0], [0.0, 0.25, 1.0]];
+        let mut state = State::new(gl).unwrap();
+        state.set_color_lut(gl, &colors);
+        state.run_init(gl, &params).unwrap();
+
+        let mut image = Vec::new();
+        state
+            .render_to_texture(
+                gl,
+                128,
+                128,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Threshold,
+                &params,
+                &mut image,
+                &mut Vec::new(),
+            )
+            .unwrap();
+
+        let mut counts = [0; 2];
+        for pixel in image.chunks_exact(4) {
+            let matching = colors.iter().position(|color| {
+                color
+                    .iter()
+                    .zip(pixel.iter())
+                    .all(|(a, b)| (a - b).abs() < 1e-3)
+            });
+
+            match matching {
+                Some(i) => counts[i] += 1,
+                None => panic!("unexpected color {:?}", pixel),
+            }
+        }
+
+        assert!(counts[0] > 0 && counts[1] > 0, "counts: {:?}", counts);
+    }
+
+    #[test]
+    fn normal_map_flat() {
+        // Zero derivatives map to +Z, rows are flipped
+        let mut derivatives = vec![0.0; 2 * 2 * 4];
+        derivatives[0] = 2.0;
+        let pixels = normal_map(&derivatives, 2, 2, 1.0);
+
+        assert_eq!(pixels.len(), 2 * 2 * 3);
+        assert_eq!(&pixels[..3], &[128, 128, 255]);
+        assert!(pixels[6] < 128);
+    }
+
+    #[test]
+    fn analytic_derivatives() {
+        const SIZE: usize = 256;
+
+        let (context, _vao) = match gl_context(SIZE as u32, SIZE as u32) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params {
+            emit_derivatives: true,
+            ..Default::default()
+        };
+
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+        state
+            .run_optimize(gl, OptimizationMode::Optimize, 4, &params)
+            .unwrap();
+
+        let mut image = Vec::new();
+        let mut derivatives = Vec::new();
+        state
+            .render_to_texture(
+                gl,
+                SIZE as u32,
+                SIZE as u32,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Noise,
+                &params,
+                &mut image,
+                &mut derivatives,
+            )
+            .unwrap();
+
+        let value = |x: usize, y: usize| image[(y * SIZE + x) * 4];
+        let (mut checked, mut agreeing) = (0, 0);
+
+        for y in 1..SIZE - 1 {
+            for x in 1..SIZE - 1 {
+                let dx = value(x + 1, y) - value(x - 1, y);
+                let dy = value(x, y + 1) - value(x, y - 1);
+
+                // Skip phase wrapping and singularities
+                if dx.abs() > 0.1 || dy.abs() > 0.1 {
+                    continue;
+                }
+
+                let fd = [dx * SIZE as f32 / 2.0, dy * SIZE as f32 / 2.0];
+                let analytic = &derivatives[(y * SIZE + x) * 4..][..2];
+
+                checked += 1;
+                if fd
+                    .iter()
+                    .zip(analytic.iter())
+                    .all(|(a, b)| (a - b).abs() < 0.1 * a.abs().max(b.abs()).max(1.0))
+                {
+                    agreeing += 1;
+                }
+            }
+        }
+
+        // Kernels are truncated at cell boundaries, where the field is not smooth
+        assert!(checked > SIZE * SIZE / 2, "checked: {}", checked);
+        assert!(
+            agreeing as f32 > 0.9 * checked as f32,
+            "{} of {} pixels agree",
+            agreeing,
+            checked
+        );
+    }
+
+    #[test]
+    fn normalize_transform() {
+        let range = ValueRange::from_sums([-2.0, 6.0, 8.0, 32.0], 4);
+        assert_eq!(range.mean, 2.0);
+        assert_eq!(range.std_dev, 2.0);
+
+        assert_eq!(NormalizeMode::None.transform(&range), None);
+        assert_eq!(NormalizeMode::MinMax.transform(&range), Some((-2.0, 0.125)));
+        assert_eq!(
+            NormalizeMode::Sigma(1.0).transform(&range),
+            Some((0.0, 0.25))
+        );
+    }
+
+    #[test]
+    fn gpu_range() {
+        // Not a multiple of the work group size
+        const WIDTH: u32 = 200;
+        const HEIGHT: u32 = 120;
+
+        let (context, _vao) = match gl_context(WIDTH, HEIGHT) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+        state
+            .run_optimize(gl, OptimizationMode::Optimize, 4, &params)
+            .unwrap();
+
+        let render = |state: &mut State, normalize| {
+            let mut image = Vec::new();
+            state
+                .render_to_texture(
+                    gl,
+                    WIDTH,
+                    HEIGHT,
+                    1,
+                    normalize,
+                    DisplayMode::Complex,
+                    &params,
+                    &mut image,
+                    &mut Vec::new(),
+                )
+                .unwrap();
+
+            let values: Vec<_> = image.chunks_exact(4).map(|pixel| pixel[0]).collect();
+            let min = values.iter().cloned().fold(std::f32::INFINITY, f32::min);
+            let max = values
+                .iter()
+                .cloned()
+                .fold(std::f32::NEG_INFINITY, f32::max);
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            (min, max, mean)
+        };
+
+        let (min, max, _) = render(&mut state, NormalizeMode::None);
+        let (gpu_min, gpu_max) = state
+            .compute_range(gl, &params, DisplayMode::Complex)
+            .unwrap();
+        assert!((min - gpu_min).abs() <= 1e-5 * min.abs().max(1.0));
+        assert!((max - gpu_max).abs() <= 1e-5 * max.abs().max(1.0));
+
+        let (min, max, _) = render(&mut state, NormalizeMode::MinMax);
+        assert!(min.abs() < 1e-4, "min: {}", min);
+        assert!((max - 1.0).abs() < 1e-4, "max: {}", max);
+
+        let (_, _, mean) = render(&mut state, NormalizeMode::Sigma(2.0));
+        assert!((mean - 0.5).abs() < 1e-3, "mean: {}", mean);
+    }
+
+    #[test]
+    fn complex_field_magnitude() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+        state
+            .run_optimize(gl, OptimizationMode::Optimize, 4, &params)
+            .unwrap();
+
+        let mut image = Vec::new();
+        let mut extra = Vec::new();
+        state
+            .render_to_texture(
+                gl,
+                128,
+                128,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Complex,
+                &params,
+                &mut image,
+                &mut extra,
+            )
+            .unwrap();
+
+        for (pixel, extra) in image.chunks_exact(4).zip(extra.chunks_exact(4)) {
+            let magnitude = pixel[0].hypot(pixel[1]);
+            assert!(
+                (magnitude - extra[3]).abs() <= 1e-4 * magnitude.max(1.0),
+                "{} != {}",
+                magnitude,
+                extra[3]
+            );
+        }
+
+        let path = std::env::temp_dir().join(format!("phasor-complex-{}.exr", std::process::id()));
+        state
+            .export_complex_field(gl, 128, 128, &params, true, &path)
+            .unwrap();
+        let size = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        // Three float channels and the line headers
+        assert!(size > 128 * (128 * 3 * 4 + 8));
+    }
+
+    #[test]
+    fn display_to_framebuffer() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+
+        // Test-owned render target
+        let framebuffer = GlHandle::new(gl, tinygl::wrappers::Framebuffer::new(gl).unwrap());
+        let texture = GlHandle::new(gl, tinygl::wrappers::Texture::new(gl).unwrap());
+        unsafe {
+            texture.bind(gl, tinygl::gl::TEXTURE_2D);
+            gl.tex_image_2d(
+                tinygl::gl::TEXTURE_2D,
+                0,
+                tinygl::gl::RGBA32F as i32,
+                128,
+                128,
+                0,
+                tinygl::gl::RGBA,
+                tinygl::gl::FLOAT,
+                None,
+            );
+            gl.bind_texture(tinygl::gl::TEXTURE_2D, None);
+
+            framebuffer.bind(gl, tinygl::gl::FRAMEBUFFER);
+            gl.framebuffer_texture(
+                tinygl::gl::FRAMEBUFFER,
+                tinygl::gl::COLOR_ATTACHMENT0,
+                Some(&texture),
+                0,
+            );
+            gl.bind_framebuffer(tinygl::gl::FRAMEBUFFER, None);
+
+            // Unrelated read binding of the caller
+            gl.bind_framebuffer(tinygl::gl::READ_FRAMEBUFFER, Some(framebuffer.name()));
+        }
+
+        state
+            .run_display_to(
+                gl,
+                Some(framebuffer.name()),
+                (0, 0, 128, 128),
+                &params,
+                DisplayMode::Noise,
+            )
+            .unwrap();
+
+        assert!(state.texture_render_target.is_none());
+        unsafe {
+            assert_eq!(
+                gl.get_parameter_i32(tinygl::gl::DRAW_FRAMEBUFFER_BINDING),
+                0
+            );
+            assert_eq!(
+                gl.get_parameter_i32(tinygl::gl::READ_FRAMEBUFFER_BINDING) as u32,
+                framebuffer.name()
+            );
+            gl.bind_framebuffer(tinygl::gl::READ_FRAMEBUFFER, None);
+        }
+
+        let mut image = vec![0.0f32; 128 * 128 * 4];
+        unsafe {
+            texture.bind(gl, tinygl::gl::TEXTURE_2D);
+            gl.get_tex_image_u8_slice(
+                tinygl::gl::TEXTURE_2D,
+                0,
+                tinygl::gl::RGBA,
+                tinygl::gl::FLOAT,
+                Some(std::slice::from_raw_parts(
+                    image.as_ptr() as *const u8,
+                    image.len() * std::mem::size_of::<f32>(),
+                )),
+            );
+            gl.bind_texture(tinygl::gl::TEXTURE_2D, None);
+        }
+
+        let mut reference = Vec::new();
+        state
+            .render_to_texture(
+                gl,
+                128,
+                128,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Noise,
+                &params,
+                &mut reference,
+                &mut Vec::new(),
+            )
+            .unwrap();
+
+        assert!(image == reference);
+    }
+
+    #[test]
+    fn update_single_kernel() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+
+        let len = (params.grid_size.x * params.grid_size.y) as usize
+            * params.kernel_count as usize
+            * shared_types::NFLOATS;
+        let mut before = vec![0.0; len];
+        state.read_kernels(gl, &mut before);
+
+        let kernel = shared::Kernel {
+            x: 0.25,
+            y: 0.75,
+            frequency: 3.0,
+            phase: 1.5,
+            angle: 0.5,
+            state: 1.0,
+        };
+        let (cell, index) = (cgmath::vec2(5, 1), 3);
+        state.update_kernel(gl, cell, index, kernel).unwrap();
+
+        let mut after = vec![0.0; len];
+        state.read_kernels(gl, &mut after);
+
+        let linear = (cell.x + cell.y * params.grid_size.x) as usize;
+        let start = (linear * params.kernel_count as usize + index) * shared_types::NFLOATS;
+        for (i, (a, b)) in before.iter().zip(after.iter()).enumerate() {
+            if i < start || i >= start + shared_types::NFLOATS {
+                assert_eq!(a.to_bits(), b.to_bits(), "texel {} changed", i);
+            }
+        }
+
+        assert_eq!(
+            &after[start..start + shared_types::NFLOATS],
+            &[0.25, 0.75, 3.0, 1.5, 0.5, 1.0]
+        );
+
+        assert!(state
+            .update_kernel(gl, cell, params.kernel_count as usize, kernel)
+            .is_err());
+        assert!(state
+            .update_kernel(gl, cgmath::vec2(params.grid_size.x, 0), 0, kernel)
+            .is_err());
+        assert!(state
+            .update_kernel(gl, cgmath::vec2(0, -1), 0, kernel)
+            .is_err());
+    }
+
+    #[test]
+    fn split_comparison() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let modes = (OptimizationMode::Optimize, OptimizationMode::Average);
+        let draw = |state: &mut State| {
+            let mut image = Vec::new();
+            let mut extra = Vec::new();
+            state
+                .render_to_texture(
+                    gl,
+                    128,
+                    128,
+                    1,
+                    NormalizeMode::None,
+                    DisplayMode::Noise,
+                    &params,
+                    &mut image,
+                    &mut extra,
+                )
+                .unwrap();
+            image
+        };
+
+        let mut references = Vec::new();
+        for mode in &[modes.0, modes.1] {
+            let mut state = State::new(gl).unwrap();
+            state.run_init(gl, &params).unwrap();
+            state.run_optimize(gl, *mode, 4, &params).unwrap();
+            references.push(draw(&mut state));
+        }
+
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+        state.start_comparison(gl).unwrap();
+        state
+            .run_optimize_comparison(gl, modes.0, modes.1, 4, &params)
+            .unwrap();
+        let split = draw(&mut state);
+
+        // RGBA rows of 128 pixels, the divider is column 64
+        let (mut left_differs, mut right_differs) = (false, false);
+        for (i, value) in split.iter().enumerate() {
+            let column = (i / 4) % 128;
+            if column < 64 {
+                assert_eq!(value.to_bits(), references[0][i].to_bits());
+                left_differs |= *value != references[1][i];
+            } else if column > 64 {
+                assert_eq!(value.to_bits(), references[1][i].to_bits());
+                right_differs |= *value != references[0][i];
+            } else {
+                assert_eq!(*value, 1.0);
+            }
+        }
+
+        assert!(left_differs && right_differs);
+
+        state.stop_comparison();
+        assert!(draw(&mut state) == references[0]);
+    }
+
+    #[test]
+    fn kernel_cell_round_trip() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let kernel_count = params.kernel_count as usize;
+
+        // Cell 416 of the 32x32 grid straddles the first two shards
+        let mut state = State::with_texel_limit(gl, 40000).unwrap();
+        state.run_init(gl, &params).unwrap();
+        assert_eq!(state.kernel_shards.len(), 3);
+
+        let len = (params.grid_size.x * params.grid_size.y) as usize
+            * kernel_count
+            * shared_types::NFLOATS;
+        let mut before = vec![0.0; len];
+        state.read_kernels(gl, &mut before);
+
+        let cell = cgmath::vec2(0, 13);
+        let kernels: Vec<_> = (0..kernel_count)
+            .map(|i| shared::Kernel {
+                x: 0.5,
+                y: 0.25,
+                frequency: i as f32,
+                phase: 1.0,
+                angle: 0.5,
+                state: 1.0,
+            })
+            .collect();
+        state.write_kernel_cell(gl, cell, &kernels).unwrap();
+
+        let mut after = vec![0.0; len];
+        state.read_kernels(gl, &mut after);
+
+        let start = 416 * kernel_count * shared_types::NFLOATS;
+        let end = start + kernel_count * shared_types::NFLOATS;
+        for (i, (a, b)) in before.iter().zip(after.iter()).enumerate() {
+            if i < start || i >= end {
+                assert_eq!(a.to_bits(), b.to_bits(), "texel {} changed", i);
+            }
+        }
+        assert!(&after[start..end] == kernels_as_texels(&kernels));
+
+        let read = state.read_kernel_cell(gl, cell).unwrap();
+        assert!(kernels_as_texels(&read) == kernels_as_texels(&kernels));
+
+        assert!(state
+            .write_kernel_cell(gl, cgmath::vec2(params.grid_size.x, 0), &kernels)
+            .is_err());
+        assert!(state
+            .write_kernel_cell(gl, cgmath::vec2(-1, 0), &kernels)
+            .is_err());
+        assert!(state.write_kernel_cell(gl, cell, &kernels[1..]).is_err());
+    }
+
+    #[test]
+    fn memory_budget() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params::default();
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+
+        // 32 * 32 * 16 kernels of 6 texels, and 2 staged texels per kernel
+        let info = state.allocation_info();
+        assert_eq!(info.kernel_bytes, 32 * 32 * 16 * 8 * 4);
+        assert_eq!(info.render_target_bytes, 0);
+        assert_eq!(info.grid, params.grid_size);
+        assert_eq!(info.kernel_count, params.kernel_count);
+
+        // Room for a 64x64 render target only
+        state.set_memory_budget(Some(info.kernel_bytes + TextureRenderTarget::bytes(64, 64)));
+
+        let render = |state: &mut State, size| {
+            let (mut image, mut extra) = (Vec::new(), Vec::new());
+            state.render_to_texture(
+                gl,
+                size,
+                size,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Noise,
+                &params,
+                &mut image,
+                &mut extra,
+            )
+        };
+
+        assert!(render(&mut state, 64).is_ok());
+        assert_eq!(
+            state.allocation_info().render_target_bytes,
+            TextureRenderTarget::bytes(64, 64)
+        );
+
+        let error = render(&mut state, 128).unwrap_err();
+        assert!(error.contains("memory budget"), "{}", error);
+
+        // Larger kernel storage and comparison kernels
+        let larger = Params {
+            kernel_count: 32,
+            ..Params::default()
+        };
+        assert!(state.check_grid(gl, &larger).is_err());
+        assert!(state.start_comparison(gl).is_err());
+        assert!(!state.is_comparing());
+        assert_eq!(state.allocation_info().kernel_count, params.kernel_count);
+
+        state.set_memory_budget(None);
+        assert!(render(&mut state, 128).is_ok());
+        assert!(state.check_grid(gl, &larger).is_ok());
+        assert_eq!(state.allocation_info().kernel_count, 32);
+    }
+
+    #[test]
+    fn kernel_viz_centers() {
+        let (context, _vao) = match gl_context(128, 128) {
+            Some(context) => context,
+            None => return,
+        };
+        let gl = &context.gl;
+
+        let params = Params {
+            grid_size: cgmath::vec3(2, 2, 1),
+            kernel_count: 2,
+            ..Default::default()
+        };
+
+        let mut state = State::new(gl).unwrap();
+        state.run_init(gl, &params).unwrap();
+
+        // Kernels on the diagonal of their cell, their ellipses don't overlap
+        for j in 0..params.grid_size.y {
+            for i in 0..params.grid_size.x {
+                let kernels: Vec<_> = [0.25, 0.75]
+                    .iter()
+                    .map(|&offset| shared::Kernel {
+                        x: offset,
+                        y: offset,
+                        frequency: 1.0,
+                        phase: 0.0,
+                        angle: 0.0,
+                        state: 1.0,
+                    })
+                    .collect();
+                state
+                    .write_kernel_cell(gl, cgmath::vec2(i, j), &kernels)
+                    .unwrap();
+            }
+        }
+
+        let mut image = Vec::new();
+        let mut extra = Vec::new();
+        state
+            .render_to_texture(
+                gl,
+                128,
+                128,
+                1,
+                NormalizeMode::None,
+                DisplayMode::Kernels,
+                &params,
+                &mut image,
+                &mut extra,
+            )
+            .unwrap();
+
+        // Center and kernel index of each pixel covered by an ellipse
+        let mut centers = std::collections::BTreeMap::new();
+        for pixel in extra.chunks_exact(4).filter(|pixel| pixel[2] > 0.0) {
+            let center = (pixel[0].to_bits(), pixel[1].to_bits());
+            assert_eq!(*centers.entry(pixel[2] as u32).or_insert(center), center);
+        }
+
+        let cells = (params.grid_size.x * params.grid_size.y) as usize;
+        assert_eq!(centers.len(), cells * params.kernel_count as usize);
+
+        let distinct: std::collections::BTreeSet<_> = centers.values().collect();
+        assert_eq!(distinct.len(), centers.len());
     }
 }
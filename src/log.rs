@@ -1,15 +1,228 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log_crate::{LevelFilter, Log, Metadata, Record};
+
 static mut LOG_INITIALIZED: bool = false;
 
+/// Default log filter, in env_logger syntax
+pub const DEFAULT_FILTER: &str = "opengl=debug,phasor=debug,tinygl=debug";
+
+/// Logging configuration
+pub struct LogConfig {
+    /// Log filter, in env_logger syntax
+    pub filter: String,
+    /// File log messages are written to, in addition to stderr
+    pub file: Option<PathBuf>,
+    /// Levels of specific targets, overriding the filter
+    pub overrides: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: DEFAULT_FILTER.to_owned(),
+            file: None,
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// Default configuration, with the filter and log file taken from the `PHASOR_LOG` and
+    /// `PHASOR_LOG_FILE` environment variables if they are set
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Ok(filter) = std::env::var("PHASOR_LOG") {
+            config.filter = filter;
+        }
+
+        if let Some(file) = std::env::var_os("PHASOR_LOG_FILE") {
+            config.file = Some(file.into());
+        }
+
+        config
+    }
+
+    /// Set the level of `target`, overriding the filter
+    pub fn with_target(mut self, target: &str, level: LevelFilter) -> Self {
+        self.overrides.push((target.to_owned(), level));
+        self
+    }
+
+    fn builder(&self) -> env_logger::Builder {
+        let mut builder = env_logger::Builder::new();
+        builder.parse_filters(&self.filter);
+
+        for (target, level) in &self.overrides {
+            builder.filter_module(target, *level);
+        }
+
+        if let Ok(style) = std::env::var("PHASOR_LOG_STYLE") {
+            builder.parse_write_style(&style);
+        }
+
+        builder
+    }
+
+    /// Build the logger described by this configuration
+    fn build(&self) -> std::io::Result<TeeLogger> {
+        let file = match &self.file {
+            Some(path) => Some(Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )),
+            None => None,
+        };
+
+        Ok(TeeLogger {
+            inner: self.builder().build(),
+            file,
+        })
+    }
+}
+
+/// Logger writing to stderr, and to a file if one is configured
+struct TeeLogger {
+    inner: env_logger::Logger,
+    file: Option<Mutex<std::fs::File>>,
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+
+        self.inner.log(record);
+
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                // Logging errors have nowhere to be reported
+                let _ = writeln!(
+                    file,
+                    "[{} {}] {}",
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Initialize logging with the default configuration, see `LogConfig::from_env`
 pub fn init() {
+    init_with(LogConfig::from_env());
+}
+
+/// Initialize logging with the given configuration. Only the first call has an effect.
+pub fn init_with(config: LogConfig) {
     unsafe {
         if !LOG_INITIALIZED {
             LOG_INITIALIZED = true;
 
-            env_logger::init_from_env(
-                env_logger::Env::new()
-                    .filter_or("PHASOR_LOG", "opengl=debug,phasor=debug,tinygl=debug")
-                    .write_style("PHASOR_LOG_STYLE"),
-            );
+            let (logger, file_error) = match config.build() {
+                Ok(logger) => (logger, None),
+                Err(e) => (
+                    LogConfig {
+                        file: None,
+                        ..config
+                    }
+                    .build()
+                    .expect("logger without file"),
+                    Some(e),
+                ),
+            };
+
+            log_crate::set_max_level(logger.inner.filter());
+            if log_crate::set_boxed_logger(Box::new(logger)).is_ok() {
+                if let Some(e) = file_error {
+                    warn!("failed to open log file: {}", e);
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log_crate::Level;
+
+    fn enabled(logger: &TeeLogger, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn log_filter() {
+        let logger = LogConfig::default().build().unwrap();
+        assert!(enabled(&logger, "opengl", Level::Debug));
+        assert!(enabled(&logger, "phasor::api", Level::Debug));
+        assert!(!enabled(&logger, "glutin", Level::Debug));
+
+        let logger = LogConfig {
+            filter: "warn,phasor=trace".to_owned(),
+            ..Default::default()
+        }
+        .with_target("opengl", LevelFilter::Off)
+        .with_target("phasor", LevelFilter::Info)
+        .build()
+        .unwrap();
+        assert!(enabled(&logger, "glutin", Level::Warn));
+        assert!(!enabled(&logger, "glutin", Level::Info));
+        assert!(!enabled(&logger, "opengl", Level::Error));
+        assert!(enabled(&logger, "phasor", Level::Info));
+        assert!(!enabled(&logger, "phasor", Level::Debug));
+    }
+
+    #[test]
+    fn log_file() {
+        let path = std::env::temp_dir().join(format!("phasor-log-{}.txt", std::process::id()));
+        let logger = LogConfig {
+            filter: "phasor=info".to_owned(),
+            file: Some(path.clone()),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!("kept"))
+                .level(Level::Info)
+                .target("phasor")
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .args(format_args!("filtered"))
+                .level(Level::Debug)
+                .target("phasor")
+                .build(),
+        );
+        logger.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "[INFO phasor] kept\n");
+    }
+}
@@ -1,15 +1,210 @@
+use std::io::Write;
 use std::rc::Rc;
 
+use ::log::{error, info};
 use glutin::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
-use glutin::event_loop::{ControlFlow, EventLoop};
+use glutin::event_loop::{ControlFlow, EventLoop, EventLoopProxy};
 use glutin::window::{Fullscreen, WindowBuilder};
 use glutin::ContextBuilder;
 
+use phasor::params_file::{self, ParamsFile};
 use phasor::*;
 
+/// Run the benchmark presets and print their results as JSON lines
+fn bench(iterations: u32) -> Result<(), String> {
+    match phasor::bench::run(iterations) {
+        Ok(results) => {
+            for result in results {
+                println!("{}", result.to_json());
+            }
+
+            Ok(())
+        }
+        Err(phasor::bench::BenchError::NoContext(e)) => {
+            eprintln!("no OpenGL context available: {}", e);
+            std::process::exit(phasor::bench::NO_CONTEXT_EXIT_CODE);
+        }
+        Err(phasor::bench::BenchError::Failed(e)) => Err(e),
+    }
+}
+
+/// Supersampling factor of screenshots
+const SCREENSHOT_SUPERSAMPLE: u32 = 4;
+
+/// Display modes cycled through with `D`, the complex output isn't meant to be viewed
+const DISPLAY_MODES: [DisplayMode; 4] = [
+    DisplayMode::Noise,
+    DisplayMode::State,
+    DisplayMode::Threshold,
+    DisplayMode::Kernels,
+];
+
+/// Write an RGBA float image, with rows ordered bottom to top, as a color PFM file
+fn write_pfm(path: &std::path::Path, width: u32, height: u32, rgba: &[f32]) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write!(file, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    for pixel in rgba.chunks_exact(4) {
+        for c in &pixel[..3] {
+            file.write_all(&c.to_le_bytes())?;
+        }
+    }
+
+    file.flush()
+}
+
+/// Render the current state at the window size with supersampling and save it to
+/// `screenshot.pfm`
+fn screenshot(
+    gl: &Rc<tinygl::Context>,
+    state: &mut State,
+    params: &Params,
+    size: glutin::dpi::PhysicalSize<u32>,
+) -> Result<(), String> {
+    let mut image = Vec::new();
+    let mut extra = Vec::new();
+    state.render_to_texture(
+        gl,
+        size.width,
+        size.height,
+        SCREENSHOT_SUPERSAMPLE,
+        NormalizeMode::None,
+        DisplayMode::Noise,
+        params,
+        &mut image,
+        &mut extra,
+    )?;
+
+    // Restore the window viewport
+    unsafe {
+        gl.viewport(0, 0, size.width as i32, size.height as i32);
+    }
+
+    let path = std::path::Path::new("screenshot.pfm");
+    write_pfm(path, size.width, size.height, &image)
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+    info!("saved {}", path.display());
+
+    Ok(())
+}
+
+/// Watch the parameters file at `path`, sending an event to `proxy` when it changes
+fn watch_params(
+    path: &std::path::Path,
+    proxy: EventLoopProxy<()>,
+) -> Result<notify::RecommendedWatcher, String> {
+    use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(tx, std::time::Duration::from_millis(100))
+        .map_err(|e| format!("failed to create watcher: {}", e))?;
+
+    // Editors often replace the file instead of writing to it, watch its directory instead
+    let directory = match path.parent() {
+        Some(parent) if parent != std::path::Path::new("") => parent,
+        _ => std::path::Path::new("."),
+    };
+    watcher
+        .watch(directory, RecursiveMode::NonRecursive)
+        .map_err(|e| format!("failed to watch {}: {}", directory.display(), e))?;
+
+    let file_name = path.file_name().map(|name| name.to_owned());
+    std::thread::spawn(move || {
+        for event in rx {
+            let changed = match &event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => path,
+                DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+
+            if changed.file_name() == file_name.as_deref() && proxy.send_event(()).is_err() {
+                // The event loop has exited
+                break;
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Handle the toggle key of `mode`. Outside of comparisons, this toggles optimizing with
+/// `mode`. During comparisons, `mode` becomes the mode of the right half and the previous
+/// right half mode moves to the left half.
+fn select_mode(
+    comparing: bool,
+    optimizing: &mut OptimizationMode,
+    active_mode: &mut OptimizationMode,
+    compare_mode: &mut OptimizationMode,
+    mode: OptimizationMode,
+) {
+    if !comparing {
+        optimizing.toggle_and_switch(active_mode, mode);
+        return;
+    }
+
+    *active_mode = *compare_mode;
+    *compare_mode = mode;
+    if optimizing.is_active() {
+        *optimizing = *active_mode;
+    }
+
+    info!(
+        "comparing {:?} (left) and {:?} (right)",
+        active_mode, compare_mode
+    );
+}
+
+/// Reload the parameters file at `path` into `params`, keeping the current parameters if it is
+/// invalid
+fn reload_params(
+    path: &std::path::Path,
+    gl: &Rc<tinygl::Context>,
+    state: &mut State,
+    params: &mut Params,
+) {
+    match ParamsFile::load(path).and_then(|file| file.apply(params)) {
+        Ok(new) => {
+            let init = params_file::requires_init(params, &new);
+            *params = new;
+
+            if init {
+                if let Err(e) = state.run_init(gl, params) {
+                    error!("initialization failed: {}", e);
+                    return;
+                }
+            }
+
+            info!("reloaded {}", path.display());
+        }
+        Err(e) => error!("keeping the current parameters: {}", e),
+    }
+}
+
 fn main() -> Result<(), String> {
     phasor::log::init();
 
+    let mut args = std::env::args().skip(1);
+    let mut params_path = None;
+    match args.next().as_deref() {
+        Some("bench") => {
+            let iterations = args
+                .next()
+                .map(|n| {
+                    n.parse()
+                        .map_err(|e| format!("invalid iteration count: {}", e))
+                })
+                .transpose()?
+                .unwrap_or(10);
+
+            return bench(iterations);
+        }
+        Some("--params-file") => {
+            let path = args.next().ok_or("--params-file requires a path")?;
+            params_path = Some(std::path::PathBuf::from(path));
+        }
+        _ => {}
+    }
+
     let el = EventLoop::new();
 
     let wb = WindowBuilder::new()
@@ -45,17 +240,35 @@ fn main() -> Result<(), String> {
     };
 
     // Initialize demo
-    let mut state = State::new(&gl).expect("failed to initialize state");
+    let mut state = State::new(&gl).map_err(|e| format!("failed to initialize state: {}", e))?;
     let mut params = Params::default();
     params.min_frequency = 1.0;
     params.max_frequency = 4.0;
-    params.frequency_mode = phasor::shared::FM_GAUSS as i32;
+    params.frequency_mode = FrequencyMode::Gauss;
     params.filter_bandwidth = 3.0 / std::f32::consts::PI.sqrt();
-    state.run_init(&gl, &params);
+
+    // Parameters file, reloaded when it changes
+    let _watcher = match &params_path {
+        Some(path) => {
+            params = ParamsFile::load(path)?.apply(&params)?;
+            Some(watch_params(path, el.create_proxy())?)
+        }
+        None => None,
+    };
+
+    state.run_init(&gl, &params)?;
+
+    // Color palette, 0 is grayscale
+    let mut palette_index = 0;
+
+    // Index in DISPLAY_MODES
+    let mut display_index = 0;
 
     // Optimization modes
     let mut optimizing = OptimizationMode::None;
     let mut active_mode = OptimizationMode::Optimize;
+    // Mode of the right half in comparisons
+    let mut compare_mode = OptimizationMode::Average;
 
     // Monitors
     let fullscreen = Some(Fullscreen::Borderless(
@@ -83,36 +296,105 @@ fn main() -> Result<(), String> {
                                     optimizing.toggle(&mut active_mode);
                                 }
                                 VirtualKeyCode::A => {
-                                    optimizing.toggle_and_switch(
+                                    select_mode(
+                                        state.is_comparing(),
+                                        &mut optimizing,
                                         &mut active_mode,
+                                        &mut compare_mode,
                                         OptimizationMode::Average,
                                     );
                                 }
                                 VirtualKeyCode::C => {
-                                    optimizing.toggle_and_switch(
+                                    select_mode(
+                                        state.is_comparing(),
+                                        &mut optimizing,
                                         &mut active_mode,
+                                        &mut compare_mode,
                                         OptimizationMode::ConditionalAverage,
                                     );
                                 }
                                 VirtualKeyCode::H => {
-                                    optimizing.toggle_and_switch(
+                                    select_mode(
+                                        state.is_comparing(),
+                                        &mut optimizing,
                                         &mut active_mode,
+                                        &mut compare_mode,
                                         OptimizationMode::Hybrid,
                                     );
                                 }
                                 VirtualKeyCode::I => {
-                                    state.run_init(&gl, &params);
+                                    if let Err(e) = state.run_init(&gl, &params) {
+                                        error!("initialization failed: {}", e);
+                                    } else if state.is_comparing() {
+                                        // Restart the comparison from the new kernels
+                                        if let Err(e) = state.start_comparison(&gl) {
+                                            error!("comparison failed: {}", e);
+                                            state.stop_comparison();
+                                        }
+                                    }
+
                                     windowed_context.window().request_redraw();
                                 }
                                 VirtualKeyCode::O => {
-                                    optimizing.toggle_and_switch(
+                                    select_mode(
+                                        state.is_comparing(),
+                                        &mut optimizing,
                                         &mut active_mode,
+                                        &mut compare_mode,
                                         OptimizationMode::Optimize,
                                     );
                                 }
+                                VirtualKeyCode::P => {
+                                    palette_index = (palette_index + 1) % 3;
+                                    match palette_index {
+                                        1 => state.set_color_lut(&gl, &palette::VIRIDIS),
+                                        2 => state.set_color_lut(&gl, &palette::COOLWARM),
+                                        _ => {}
+                                    }
+
+                                    params.use_lut = palette_index != 0;
+                                    windowed_context.window().request_redraw();
+                                }
+                                VirtualKeyCode::D => {
+                                    display_index = (display_index + 1) % DISPLAY_MODES.len();
+                                    info!("display mode: {:?}", DISPLAY_MODES[display_index]);
+                                    windowed_context.window().request_redraw();
+                                }
+                                VirtualKeyCode::S => {
+                                    if let Err(e) = screenshot(
+                                        &gl,
+                                        &mut state,
+                                        &params,
+                                        windowed_context.window().inner_size(),
+                                    ) {
+                                        error!("screenshot failed: {}", e);
+                                    }
+
+                                    windowed_context.window().request_redraw();
+                                }
+                                VirtualKeyCode::V => {
+                                    if state.is_comparing() {
+                                        state.stop_comparison();
+                                    } else if let Err(e) = state.start_comparison(&gl) {
+                                        error!("comparison failed: {}", e);
+                                    } else {
+                                        info!(
+                                            "comparing {:?} (left) and {:?} (right)",
+                                            active_mode, compare_mode
+                                        );
+                                    }
+
+                                    windowed_context.window().request_redraw();
+                                }
                                 VirtualKeyCode::Escape => {
                                     *control_flow = ControlFlow::Exit;
                                 }
+                                VirtualKeyCode::F5 => {
+                                    if let Some(path) = &params_path {
+                                        reload_params(path, &gl, &mut state, &mut params);
+                                        windowed_context.window().request_redraw();
+                                    }
+                                }
                                 VirtualKeyCode::F11 => {
                                     if windowed_context.window().fullscreen().is_some() {
                                         windowed_context.window().set_fullscreen(None);
@@ -141,6 +423,12 @@ fn main() -> Result<(), String> {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                 _ => {}
             },
+            Event::UserEvent(()) => {
+                if let Some(path) = &params_path {
+                    reload_params(path, &gl, &mut state, &mut params);
+                    windowed_context.window().request_redraw();
+                }
+            }
             Event::RedrawRequested(_) => {
                 // Render demo
                 unsafe {
@@ -149,10 +437,17 @@ fn main() -> Result<(), String> {
                     gl.clear(tinygl::gl::COLOR_BUFFER_BIT);
 
                     if optimizing.is_active() {
-                        state.run_optimize(&gl, optimizing, 1, &params);
+                        if let Err(e) =
+                            state.run_optimize_comparison(&gl, optimizing, compare_mode, 1, &params)
+                        {
+                            error!("optimization failed: {}", e);
+                            optimizing = OptimizationMode::None;
+                        }
                     }
 
-                    state.run_display(&gl, &params, shared::DM_NOISE as i32);
+                    if let Err(e) = state.run_display(&gl, &params, DISPLAY_MODES[display_index]) {
+                        error!("display failed: {}", e);
+                    }
                 }
 
                 windowed_context.swap_buffers().unwrap();
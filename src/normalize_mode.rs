@@ -0,0 +1,75 @@
+use super::shared;
+
+/// Statistics of the first channel of a rendered image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueRange {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub std_dev: f32,
+}
+
+impl ValueRange {
+    /// Range from the minimum, maximum, sum and sum of squares of `count` values
+    pub fn from_sums(sums: [f32; 4], count: usize) -> Self {
+        let count = count.max(1) as f64;
+        let mean = sums[2] as f64 / count;
+        let variance = (sums[3] as f64 / count - mean * mean).max(0.0);
+
+        Self {
+            min: sums[0],
+            max: sums[1],
+            mean: mean as f32,
+            std_dev: variance.sqrt() as f32,
+        }
+    }
+}
+
+/// Normalization of rendered images before readback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalizeMode {
+    /// Raw values
+    None,
+    /// Map the minimum and maximum to 0 and 1
+    MinMax,
+    /// Map the mean minus and plus the given number of standard deviations to 0 and 1
+    Sigma(f32),
+}
+
+impl NormalizeMode {
+    /// Offset and scale mapping `range` to [0, 1], values are mapped to
+    /// `(value - offset) * scale`. Returns None if no normalization is needed.
+    pub fn transform(&self, range: &ValueRange) -> Option<(f32, f32)> {
+        let (low, high) = match self {
+            Self::None => return None,
+            Self::MinMax => (range.min, range.max),
+            Self::Sigma(k) => (
+                range.mean - k * range.std_dev,
+                range.mean + k * range.std_dev,
+            ),
+        };
+
+        // Constant images are only offset
+        let span = high - low;
+        Some((low, if span > 0.0 { 1.0 / span } else { 1.0 }))
+    }
+
+    /// Decode a mode from its `NM_*` constant and the number of standard deviations used by
+    /// `NM_SIGMA`
+    pub fn from_raw(mode: i32, sigma: f32) -> Option<Self> {
+        use std::convert::TryFrom;
+
+        match u32::try_from(mode) {
+            Ok(shared::NM_NONE) => Some(Self::None),
+            Ok(shared::NM_MINMAX) => Some(Self::MinMax),
+            Ok(shared::NM_SIGMA) => Some(Self::Sigma(sigma)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for NormalizeMode {
+    fn default() -> Self {
+        Self::None
+    }
+}
@@ -1,4 +1,6 @@
-use super::shared;
+use std::convert::TryFrom;
+
+use super::{InvalidConstant, OptimizationMethod};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum OptimizationMode {
@@ -11,12 +13,17 @@ pub enum OptimizationMode {
 
 impl OptimizationMode {
     pub fn as_mode(&self) -> i32 {
+        self.method().map(|method| method.as_raw()).unwrap_or(-1)
+    }
+
+    /// Optimization method of the shaders, None if not optimizing
+    pub fn method(&self) -> Option<OptimizationMethod> {
         match self {
-            Self::None => -1,
-            Self::Optimize => shared::OM_OPTIMIZE as i32,
-            Self::Average => shared::OM_AVERAGE as i32,
-            Self::Hybrid => shared::OM_HYBRID as i32,
-            Self::ConditionalAverage => shared::OM_COND_AVERAGE as i32,
+            Self::None => None,
+            Self::Optimize => Some(OptimizationMethod::Optimize),
+            Self::Average => Some(OptimizationMethod::Average),
+            Self::Hybrid => Some(OptimizationMethod::Hybrid),
+            Self::ConditionalAverage => Some(OptimizationMethod::ConditionalAverage),
         }
     }
 
@@ -57,16 +64,21 @@ impl Default for OptimizationMode {
     }
 }
 
-impl From<i32> for OptimizationMode {
-    fn from(value: i32) -> Self {
-        use std::convert::TryFrom;
-
-        match u32::try_from(value) {
-            Ok(shared::OM_OPTIMIZE) => Self::Optimize,
-            Ok(shared::OM_AVERAGE) => Self::Average,
-            Ok(shared::OM_HYBRID) => Self::Hybrid,
-            Ok(shared::OM_COND_AVERAGE) => Self::ConditionalAverage,
-            _ => Self::None,
+impl From<OptimizationMethod> for OptimizationMode {
+    fn from(method: OptimizationMethod) -> Self {
+        match method {
+            OptimizationMethod::Optimize => Self::Optimize,
+            OptimizationMethod::Average => Self::Average,
+            OptimizationMethod::Hybrid => Self::Hybrid,
+            OptimizationMethod::ConditionalAverage => Self::ConditionalAverage,
         }
     }
 }
+
+impl TryFrom<i32> for OptimizationMode {
+    type Error = InvalidConstant;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        OptimizationMethod::try_from(value).map(Self::from)
+    }
+}
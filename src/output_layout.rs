@@ -0,0 +1,98 @@
+/// Memory layout of images returned through the C API
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct OutputLayout {
+    /// Rows are ordered from the top of the image instead of the bottom
+    pub origin_top_left: bool,
+    /// Number of channels per pixel, 4 for RGBA or 1 for the first channel only
+    pub channels: u32,
+}
+
+impl Default for OutputLayout {
+    /// OpenGL layout: bottom-left origin, RGBA
+    fn default() -> Self {
+        Self {
+            origin_top_left: false,
+            channels: 4,
+        }
+    }
+}
+
+impl OutputLayout {
+    pub fn new(origin_top_left: bool, channels: u32) -> Result<Self, String> {
+        match channels {
+            1 | 4 => Ok(Self {
+                origin_top_left,
+                channels,
+            }),
+            _ => Err(format!(
+                "invalid channel count {}, expected 1 or 4",
+                channels
+            )),
+        }
+    }
+
+    /// Convert `buffer`, a `width` by `height` RGBA image with a bottom-left origin as read
+    /// back from OpenGL, to this layout
+    pub fn apply(&self, buffer: &mut Vec<f32>, width: usize, height: usize) {
+        let row_len = width * 4;
+        debug_assert_eq!(buffer.len(), row_len * height);
+
+        if self.origin_top_left {
+            for y in 0..height / 2 {
+                let (top, bottom) = buffer.split_at_mut((height - 1 - y) * row_len);
+                top[y * row_len..(y + 1) * row_len].swap_with_slice(&mut bottom[..row_len]);
+            }
+        }
+
+        if self.channels == 1 {
+            for i in 0..width * height {
+                buffer[i] = buffer[i * 4];
+            }
+
+            buffer.truncate(width * height);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize) -> Vec<f32> {
+        (0..width * height * 4).map(|i| i as f32).collect()
+    }
+
+    #[test]
+    fn flip_twice() {
+        let original = image(5, 3);
+        let flip = OutputLayout::new(true, 4).unwrap();
+
+        let mut flipped = original.clone();
+        flip.apply(&mut flipped, 5, 3);
+        assert_eq!(&flipped[..20], &original[40..]);
+        assert_eq!(&flipped[20..40], &original[20..40]);
+
+        flip.apply(&mut flipped, 5, 3);
+        assert_eq!(flipped, original);
+    }
+
+    #[test]
+    fn single_channel() {
+        for origin_top_left in &[false, true] {
+            let mut rgba = image(4, 4);
+            OutputLayout::new(*origin_top_left, 4)
+                .unwrap()
+                .apply(&mut rgba, 4, 4);
+
+            let mut r = image(4, 4);
+            OutputLayout::new(*origin_top_left, 1)
+                .unwrap()
+                .apply(&mut r, 4, 4);
+
+            let expected: Vec<_> = rgba.chunks_exact(4).map(|pixel| pixel[0]).collect();
+            assert_eq!(r, expected);
+        }
+
+        assert!(OutputLayout::new(false, 3).is_err());
+    }
+}
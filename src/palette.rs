@@ -0,0 +1,30 @@
+//! Built-in color lookup tables for `State::set_color_lut`
+
+/// Identity grayscale ramp, the default lookup table
+pub const GRAYSCALE: [[f32; 3]; 2] = [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0]];
+
+/// Perceptually uniform viridis colormap, sampled from matplotlib at 9 evenly spaced positions
+pub const VIRIDIS: [[f32; 3]; 9] = [
+    [0.267004, 0.004874, 0.329415],
+    [0.278826, 0.175490, 0.483397],
+    [0.229739, 0.322361, 0.545706],
+    [0.172719, 0.448791, 0.557885],
+    [0.127568, 0.566949, 0.550556],
+    [0.157851, 0.683765, 0.501686],
+    [0.369214, 0.788888, 0.382914],
+    [0.678489, 0.863742, 0.189503],
+    [0.993248, 0.906157, 0.143936],
+];
+
+/// Diverging blue to red colormap (Moreland's cool to warm)
+pub const COOLWARM: [[f32; 3]; 9] = [
+    [0.230, 0.299, 0.754],
+    [0.358, 0.483, 0.908],
+    [0.497, 0.640, 0.986],
+    [0.643, 0.763, 0.999],
+    [0.865, 0.865, 0.865],
+    [0.957, 0.757, 0.657],
+    [0.958, 0.603, 0.481],
+    [0.887, 0.400, 0.316],
+    [0.706, 0.016, 0.150],
+];
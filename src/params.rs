@@ -1,21 +1,22 @@
-use super::{shaders, shared};
+use super::{shaders, AngleMode, CellMode, FrequencyMode, IsotropyMode};
 
 use std::rc::Rc;
 
 const DEFAULT_BANDWIDTH: f32 = 1.692568750643269; // 3.0 / sqrt(M_PI)
 
 #[repr(C)]
+#[derive(Clone)]
 pub struct Params {
     // Shared params
     pub angle_bandwidth: f32,
-    pub angle_mode: i32,
+    pub angle_mode: AngleMode,
     pub angle_offset: f32,
     pub angle_range: f32,
     pub frequency_bandwidth: f32,
-    pub frequency_mode: i32,
+    pub frequency_mode: FrequencyMode,
     pub global_seed: i32,
     pub isotropy_bandwidth: f32,
-    pub isotropy_mode: i32,
+    pub isotropy_mode: IsotropyMode,
     pub isotropy_power: f32,
     pub max_frequency: f32,
     pub min_frequency: f32,
@@ -30,23 +31,30 @@ pub struct Params {
     pub filter_modulation: f32,
 
     // Global params
-    pub cell_mode: i32,
+    pub cell_mode: CellMode,
     pub kernel_count: u32,
     pub grid_size: cgmath::Vector3<i32>,
+    /// Update kernels in place during optimization, faster but not deterministic
+    pub in_place_optimize: bool,
+    /// Map scalar display modes through the color lookup table, see `State::set_color_lut`
+    pub use_lut: bool,
+    /// Write the derivatives of the noise phase to the extra attachment in scalar display
+    /// modes, see `State::render_to_texture`
+    pub emit_derivatives: bool,
 }
 
 impl Default for Params {
     fn default() -> Self {
         Self {
             angle_bandwidth: 0.1,
-            angle_mode: shared::AM_GAUSS as i32,
+            angle_mode: AngleMode::Gauss,
             angle_offset: 0.0,
             angle_range: std::f32::consts::PI,
             frequency_bandwidth: 0.1,
-            frequency_mode: shared::FM_STATIC as i32,
+            frequency_mode: FrequencyMode::Static,
             global_seed: 171,
             isotropy_bandwidth: 0.1,
-            isotropy_mode: shared::IM_ANISOTROPIC as i32,
+            isotropy_mode: IsotropyMode::Anisotropic,
             isotropy_power: 1.0,
             max_frequency: 4.0,
             min_frequency: 2.0,
@@ -61,7 +69,10 @@ impl Default for Params {
             //
             kernel_count: 16,
             grid_size: Self::compute_grid_size(DEFAULT_BANDWIDTH),
-            cell_mode: shared::CM_CLAMP as i32,
+            cell_mode: CellMode::Clamp,
+            in_place_optimize: false,
+            use_lut: false,
+            emit_derivatives: false,
         }
     }
 }
@@ -79,14 +90,14 @@ impl Params {
     ) {
         self.apply_global(gl, program);
         program.set_u_angle_bandwidth(&gl, self.angle_bandwidth);
-        program.set_u_angle_mode(&gl, self.angle_mode);
+        program.set_u_angle_mode(&gl, self.angle_mode.as_raw());
         program.set_u_angle_offset(&gl, self.angle_offset);
         program.set_u_angle_range(&gl, self.angle_range);
         program.set_u_frequency_bandwidth(&gl, self.frequency_bandwidth);
-        program.set_u_frequency_mode(&gl, self.frequency_mode);
+        program.set_u_frequency_mode(&gl, self.frequency_mode.as_raw());
         program.set_u_global_seed(&gl, self.global_seed);
         program.set_u_isotropy_bandwidth(&gl, self.isotropy_bandwidth);
-        program.set_u_isotropy_mode(&gl, self.isotropy_mode);
+        program.set_u_isotropy_mode(&gl, self.isotropy_mode.as_raw());
         program.set_u_isotropy_power(&gl, self.isotropy_power);
         program.set_u_max_frequency(&gl, self.max_frequency);
         program.set_u_max_isotropy(&gl, self.max_isotropy);
@@ -95,7 +106,7 @@ impl Params {
     }
 
     pub fn apply_global(&self, gl: &Rc<tinygl::Context>, program: &impl shaders::GlobalUniformSet) {
-        program.set_u_cell_mode(&gl, self.cell_mode);
+        program.set_u_cell_mode(&gl, self.cell_mode.as_raw());
         program.set_u_grid(&gl, self.grid_size);
         program.set_u_kernel_count(&gl, self.kernel_count);
     }
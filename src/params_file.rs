@@ -0,0 +1,305 @@
+//! TOML representation of `Params`, with modes given by name
+
+use serde::{Deserialize, Serialize};
+
+use super::{shared, AngleMode, CellMode, FrequencyMode, IsotropyMode, Params};
+
+/// Names of the angle, frequency, isotropy and cell modes
+const ANGLE_MODES: &[(&str, AngleMode)] = &[
+    ("static", AngleMode::Static),
+    ("gauss", AngleMode::Gauss),
+    ("rangle", AngleMode::RAngle),
+    ("radial", AngleMode::Radial),
+];
+const FREQUENCY_MODES: &[(&str, FrequencyMode)] = &[
+    ("static", FrequencyMode::Static),
+    ("gauss", FrequencyMode::Gauss),
+];
+const ISOTROPY_MODES: &[(&str, IsotropyMode)] = &[
+    ("anisotropic", IsotropyMode::Anisotropic),
+    ("gauss", IsotropyMode::Gauss),
+    ("isotropic", IsotropyMode::Isotropic),
+    ("ramp", IsotropyMode::Ramp),
+];
+const CELL_MODES: &[(&str, CellMode)] = &[("clamp", CellMode::Clamp), ("mod", CellMode::Mod)];
+
+fn mode_value<T: Copy>(modes: &[(&str, T)], kind: &str, name: &str) -> Result<T, String> {
+    modes
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| {
+            format!(
+                "invalid {} mode {:?}, expected one of: {}",
+                kind,
+                name,
+                modes.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")
+            )
+        })
+}
+
+fn mode_name<T: PartialEq>(modes: &[(&str, T)], value: T) -> Option<String> {
+    modes
+        .iter()
+        .find(|(_, v)| *v == value)
+        .map(|(n, _)| (*n).to_owned())
+}
+
+/// Parameters read from a TOML file. Missing fields keep their current value.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParamsFile {
+    pub angle_bandwidth: Option<f32>,
+    pub angle_mode: Option<String>,
+    pub angle_offset: Option<f32>,
+    pub angle_range: Option<f32>,
+    pub frequency_bandwidth: Option<f32>,
+    pub frequency_mode: Option<String>,
+    pub global_seed: Option<i32>,
+    pub isotropy_bandwidth: Option<f32>,
+    pub isotropy_mode: Option<String>,
+    pub isotropy_power: Option<f32>,
+    pub max_frequency: Option<f32>,
+    pub min_frequency: Option<f32>,
+    pub max_isotropy: Option<f32>,
+    pub min_isotropy: Option<f32>,
+    /// Also sets the grid size, see `Params::compute_grid_size`
+    pub noise_bandwidth: Option<f32>,
+    pub filter_bandwidth: Option<f32>,
+    pub isotropy_modulation: Option<f32>,
+    pub filter_mod_power: Option<f32>,
+    pub filter_modulation: Option<f32>,
+    pub cell_mode: Option<String>,
+    pub kernel_count: Option<u32>,
+    pub in_place_optimize: Option<bool>,
+    pub use_lut: Option<bool>,
+    pub emit_derivatives: Option<bool>,
+}
+
+impl ParamsFile {
+    /// Parse the TOML representation of parameters
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Read and parse a parameters file
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Representation of all the values of `params`
+    pub fn from_params(params: &Params) -> Self {
+        Self {
+            angle_bandwidth: Some(params.angle_bandwidth),
+            angle_mode: mode_name(ANGLE_MODES, params.angle_mode),
+            angle_offset: Some(params.angle_offset),
+            angle_range: Some(params.angle_range),
+            frequency_bandwidth: Some(params.frequency_bandwidth),
+            frequency_mode: mode_name(FREQUENCY_MODES, params.frequency_mode),
+            global_seed: Some(params.global_seed),
+            isotropy_bandwidth: Some(params.isotropy_bandwidth),
+            isotropy_mode: mode_name(ISOTROPY_MODES, params.isotropy_mode),
+            isotropy_power: Some(params.isotropy_power),
+            max_frequency: Some(params.max_frequency),
+            min_frequency: Some(params.min_frequency),
+            max_isotropy: Some(params.max_isotropy),
+            min_isotropy: Some(params.min_isotropy),
+            noise_bandwidth: Some(params.noise_bandwidth),
+            filter_bandwidth: Some(params.filter_bandwidth),
+            isotropy_modulation: Some(params.isotropy_modulation),
+            filter_mod_power: Some(params.filter_mod_power),
+            filter_modulation: Some(params.filter_modulation),
+            cell_mode: mode_name(CELL_MODES, params.cell_mode),
+            kernel_count: Some(params.kernel_count),
+            in_place_optimize: Some(params.in_place_optimize),
+            use_lut: Some(params.use_lut),
+            emit_derivatives: Some(params.emit_derivatives),
+        }
+    }
+
+    /// TOML representation of these parameters
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("parameters are always representable")
+    }
+
+    /// `params` updated with the values of this file, if they are valid
+    pub fn apply(&self, params: &Params) -> Result<Params, String> {
+        let mut new = params.clone();
+
+        macro_rules! set {
+            ($($field:ident),*) => {
+                $(if let Some(value) = self.$field {
+                    new.$field = value;
+                })*
+            };
+        }
+
+        set!(
+            angle_bandwidth,
+            angle_offset,
+            angle_range,
+            frequency_bandwidth,
+            global_seed,
+            isotropy_bandwidth,
+            isotropy_power,
+            max_frequency,
+            min_frequency,
+            max_isotropy,
+            min_isotropy,
+            noise_bandwidth,
+            filter_bandwidth,
+            isotropy_modulation,
+            filter_mod_power,
+            filter_modulation,
+            kernel_count,
+            in_place_optimize,
+            use_lut,
+            emit_derivatives
+        );
+
+        if let Some(name) = &self.angle_mode {
+            new.angle_mode = mode_value(ANGLE_MODES, "angle", name)?;
+        }
+        if let Some(name) = &self.frequency_mode {
+            new.frequency_mode = mode_value(FREQUENCY_MODES, "frequency", name)?;
+        }
+        if let Some(name) = &self.isotropy_mode {
+            new.isotropy_mode = mode_value(ISOTROPY_MODES, "isotropy", name)?;
+        }
+        if let Some(name) = &self.cell_mode {
+            new.cell_mode = mode_value(CELL_MODES, "cell", name)?;
+        }
+
+        new.grid_size = Params::compute_grid_size(new.noise_bandwidth);
+
+        validate(&new)?;
+        Ok(new)
+    }
+}
+
+/// Check that `params` can be used for optimization and display
+pub fn validate(params: &Params) -> Result<(), String> {
+    if params.kernel_count == 0 || params.kernel_count > shared::MAX_K {
+        return Err(format!(
+            "invalid kernel_count {}, expected 1 to {}",
+            params.kernel_count,
+            shared::MAX_K
+        ));
+    }
+
+    if params.noise_bandwidth.is_nan() || params.noise_bandwidth <= 0.0 {
+        return Err(format!(
+            "invalid noise_bandwidth {}, expected a positive value",
+            params.noise_bandwidth
+        ));
+    }
+
+    if params.min_frequency > params.max_frequency {
+        return Err(format!(
+            "min_frequency {} is greater than max_frequency {}",
+            params.min_frequency, params.max_frequency
+        ));
+    }
+
+    if params.min_isotropy > params.max_isotropy {
+        return Err(format!(
+            "min_isotropy {} is greater than max_isotropy {}",
+            params.min_isotropy, params.max_isotropy
+        ));
+    }
+
+    Ok(())
+}
+
+/// true if switching from `old` to `new` requires running the init pass again, because the
+/// kernel layout or the fields sampled when initializing kernels changed. Other parameters
+/// only affect optimization and display.
+pub fn requires_init(old: &Params, new: &Params) -> bool {
+    old.grid_size != new.grid_size
+        || old.kernel_count != new.kernel_count
+        || old.cell_mode != new.cell_mode
+        || old.global_seed != new.global_seed
+        || old.angle_bandwidth != new.angle_bandwidth
+        || old.angle_mode != new.angle_mode
+        || old.angle_offset != new.angle_offset
+        || old.angle_range != new.angle_range
+        || old.frequency_bandwidth != new.frequency_bandwidth
+        || old.frequency_mode != new.frequency_mode
+        || old.isotropy_bandwidth != new.isotropy_bandwidth
+        || old.isotropy_mode != new.isotropy_mode
+        || old.isotropy_power != new.isotropy_power
+        || old.max_frequency != new.max_frequency
+        || old.min_frequency != new.min_frequency
+        || old.max_isotropy != new.max_isotropy
+        || old.min_isotropy != new.min_isotropy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_round_trip() {
+        let params = Params {
+            angle_mode: AngleMode::Radial,
+            isotropy_mode: IsotropyMode::Ramp,
+            kernel_count: 24,
+            emit_derivatives: true,
+            ..Default::default()
+        };
+
+        let text = ParamsFile::from_params(&params).to_toml();
+        assert!(text.contains("angle_mode = \"radial\""));
+        assert!(text.contains("isotropy_mode = \"ramp\""));
+
+        let parsed = ParamsFile::parse(&text)
+            .unwrap()
+            .apply(&Params::default())
+            .unwrap();
+        assert_eq!(
+            ParamsFile::from_params(&parsed),
+            ParamsFile::from_params(&params)
+        );
+        assert_eq!(parsed.grid_size, params.grid_size);
+    }
+
+    #[test]
+    fn partial_files() {
+        let params = ParamsFile::parse("frequency_mode = \"gauss\"\nnoise_bandwidth = 3.0\n")
+            .unwrap()
+            .apply(&Params::default())
+            .unwrap();
+
+        assert_eq!(params.frequency_mode, FrequencyMode::Gauss);
+        assert_eq!(params.grid_size, Params::compute_grid_size(3.0));
+        assert_eq!(params.kernel_count, Params::default().kernel_count);
+
+        assert!(ParamsFile::parse("angle_mode = \"spiral\"")
+            .unwrap()
+            .apply(&Params::default())
+            .is_err());
+        assert!(ParamsFile::parse("kernel_count = 0")
+            .unwrap()
+            .apply(&Params::default())
+            .is_err());
+        assert!(ParamsFile::parse("kernel_cuont = 4").is_err());
+    }
+
+    #[test]
+    fn init_classification() {
+        let old = Params::default();
+        let apply = |text: &str| ParamsFile::parse(text).unwrap().apply(&old).unwrap();
+
+        assert!(!requires_init(&old, &old));
+        assert!(!requires_init(&old, &apply("filter_modulation = 1.0")));
+        assert!(!requires_init(&old, &apply("filter_bandwidth = 1.0")));
+        assert!(!requires_init(&old, &apply("use_lut = true")));
+        assert!(!requires_init(&old, &apply("emit_derivatives = true")));
+        assert!(requires_init(&old, &apply("kernel_count = 8")));
+        assert!(requires_init(&old, &apply("noise_bandwidth = 3.0")));
+        assert!(requires_init(&old, &apply("global_seed = 3")));
+        assert!(requires_init(&old, &apply("angle_mode = \"static\"")));
+    }
+}
@@ -0,0 +1,158 @@
+use std::rc::Rc;
+
+use glutin::event_loop::EventLoop;
+use glutin::{Context, ContextBuilder, PossiblyCurrent};
+use tinygl::prelude::*;
+
+/// Environment variable caching the result of `gl_available`
+pub const GL_AVAILABLE_VAR: &str = "PHASOR_GL_AVAILABLE";
+
+/// Headless OpenGL 4.6 context
+pub struct HeadlessContext {
+    pub gl: Rc<tinygl::Context>,
+    // Only None while switching contexts
+    context: Option<Context<PossiblyCurrent>>,
+    _el: EventLoop<()>,
+}
+
+impl HeadlessContext {
+    pub fn new(width: u32, height: u32) -> Result<Self, String> {
+        Self::with_debug(width, height, false)
+    }
+
+    /// Create a context, with the OpenGL debug flag set if `debug` is true
+    pub fn with_debug(width: u32, height: u32, debug: bool) -> Result<Self, String> {
+        // Creating the event loop panics when there is no display to connect to
+        let el = std::panic::catch_unwind(crate::api::get_event_loop)
+            .map_err(|_| "failed to create event loop".to_owned())?;
+
+        let headless_context = ContextBuilder::new()
+            .with_gl(glutin::GlRequest::Specific(glutin::Api::OpenGl, (4, 6)))
+            .with_gl_profile(glutin::GlProfile::Core)
+            .with_gl_debug_flag(debug)
+            .build_headless(&el, glutin::dpi::PhysicalSize::new(width, height))
+            .map_err(|e| e.to_string())?;
+
+        let (gl, headless_context) = unsafe {
+            let headless_context = headless_context
+                .make_current()
+                .map_err(|(_, e)| e.to_string())?;
+
+            (
+                Rc::new(tinygl::Context::from_loader_function(|s| {
+                    headless_context.get_proc_address(s) as *const _
+                })),
+                headless_context,
+            )
+        };
+
+        Ok(Self {
+            gl,
+            context: Some(headless_context),
+            _el: el,
+        })
+    }
+
+    /// Make this context current on the calling thread, if it isn't already
+    pub fn make_current(&mut self) -> Result<(), String> {
+        let context = self.context.take().expect("context");
+        if context.is_current() {
+            self.context = Some(context);
+            return Ok(());
+        }
+
+        match unsafe { context.make_current() } {
+            Ok(context) => {
+                self.context = Some(context);
+                Ok(())
+            }
+            Err((context, e)) => {
+                self.context = Some(context);
+                Err(e.to_string())
+            }
+        }
+    }
+}
+
+/// Description of the available OpenGL implementation
+pub struct GlInfo {
+    pub vendor: String,
+    pub renderer: String,
+    pub version: String,
+    pub max_texture_size: i32,
+    pub max_texture_buffer_size: i32,
+    pub max_compute_work_group_invocations: i32,
+    pub max_compute_work_group_size: [i32; 3],
+    pub max_shader_storage_block_size: i32,
+}
+
+/// Quote `s` as a JSON string
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl GlInfo {
+    /// Query the implementation of the current context
+    pub fn query(gl: &tinygl::Context) -> Self {
+        unsafe {
+            Self {
+                vendor: gl.get_parameter_string(tinygl::gl::VENDOR),
+                renderer: gl.get_parameter_string(tinygl::gl::RENDERER),
+                version: gl.get_parameter_string(tinygl::gl::VERSION),
+                max_texture_size: gl.get_parameter_i32(tinygl::gl::MAX_TEXTURE_SIZE),
+                max_texture_buffer_size: gl.get_parameter_i32(tinygl::gl::MAX_TEXTURE_BUFFER_SIZE),
+                max_compute_work_group_invocations: gl
+                    .get_parameter_i32(tinygl::gl::MAX_COMPUTE_WORK_GROUP_INVOCATIONS),
+                max_compute_work_group_size: [
+                    gl.get_parameter_indexed_i32(tinygl::gl::MAX_COMPUTE_WORK_GROUP_SIZE, 0),
+                    gl.get_parameter_indexed_i32(tinygl::gl::MAX_COMPUTE_WORK_GROUP_SIZE, 1),
+                    gl.get_parameter_indexed_i32(tinygl::gl::MAX_COMPUTE_WORK_GROUP_SIZE, 2),
+                ],
+                max_shader_storage_block_size: gl
+                    .get_parameter_i32(tinygl::gl::MAX_SHADER_STORAGE_BLOCK_SIZE),
+            }
+        }
+    }
+
+    /// Single-line JSON representation of this description
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"vendor": {}, "renderer": {}, "version": {}, "max_texture_size": {}, "max_texture_buffer_size": {}, "max_compute_work_group_invocations": {}, "max_compute_work_group_size_x": {}, "max_compute_work_group_size_y": {}, "max_compute_work_group_size_z": {}, "max_shader_storage_block_size": {}}}"#,
+            json_string(&self.vendor),
+            json_string(&self.renderer),
+            json_string(&self.version),
+            self.max_texture_size,
+            self.max_texture_buffer_size,
+            self.max_compute_work_group_invocations,
+            self.max_compute_work_group_size[0],
+            self.max_compute_work_group_size[1],
+            self.max_compute_work_group_size[2],
+            self.max_shader_storage_block_size
+        )
+    }
+}
+
+/// Create a headless context and describe its implementation
+pub fn probe() -> Result<GlInfo, String> {
+    let context = HeadlessContext::new(1, 1)?;
+    Ok(GlInfo::query(&context.gl))
+}
+
+/// true if an OpenGL 4.6 context can be created. The result is cached in the
+/// `PHASOR_GL_AVAILABLE` environment variable, which can also be set to skip the probe.
+pub fn gl_available() -> bool {
+    if let Ok(value) = std::env::var(GL_AVAILABLE_VAR) {
+        return value == "1";
+    }
+
+    let available = match probe() {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("no OpenGL context available: {}", e);
+            false
+        }
+    };
+
+    std::env::set_var(GL_AVAILABLE_VAR, if available { "1" } else { "0" });
+    available
+}
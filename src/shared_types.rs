@@ -0,0 +1,248 @@
+//! Typed wrappers over the constants of `shared.h`
+//!
+//! Each constant family of the generated `shared` module is mapped to an enum whose
+//! discriminants are the raw constant values, so the enums can be passed to the shaders with
+//! `as_raw` and checked when received from C with `TryFrom<i32>`.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::shared;
+
+/// Maximum number of kernels per cell
+pub const MAX_K: usize = shared::MAX_K as usize;
+
+/// Number of floats in a `shared::Kernel`
+pub const NFLOATS: usize = shared::NFLOATS as usize;
+
+/// Enum over a constant family of `shared.h`
+pub trait SharedEnum: Copy + Eq + 'static {
+    /// Name of the constant family, used in error messages
+    const FAMILY: &'static str;
+    /// All variants, with the name of their `shared.h` constant
+    const CONSTANTS: &'static [(&'static str, Self)];
+
+    /// Raw value of the constant, as expected by the shaders
+    fn as_raw(self) -> i32;
+
+    /// Name of the `shared.h` constant of this variant
+    fn constant_name(self) -> &'static str {
+        Self::CONSTANTS
+            .iter()
+            .find(|(_, variant)| *variant == self)
+            .map(|(name, _)| *name)
+            .expect("all variants are listed")
+    }
+}
+
+/// Raw value that doesn't match any constant of a family
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidConstant {
+    pub family: &'static str,
+    pub value: i32,
+    /// Valid constants of the family, with their values
+    pub expected: Vec<(&'static str, i32)>,
+}
+
+impl InvalidConstant {
+    pub fn new<T: SharedEnum>(value: i32) -> Self {
+        Self {
+            family: T::FAMILY,
+            value,
+            expected: T::CONSTANTS
+                .iter()
+                .map(|(name, variant)| (*name, variant.as_raw()))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for InvalidConstant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid {} {}, expected one of: {}",
+            self.family,
+            self.value,
+            self.expected
+                .iter()
+                .map(|(name, value)| format!("{} ({})", name, value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+impl std::error::Error for InvalidConstant {}
+
+macro_rules! shared_enum {
+    ($(#[$meta:meta])* $name:ident, $family:expr, {
+        $($(#[$vmeta:meta])* $variant:ident = $constant:ident),+ $(,)?
+    }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+        #[repr(i32)]
+        pub enum $name {
+            $($(#[$vmeta])* $variant = shared::$constant as i32),+
+        }
+
+        impl SharedEnum for $name {
+            const FAMILY: &'static str = $family;
+            const CONSTANTS: &'static [(&'static str, Self)] =
+                &[$((stringify!($constant), Self::$variant)),+];
+
+            fn as_raw(self) -> i32 {
+                self as i32
+            }
+        }
+
+        impl $name {
+            /// Raw value of the constant, as expected by the shaders
+            pub fn as_raw(self) -> i32 {
+                self as i32
+            }
+        }
+
+        impl TryFrom<i32> for $name {
+            type Error = InvalidConstant;
+
+            fn try_from(value: i32) -> Result<Self, Self::Error> {
+                Self::CONSTANTS
+                    .iter()
+                    .map(|(_, variant)| *variant)
+                    .find(|variant| variant.as_raw() == value)
+                    .ok_or_else(|| InvalidConstant::new::<Self>(value))
+            }
+        }
+
+        impl From<$name> for i32 {
+            fn from(value: $name) -> i32 {
+                value.as_raw()
+            }
+        }
+    };
+}
+
+shared_enum!(
+    /// Distribution of the kernel orientations, `AM_*`
+    AngleMode, "angle mode", {
+        Static = AM_STATIC,
+        Gauss = AM_GAUSS,
+        RAngle = AM_RANGLE,
+        Radial = AM_RADIAL,
+    }
+);
+
+shared_enum!(
+    /// Distribution of the kernel frequencies, `FM_*`
+    FrequencyMode, "frequency mode", {
+        Static = FM_STATIC,
+        Gauss = FM_GAUSS,
+    }
+);
+
+shared_enum!(
+    /// Distribution of the kernel isotropy, `IM_*`
+    IsotropyMode, "isotropy mode", {
+        Anisotropic = IM_ANISOTROPIC,
+        Gauss = IM_GAUSS,
+        Isotropic = IM_ISOTROPIC,
+        Ramp = IM_RAMP,
+    }
+);
+
+shared_enum!(
+    /// Handling of cells outside of the grid, `CM_*`
+    CellMode, "cell mode", {
+        Clamp = CM_CLAMP,
+        Mod = CM_MOD,
+    }
+);
+
+shared_enum!(
+    /// Output of the display pass, `DM_*`
+    DisplayMode, "display mode", {
+        Noise = DM_NOISE,
+        Complex = DM_COMPLEX,
+        State = DM_STATE,
+        Threshold = DM_THRESHOLD,
+        Kernels = DM_KERNELS,
+    }
+);
+
+shared_enum!(
+    /// Update rule of the optimization pass, `OM_*` without the `_BIT` flags
+    OptimizationMethod, "optimization method", {
+        Optimize = OM_OPTIMIZE,
+        Average = OM_AVERAGE,
+        Hybrid = OM_HYBRID,
+        ConditionalAverage = OM_COND_AVERAGE,
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHARED_H: &str = include_str!("../shaders/shared.h");
+
+    /// Check that every `#define` of the family of `T` is mapped to a variant, and that
+    /// variants round-trip through their raw value
+    fn assert_complete<T: SharedEnum + TryFrom<i32, Error = InvalidConstant> + fmt::Debug>() {
+        let first = T::CONSTANTS[0].0;
+        let prefix = &first[..=first.find('_').unwrap()];
+
+        let defined: Vec<&str> = SHARED_H
+            .lines()
+            .filter(|line| line.starts_with("#define "))
+            .filter_map(|line| line["#define ".len()..].split_whitespace().next())
+            .filter(|name| name.starts_with(prefix) && !name.ends_with("_BIT"))
+            .collect();
+
+        for name in &defined {
+            assert!(
+                T::CONSTANTS.iter().any(|(constant, _)| constant == name),
+                "{} is not mapped to a {} variant",
+                name,
+                T::FAMILY
+            );
+        }
+        assert_eq!(defined.len(), T::CONSTANTS.len());
+
+        for (name, variant) in T::CONSTANTS {
+            assert_eq!(T::try_from(variant.as_raw()), Ok(*variant));
+            assert_eq!(variant.constant_name(), *name);
+        }
+    }
+
+    #[test]
+    fn families_are_complete() {
+        assert_complete::<AngleMode>();
+        assert_complete::<FrequencyMode>();
+        assert_complete::<IsotropyMode>();
+        assert_complete::<CellMode>();
+        assert_complete::<DisplayMode>();
+        assert_complete::<OptimizationMethod>();
+    }
+
+    #[test]
+    fn invalid_values() {
+        let error = AngleMode::try_from(42).unwrap_err();
+        assert_eq!(error.value, 42);
+        assert_eq!(
+            error.to_string(),
+            "invalid angle mode 42, expected one of: AM_STATIC (0), AM_GAUSS (1), AM_RANGLE (2), AM_RADIAL (3)"
+        );
+
+        assert!(CellMode::try_from(-1).is_err());
+        assert!(OptimizationMethod::try_from(shared::OM_CONDITIONAL_BIT as i32).is_err());
+    }
+
+    #[test]
+    fn kernel_size() {
+        assert_eq!(
+            NFLOATS * std::mem::size_of::<f32>(),
+            std::mem::size_of::<shared::Kernel>()
+        );
+    }
+}
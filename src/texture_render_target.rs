@@ -7,30 +7,35 @@ pub struct TextureRenderTarget {
     pub depthbuffer: GlHandle<tinygl::wrappers::Renderbuffer>,
     pub texture_main: GlHandle<tinygl::wrappers::Texture>,
     pub texture_extra: GlHandle<tinygl::wrappers::Texture>,
+    /// Output of the normalization pass, see `NormalizeMode`
+    pub normalize_framebuffer: GlHandle<tinygl::wrappers::Framebuffer>,
+    pub texture_normalized: GlHandle<tinygl::wrappers::Texture>,
     current_size: Option<cgmath::Vector2<i32>>,
 }
 
 impl TextureRenderTarget {
-    pub fn new(
-        gl: &Rc<tinygl::Context>,
-        width: u32,
-        height: u32,
-    ) -> tinygl::Result<TextureRenderTarget> {
+    /// Create the render target objects, storage is allocated by `alloc`
+    pub fn new(gl: &Rc<tinygl::Context>) -> tinygl::Result<TextureRenderTarget> {
         // Create objects
         let mut this = Self {
             framebuffer: GlHandle::new(gl, tinygl::wrappers::Framebuffer::new(gl)?),
             depthbuffer: GlHandle::new(gl, tinygl::wrappers::Renderbuffer::new(gl)?),
             texture_main: GlHandle::new(gl, tinygl::wrappers::Texture::new(gl)?),
             texture_extra: GlHandle::new(gl, tinygl::wrappers::Texture::new(gl)?),
+            normalize_framebuffer: GlHandle::new(gl, tinygl::wrappers::Framebuffer::new(gl)?),
+            texture_normalized: GlHandle::new(gl, tinygl::wrappers::Texture::new(gl)?),
             current_size: None,
         };
 
-        // Initial allocation
-        this.alloc(gl, width, height);
-
         // Don't use mipmaps
         unsafe {
-            for tex in [&this.texture_main, &this.texture_extra].iter() {
+            for tex in [
+                &this.texture_main,
+                &this.texture_extra,
+                &this.texture_normalized,
+            ]
+            .iter()
+            {
                 tex.bind(gl, tinygl::gl::TEXTURE_2D);
                 gl.tex_parameteri(
                     tinygl::gl::TEXTURE_2D,
@@ -69,16 +74,59 @@ impl TextureRenderTarget {
                 0,
             );
             gl.draw_buffers(&[tinygl::gl::COLOR_ATTACHMENT0, tinygl::gl::COLOR_ATTACHMENT1]);
+
+            this.normalize_framebuffer.bind(gl, tinygl::gl::FRAMEBUFFER);
+            gl.framebuffer_texture(
+                tinygl::gl::FRAMEBUFFER,
+                tinygl::gl::COLOR_ATTACHMENT0,
+                Some(&this.texture_normalized),
+                0,
+            );
+            gl.draw_buffers(&[tinygl::gl::COLOR_ATTACHMENT0]);
+
             gl.bind_framebuffer(tinygl::gl::FRAMEBUFFER, None);
         }
 
         Ok(this)
     }
 
-    pub fn alloc(&mut self, gl: &Rc<tinygl::Context>, width: u32, height: u32) {
+    /// Current size of the render target, in pixels
+    pub fn size(&self) -> Option<(u32, u32)> {
+        self.current_size.map(|cs| (cs.x as u32, cs.y as u32))
+    }
+
+    /// Storage of a `width` by `height` render target, in bytes: three RGBA32F textures and a
+    /// depth buffer, counted as 32 bits per pixel. Mipmaps generated for supersampling are not
+    /// included.
+    pub fn bytes(width: u32, height: u32) -> u64 {
+        width as u64 * height as u64 * (3 * 16 + 4)
+    }
+
+    /// Resize the storage to `width` by `height`, failing if it would need more than `budget`
+    /// bytes
+    pub fn alloc(
+        &mut self,
+        gl: &Rc<tinygl::Context>,
+        width: u32,
+        height: u32,
+        budget: Option<u64>,
+    ) -> Result<(), String> {
         let new_size = cgmath::vec2(width as i32, height as i32);
 
         if !self.current_size.map(|cs| cs == new_size).unwrap_or(false) {
+            let bytes = Self::bytes(width, height);
+            if let Some(budget) = budget {
+                if bytes > budget {
+                    return Err(format!(
+                        "{}x{} render target needs {}, exceeding the {} left by the memory budget",
+                        width,
+                        height,
+                        bytesize::ByteSize(bytes),
+                        bytesize::ByteSize(budget)
+                    ));
+                }
+            }
+
             // Setup storage
             unsafe {
                 // Depth buffer
@@ -92,7 +140,13 @@ impl TextureRenderTarget {
                 gl.bind_renderbuffer(tinygl::gl::RENDERBUFFER, None);
 
                 // Textures
-                for tex in [&self.texture_main, &self.texture_extra].iter() {
+                for tex in [
+                    &self.texture_main,
+                    &self.texture_extra,
+                    &self.texture_normalized,
+                ]
+                .iter()
+                {
                     tex.bind(gl, tinygl::gl::TEXTURE_2D);
                     gl.tex_image_2d(
                         tinygl::gl::TEXTURE_2D,
@@ -113,5 +167,7 @@ impl TextureRenderTarget {
             // Update size
             self.current_size = Some(new_size);
         }
+
+        Ok(())
     }
 }
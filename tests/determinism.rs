@@ -0,0 +1,85 @@
+use std::rc::Rc;
+
+use phasor::probe::{self, HeadlessContext};
+use phasor::*;
+
+/// Kernel data and rendered image of a fresh `State` optimized with `seed`
+fn run(gl: &Rc<tinygl::Context>, seed: i32) -> (Vec<u32>, Vec<u32>) {
+    let params = Params {
+        global_seed: seed,
+        ..Default::default()
+    };
+
+    let mut state = State::new(gl).unwrap();
+    let mut image = Vec::new();
+    let mut extra = Vec::new();
+    state
+        .render_deterministic(
+            gl,
+            256,
+            256,
+            8,
+            DisplayMode::Noise,
+            &params,
+            &mut image,
+            &mut extra,
+        )
+        .unwrap();
+
+    let mut kernels = vec![
+        0.0;
+        (params.grid_size.x * params.grid_size.y * params.grid_size.z) as usize
+            * params.kernel_count as usize
+            * shared_types::NFLOATS
+    ];
+    state.read_kernels(gl, &mut kernels);
+
+    // Compare bits, not float values
+    (
+        kernels.iter().map(|x| x.to_bits()).collect(),
+        image.iter().map(|x| x.to_bits()).collect(),
+    )
+}
+
+fn context() -> Option<HeadlessContext> {
+    if !probe::gl_available() {
+        eprintln!("skipped: no GL");
+        return None;
+    }
+
+    let context = HeadlessContext::new(256, 256).unwrap();
+    let vao = tinygl::wrappers::VertexArray::new(&context.gl).unwrap();
+    unsafe {
+        vao.bind(&context.gl);
+    }
+
+    Some(context)
+}
+
+#[test]
+fn same_seed_is_identical() {
+    let context = match context() {
+        Some(context) => context,
+        None => return,
+    };
+
+    let first = run(&context.gl, 171);
+    let second = run(&context.gl, 171);
+
+    assert!(first.0 == second.0, "kernels differ");
+    assert!(first.1 == second.1, "images differ");
+}
+
+#[test]
+fn different_seeds_differ() {
+    let context = match context() {
+        Some(context) => context,
+        None => return,
+    };
+
+    let first = run(&context.gl, 171);
+    let second = run(&context.gl, 172);
+
+    assert!(first.0 != second.0, "kernels are identical");
+    assert!(first.1 != second.1, "images are identical");
+}
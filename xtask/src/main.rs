@@ -1,6 +1,7 @@
 use std::env;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::ffi::OsString;
 
 type DynError = Box<dyn std::error::Error>;
 
@@ -13,8 +14,14 @@ fn main() {
 
 fn try_main() -> Result<(), DynError> {
     let task = env::args().nth(1);
-    match task.as_ref().map(|it| it.as_str()) {
+    match task.as_deref() {
         Some("nsight") => nsight(),
+        Some("renderdoc") => renderdoc(env::args_os().skip(2).collect())?,
+        Some("dist") => dist(env::args().skip(2).any(|arg| arg == "--dry-run"))?,
+        Some("shaders") => shaders(env::args_os().skip(2).map(PathBuf::from).collect())?,
+        Some("gpu-info") => gpu_info()?,
+        Some("bench") => bench(env::args().nth(2))?,
+        Some("profile") => profile(ProfileOptions::parse(env::args_os().skip(2))?)?,
         _ => print_help(),
     }
     Ok(())
@@ -24,6 +31,15 @@ fn print_help() {
     eprintln!(
         "Tasks:
 nsight            runs nv-nsight-gfx on the debug executable
+renderdoc [args]  builds the debug executable and captures it with RenderDoc, passing args to it
+dist [--dry-run]  packages the release library and C header into dist/
+shaders [paths]   validates the phasor and icesl2voxel shaders, and any given files or directories
+gpu-info          describes the OpenGL implementation available to phasor
+bench [iterations]
+                  benchmarks the release build and appends the timings to benchmarks.csv
+profile [--bin icesl2voxel|phasor] [-- args]
+                  records a flamegraph of the release build into target/profiles/, icesl2voxel
+                  is run with args, phasor runs its benchmarks with args as the iteration count
 "
     )
 }
@@ -44,3 +60,1250 @@ fn nsight() {
         .wait()
         .expect("waiting for child process failed");
 }
+
+/// RenderDoc executables, in order of preference
+const RENDERDOC_TOOLS: &[&str] = &["renderdoccmd", "qrenderdoc"];
+
+/// Find the first of `names` in the directories of `path`, which is formatted like `PATH`
+fn find_tool(names: &[&str], path: Option<OsString>) -> Result<PathBuf, DynError> {
+    let dirs: Vec<_> = path
+        .map(|p| env::split_paths(&p).collect())
+        .unwrap_or_default();
+
+    for name in names {
+        for dir in &dirs {
+            let candidate = dir.join(name).with_extension(env::consts::EXE_EXTENSION);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+    }
+
+    Err(format!(
+        "could not find {} in any of the PATH directories:\n{}",
+        names.join(" or "),
+        dirs.iter()
+            .map(|dir| format!("  {}", dir.display()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+    .into())
+}
+
+/// Build the phasor binary, in release mode if `release` is set and in debug mode otherwise
+fn build_phasor(release: bool) -> Result<(), DynError> {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command.args(["build", "-p", "phasor"]);
+    if release {
+        command.arg("--release");
+    }
+
+    let status = command.status()?;
+
+    if !status.success() {
+        return Err("cargo build failed".into());
+    }
+
+    Ok(())
+}
+
+/// Quote `s` as a JSON string
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// RenderDoc capture settings launching `exe` with `args` from `cd`, for qrenderdoc
+fn renderdoc_capture_settings(cd: &Path, exe: &Path, args: &[OsString]) -> String {
+    let command_line = args
+        .iter()
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.contains(' ') {
+                format!("\"{}\"", arg)
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    format!(
+        r#"{{
+    "rdocCaptureSettings": 1,
+    "settings": {{
+        "autoStart": true,
+        "commandLine": {},
+        "environment": [],
+        "executable": {},
+        "inject": false,
+        "workingDir": {}
+    }}
+}}
+"#,
+        json_string(&command_line),
+        json_string(&exe.to_string_lossy()),
+        json_string(&cd.to_string_lossy())
+    )
+}
+
+/// True if `tool` is the RenderDoc UI, which only takes capture settings files
+fn is_qrenderdoc(tool: &Path) -> bool {
+    tool.file_stem() == Some(OsStr::new("qrenderdoc"))
+}
+
+/// Command capturing `exe` with the RenderDoc `tool`. qrenderdoc is given the path to capture
+/// settings written by `renderdoc_capture_settings`.
+fn renderdoc_command(
+    tool: &Path,
+    cd: &Path,
+    exe: &Path,
+    args: &[OsString],
+    settings_path: &Path,
+) -> Command {
+    let mut command = Command::new(tool);
+    command.current_dir(cd);
+
+    if is_qrenderdoc(tool) {
+        command.arg(settings_path);
+    } else {
+        command
+            .arg("capture")
+            .arg("--wait-for-exit")
+            .arg("--working-dir")
+            .arg(cd)
+            .arg(exe)
+            .args(args);
+    }
+
+    command
+}
+
+fn renderdoc(args: Vec<OsString>) -> Result<(), DynError> {
+    let tool = find_tool(RENDERDOC_TOOLS, env::var_os("PATH"))?;
+    build_phasor(false)?;
+
+    let cd = env::current_dir()?;
+    let exe = cd
+        .join("target/debug/phasor")
+        .with_extension(env::consts::EXE_EXTENSION);
+    let settings_path = cd.join("target/debug/phasor.cap");
+
+    if is_qrenderdoc(&tool) {
+        std::fs::write(&settings_path, renderdoc_capture_settings(&cd, &exe, &args))?;
+    }
+
+    let status = renderdoc_command(&tool, &cd, &exe, &args, &settings_path).status()?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", tool.display(), status).into());
+    }
+
+    Ok(())
+}
+
+/// Name of the C header generated by build.rs
+const HEADER_NAME: &str = "phasoropt.h";
+
+/// Run `program` with `args` and return its trimmed standard output, or `None` if it could not
+/// be run or failed
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|stdout| stdout.trim().to_owned())
+}
+
+/// Version of the package described by the Cargo.toml manifest `src`
+fn package_version(src: &str) -> Option<&str> {
+    src.lines()
+        .skip_while(|line| line.trim() != "[package]")
+        .skip(1)
+        .take_while(|line| !line.trim_start().starts_with('['))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next()?.trim(), parts.next()?.trim()) {
+                ("version", value) => Some(value.trim_matches('"')),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Format seconds since the UNIX epoch as an RFC 3339 UTC timestamp
+fn format_timestamp(secs: u64) -> String {
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Build information recorded in the distribution manifest
+struct BuildInfo {
+    version: String,
+    git_hash: String,
+    target: String,
+    timestamp: String,
+}
+
+impl BuildInfo {
+    /// Information about the current build. The git hash and target fall back to "unknown" if
+    /// git or rustc can't be run.
+    fn current(root: &Path) -> Result<Self, DynError> {
+        let manifest = std::fs::read_to_string(root.join("Cargo.toml"))?;
+        let version = package_version(&manifest)
+            .ok_or("no package version found in Cargo.toml")?
+            .to_owned();
+
+        let git_hash = command_output("git", &["rev-parse", "HEAD"]).unwrap_or_else(|| {
+            eprintln!("warning: could not get the commit hash, is git installed?");
+            "unknown".to_owned()
+        });
+
+        let target = command_output("rustc", &["-vV"])
+            .and_then(|info| {
+                info.lines()
+                    .find_map(|line| line.strip_prefix("host: ").map(str::to_owned))
+            })
+            .unwrap_or_else(|| "unknown".to_owned());
+
+        let timestamp = format_timestamp(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        );
+
+        Ok(Self {
+            version,
+            git_hash,
+            target,
+            timestamp,
+        })
+    }
+
+    fn manifest(&self) -> String {
+        format!(
+            r#"{{
+    "version": {},
+    "git_hash": {},
+    "target": {},
+    "build_timestamp": {}
+}}
+"#,
+            json_string(&self.version),
+            json_string(&self.git_hash),
+            json_string(&self.target),
+            json_string(&self.timestamp)
+        )
+    }
+}
+
+/// Files making up a distribution package
+struct DistLayout {
+    /// Directory the files are copied to
+    dir: PathBuf,
+    /// Zip archive of `dir`
+    archive: PathBuf,
+    /// Source and destination of the copied files
+    files: Vec<(PathBuf, PathBuf)>,
+    manifest: PathBuf,
+}
+
+impl DistLayout {
+    fn new(root: &Path, info: &BuildInfo) -> Self {
+        let name = format!("phasor-{}-{}", info.version, info.target);
+        let dist = root.join("dist");
+        let dir = dist.join(&name);
+
+        let library = format!(
+            "{}phasor{}",
+            env::consts::DLL_PREFIX,
+            env::consts::DLL_SUFFIX
+        );
+
+        Self {
+            files: vec![
+                (
+                    root.join("target/release").join(&library),
+                    dir.join(&library),
+                ),
+                (root.join(HEADER_NAME), dir.join(HEADER_NAME)),
+            ],
+            manifest: dir.join("MANIFEST.json"),
+            archive: dist.join(format!("{}.zip", name)),
+            dir,
+        }
+    }
+}
+
+fn dist(dry_run: bool) -> Result<(), DynError> {
+    let root = env::current_dir()?;
+    let info = BuildInfo::current(&root)?;
+    let layout = DistLayout::new(&root, &info);
+
+    if dry_run {
+        for (src, dst) in &layout.files {
+            println!("{} -> {}", src.display(), dst.display());
+        }
+        println!(
+            "{}:
+{}",
+            layout.manifest.display(),
+            info.manifest()
+        );
+        println!("archive: {}", layout.archive.display());
+        return Ok(());
+    }
+
+    build_phasor(true)?;
+
+    if !root.join(HEADER_NAME).is_file() {
+        return Err(format!(
+            "{} not found, it is generated by cbindgen in build.rs: check the build output for cbindgen errors",
+            HEADER_NAME
+        )
+        .into());
+    }
+
+    if layout.dir.exists() {
+        std::fs::remove_dir_all(&layout.dir)?;
+    }
+    std::fs::create_dir_all(&layout.dir)?;
+
+    for (src, dst) in &layout.files {
+        std::fs::copy(src, dst).map_err(|e| format!("could not copy {}: {}", src.display(), e))?;
+    }
+    std::fs::write(&layout.manifest, info.manifest())?;
+
+    if layout.archive.exists() {
+        std::fs::remove_file(&layout.archive)?;
+    }
+    let status = Command::new("zip")
+        .current_dir(layout.dir.parent().unwrap())
+        .arg("-r")
+        .arg(&layout.archive)
+        .arg(layout.dir.file_name().unwrap())
+        .status()
+        .map_err(|e| format!("could not run zip, is it installed? {}", e))?;
+    if !status.success() {
+        return Err("zip failed".into());
+    }
+
+    println!("wrote {}", layout.archive.display());
+    Ok(())
+}
+
+/// Shader directories of the workspace crates
+const SHADER_DIRS: &[&str] = &["shaders", "icesl2voxel/shaders"];
+
+/// glslc stage name of a shader file, `None` for included sources
+fn shader_stage(path: &Path) -> Option<&'static str> {
+    match path.extension().and_then(OsStr::to_str)? {
+        "vert" => Some("vertex"),
+        "frag" => Some("fragment"),
+        "comp" => Some("compute"),
+        "geom" => Some("geometry"),
+        "tesc" => Some("tesscontrol"),
+        "tese" => Some("tesseval"),
+        _ => None,
+    }
+}
+
+/// Shader files in `paths`, which are either shader files or directories to search recursively
+fn collect_shaders(paths: &[PathBuf]) -> Result<Vec<PathBuf>, DynError> {
+    let mut shaders = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            let entries: Vec<_> = std::fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+            shaders.extend(collect_shaders(&entries)?);
+        } else if shader_stage(path).is_some() {
+            shaders.push(path.clone());
+        }
+    }
+
+    shaders.sort();
+    Ok(shaders)
+}
+
+/// Command validating `shader` with `glslc`. Includes are resolved relative to the shader
+/// directory and uniforms are mapped for OpenGL, as tinygl-compiler does in the build scripts.
+fn glslc_command(glslc: &Path, shader: &Path) -> Command {
+    let mut command = Command::new(glslc);
+    command
+        .arg(format!(
+            "-fshader-stage={}",
+            shader_stage(shader).unwrap_or("vertex")
+        ))
+        .args([
+            "--target-env=opengl",
+            "-fauto-map-locations",
+            "-fauto-bind-uniforms",
+        ])
+        .arg("-I")
+        .arg(shader.parent().unwrap_or_else(|| Path::new(".")))
+        .args(["-c", "-o"])
+        .arg(if cfg!(windows) { "NUL" } else { "/dev/null" })
+        .arg(shader);
+    command
+}
+
+/// Validate `shader`, returning whether it compiled and the compiler messages
+fn validate_shader(glslc: &Path, shader: &Path) -> Result<(bool, String), DynError> {
+    let output = glslc_command(glslc, shader).output()?;
+    let mut messages = String::from_utf8_lossy(&output.stdout).into_owned();
+    messages.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    Ok((output.status.success(), messages.trim().to_owned()))
+}
+
+fn shaders(extra_paths: Vec<PathBuf>) -> Result<(), DynError> {
+    let glslc = find_tool(&["glslc"], env::var_os("PATH")).map_err(|e| {
+        format!(
+            "{}\nglslc is part of shaderc, install it or the Vulkan SDK",
+            e
+        )
+    })?;
+
+    let paths: Vec<_> = SHADER_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .chain(extra_paths)
+        .collect();
+
+    let mut failures = 0;
+    for shader in collect_shaders(&paths)? {
+        let (ok, messages) = validate_shader(&glslc, &shader)?;
+        println!("{} {}", if ok { "ok  " } else { "FAIL" }, shader.display());
+
+        for line in messages.lines() {
+            println!("     {}", line);
+        }
+
+        if !ok {
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        return Err(format!("{} shader(s) failed to compile", failures).into());
+    }
+
+    Ok(())
+}
+
+/// Run the gl-probe helper, returning its JSON output or `None` if no context could be created
+fn gl_probe() -> Result<Option<Vec<(String, String)>>, DynError> {
+    build_phasor(false)?;
+
+    let output = Command::new(
+        env::current_dir()?
+            .join("target/debug")
+            .join(format!("gl-probe{}", env::consts::EXE_SUFFIX)),
+    )
+    .output()?;
+
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    parse_json_object(&stdout)
+        .map(Some)
+        .ok_or_else(|| format!("invalid gl-probe output: {}", stdout).into())
+}
+
+fn gpu_info() -> Result<(), DynError> {
+    match gl_probe()? {
+        Some(fields) => {
+            let width = fields.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+            for (key, value) in fields {
+                println!("{:width$}  {}", key, value, width = width);
+            }
+
+            Ok(())
+        }
+        None => Err("no OpenGL 4.6 context could be created".into()),
+    }
+}
+
+/// File the benchmark results are appended to
+const BENCHMARKS_CSV: &str = "benchmarks.csv";
+
+/// Exit code of `phasor bench` when no OpenGL context could be created
+const BENCH_NO_CONTEXT_EXIT_CODE: i32 = 3;
+
+/// Read a JSON string from `chars`, after its opening quote
+fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    let mut result = String::new();
+
+    loop {
+        match chars.next()? {
+            '"' => return Some(result),
+            '\\' => match chars.next()? {
+                'n' => result.push('\n'),
+                't' => result.push('\t'),
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    result.push(std::char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                }
+                c => result.push(c),
+            },
+            c => result.push(c),
+        }
+    }
+}
+
+/// Fields of a flat JSON object with string or number values, as printed by `phasor bench`
+/// and `gl-probe`. String values are unescaped.
+fn parse_json_object(line: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = Vec::new();
+
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+
+    if chars.next()? != '{' {
+        return None;
+    }
+
+    loop {
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            '}' if fields.is_empty() => break,
+            '"' => {}
+            _ => return None,
+        }
+
+        let key = parse_json_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+
+        skip_whitespace(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            parse_json_string(&mut chars)?
+        } else {
+            let mut value = String::new();
+            while let Some(c) = chars.next_if(|c| !matches!(c, ',' | '}') && !c.is_whitespace()) {
+                value.push(c);
+            }
+            value
+        };
+
+        fields.push((key, value));
+
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => {}
+            '}' => break,
+            _ => return None,
+        }
+    }
+
+    if chars.next().is_some() {
+        return None;
+    }
+
+    Some(fields)
+}
+
+/// Benchmark columns and timings from the output of `phasor bench`
+fn bench_columns(output: &str) -> Result<Vec<(String, String)>, DynError> {
+    let mut columns = Vec::new();
+
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let fields =
+            parse_json_object(line).ok_or_else(|| format!("invalid bench output: {}", line))?;
+
+        let preset = &fields
+            .iter()
+            .find(|(key, _)| key == "preset")
+            .ok_or_else(|| format!("missing preset in bench output: {}", line))?
+            .1;
+
+        columns.extend(
+            fields
+                .iter()
+                .filter(|(key, _)| key.ends_with("_ms"))
+                .map(|(key, value)| (format!("{}_{}", preset, key), value.clone())),
+        );
+    }
+
+    Ok(columns)
+}
+
+/// Contents of the benchmark CSV after appending `row` with the given `header` to `existing`
+fn append_bench_row(existing: &str, header: &[String], row: &[String]) -> Result<String, DynError> {
+    let header = header.join(",");
+    let mut result = existing.to_owned();
+
+    match existing.lines().next() {
+        None => {
+            result.push_str(&header);
+            result.push('\n');
+        }
+        Some(existing_header) if existing_header != header => {
+            return Err(format!(
+                "the columns of {} don't match the current benchmarks, move it away to start a new one",
+                BENCHMARKS_CSV
+            )
+            .into());
+        }
+        Some(_) => {
+            if !result.ends_with('\n') {
+                result.push('\n');
+            }
+        }
+    }
+
+    result.push_str(&row.join(","));
+    result.push('\n');
+    Ok(result)
+}
+
+/// Comparison of the last two rows of the benchmark CSV `contents`, one line per timing
+fn compare_bench_rows(contents: &str) -> Option<Vec<String>> {
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<_> = lines.next()?.split(',').collect();
+    let rows: Vec<Vec<_>> = lines.map(|line| line.split(',').collect()).collect();
+
+    let (previous, current) = match &rows[..] {
+        [.., previous, current] => (previous, current),
+        _ => return None,
+    };
+
+    Some(
+        header
+            .iter()
+            .zip(previous.iter().zip(current.iter()))
+            .filter(|(name, _)| name.ends_with("_ms"))
+            .filter_map(|(name, (previous, current))| {
+                let previous: f64 = previous.parse().ok()?;
+                let current: f64 = current.parse().ok()?;
+                let delta = (current - previous) / previous * 100.0;
+
+                Some(format!(
+                    "{}: {} -> {} ms ({:+.1}%)",
+                    name, previous, current, delta
+                ))
+            })
+            .collect(),
+    )
+}
+
+fn bench(iterations: Option<String>) -> Result<(), DynError> {
+    let root = env::current_dir()?;
+    build_phasor(true)?;
+
+    let output = Command::new(
+        root.join("target/release")
+            .join(format!("phasor{}", env::consts::EXE_SUFFIX)),
+    )
+    .arg("bench")
+    .args(iterations)
+    .output()?;
+
+    if output.status.code() == Some(BENCH_NO_CONTEXT_EXIT_CODE) {
+        println!(
+            "skipping benchmarks, no OpenGL context could be created: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Ok(());
+    }
+
+    if !output.status.success() {
+        return Err(format!(
+            "phasor bench failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+
+    let columns = bench_columns(&String::from_utf8(output.stdout)?)?;
+    let git_hash = command_output("git", &["rev-parse", "HEAD"]).unwrap_or_else(|| {
+        eprintln!("warning: could not get the commit hash, is git installed?");
+        "unknown".to_owned()
+    });
+    let timestamp = format_timestamp(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    );
+
+    let header: Vec<_> = ["git_hash".to_owned(), "timestamp".to_owned()]
+        .iter()
+        .cloned()
+        .chain(columns.iter().map(|(name, _)| name.clone()))
+        .collect();
+    let row: Vec<_> = [git_hash, timestamp]
+        .iter()
+        .cloned()
+        .chain(columns.into_iter().map(|(_, value)| value))
+        .collect();
+
+    let path = root.join(BENCHMARKS_CSV);
+    let existing = match std::fs::read_to_string(&path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let contents = append_bench_row(&existing, &header, &row)?;
+    std::fs::write(&path, &contents)?;
+
+    match compare_bench_rows(&contents) {
+        Some(comparison) => {
+            println!("compared to the previous run:");
+            for line in comparison {
+                println!("  {}", line);
+            }
+        }
+        None => println!("first run recorded in {}", path.display()),
+    }
+
+    Ok(())
+}
+
+/// Output directory of the profile task, relative to the crate root
+const PROFILES_DIR: &str = "target/profiles";
+
+/// Binary profiled by the profile task
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProfileTarget {
+    Icesl2voxel,
+    Phasor,
+}
+
+impl ProfileTarget {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Icesl2voxel => "icesl2voxel",
+            Self::Phasor => "phasor",
+        }
+    }
+}
+
+/// Options of the profile task
+#[derive(Debug, PartialEq)]
+struct ProfileOptions {
+    target: ProfileTarget,
+    /// Arguments passed to the profiled binary, after `--`
+    args: Vec<OsString>,
+}
+
+impl ProfileOptions {
+    fn parse(mut args: impl Iterator<Item = OsString>) -> Result<Self, DynError> {
+        let mut target = ProfileTarget::Icesl2voxel;
+
+        while let Some(arg) = args.next() {
+            let arg = arg
+                .into_string()
+                .map_err(|arg| format!("invalid profile option: {}", arg.to_string_lossy()))?;
+
+            let bin = if arg == "--" {
+                break;
+            } else if arg == "--bin" {
+                args.next()
+                    .ok_or("--bin requires a value")?
+                    .to_string_lossy()
+                    .into_owned()
+            } else if let Some(bin) = arg.strip_prefix("--bin=") {
+                bin.to_owned()
+            } else {
+                return Err(format!("unknown profile option: {}", arg).into());
+            };
+
+            target = match bin.as_str() {
+                "icesl2voxel" => ProfileTarget::Icesl2voxel,
+                "phasor" => ProfileTarget::Phasor,
+                _ => {
+                    return Err(
+                        format!("unknown binary {}, expected icesl2voxel or phasor", bin).into(),
+                    )
+                }
+            };
+        }
+
+        let args: Vec<_> = args.collect();
+        if target == ProfileTarget::Icesl2voxel && args.is_empty() {
+            return Err(
+                "pass the icesl2voxel arguments after --, e.g. -- -i file.xml -o file.h5 -g file.gcode"
+                    .into(),
+            );
+        }
+
+        Ok(Self { target, args })
+    }
+}
+
+/// Path of the flamegraph recorded at `secs` since the UNIX epoch
+fn profile_output_path(root: &Path, secs: u64) -> PathBuf {
+    // Basic ISO 8601 format, colons aren't allowed in Windows file names
+    let timestamp = format_timestamp(secs).replace(&['-', ':'][..], "");
+    root.join(PROFILES_DIR).join(format!("{}.svg", timestamp))
+}
+
+/// Command recording a flamegraph of the release build of `options.target` into `output`.
+/// Debug info is enabled for the release profile through the environment, so the manifests
+/// don't have to be changed.
+fn profile_command(cargo: &OsStr, root: &Path, options: &ProfileOptions, output: &Path) -> Command {
+    let mut command = Command::new(cargo);
+    command
+        .current_dir(root)
+        .env("CARGO_PROFILE_RELEASE_DEBUG", "true")
+        .arg("flamegraph")
+        .arg("--output")
+        .arg(output);
+
+    match options.target {
+        // icesl2voxel is a separate package, it still runs from the root so paths to the input
+        // files are relative to it
+        ProfileTarget::Icesl2voxel => {
+            command
+                .arg("--manifest-path")
+                .arg(root.join("icesl2voxel/Cargo.toml"))
+                .args(["--bin", "icesl2voxel", "--"]);
+        }
+        // The benchmarks are the headless batch mode of phasor
+        ProfileTarget::Phasor => {
+            command.args(["--bin", "phasor", "--", "bench"]);
+        }
+    }
+
+    command.args(&options.args);
+    command
+}
+
+fn profile(options: ProfileOptions) -> Result<(), DynError> {
+    find_tool(&["cargo-flamegraph"], env::var_os("PATH")).map_err(|e| {
+        format!(
+            "{}\ninstall it with `cargo install flamegraph`, it also needs perf on Linux",
+            e
+        )
+    })?;
+
+    let root = env::current_dir()?;
+    let output = profile_output_path(
+        &root,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    );
+    std::fs::create_dir_all(output.parent().unwrap())?;
+
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let status = profile_command(&cargo, &root, &options, &output).status()?;
+    if !status.success() {
+        return Err(format!("cargo flamegraph failed for {}", options.target.name()).into());
+    }
+
+    println!("wrote {}", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renderdoccmd_arguments() {
+        let command = renderdoc_command(
+            Path::new("/usr/bin/renderdoccmd"),
+            Path::new("/src/phasor"),
+            Path::new("/src/phasor/target/debug/phasor"),
+            &["--width".into(), "512".into()],
+            Path::new("/src/phasor/target/debug/phasor.cap"),
+        );
+
+        assert_eq!(command.get_program(), "/usr/bin/renderdoccmd");
+        assert_eq!(command.get_current_dir(), Some(Path::new("/src/phasor")));
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "capture",
+                "--wait-for-exit",
+                "--working-dir",
+                "/src/phasor",
+                "/src/phasor/target/debug/phasor",
+                "--width",
+                "512"
+            ]
+        );
+    }
+
+    #[test]
+    fn qrenderdoc_arguments() {
+        let command = renderdoc_command(
+            Path::new("/usr/bin/qrenderdoc"),
+            Path::new("/src/phasor"),
+            Path::new("/src/phasor/target/debug/phasor"),
+            &["--width".into()],
+            Path::new("/src/phasor/target/debug/phasor.cap"),
+        );
+
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["/src/phasor/target/debug/phasor.cap"]
+        );
+
+        let settings = renderdoc_capture_settings(
+            Path::new("/src/phasor"),
+            Path::new("/src/phasor/target/debug/phasor"),
+            &["--title".into(), "a \"b\" c".into()],
+        );
+        assert!(settings.contains(r#""commandLine": "--title \"a \"b\" c\"""#));
+        assert!(settings.contains(r#""workingDir": "/src/phasor""#));
+    }
+
+    fn test_info() -> BuildInfo {
+        BuildInfo {
+            version: "0.1.0".to_owned(),
+            git_hash: "unknown".to_owned(),
+            target: "x86_64-unknown-linux-gnu".to_owned(),
+            timestamp: format_timestamp(1_610_373_751),
+        }
+    }
+
+    #[test]
+    fn dist_manifest() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+
+        assert_eq!(
+            test_info().manifest(),
+            r#"{
+    "version": "0.1.0",
+    "git_hash": "unknown",
+    "target": "x86_64-unknown-linux-gnu",
+    "build_timestamp": "2021-01-11T14:02:31Z"
+}
+"#
+        );
+
+        assert_eq!(
+            package_version(
+                "[package]\nname = \"phasor\"\nversion = \"0.2.1\"\n\n[dependencies]\nlog = \"0.4.8\"\n"
+            ),
+            Some("0.2.1")
+        );
+        assert_eq!(package_version("[dependencies]\nversion = \"1\"\n"), None);
+    }
+
+    #[test]
+    fn dist_layout() {
+        let layout = DistLayout::new(Path::new("/src/phasor"), &test_info());
+        let dir = Path::new("/src/phasor/dist/phasor-0.1.0-x86_64-unknown-linux-gnu");
+
+        assert_eq!(layout.dir, dir);
+        assert_eq!(
+            layout.archive,
+            Path::new("/src/phasor/dist/phasor-0.1.0-x86_64-unknown-linux-gnu.zip")
+        );
+        assert_eq!(layout.manifest, dir.join("MANIFEST.json"));
+
+        let library = format!(
+            "{}phasor{}",
+            env::consts::DLL_PREFIX,
+            env::consts::DLL_SUFFIX
+        );
+        assert_eq!(
+            layout.files,
+            [
+                (
+                    Path::new("/src/phasor/target/release").join(&library),
+                    dir.join(&library)
+                ),
+                (
+                    PathBuf::from("/src/phasor/phasoropt.h"),
+                    dir.join("phasoropt.h")
+                ),
+            ]
+        );
+    }
+
+    /// Temporary shader tree with one valid and one broken shader sharing an include
+    fn shader_tree() -> PathBuf {
+        let root = env::temp_dir().join(format!("xtask-shaders-{}", std::process::id()));
+        let dir = root.join("nested");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("shared.h"), "#define VALUE 1.0\n").unwrap();
+        std::fs::write(
+            dir.join("good.frag"),
+            "#version 460 core\n#include \"shared.h\"\nlayout(location = 0) out vec4 color;\nvoid main() { color = vec4(VALUE); }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("broken.comp"),
+            "#version 460 core\nlayout(local_size_x = 1) in;\nvoid main() { undefined_call(); }\n",
+        )
+        .unwrap();
+
+        root
+    }
+
+    #[test]
+    fn validate_shader_tree() {
+        let root = shader_tree();
+
+        let shaders = collect_shaders(std::slice::from_ref(&root)).unwrap();
+        assert_eq!(
+            shaders,
+            [root.join("broken.comp"), root.join("nested/good.frag")]
+        );
+
+        assert_eq!(shader_stage(Path::new("opt.comp")), Some("compute"));
+        assert_eq!(shader_stage(Path::new("gabor.glsl")), None);
+
+        match find_tool(&["glslc"], env::var_os("PATH")) {
+            Ok(glslc) => {
+                let (ok, messages) = validate_shader(&glslc, &shaders[0]).unwrap();
+                assert!(!ok);
+                assert!(messages.contains("undefined_call"));
+
+                assert!(validate_shader(&glslc, &shaders[1]).unwrap().0);
+            }
+            Err(_) => eprintln!("skipped: glslc not found"),
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    const BENCH_OUTPUT: &str = r#"{"preset": "k16_b1.00", "kernel_count": 16, "noise_bandwidth": 1, "init_ms": 1.5000, "optimize_ms": 4.0000, "display_ms": 2.0000}
+{"preset": "k32_b1.00", "kernel_count": 32, "noise_bandwidth": 1, "init_ms": 3.0000, "optimize_ms": 8.0000, "display_ms": 4.0000}
+"#;
+
+    const BENCH_CSV: &str = "git_hash,timestamp,k16_b1.00_init_ms,k16_b1.00_optimize_ms
+abc123,2021-01-11T14:02:31Z,2.0000,4.0000
+";
+
+    #[test]
+    fn parse_gl_probe_output() {
+        let fields = parse_json_object(
+            r#"{"vendor": "Intel", "renderer": "Mesa Intel(R) UHD Graphics 620 (KBL GT2), \"test\"", "version": "4.6 (Core Profile) Mesa 20.3.2", "max_texture_size": 16384, "max_compute_work_group_size_x": 1024}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fields,
+            [
+                ("vendor".to_owned(), "Intel".to_owned()),
+                (
+                    "renderer".to_owned(),
+                    r#"Mesa Intel(R) UHD Graphics 620 (KBL GT2), "test""#.to_owned()
+                ),
+                (
+                    "version".to_owned(),
+                    "4.6 (Core Profile) Mesa 20.3.2".to_owned()
+                ),
+                ("max_texture_size".to_owned(), "16384".to_owned()),
+                (
+                    "max_compute_work_group_size_x".to_owned(),
+                    "1024".to_owned()
+                ),
+            ]
+        );
+
+        assert_eq!(parse_json_object("{}"), Some(vec![]));
+        assert_eq!(parse_json_object(r#"{"vendor": "Intel""#), None);
+        assert_eq!(parse_json_object(r#"{"vendor": "Intel"} trailing"#), None);
+        assert_eq!(parse_json_object("no OpenGL context available"), None);
+    }
+
+    #[test]
+    fn bench_output_columns() {
+        let columns = bench_columns(BENCH_OUTPUT).unwrap();
+        let names: Vec<_> = columns.iter().map(|(name, _)| name.as_str()).collect();
+
+        assert_eq!(
+            names,
+            [
+                "k16_b1.00_init_ms",
+                "k16_b1.00_optimize_ms",
+                "k16_b1.00_display_ms",
+                "k32_b1.00_init_ms",
+                "k32_b1.00_optimize_ms",
+                "k32_b1.00_display_ms"
+            ]
+        );
+        assert_eq!(columns[1].1, "4.0000");
+
+        assert!(bench_columns("not json").is_err());
+        assert!(bench_columns(r#"{"init_ms": 1.0}"#).is_err());
+    }
+
+    #[test]
+    fn bench_csv_append_and_compare() {
+        let header: Vec<_> = BENCH_CSV
+            .lines()
+            .next()
+            .unwrap()
+            .split(',')
+            .map(String::from)
+            .collect();
+        let row: Vec<_> = ["def456", "2021-01-12T00:00:00Z", "1.5000", "5.0000"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        // New file
+        let contents = append_bench_row("", &header, &row).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(compare_bench_rows(&contents), None);
+
+        // Existing file
+        let contents = append_bench_row(BENCH_CSV, &header, &row).unwrap();
+        assert_eq!(
+            contents.lines().last(),
+            Some("def456,2021-01-12T00:00:00Z,1.5000,5.0000")
+        );
+        assert_eq!(
+            compare_bench_rows(&contents).unwrap(),
+            [
+                "k16_b1.00_init_ms: 2 -> 1.5 ms (-25.0%)",
+                "k16_b1.00_optimize_ms: 4 -> 5 ms (+25.0%)"
+            ]
+        );
+
+        // Mismatched columns
+        assert!(append_bench_row(BENCH_CSV, &header[..3], &row[..3]).is_err());
+    }
+
+    fn profile_args(args: &[&str]) -> Result<ProfileOptions, DynError> {
+        ProfileOptions::parse(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn profile_arguments() {
+        assert_eq!(
+            profile_args(&["--", "-i", "file.xml"]).unwrap(),
+            ProfileOptions {
+                target: ProfileTarget::Icesl2voxel,
+                args: vec!["-i".into(), "file.xml".into()],
+            }
+        );
+        assert_eq!(
+            profile_args(&["--bin", "phasor"]).unwrap(),
+            ProfileOptions {
+                target: ProfileTarget::Phasor,
+                args: vec![],
+            }
+        );
+        assert_eq!(
+            profile_args(&["--bin=phasor", "--", "--bin", "20"])
+                .unwrap()
+                .args,
+            ["--bin", "20"]
+        );
+        assert!(profile_args(&[]).is_err());
+        assert!(profile_args(&["--bin"]).is_err());
+        assert!(profile_args(&["--bin", "xtask", "--", "a"]).is_err());
+        assert!(profile_args(&["--release"]).is_err());
+    }
+
+    #[test]
+    fn profile_commands() {
+        let root = Path::new("/src/phasor");
+        let output = Path::new("/src/phasor/target/profiles/20210111T140231Z.svg");
+
+        let command = profile_command(
+            OsStr::new("cargo"),
+            root,
+            &profile_args(&["--", "-i", "fixtures/part.xml"]).unwrap(),
+            output,
+        );
+        assert_eq!(command.get_program(), "cargo");
+        assert_eq!(command.get_current_dir(), Some(root));
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            [(
+                OsStr::new("CARGO_PROFILE_RELEASE_DEBUG"),
+                Some(OsStr::new("true"))
+            )]
+        );
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "flamegraph",
+                "--output",
+                "/src/phasor/target/profiles/20210111T140231Z.svg",
+                "--manifest-path",
+                "/src/phasor/icesl2voxel/Cargo.toml",
+                "--bin",
+                "icesl2voxel",
+                "--",
+                "-i",
+                "fixtures/part.xml"
+            ]
+        );
+
+        let command = profile_command(
+            OsStr::new("cargo"),
+            root,
+            &profile_args(&["--bin", "phasor", "--", "5"]).unwrap(),
+            output,
+        );
+        assert_eq!(
+            command.get_args().skip(3).collect::<Vec<_>>(),
+            ["--bin", "phasor", "--", "bench", "5"]
+        );
+    }
+
+    #[test]
+    fn profile_output_paths() {
+        assert_eq!(
+            profile_output_path(Path::new("/src/phasor"), 1_610_373_751),
+            Path::new("/src/phasor/target/profiles/20210111T140231Z.svg")
+        );
+    }
+
+    #[test]
+    fn find_tool_lists_searched_paths() {
+        let dir = env::temp_dir().join(format!("xtask_find_tool_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tool = dir
+            .join("qrenderdoc")
+            .with_extension(env::consts::EXE_EXTENSION);
+        std::fs::write(&tool, "").unwrap();
+
+        let path = env::join_paths([Path::new("/nonexistent"), &dir]).unwrap();
+        assert_eq!(
+            find_tool(RENDERDOC_TOOLS, Some(path.clone())).unwrap(),
+            tool
+        );
+
+        let error = find_tool(&["renderdoccmd"], Some(path))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("renderdoccmd"));
+        assert!(error.contains("/nonexistent"));
+        assert!(error.contains(&dir.display().to_string()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}